@@ -0,0 +1,101 @@
+//! Api keys of `--acl`, each one allowed a level of commands and possibly only the keys
+//! under some prefixes, in place of the single `--api-key`.
+//!
+//! The file holds one key per line, `<api key> <level> [<key prefix>...]`, the level being
+//! `read-only`, `read-write` or `admin`, each allowing the commands of the previous one.
+//! Blank lines and lines starting with `#` are skipped.
+
+use std::{collections::HashMap, fs, path::Path, str::FromStr, sync::Arc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Level {
+    ReadOnly,
+    ReadWrite,
+    Admin,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-only" => Ok(Level::ReadOnly),
+            "read-write" => Ok(Level::ReadWrite),
+            "admin" => Ok(Level::Admin),
+            _ => Err(format!("unknown level {}, expected read-only, read-write or admin", s)),
+        }
+    }
+}
+
+/// What the holder of an api key may run.
+#[derive(Debug)]
+pub(crate) struct Grant {
+    level: Level,
+    // any key when empty
+    prefixes: Vec<String>,
+}
+
+impl Grant {
+    /// Checks a command, an admin endpoint or not, writing or not, on `keys`, all of which
+    /// have to be allowed. Keyless commands are refused to grants limited to some prefixes.
+    pub(crate) fn check(&self, admin: bool, write: bool, keys: &[&str]) -> Result<(), &'static str> {
+        if admin && self.level < Level::Admin {
+            return Err("admin_only");
+        }
+        if write && self.level < Level::ReadWrite {
+            return Err("read_only");
+        }
+        let allowed = |key: &str| self.prefixes.iter().any(|prefix| key.starts_with(prefix.as_str()));
+        match keys {
+            _ if self.prefixes.is_empty() => Ok(()),
+            [] => Err("key_not_allowed"),
+            keys if keys.iter().all(|key| allowed(key)) => Ok(()),
+            _ => Err("key_not_allowed"),
+        }
+    }
+}
+
+/// The grants of the api keys of the `--acl` file.
+#[derive(Debug)]
+pub(crate) struct Acl {
+    grants: HashMap<String, Arc<Grant>>,
+}
+
+impl Acl {
+    pub(crate) fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("unable to read --acl {}: {}", path.display(), e))?;
+        content.parse().map_err(|e| format!("invalid --acl {}: {}", path.display(), e))
+    }
+
+    pub(crate) fn grant(&self, api_key: &str) -> Option<Arc<Grant>> {
+        self.grants.get(api_key).cloned()
+    }
+}
+
+impl FromStr for Acl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut grants = HashMap::new();
+        for (number, line) in s.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut tokens = line.split_ascii_whitespace();
+            let (Some(api_key), Some(level)) = (tokens.next(), tokens.next()) else {
+                return Err(format!("line {}: expected <api key> <level> [<key prefix>...]", number));
+            };
+            let grant = Grant {
+                level: level.parse().map_err(|e| format!("line {}: {}", number, e))?,
+                prefixes: tokens.map(str::to_owned).collect(),
+            };
+            if grants.insert(api_key.to_string(), Arc::new(grant)).is_some() {
+                return Err(format!("line {}: api key listed twice", number));
+            }
+        }
+        if grants.is_empty() {
+            return Err("no api key".to_string());
+        }
+        Ok(Acl { grants })
+    }
+}
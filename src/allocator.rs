@@ -0,0 +1,136 @@
+//! The global allocator picked by the `jemalloc` or `mimalloc` feature, and what it tells
+//! about the memory of the process beyond the sizes of the records. With both features,
+//! jemalloc is used.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::errors::TransactionError;
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+/// Allocator counters in bytes, null for the ones the allocator does not keep.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct MemStats {
+    allocator: &'static str,
+    // keys and values of the records, without the map overhead
+    dataset: usize,
+    // bytes handed out to the program
+    allocated: Option<u64>,
+    // bytes of the pages holding allocations
+    active: Option<u64>,
+    // bytes of the pages in physical memory
+    resident: Option<u64>,
+    peak_resident: Option<u64>,
+    mapped: Option<u64>,
+    // unmapped but kept reserved for reuse
+    retained: Option<u64>,
+    committed: Option<u64>,
+    metadata: Option<u64>,
+    // active over allocated, the space lost between allocations
+    fragmentation_ratio: Option<f64>,
+    // resident over the dataset
+    overhead_ratio: Option<f64>,
+    heap_profiling: bool,
+}
+
+#[cfg(feature = "jemalloc")]
+pub(crate) fn stats(dataset: usize) -> MemStats {
+    use tikv_jemalloc_ctl::{epoch, profiling, stats};
+
+    // the counters are cached, refreshed by moving the epoch
+    let _ = epoch::advance();
+    let read = |value: tikv_jemalloc_ctl::Result<usize>| value.ok().map(|value| value as u64);
+    let allocated = read(stats::allocated::read());
+    let active = read(stats::active::read());
+    let resident = read(stats::resident::read());
+
+    MemStats {
+        allocator: "jemalloc",
+        dataset,
+        allocated,
+        active,
+        resident,
+        mapped: read(stats::mapped::read()),
+        retained: read(stats::retained::read()),
+        metadata: read(stats::metadata::read()),
+        fragmentation_ratio: ratio(active, allocated),
+        overhead_ratio: ratio(resident, Some(dataset as u64)),
+        heap_profiling: profiling::prof::read().unwrap_or(false),
+        ..Default::default()
+    }
+}
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+pub(crate) fn stats(dataset: usize) -> MemStats {
+    let mut info = [0usize; 8];
+    let [elapsed, user, system, rss, peak_rss, commit, peak_commit, page_faults] = &mut info;
+    // SAFETY: every pointer is to a distinct usize living through the call
+    unsafe {
+        libmimalloc_sys::mi_process_info(elapsed, user, system, rss, peak_rss, commit, peak_commit, page_faults);
+    }
+    let [_, _, _, rss, peak_rss, commit, _, _] = info;
+
+    MemStats {
+        allocator: "mimalloc",
+        dataset,
+        resident: Some(rss as u64),
+        peak_resident: Some(peak_rss as u64),
+        committed: Some(commit as u64),
+        overhead_ratio: ratio(Some(rss as u64), Some(dataset as u64)),
+        heap_profiling: false,
+        ..Default::default()
+    }
+}
+
+fn ratio(part: Option<u64>, whole: Option<u64>) -> Option<f64> {
+    match (part, whole) {
+        (Some(part), Some(whole)) if whole > 0 => Some(part as f64 / whole as f64),
+        _ => None,
+    }
+}
+
+/// Writes a heap profile to `path`, by default `mapper.<pid>.<unix time>.heap` in the
+/// working directory, returning the path written.
+///
+/// Only jemalloc profiles the heap, once started with profiling on: `prof:true` in the
+/// `_RJEM_MALLOC_CONF` environment variable.
+pub(crate) fn dump_heap_profile(path: Option<String>) -> Result<String, TransactionError> {
+    let path = path.unwrap_or_else(|| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        format!("mapper.{}.{}.heap", std::process::id(), now)
+    });
+    dump(&path)?;
+    Ok(path)
+}
+
+#[cfg(feature = "jemalloc")]
+fn dump(path: &str) -> Result<(), TransactionError> {
+    use std::ffi::{c_char, CString};
+
+    use tikv_jemalloc_ctl::{profiling, raw};
+
+    if !profiling::prof::read().unwrap_or(false) {
+        return Err(TransactionError::HeapProfilingUnavailable);
+    }
+    let path = CString::new(path).map_err(|_| TransactionError::HeapProfilingUnavailable)?;
+    // SAFETY: prof.dump takes a nul terminated path, alive until the call returns
+    unsafe { raw::write(b"prof.dump\0", path.as_ptr() as *const c_char) }.map_err(|e| {
+        log::error!("heap profile dump failed: {}", e);
+        TransactionError::HeapProfilingUnavailable
+    })
+}
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+fn dump(_path: &str) -> Result<(), TransactionError> {
+    Err(TransactionError::HeapProfilingUnavailable)
+}
@@ -0,0 +1,642 @@
+//! Append-only file of `--aof`: the changes to the dataset are appended as they happen and
+//! replayed at startup over the backup recovered, so a crash loses what `--aof-fsync` left
+//! unsynced instead of every write since the last backup.
+//!
+//! The file starts with a header, then holds one entry per change: a record as it was right
+//! after it, the removal of a key or a flush, each with the journal sequence number of the
+//! change. Like the mirror, the keys the journal lists are read back when appended, a key
+//! changed several times between two appends goes once. Entries the recovered backup already
+//! holds are skipped on replay. Once the file has grown by `--aof-rewrite-percentage` since
+//! it was last written whole, it is rewritten as a flush followed by every record.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info, warn};
+use smol::{
+    channel::{self, Receiver, Sender},
+    fs::File,
+    future::FutureExt,
+    io::AsyncWriteExt,
+    Task, Timer,
+};
+
+use crate::{
+    errors::TransactionError,
+    journal::{Change, ChangeKind},
+    record::Record,
+    storage::{Snapshot, Storage},
+};
+
+pub(crate) const DEFAULT_AOF_FSYNC: &str = "everysec";
+pub(crate) const DEFAULT_AOF_REWRITE_PERCENTAGE: u64 = 100;
+// 64 MiB
+pub(crate) const DEFAULT_AOF_REWRITE_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+const AOF_MAGIC: &[u8; 9] = b"MAPPERAOF";
+const AOF_FORMAT_VERSION: u16 = 1;
+// magic + version (u16), little endian
+const AOF_HEADER_LEN: usize = AOF_MAGIC.len() + 2;
+
+// entries, their integers little endian: the tag, then the sequence number (u64)
+// followed by the length (u32) of the bincode encoded key and record
+const ENTRY_SET: u8 = b'S';
+// followed by the length (u32) of the key and the key
+const ENTRY_DEL: u8 = b'D';
+const ENTRY_FLUSH: u8 = b'F';
+
+// how often the journal is read when no write waits for its changes to be synced
+const POLL: Duration = Duration::from_millis(100);
+const EVERYSEC: Duration = Duration::from_secs(1);
+// pause after a failed append or rewrite, before trying again
+const RETRY: Duration = Duration::from_secs(1);
+// time left to the last changes to get synced at shutdown
+const DRAIN: Duration = Duration::from_secs(5);
+const REPLAY_BATCH: usize = 512;
+// rewritten records are written to disk by chunks of about this size
+const REWRITE_CHUNK: usize = 1024 * 1024;
+
+/// When the appended changes are synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Fsync {
+    /// Before a write is answered, a write answered survives a power loss.
+    Always,
+    /// Every second at most, a crash of the machine loses about a second of writes.
+    EverySec,
+    /// Left to the operating system.
+    No,
+}
+
+impl FromStr for Fsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::EverySec),
+            "no" => Ok(Fsync::No),
+            _ => Err(format!("unknown fsync policy {}, expected always, everysec or no", s)),
+        }
+    }
+}
+
+impl fmt::Display for Fsync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fsync::Always => write!(f, "always"),
+            Fsync::EverySec => write!(f, "everysec"),
+            Fsync::No => write!(f, "no"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Aof {
+    path: PathBuf,
+    fsync: Fsync,
+    // growth since the last rewrite that triggers the next one, 0 for none
+    rewrite_percentage: u64,
+    rewrite_min_size: u64,
+    // time the first write waiting to be synced gives others to share its sync
+    commit_window: Duration,
+    // a damaged file fails the startup instead of being cut at the damage
+    strict: bool,
+    // wakes the writer ahead of its next poll
+    wake: (Sender<()>, Receiver<()>),
+    // a rewrite asked by /ADMIN/AOF/REWRITE, made by the writer once woken
+    rewrite_requested: AtomicBool,
+    inner: Mutex<AofInner>,
+}
+
+#[derive(Debug, Default)]
+struct AofInner {
+    // last journal change appended and synced
+    synced: u64,
+    // writes waiting for the change of a sequence number to be synced
+    waiters: Vec<(u64, Sender<bool>)>,
+    // syncs of appended changes, and the waiting writes they answered
+    syncs: u64,
+    synced_writes: u64,
+    size: u64,
+    rewrites: u64,
+    // seconds since the unix epoch
+    last_rewrite_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl Aof {
+    pub(crate) fn new(
+        path: PathBuf,
+        fsync: Fsync,
+        rewrite_percentage: u64,
+        rewrite_min_size: u64,
+        commit_window: Duration,
+        strict: bool,
+    ) -> Self {
+        Self {
+            path,
+            fsync,
+            rewrite_percentage,
+            rewrite_min_size,
+            commit_window,
+            strict,
+            wake: channel::bounded(1),
+            rewrite_requested: AtomicBool::new(false),
+            inner: Mutex::new(AofInner::default()),
+        }
+    }
+
+    /// Whether writes are answered only once synced, see [`Aof::synced`].
+    pub(crate) fn syncs_every_write(&self) -> bool {
+        self.fsync == Fsync::Always
+    }
+
+    /// Waits for the changes up to `seq` to be appended and synced, whatever the policy.
+    pub(crate) async fn synced(&self, seq: u64) -> Result<(), TransactionError> {
+        let receiver = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.synced >= seq {
+                return Ok(());
+            }
+            let (sender, receiver) = channel::bounded(1);
+            inner.waiters.push((seq, sender));
+            receiver
+        };
+        let _ = self.wake.0.try_send(());
+        match receiver.recv().await {
+            Ok(true) => Ok(()),
+            _ => Err(TransactionError::AofWriteFailed),
+        }
+    }
+
+    /// Has the writer rewrite the file in the background, whatever its growth.
+    pub(crate) fn request_rewrite(&self) {
+        self.rewrite_requested.store(true, Ordering::Relaxed);
+        let _ = self.wake.0.try_send(());
+    }
+
+    /// `name:value` lines of the persistence section of `/INFO`.
+    pub(crate) fn info(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut lines = vec![
+            format!("aof_fsync:{}", self.fsync),
+            format!("aof_size:{}", inner.size),
+            format!("aof_syncs:{}", inner.syncs),
+            format!("aof_synced_writes:{}", inner.synced_writes),
+            format!("aof_rewrites:{}", inner.rewrites),
+        ];
+        if let Some(at) = inner.last_rewrite_at {
+            lines.push(format!("aof_last_rewrite_at:{}", at));
+        }
+        if let Some(error) = &inner.last_error {
+            lines.push(format!("aof_last_error:{}", error));
+        }
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    fn appended(&self, size: u64) {
+        self.inner.lock().unwrap().size = size;
+    }
+
+    // marks the changes up to `seq` synced, answering the writes waiting for them
+    fn mark_synced(&self, seq: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.synced = inner.synced.max(seq);
+        inner.last_error = None;
+        let synced = inner.synced;
+        let (answered, waiting): (Vec<_>, Vec<_>) = inner.waiters.drain(..).partition(|(seq, _)| *seq <= synced);
+        inner.waiters = waiting;
+        inner.synced_writes += answered.len() as u64;
+        for (_, waiter) in answered {
+            let _ = waiter.try_send(true);
+        }
+    }
+
+    // fails the writes waiting for their changes
+    fn mark_failed(&self, error: String) {
+        error!("{}", error);
+        let mut inner = self.inner.lock().unwrap();
+        for (_, waiter) in inner.waiters.drain(..) {
+            let _ = waiter.try_send(false);
+        }
+        inner.last_error = Some(error);
+    }
+
+    fn has_waiters(&self) -> bool {
+        !self.inner.lock().unwrap().waiters.is_empty()
+    }
+}
+
+/// Appends the changes of the dataset to the `--aof` file, from the end of its replay on.
+pub(crate) struct AofWriter {
+    aof: Arc<Aof>,
+    storage: Storage,
+    task: Task<()>,
+}
+
+impl AofWriter {
+    /// Replays the file over the records recovered, then appends the changes that follow.
+    /// Entries the recovered backup already holds are skipped. A file cut short or damaged
+    /// is truncated to the entries in front of the damage, unless `--strict-recovery`.
+    pub(crate) async fn start(aof: Arc<Aof>, storage: Storage) -> Result<Self, String> {
+        let recovered = storage.journal.last_seq();
+        let content = match smol::unblock({
+            let path = aof.path.clone();
+            move || fs::read(path)
+        })
+        .await
+        {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("unable to read --aof {}: {}", aof.path.display(), e)),
+        };
+
+        let size = match content.is_empty() {
+            true => write_header(&aof.path)?,
+            false => replay(&aof, &storage, &content, recovered).await?,
+        };
+        aof.appended(size);
+        aof.mark_synced(storage.journal.last_seq());
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&aof.path)
+            .map_err(|e| format!("unable to open --aof {}: {}", aof.path.display(), e))?;
+        let writer = Writer {
+            aof: aof.clone(),
+            storage: storage.clone(),
+            file: File::from(file),
+            position: storage.journal.last_seq(),
+            size,
+            base_size: size,
+            last_sync: Instant::now(),
+        };
+        info!("appending changes to {} with fsync {}", aof.path.display(), aof.fsync);
+        let task = smol::spawn(writer.run());
+        Ok(Self { aof, storage, task })
+    }
+
+    /// Gives the writer a few seconds to append and sync the last changes, then stops it.
+    pub(crate) async fn stop(self) {
+        let seq = self.storage.journal.last_seq();
+        let drained = self
+            .aof
+            .synced(seq)
+            .or(async {
+                Timer::after(DRAIN).await;
+                Err(TransactionError::DeadlineExceeded)
+            })
+            .await;
+        self.task.cancel().await;
+        if let Err(e) = drained {
+            warn!("aof stopped before syncing the last changes: {}", e);
+        }
+    }
+}
+
+struct Writer {
+    aof: Arc<Aof>,
+    storage: Storage,
+    file: File,
+    // last journal change appended
+    position: u64,
+    size: u64,
+    // size right after the file was last written whole, or replayed
+    base_size: u64,
+    last_sync: Instant,
+}
+
+impl Writer {
+    async fn run(mut self) {
+        loop {
+            let wake = self.aof.wake.1.clone();
+            Timer::after(POLL)
+                .or(async {
+                    let _ = wake.recv().await;
+                    Instant::now()
+                })
+                .await;
+            // group commit: the writes arriving meanwhile share the sync of the waiting ones
+            if !self.aof.commit_window.is_zero() && self.aof.has_waiters() {
+                Timer::after(self.aof.commit_window).await;
+            }
+
+            if let Err(e) = self.append().await {
+                self.aof.mark_failed(e);
+                Timer::after(RETRY).await;
+                continue;
+            }
+            if self.aof.rewrite_requested.swap(false, Ordering::Relaxed) || self.grown() {
+                if let Err(e) = self.rewrite().await {
+                    self.aof.mark_failed(e);
+                    // tried again after as much growth
+                    self.base_size = self.size;
+                }
+            }
+        }
+    }
+
+    // appends the changes since the last append, syncing them as the policy asks
+    async fn append(&mut self) -> Result<(), String> {
+        let changes = match self.storage.journal.since(self.position) {
+            Ok(changes) => changes,
+            Err(_) => {
+                warn!("the journal dropped changes not appended yet, rewriting {}", self.aof.path.display());
+                return self.rewrite().await;
+            }
+        };
+        let Some(last) = changes.last().map(|change| change.seq) else {
+            return self.sync(self.position).await;
+        };
+
+        let mut entries = Vec::new();
+        // a flush goes on its own, the keys changed around it are read before or after it
+        for segment in changes.split_inclusive(|change| matches!(change.kind, ChangeKind::FlushAll)) {
+            let (keys, flush) = match segment.split_last() {
+                Some((flush, keys)) if matches!(flush.kind, ChangeKind::FlushAll) => (keys, Some(flush.seq)),
+                _ => (segment, None),
+            };
+            encode_changes(&self.storage, keys, &mut entries).await?;
+            if let Some(seq) = flush {
+                encode_flush(&mut entries, seq);
+            }
+        }
+
+        let written = async {
+            self.file.write_all(&entries).await?;
+            self.file.flush().await
+        }
+        .await;
+        if let Err(e) = written {
+            // a partial entry would cut the replay short
+            let _ = self.file.set_len(self.size).await;
+            return Err(format!("unable to append to {}: {}", self.aof.path.display(), e));
+        }
+        self.size += entries.len() as u64;
+        self.aof.appended(self.size);
+        self.position = last;
+        self.sync(last).await
+    }
+
+    // syncs the file if the policy or a waiting write asks for it
+    async fn sync(&mut self, seq: u64) -> Result<(), String> {
+        let due = match self.aof.fsync {
+            Fsync::Always => true,
+            Fsync::EverySec => self.last_sync.elapsed() >= EVERYSEC,
+            Fsync::No => false,
+        };
+        if !due && !self.aof.has_waiters() {
+            return Ok(());
+        }
+        self.file
+            .sync_data()
+            .await
+            .map_err(|e| format!("unable to sync {}: {}", self.aof.path.display(), e))?;
+        self.last_sync = Instant::now();
+        self.aof.inner.lock().unwrap().syncs += 1;
+        self.aof.mark_synced(seq);
+        Ok(())
+    }
+
+    fn grown(&self) -> bool {
+        let percentage = self.aof.rewrite_percentage;
+        percentage > 0
+            && self.size >= self.aof.rewrite_min_size
+            && self.size >= self.base_size + self.base_size * percentage / 100
+    }
+
+    // writes the whole dataset to a new file replacing the current one
+    async fn rewrite(&mut self) -> Result<(), String> {
+        let started = Instant::now();
+        let snapshot = self.storage.snapshot().await.map_err(|e| e.to_string())?;
+        let seq = snapshot.seq;
+        let path = self.aof.path.clone();
+        let size = smol::unblock(move || write_snapshot(&path, &snapshot)).await?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&self.aof.path)
+            .map_err(|e| format!("unable to open {}: {}", self.aof.path.display(), e))?;
+        info!(
+            "rewrote {} from {} to {} bytes in {:?}",
+            self.aof.path.display(),
+            self.size,
+            size,
+            started.elapsed()
+        );
+        self.file = File::from(file);
+        self.position = seq;
+        self.size = size;
+        self.base_size = size;
+        self.last_sync = Instant::now();
+        {
+            let mut inner = self.aof.inner.lock().unwrap();
+            inner.rewrites += 1;
+            inner.last_rewrite_at = Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs()),
+            );
+        }
+        self.aof.appended(size);
+        self.aof.mark_synced(seq);
+        Ok(())
+    }
+}
+
+// the keys of `changes` as they are now
+async fn encode_changes(storage: &Storage, changes: &[Change], entries: &mut Vec<u8>) -> Result<(), String> {
+    let mut keys: HashMap<&str, u64> = HashMap::new();
+    for change in changes {
+        if let Some(key) = &change.key {
+            keys.insert(key, change.seq);
+        }
+    }
+    for (key, seq) in keys {
+        let Ok((_, shard)) = storage.read_key_shard(key).await else {
+            continue;
+        };
+        match shard.records.get(key) {
+            Some(wrecord) => encode_set(entries, seq.max(wrecord.version), key, &wrecord.record)?,
+            None => encode_del(entries, seq, key),
+        }
+    }
+    Ok(())
+}
+
+fn encode_set(entries: &mut Vec<u8>, seq: u64, key: &str, record: &Record) -> Result<(), String> {
+    let encoded = bincode::serialize(&(key, record)).map_err(|e| format!("unable to encode {}: {}", key, e))?;
+    entries.push(ENTRY_SET);
+    entries.extend_from_slice(&seq.to_le_bytes());
+    entries.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+    entries.extend_from_slice(&encoded);
+    Ok(())
+}
+
+fn encode_del(entries: &mut Vec<u8>, seq: u64, key: &str) {
+    entries.push(ENTRY_DEL);
+    entries.extend_from_slice(&seq.to_le_bytes());
+    entries.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    entries.extend_from_slice(key.as_bytes());
+}
+
+fn encode_flush(entries: &mut Vec<u8>, seq: u64) {
+    entries.push(ENTRY_FLUSH);
+    entries.extend_from_slice(&seq.to_le_bytes());
+}
+
+fn header() -> Vec<u8> {
+    let mut header = AOF_MAGIC.to_vec();
+    header.extend_from_slice(&AOF_FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+// starts an empty file, returning its size
+fn write_header(path: &Path) -> Result<u64, String> {
+    let header = header();
+    fs::write(path, &header).map_err(|e| format!("unable to create --aof {}: {}", path.display(), e))?;
+    Ok(header.len() as u64)
+}
+
+// writes a flush and every record of `snapshot` next to `path`, then moves it over `path`
+fn write_snapshot(path: &Path, snapshot: &Snapshot) -> Result<u64, String> {
+    let mut rewritten = path.as_os_str().to_owned();
+    rewritten.push(".rewrite");
+    let rewritten = PathBuf::from(rewritten);
+
+    let written = (|| {
+        let file = fs::File::create(&rewritten).map_err(|e| e.to_string())?;
+        let mut out = BufWriter::new(file);
+        let mut chunk = header();
+        encode_flush(&mut chunk, snapshot.seq);
+        let mut size = 0;
+        // every record is as recent as the snapshot, even when its last change is older
+        for (key, wrecord) in snapshot.shards.iter().flat_map(|records| records.iter()) {
+            encode_set(&mut chunk, snapshot.seq, key, &wrecord.record)?;
+            if chunk.len() >= REWRITE_CHUNK {
+                out.write_all(&chunk).map_err(|e| e.to_string())?;
+                size += chunk.len() as u64;
+                chunk.clear();
+            }
+        }
+        out.write_all(&chunk).map_err(|e| e.to_string())?;
+        size += chunk.len() as u64;
+        let file = out.into_inner().map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())?;
+        fs::rename(&rewritten, path).map_err(|e| e.to_string())?;
+        Ok(size)
+    })();
+    written.map_err(|e: String| {
+        let _ = fs::remove_file(&rewritten);
+        format!("unable to rewrite {}: {}", path.display(), e)
+    })
+}
+
+enum Entry {
+    Set(String, Record),
+    Del(String),
+    Flush,
+}
+
+// the entry at the start of `buff` and its length, `None` when it is cut short or damaged
+fn decode_entry(buff: &[u8]) -> Option<(u64, Entry, usize)> {
+    let tag = *buff.first()?;
+    let seq = u64::from_le_bytes(buff.get(1..9)?.try_into().ok()?);
+    if tag == ENTRY_FLUSH {
+        return Some((seq, Entry::Flush, 9));
+    }
+    let len = u32::from_le_bytes(buff.get(9..13)?.try_into().ok()?) as usize;
+    let payload = buff.get(13..13 + len)?;
+    let entry = match tag {
+        ENTRY_SET => {
+            let (key, record) = bincode::deserialize(payload).ok()?;
+            Entry::Set(key, record)
+        }
+        ENTRY_DEL => Entry::Del(String::from_utf8(payload.to_vec()).ok()?),
+        _ => return None,
+    };
+    Some((seq, entry, 13 + len))
+}
+
+// applies the entries of `content` newer than `recovered`, returning the size of the file
+// kept, without what follows a damage
+async fn replay(aof: &Aof, storage: &Storage, content: &[u8], recovered: u64) -> Result<u64, String> {
+    let path = aof.path.display();
+    match content.get(..AOF_HEADER_LEN) {
+        Some(header) if header.starts_with(AOF_MAGIC) => {
+            let version = u16::from_le_bytes([header[AOF_MAGIC.len()], header[AOF_MAGIC.len() + 1]]);
+            if version != AOF_FORMAT_VERSION {
+                return Err(format!("--aof {} has unsupported format version {}", path, version));
+            }
+        }
+        _ => return Err(format!("--aof {} is not an append-only file", path)),
+    }
+
+    let started = Instant::now();
+    let (mut offset, mut applied, mut last_seq) = (AOF_HEADER_LEN, 0, recovered);
+    let mut sets = Vec::new();
+    while offset < content.len() {
+        let Some((seq, entry, len)) = decode_entry(&content[offset..]) else {
+            break;
+        };
+        offset += len;
+        last_seq = last_seq.max(seq);
+        // already in the backup
+        if seq <= recovered {
+            continue;
+        }
+        applied += 1;
+
+        // records are applied in batches, before any other entry so the order holds
+        if let Entry::Set(key, record) = entry {
+            sets.push((key, record));
+            if sets.len() >= REPLAY_BATCH {
+                storage.set_many(std::mem::take(&mut sets)).await.map_err(|e| e.to_string())?;
+            }
+            continue;
+        }
+        if !sets.is_empty() {
+            storage.set_many(std::mem::take(&mut sets)).await.map_err(|e| e.to_string())?;
+        }
+        match entry {
+            Entry::Set(..) => unreachable!("records are applied above"),
+            Entry::Del(key) => {
+                storage.remove_record(&key).await.map_err(|e| e.to_string())?;
+            }
+            Entry::Flush => storage.flush_all().await.map_err(|e| e.to_string())?,
+        }
+    }
+    if !sets.is_empty() {
+        storage.set_many(sets).await.map_err(|e| e.to_string())?;
+    }
+    // changes made from now on come after the ones of the file
+    storage.journal.observe(last_seq);
+
+    if offset < content.len() {
+        let reason = format!("--aof {} is damaged after {} of its {} bytes", path, offset, content.len());
+        if aof.strict {
+            return Err(reason);
+        }
+        error!("{}, dropping the rest", reason);
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&aof.path)
+            .map_err(|e| format!("unable to open --aof {}: {}", path, e))?;
+        file.set_len(offset as u64)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| format!("unable to truncate --aof {}: {}", path, e))?;
+    }
+    info!("replayed {} changes of {} in {:?}", applied, path, started.elapsed());
+    Ok(offset as u64)
+}
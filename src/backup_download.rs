@@ -0,0 +1,166 @@
+use std::{
+    cell::RefCell,
+    io::{self, Seek, SeekFrom, Write},
+    rc::Rc,
+};
+
+use http_types::Body;
+use log::{debug, error};
+use smol::{
+    channel::{self, Sender},
+    io::BufReader,
+};
+use zip::{write::FileOptions, ZipWriter};
+
+use crate::{
+    backup_format::{self, BackupCompression, MdbHeader},
+    errors::TransactionError,
+    backup_handler::{get_mdb_shard, CLOCK_FILE_NAME, HASH_FILE_NAME, LAYOUT_FILE_NAME},
+    export::ChunkReader,
+    operations::Operation,
+    storage::{Snapshot, Storage},
+};
+
+// archive parts written ahead of a slow client
+const DOWNLOAD_CHUNKS_AHEAD: usize = 4;
+
+/// Streams a backup archive of the dataset as it is now, with the entries of the archives
+/// written by periodic backups, so it can be recovered from like them.
+///
+/// Shards are taken from a consistent snapshot and written one at a time by a background
+/// thread, each sent as soon as the archive moves past it. The download stops if the
+/// client goes away or it is cancelled through `/ADMIN/OPS`.
+pub(crate) async fn download(storage: &Storage, compression: BackupCompression) -> Result<Body, TransactionError> {
+    let operation = storage.operations.start(
+        "backup",
+        format!("streaming a {} backup archive", compression),
+    );
+    let snapshot = storage.snapshot().await?;
+    let (sender, receiver) = channel::bounded(DOWNLOAD_CHUNKS_AHEAD);
+    smol::spawn(smol::unblock(move || {
+        match write_archive(snapshot, compression.file_options(), &sender, &operation) {
+            Ok(()) => debug!("backup archive streamed"),
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => debug!("backup download stopped: {}", e),
+            Err(e) => {
+                error!("backup download failed: {}", e);
+                let _ = sender.send_blocking(Err(e));
+            }
+        }
+    }))
+    .detach();
+
+    let mut body = Body::from_reader(BufReader::new(ChunkReader::new(receiver)), None);
+    body.set_mime("application/zip");
+    Ok(body)
+}
+
+fn write_archive(
+    snapshot: Snapshot,
+    options: FileOptions,
+    sender: &Sender<io::Result<Vec<u8>>>,
+    operation: &Operation,
+) -> io::Result<()> {
+    let spool = Rc::new(RefCell::new(Spool::default()));
+    let mut zip = ZipWriter::new(SpoolWriter(spool.clone()));
+    let header = MdbHeader::new(snapshot.shards.len());
+    let shard_count = snapshot.shards.len() as u64;
+
+    let start_entry = |zip: &mut ZipWriter<SpoolWriter>, name: &str| -> io::Result<()> {
+        if operation.is_cancelled() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"));
+        }
+        // the previous entry is complete once the next one starts
+        let entry_start = spool.borrow().end();
+        zip.start_file(name, options).map_err(io::Error::other)?;
+        spool.borrow_mut().send_until(entry_start, sender)
+    };
+
+    for (i, records) in snapshot.shards.iter().enumerate() {
+        let content = backup_format::encode_shard(records, header).map_err(io::Error::other)?;
+        start_entry(&mut zip, &get_mdb_shard(i))?;
+        zip.write_all(&content)?;
+        operation.progress(i as u64 + 1, shard_count);
+    }
+
+    let layout = bincode::serialize(&snapshot.layout).map_err(io::Error::other)?;
+    start_entry(&mut zip, LAYOUT_FILE_NAME)?;
+    zip.write_all(&layout)?;
+
+    start_entry(&mut zip, HASH_FILE_NAME)?;
+    zip.write_all(snapshot.shard_hash.spec().as_bytes())?;
+
+    let seq = bincode::serialize(&snapshot.seq).map_err(io::Error::other)?;
+    start_entry(&mut zip, CLOCK_FILE_NAME)?;
+    zip.write_all(&seq)?;
+
+    zip.finish().map_err(io::Error::other)?;
+    let end = spool.borrow().end();
+    spool.borrow_mut().send_until(end, sender)?;
+    Ok(())
+}
+
+/// Part of the archive not sent yet.
+#[derive(Default)]
+struct Spool {
+    buff: Vec<u8>,
+    // archive offset of the first byte of `buff`
+    sent: u64,
+    pos: u64,
+}
+
+impl Spool {
+    fn end(&self) -> u64 {
+        self.sent + self.buff.len() as u64
+    }
+
+    fn send_until(&mut self, offset: u64, sender: &Sender<io::Result<Vec<u8>>>) -> io::Result<()> {
+        let len = (offset - self.sent) as usize;
+        if len == 0 {
+            return Ok(());
+        }
+        let chunk = self.buff.drain(..len).collect();
+        self.sent = offset;
+        sender
+            .send_blocking(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))
+    }
+}
+
+/// Output of the zip writer, which seeks back only into the entry it is writing.
+struct SpoolWriter(Rc<RefCell<Spool>>);
+
+impl Write for SpoolWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut spool = self.0.borrow_mut();
+        let start = (spool.pos - spool.sent) as usize;
+        let end = start + buf.len();
+        if spool.buff.len() < end {
+            spool.buff.resize(end, 0);
+        }
+        spool.buff[start..end].copy_from_slice(buf);
+        spool.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SpoolWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut spool = self.0.borrow_mut();
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => spool.pos.checked_add_signed(delta),
+            SeekFrom::End(delta) => spool.end().checked_add_signed(delta),
+        };
+        match target {
+            Some(target) if target >= spool.sent => {
+                spool.pos = target;
+                Ok(target)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "seek into a part already sent")),
+        }
+    }
+}
@@ -0,0 +1,436 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use zip::{write::FileOptions, CompressionMethod};
+
+use crate::{
+    errors::BackupFormatError,
+    record::{Record, RecordKind, TTLPolicy},
+    wrapped_record::WrappedRecord,
+};
+
+const MDB_MAGIC: &[u8; 8] = b"MAPPERDB";
+// magic + version (u16) + shard count (u32) + creation timestamp (u64), little endian
+const MDB_HEADER_LEN: usize = MDB_MAGIC.len() + 2 + 4 + 8;
+
+/// Shard files written before the header was introduced: a bare bincode map.
+pub(crate) const LEGACY_FORMAT_VERSION: u16 = 0;
+/// Header followed by the records, without their logical clock.
+const UNVERSIONED_FORMAT_VERSION: u16 = 1;
+/// Header followed by the records with their logical clock, without their kind.
+const UNTYPED_FORMAT_VERSION: u16 = 2;
+/// Header followed by the records with their logical clock and kind, without their tags.
+const UNTAGGED_FORMAT_VERSION: u16 = 3;
+/// Header followed by the records with their logical clock, kind and tags, every ttl fixed.
+const FIXED_TTL_FORMAT_VERSION: u16 = 4;
+pub(crate) const CURRENT_FORMAT_VERSION: u16 = 5;
+
+// a ttl as formats before sliding ones store it
+#[derive(Serialize, Deserialize)]
+struct FixedTtlPolicy {
+    ttl_secs: u64,
+    last_policy_update: u64,
+}
+
+impl From<&TTLPolicy> for FixedTtlPolicy {
+    fn from(policy: &TTLPolicy) -> Self {
+        Self {
+            ttl_secs: policy.ttl.as_secs(),
+            last_policy_update: policy.idle().as_secs(),
+        }
+    }
+}
+
+impl From<FixedTtlPolicy> for TTLPolicy {
+    fn from(policy: FixedTtlPolicy) -> Self {
+        TTLPolicy::started(
+            Duration::from_secs(policy.ttl_secs),
+            Instant::now() - Duration::from_secs(policy.last_policy_update),
+            false,
+        )
+    }
+}
+
+// a record along with its logical clock, as formats before the current one store it
+#[derive(Serialize, Deserialize)]
+struct LegacyWrappedRecord<R> {
+    record: R,
+    version: u64,
+}
+
+// a record as formats before the untyped one store it, every record being plain bytes
+#[derive(Serialize, Deserialize)]
+struct UntypedRecord<'a> {
+    data: Cow<'a, [u8]>,
+    ttl_policy: Option<FixedTtlPolicy>,
+}
+
+impl<'a> From<&'a Record> for UntypedRecord<'a> {
+    fn from(record: &'a Record) -> Self {
+        Self {
+            data: Cow::Borrowed(&record.data),
+            ttl_policy: record.ttl_policy.as_ref().map(FixedTtlPolicy::from),
+        }
+    }
+}
+
+impl From<UntypedRecord<'_>> for Record {
+    fn from(record: UntypedRecord<'_>) -> Self {
+        Self {
+            data: record.data.into_owned(),
+            ttl_policy: record.ttl_policy.map(TTLPolicy::from),
+            kind: RecordKind::Bytes,
+            tags: Vec::new(),
+        }
+    }
+}
+
+// a record as the untagged format stores it
+#[derive(Serialize, Deserialize)]
+struct UntaggedRecord<'a> {
+    data: Cow<'a, [u8]>,
+    ttl_policy: Option<FixedTtlPolicy>,
+    kind: RecordKind,
+}
+
+impl<'a> From<&'a Record> for UntaggedRecord<'a> {
+    fn from(record: &'a Record) -> Self {
+        Self {
+            data: Cow::Borrowed(&record.data),
+            ttl_policy: record.ttl_policy.as_ref().map(FixedTtlPolicy::from),
+            kind: record.kind,
+        }
+    }
+}
+
+impl From<UntaggedRecord<'_>> for Record {
+    fn from(record: UntaggedRecord<'_>) -> Self {
+        Self {
+            data: record.data.into_owned(),
+            ttl_policy: record.ttl_policy.map(TTLPolicy::from),
+            kind: record.kind,
+            tags: Vec::new(),
+        }
+    }
+}
+
+// a record as the fixed ttl format stores it
+#[derive(Serialize, Deserialize)]
+struct FixedTtlRecord<'a> {
+    data: Cow<'a, [u8]>,
+    ttl_policy: Option<FixedTtlPolicy>,
+    kind: RecordKind,
+    tags: Cow<'a, [String]>,
+}
+
+impl<'a> From<&'a Record> for FixedTtlRecord<'a> {
+    fn from(record: &'a Record) -> Self {
+        Self {
+            data: Cow::Borrowed(&record.data),
+            ttl_policy: record.ttl_policy.as_ref().map(FixedTtlPolicy::from),
+            kind: record.kind,
+            tags: Cow::Borrowed(&record.tags),
+        }
+    }
+}
+
+impl From<FixedTtlRecord<'_>> for Record {
+    fn from(record: FixedTtlRecord<'_>) -> Self {
+        Self {
+            data: record.data.into_owned(),
+            ttl_policy: record.ttl_policy.map(TTLPolicy::from),
+            kind: record.kind,
+            tags: record.tags.into_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MdbHeader {
+    pub(crate) version: u16,
+    pub(crate) shard_count: u32,
+    // seconds since the unix epoch
+    pub(crate) created_at: u64,
+}
+
+impl MdbHeader {
+    pub(crate) fn new(shard_count: usize) -> Self {
+        Self {
+            version: CURRENT_FORMAT_VERSION,
+            shard_count: shard_count as u32,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MDB_HEADER_LEN);
+        bytes.extend_from_slice(MDB_MAGIC);
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&self.shard_count.to_le_bytes());
+        bytes.extend_from_slice(&self.created_at.to_le_bytes());
+        bytes
+    }
+
+    /// Reads the header in front of a shard file, `None` for legacy headerless files.
+    pub(crate) fn read(buff: &[u8]) -> Result<Option<Self>, BackupFormatError> {
+        if !buff.starts_with(MDB_MAGIC) {
+            return Ok(None);
+        }
+        if buff.len() < MDB_HEADER_LEN {
+            return Err(BackupFormatError::Truncated);
+        }
+
+        let version = u16::from_le_bytes([buff[8], buff[9]]);
+        let shard_count = u32::from_le_bytes([buff[10], buff[11], buff[12], buff[13]]);
+        let mut created_at = [0u8; 8];
+        created_at.copy_from_slice(&buff[14..MDB_HEADER_LEN]);
+
+        Ok(Some(Self {
+            version,
+            shard_count,
+            created_at: u64::from_le_bytes(created_at),
+        }))
+    }
+}
+
+/// Serializes a shard in the format version set in the header.
+pub(crate) fn encode_shard(
+    records: &HashMap<String, WrappedRecord>,
+    header: MdbHeader,
+) -> Result<Vec<u8>, BackupFormatError> {
+    let mut buff = match header.version {
+        LEGACY_FORMAT_VERSION => Vec::new(),
+        UNVERSIONED_FORMAT_VERSION..=CURRENT_FORMAT_VERSION => header.to_bytes(),
+        unknown => return Err(BackupFormatError::UnsupportedVersion(unknown)),
+    };
+
+    // older formats can't tell a bloom filter from a plain value
+    if header.version < UNTAGGED_FORMAT_VERSION
+        && records.values().any(|wrecord| wrecord.record.kind != RecordKind::Bytes)
+    {
+        return Err(BackupFormatError::UnsupportedRecordKind(header.version));
+    }
+    if header.version < FIXED_TTL_FORMAT_VERSION && records.values().any(|wrecord| !wrecord.record.tags.is_empty()) {
+        return Err(BackupFormatError::UnsupportedTags(header.version));
+    }
+    let sliding = |wrecord: &WrappedRecord| wrecord.record.ttl_policy.as_ref().is_some_and(|ttl_policy| ttl_policy.sliding);
+    if header.version < CURRENT_FORMAT_VERSION && records.values().any(sliding) {
+        return Err(BackupFormatError::UnsupportedSlidingTtl(header.version));
+    }
+
+    let serialized = match header.version {
+        UNTYPED_FORMAT_VERSION => encode_legacy::<UntypedRecord>(&mut buff, records),
+        UNTAGGED_FORMAT_VERSION => encode_legacy::<UntaggedRecord>(&mut buff, records),
+        FIXED_TTL_FORMAT_VERSION => encode_legacy::<FixedTtlRecord>(&mut buff, records),
+        CURRENT_FORMAT_VERSION => bincode::serialize_into(&mut buff, records),
+        // older formats only know the record itself
+        _ => {
+            let unversioned: HashMap<&String, UntypedRecord> = records
+                .iter()
+                .map(|(key, wrecord)| (key, (&wrecord.record).into()))
+                .collect();
+            bincode::serialize_into(&mut buff, &unversioned)
+        }
+    };
+    serialized.map_err(|e| BackupFormatError::Undecodable(e.to_string()))?;
+    Ok(buff)
+}
+
+fn encode_legacy<'a, R>(buff: &mut Vec<u8>, records: &'a HashMap<String, WrappedRecord>) -> bincode::Result<()>
+where
+    R: From<&'a Record> + Serialize,
+{
+    let legacy: HashMap<&String, LegacyWrappedRecord<R>> = records
+        .iter()
+        .map(|(key, wrecord)| {
+            let legacy = LegacyWrappedRecord {
+                record: R::from(&wrecord.record),
+                version: wrecord.version,
+            };
+            (key, legacy)
+        })
+        .collect();
+    bincode::serialize_into(buff, &legacy)
+}
+
+/// Deserializes a shard file of any known format version, records coming from
+/// formats without a logical clock get version 0, those without a kind are bytes,
+/// those without tags are untagged and those without sliding ttls have fixed ones.
+pub(crate) fn decode_shard(
+    buff: &[u8],
+) -> Result<(u16, HashMap<String, WrappedRecord>), BackupFormatError> {
+    let header = MdbHeader::read(buff)?;
+    let version = header.map_or(LEGACY_FORMAT_VERSION, |header| header.version);
+
+    let records = match version {
+        LEGACY_FORMAT_VERSION => decode_map(buff, from_unversioned),
+        UNVERSIONED_FORMAT_VERSION => decode_map(&buff[MDB_HEADER_LEN..], from_unversioned),
+        UNTYPED_FORMAT_VERSION => decode_map(&buff[MDB_HEADER_LEN..], from_legacy::<UntypedRecord>),
+        UNTAGGED_FORMAT_VERSION => decode_map(&buff[MDB_HEADER_LEN..], from_legacy::<UntaggedRecord>),
+        FIXED_TTL_FORMAT_VERSION => decode_map(&buff[MDB_HEADER_LEN..], from_legacy::<FixedTtlRecord>),
+        CURRENT_FORMAT_VERSION => bincode::deserialize(&buff[MDB_HEADER_LEN..]),
+        unknown => return Err(BackupFormatError::UnsupportedVersion(unknown)),
+    };
+
+    records
+        .map(|records| (version, records))
+        .map_err(|e| BackupFormatError::Undecodable(e.to_string()))
+}
+
+/// Reads what can be read of a damaged shard file: the records in front of the damage,
+/// like [`decode_shard`] does for a whole one. Returns the format version, the records
+/// and whether every record could be read.
+pub(crate) fn salvage_shard(
+    buff: &[u8],
+) -> Result<(u16, HashMap<String, WrappedRecord>, bool), BackupFormatError> {
+    let header = MdbHeader::read(buff)?;
+    let version = header.map_or(LEGACY_FORMAT_VERSION, |header| header.version);
+
+    let (records, complete) = match version {
+        LEGACY_FORMAT_VERSION => salvage_map(buff, from_unversioned),
+        UNVERSIONED_FORMAT_VERSION => salvage_map(&buff[MDB_HEADER_LEN..], from_unversioned),
+        UNTYPED_FORMAT_VERSION => salvage_map(&buff[MDB_HEADER_LEN..], from_legacy::<UntypedRecord>),
+        UNTAGGED_FORMAT_VERSION => salvage_map(&buff[MDB_HEADER_LEN..], from_legacy::<UntaggedRecord>),
+        FIXED_TTL_FORMAT_VERSION => salvage_map(&buff[MDB_HEADER_LEN..], from_legacy::<FixedTtlRecord>),
+        CURRENT_FORMAT_VERSION => salvage_map(&buff[MDB_HEADER_LEN..], |wrecord: WrappedRecord| wrecord),
+        unknown => return Err(BackupFormatError::UnsupportedVersion(unknown)),
+    };
+    Ok((version, records, complete))
+}
+
+fn decode_map<'a, V>(
+    buff: &'a [u8],
+    into: impl Fn(V) -> WrappedRecord,
+) -> bincode::Result<HashMap<String, WrappedRecord>>
+where
+    V: Deserialize<'a>,
+{
+    let records: HashMap<String, V> = bincode::deserialize(buff)?;
+    Ok(records
+        .into_iter()
+        .map(|(key, record)| (key, into(record)))
+        .collect())
+}
+
+// reads the entries of a bincode map one at a time, keeping the ones in front of the
+// first that does not decode
+fn salvage_map<'a, V>(buff: &'a [u8], into: impl Fn(V) -> WrappedRecord) -> (HashMap<String, WrappedRecord>, bool)
+where
+    V: Deserialize<'a>,
+{
+    // the encoding of bincode::deserialize
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+    let mut deserializer = bincode::Deserializer::from_slice(buff, options);
+
+    let mut records = HashMap::new();
+    let Ok(len) = u64::deserialize(&mut deserializer) else {
+        return (records, false);
+    };
+    for _ in 0..len {
+        match <(String, V)>::deserialize(&mut deserializer) {
+            Ok((key, record)) => {
+                records.insert(key, into(record));
+            }
+            Err(_) => return (records, false),
+        }
+    }
+    (records, true)
+}
+
+fn from_unversioned(record: UntypedRecord<'_>) -> WrappedRecord {
+    WrappedRecord {
+        record: record.into(),
+        version: 0,
+        detatched_task_ch: None,
+    }
+}
+
+fn from_legacy<R: Into<Record>>(wrecord: LegacyWrappedRecord<R>) -> WrappedRecord {
+    WrappedRecord {
+        record: wrecord.record.into(),
+        version: wrecord.version,
+        detatched_task_ch: None,
+    }
+}
+
+pub(crate) const DEFAULT_BACKUP_COMPRESSION: &str = "zstd:3";
+
+/// Compression applied to the entries of a backup archive.
+///
+/// Archives are always zip files, recovery reads whatever method their entries use.
+#[derive(Debug, Clone, Copy)]
+pub enum BackupCompression {
+    Stored,
+    Deflate(Option<i32>),
+    Zstd(Option<i32>),
+}
+
+impl BackupCompression {
+    pub(crate) fn file_options(&self) -> FileOptions {
+        let (method, level) = match self {
+            BackupCompression::Stored => (CompressionMethod::Stored, None),
+            BackupCompression::Deflate(level) => (CompressionMethod::Deflated, *level),
+            BackupCompression::Zstd(level) => (CompressionMethod::Zstd, *level),
+        };
+
+        FileOptions::default()
+            .compression_method(method)
+            .compression_level(level)
+    }
+}
+
+impl FromStr for BackupCompression {
+    type Err = String;
+
+    /// Parses `none`, `deflate[:0-9]` or `zstd[:1-22]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (method, level) = match s.split_once(':') {
+            Some((method, level)) => {
+                let level = level
+                    .parse::<i32>()
+                    .map_err(|_| format!("invalid compression level: {}", level))?;
+                (method, Some(level))
+            }
+            None => (s, None),
+        };
+
+        match (method.to_lowercase().as_str(), level) {
+            ("none", None) => Ok(BackupCompression::Stored),
+            ("deflate", None) => Ok(BackupCompression::Deflate(None)),
+            ("deflate", Some(level)) if (0..=9).contains(&level) => {
+                Ok(BackupCompression::Deflate(Some(level)))
+            }
+            ("zstd", None) => Ok(BackupCompression::Zstd(None)),
+            ("zstd", Some(level)) if (1..=22).contains(&level) => {
+                Ok(BackupCompression::Zstd(Some(level)))
+            }
+            ("none" | "deflate" | "zstd", Some(level)) => {
+                Err(format!("compression level {} out of range for {}", level, method))
+            }
+            _ => Err(format!("unknown compression: {}", method)),
+        }
+    }
+}
+
+impl fmt::Display for BackupCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupCompression::Stored => write!(f, "none"),
+            BackupCompression::Deflate(None) => write!(f, "deflate"),
+            BackupCompression::Deflate(Some(level)) => write!(f, "deflate:{}", level),
+            BackupCompression::Zstd(None) => write!(f, "zstd"),
+            BackupCompression::Zstd(Some(level)) => write!(f, "zstd:{}", level),
+        }
+    }
+}
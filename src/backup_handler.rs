@@ -1,162 +1,554 @@
-use std::time::Duration;
-use std::{io::Write, path::PathBuf};
-use smol::fs::remove_dir_all;
-use zip::{write::FileOptions, ZipWriter};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{io::Read, io::Write, path::{Path, PathBuf}};
+use zip::{write::FileOptions, ZipArchive, ZipWriter};
 
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use smol::{
-    fs::{self, create_dir_all, File, OpenOptions},
-    io::{AsyncReadExt, AsyncWriteExt},
+    fs::{create_dir_all, OpenOptions},
+    future::FutureExt,
+    io::AsyncWriteExt,
+    lock::{Mutex, Semaphore},
     stream::StreamExt,
     Timer,
 };
 
-use crate::storage::Storage;
+#[cfg(feature = "s3")]
+use crate::s3::Bucket;
+use crate::{
+    backup_format::{self, BackupCompression, MdbHeader},
+    shard_hash::ShardHash,
+    stats::BackupWritten,
+    storage::{Storage, DEFAULT_SHARD_COUNT, SLOT_COUNT},
+    wrapped_record::WrappedRecord,
+};
 
-const MDB_FILE_NAME: &'static str = "shard";
-const MDB_FILE_EXTENSION: &'static str = "mdb";
-const MDB_BACKUP_DIR: &'static str = "mapper-backup";
-const ZIP_MDB_BACKUP_NAME: &'static str = "mapper-backup.zip";
+const MDB_FILE_NAME: &str = "shard";
+pub(crate) const MDB_FILE_EXTENSION: &str = "mdb";
+const MDB_BACKUP_DIR: &str = "mapper-backup";
+// archive written by versions without backup slots
+const LEGACY_ZIP_MDB_BACKUP_NAME: &str = "mapper-backup.zip";
+// backups alternate between two archives so the last good one is never overwritten
+const ZIP_MDB_BACKUP_SLOTS: [&str; 2] = ["mapper-backup-a.zip", "mapper-backup-b.zip"];
+// name of the slot holding the last verified backup
+const CURRENT_BACKUP_SLOT_FILE: &str = "mapper-backup.current";
+pub(crate) const LAYOUT_FILE_NAME: &str = "slots.layout";
+// hash function keys were spread over the slots with, siphash when missing
+pub(crate) const HASH_FILE_NAME: &str = "slots.hash";
+// journal sequence at backup time, record clocks keep growing from there after a restart
+pub(crate) const CLOCK_FILE_NAME: &str = "clock.seq";
+
+pub(crate) const DEFAULT_BACKUP_FULL_EVERY: u32 = 10;
+
+// shard versions and layout saved by the last verified backup, with its archive
+struct LastBackup {
+    versions: Vec<u64>,
+    layout: Vec<usize>,
+    archive: PathBuf,
+    // cycles copying shards from the previous archive since the last full one
+    incremental: u32,
+    // whether the archive made it to --backup-s3-bucket
+    #[cfg(feature = "s3")]
+    uploaded: bool,
+}
 
 pub(crate) struct BackupHandler {
     interval: Duration,
     path: String,
+    lazy_recovery: bool,
+    // refuse to start on a damaged backup instead of restoring what is readable
+    strict_recovery: bool,
+    compression: BackupCompression,
+    // shards serialized at once
+    semaphore: Arc<Semaphore>,
+    // every how many cycles all the shards are serialized again, 0 for never
+    full_every: u32,
+    // held for a whole cycle, the periodic ones and the final one never overlap
+    last_backup: Arc<Mutex<Option<LastBackup>>>,
+    #[cfg(feature = "s3")]
+    bucket: Option<Arc<Bucket>>,
     storage: Storage,
 }
 
+/// A shard whose content is still stored inside the backup archive.
+#[derive(Debug)]
+pub(crate) struct PendingShard {
+    archive: PathBuf,
+    entry: String,
+}
+
+impl PendingShard {
+    /// Reads the shard from the archive, which stays pending when it fails: an empty shard
+    /// in its place would replace the good copy at the next backup.
+    pub(crate) async fn load(&self) -> Result<HashMap<String, WrappedRecord>, String> {
+        debug!("lazily restoring {} from {}", self.entry, self.archive.display());
+        let (archive, entry) = (self.archive.clone(), self.entry.clone());
+        let buff = smol::unblock(move || read_zip_entry(&archive, &entry))
+            .await
+            .map_err(|e| format!("error reading {} from backup archive: {}", self.entry, e))?;
+
+        let (version, deserialized_shard) = backup_format::decode_shard(&buff)
+            .map_err(|e| format!("error deserializing {}: {}", self.entry, e))?;
+        debug!("restored {} records from format version {}", deserialized_shard.len(), version);
+        Ok(deserialized_shard)
+    }
+}
+
 impl BackupHandler {
-    pub(crate) fn new(interval: Duration, path: String, storage: Storage) -> Self {
+    pub(crate) fn new(
+        interval: Duration,
+        path: String,
+        lazy_recovery: bool,
+        strict_recovery: bool,
+        compression: BackupCompression,
+        parallelism: usize,
+        storage: Storage,
+    ) -> Self {
         Self {
             interval,
             path,
+            lazy_recovery,
+            strict_recovery,
+            compression,
+            semaphore: Arc::new(Semaphore::new(parallelism.max(1))),
+            full_every: 0,
+            last_backup: Arc::new(Mutex::new(None)),
+            #[cfg(feature = "s3")]
+            bucket: None,
             storage,
         }
     }
 
-    async fn recover(&self, storage_shard_len: usize) {
-        let shard_dir_path = format!("{}/{}", self.path, MDB_BACKUP_DIR);
+    /// Serializes all the shards again every `cycles` cycles, instead of copying the unchanged
+    /// ones from the previous archive.
+    pub(crate) fn with_full_every(mut self, cycles: u32) -> Self {
+        self.full_every = cycles;
+        self
+    }
+
+    /// Uploads every backup to `bucket` too, recovering from it when there is no local backup.
+    #[cfg(feature = "s3")]
+    pub(crate) fn with_bucket(mut self, bucket: Option<Arc<Bucket>>) -> Self {
+        self.bucket = bucket;
+        self
+    }
 
-        // Unzip backup if it exists
-        let zip_path = format!("{}/{}", &self.path, ZIP_MDB_BACKUP_NAME);
-        if std::fs::metadata(&zip_path).is_ok() {
-            if let Err(e) = unzip_backup(&zip_path, &shard_dir_path).await {
-                error!("Failed to unzip backup: {}", e);
+    /// Restores the last backup. Missing or damaged parts of it are logged and skipped,
+    /// with `strict_recovery` they fail the recovery instead.
+    async fn recover(&self) -> Result<(), String> {
+        let backup = find_backup(&self.path, self.strict_recovery)?;
+        #[cfg(feature = "s3")]
+        let backup = match (backup, &self.bucket) {
+            (None, Some(bucket)) => self.download(bucket).await?,
+            (backup, _) => backup,
+        };
+        let (zip_path, entries) = match backup {
+            Some(backup) => backup,
+            None => {
+                debug!("no backup found in {}", self.path);
+                return Ok(());
             }
+        };
+        info!("recovering from {}", zip_path.display());
+
+        // the slot layout has to be in place before shards get restored by index, it takes
+        // over the shard count asked for with --shards
+        let shard_count = self.storage.shard_count();
+        if entries.iter().any(|entry| entry == LAYOUT_FILE_NAME) {
+            let layout = read_zip_entry(&zip_path, LAYOUT_FILE_NAME)
+                .map_err(|e| e.to_string())
+                .and_then(|buff| bincode::deserialize::<Vec<usize>>(&buff).map_err(|e| e.to_string()));
+            match layout {
+                Ok(layout) if self.storage.restore_layout(&layout) => {
+                    info!("restored slot layout over {} shards", self.storage.shard_count())
+                }
+                Ok(_) => self.damaged("backup slot layout does not fit the storage, keeping the current one".to_string())?,
+                Err(e) => self.damaged(format!("error reading backup slot layout: {}", e))?,
+            }
+        } else {
+            // archives older than the slot layout were always written over the default count
+            let layout: Vec<usize> = (0..SLOT_COUNT).map(|slot| slot % DEFAULT_SHARD_COUNT).collect();
+            self.storage.restore_layout(&layout);
+        }
+        if self.storage.shard_count() != shard_count {
+            warn!(
+                "backup was written over {} shards instead of {}, use /RESHARD to change it",
+                self.storage.shard_count(),
+                shard_count
+            );
         }
 
-        // Original recovery logic
-        let mut entries = match fs::read_dir(&shard_dir_path).await {
-            Ok(entries) => entries,
+        // records hashed differently are loaded right away and moved to the shards owning
+        // their keys now
+        let rehash = match read_shard_hash(&zip_path, &entries) {
+            Ok(shard_hash) if shard_hash == self.storage.shard_hash => false,
+            Ok(shard_hash) => {
+                info!(
+                    "backup keys were hashed with {}, redistributing them with {}",
+                    shard_hash, self.storage.shard_hash
+                );
+                true
+            }
             Err(e) => {
-                debug!("error reading shard directory: {}", e);
-                return;
+                self.damaged(format!("error reading backup shard hash: {}", e))?;
+                true
             }
         };
 
-        while let Some(entry) = entries.next().await {
-            let entry = match entry {
-                Ok(entry) => entry,
-                Err(e) => {
-                    error!("error reading directory entry: {}", e);
-                    continue;
-                }
+        if entries.iter().any(|entry| entry == CLOCK_FILE_NAME) {
+            let seq = read_zip_entry(&zip_path, CLOCK_FILE_NAME)
+                .map_err(|e| e.to_string())
+                .and_then(|buff| bincode::deserialize::<u64>(&buff).map_err(|e| e.to_string()));
+            match seq {
+                Ok(seq) => self.storage.journal.observe(seq),
+                Err(e) => self.damaged(format!("error reading backup clock: {}", e))?,
+            }
+        }
+
+        let mut restored = vec![false; self.storage.shard_count()];
+        for entry in entries {
+            let shard_num = match parse_mdb_shard(&entry) {
+                Some(shard_num) if shard_num < self.storage.shard_count() => shard_num,
+                _ => continue,
             };
-            let path = entry.path();
-            if path.is_file()
-                && path.extension().and_then(|s| s.to_str()) == Some(MDB_FILE_EXTENSION)
-            {
-                let mut file = match File::open(&path).await {
-                    Ok(file) => file,
-                    Err(e) => {
-                        error!("error opening shard file: {}", e);
-                        continue;
-                    }
-                };
-                let mut buff = Vec::new();
-                if let Err(e) = file.read_to_end(&mut buff).await {
-                    error!("error reading shard file: {}", e);
-                    continue;
-                }
+            restored[shard_num] = true;
 
-                match bincode::deserialize(&buff) {
-                    Ok(deserialized_shard) => {
-                        let shard_num: usize = path
-                            .file_stem()
-                            .and_then(|s| s.to_str())
-                            .and_then(|s| s.split('_').last())
-                            .and_then(|s| s.parse().ok())
-                            .unwrap_or(usize::MAX);
-                        if shard_num < storage_shard_len {
-                            *self.storage.0[shard_num].write().await = deserialized_shard;
-                        }
-                    }
-                    Err(e) => error!("error deserializing shard file: {}", e),
+            let pending = PendingShard {
+                archive: zip_path.clone(),
+                entry,
+            };
+
+            if rehash {
+                match pending.load().await {
+                    Ok(records) => self.storage.restore_rehashed(records).await,
+                    Err(e) => self.damaged(format!("{} could not be restored: {}", pending.entry, e))?,
+                }
+            } else if self.lazy_recovery {
+                // a damaged shard would only show up on first access
+                if self.strict_recovery {
+                    let (archive, entry) = (pending.archive.clone(), pending.entry.clone());
+                    smol::unblock(move || verify_zip_entry(&archive, &entry))
+                        .await
+                        .map_err(|e| format!("{} is damaged: {}", pending.entry, e))?;
+                }
+                self.storage.defer_shard(shard_num, pending).await;
+            } else {
+                match pending.load().await {
+                    Ok(records) => self.storage.restore_shard(shard_num, records).await,
+                    Err(e) => self.damaged(format!("{} could not be restored: {}", pending.entry, e))?,
                 }
             }
+        }
+
+        let missing: Vec<String> = (0..restored.len())
+            .filter(|i| !restored[*i])
+            .map(get_mdb_shard)
+            .collect();
+        if !missing.is_empty() {
+            self.damaged(format!("backup is missing {}", missing.join(", ")))?;
+        }
+
+        if self.lazy_recovery && !rehash {
+            info!("backup shards will be restored on first access");
+        }
+        Ok(())
+    }
+
+    // downloads the archive of the bucket as the current backup, a failure refuses to start
+    // as the next cycle would replace the archive with an empty one
+    #[cfg(feature = "s3")]
+    async fn download(&self, bucket: &Bucket) -> Result<Option<(PathBuf, Vec<String>)>, String> {
+        info!("no local backup, downloading {}", bucket.object_url());
+        create_dir_all(&self.path).await.map_err(|e| e.to_string())?;
+        let slot = next_backup_slot(&self.path);
+        let download_path = PathBuf::from(format!("{}/{}.download", self.path, slot));
+        let found = bucket
+            .download(&download_path)
+            .await
+            .map_err(|e| format!("unable to download backup from {}: {}", bucket.object_url(), e))?;
+        if !found {
+            info!("no backup in {} either", bucket.object_url());
+            return Ok(None);
+        }
+        std::fs::rename(&download_path, format!("{}/{}", self.path, slot))
+            .and_then(|_| set_current_backup_slot(&self.path, slot))
+            .map_err(|e| format!("unable to store downloaded backup as {}: {}", slot, e))?;
+        find_backup(&self.path, self.strict_recovery)
+    }
 
-            let _ = remove_dir_all(&shard_dir_path).await;
+    // logs a damaged part of the backup, or fails the recovery on it in strict mode
+    fn damaged(&self, reason: String) -> Result<(), String> {
+        if self.strict_recovery {
+            return Err(reason);
         }
+        error!("{}", reason);
+        Ok(())
     }
 
-    pub(crate) async fn recover_and_backup(&self) {
-        let storage_shard_len = self.storage.0.len();
-        self.recover(storage_shard_len).await;
+    pub(crate) async fn recover_and_backup(&self) -> Result<(), String> {
+        self.recover().await?;
 
-        let interval = self.interval.clone();
+        let interval = self.interval;
         let path = self.path.clone();
         let storage = self.storage.clone();
+        let options = self.compression.file_options();
+        let semaphore = self.semaphore.clone();
+        let full_every = self.full_every;
+        let last_backup = self.last_backup.clone();
+        #[cfg(feature = "s3")]
+        let bucket = self.bucket.clone();
 
         let mut ticker = Timer::interval(interval);
+        let requests = self.storage.backup_requests.1.clone();
 
         smol::spawn(async move {
-            Timer::after(interval.clone()).await;
-            loop {
-                if let None = ticker.next().await {
-                    break;
-                }
-
-                // Backup all shards first
-                for i in 0..storage_shard_len {
-                    let curr_shard = storage.0.get(i).unwrap();
-                    match bincode::serialize(&curr_shard.read().await.0) {
-                        Ok(ser_content) => {
-                            if let Err(e) = write_backup(&path, ser_content, i).await {
-                                error!("Failed to backup shard {}: {}", i, e);
-                                continue;
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed to serialize shard {}: {}", i, e);
-                            continue;
-                        }
+            // a backup asked for runs without waiting for the next tick, the first one
+            // comes after an interval
+            let requested = || async { requests.recv().await.ok().map(|_| Instant::now()) };
+            while ticker.next().or(requested()).await.is_some() {
+                #[cfg(feature = "chaos")]
+                {
+                    let delay = storage.chaos.backup_delay();
+                    if !delay.is_zero() {
+                        warn!("chaos: backup delayed by {:?}", delay);
+                        Timer::after(delay).await;
                     }
                 }
-
-                // Create zip archive after all shards are backed up
-                let shard_dir_path = format!("{}/{}", path, MDB_BACKUP_DIR);
-                let zip_path = format!("{}/{}", path, ZIP_MDB_BACKUP_NAME);
-                if let Err(e) = create_zip_backup(&shard_dir_path, &zip_path).await {
-                    error!("Failed to create zip backup: {}", e);
+                let mut last_backup = last_backup.lock().await;
+                let _ = run_backup(&storage, &path, options, &semaphore, full_every, &mut last_backup).await;
+                #[cfg(feature = "s3")]
+                if let Some(bucket) = &bucket {
+                    let _ = upload_backup(bucket, &mut last_backup).await;
                 }
             }
         })
         .detach();
+        Ok(())
+    }
+
+    /// Runs a last backup cycle, once a periodic one in progress has finished, so the writes
+    /// made since the last tick are not lost on shutdown.
+    pub(crate) async fn final_backup(&self) -> Result<(), String> {
+        info!("backing up before shutting down");
+        let options = self.compression.file_options();
+        let mut last_backup = self.last_backup.lock().await;
+        run_backup(&self.storage, &self.path, options, &self.semaphore, self.full_every, &mut last_backup).await?;
+        #[cfg(feature = "s3")]
+        if let Some(bucket) = &self.bucket {
+            upload_backup(bucket, &mut last_backup).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Uploads the archive of `last_backup` unless the bucket already holds it, a failed upload
+/// being tried again after the next cycle, even one finding nothing changed.
+#[cfg(feature = "s3")]
+async fn upload_backup(bucket: &Bucket, last_backup: &mut Option<LastBackup>) -> Result<(), String> {
+    let Some(last_backup) = last_backup.as_mut().filter(|last_backup| !last_backup.uploaded) else {
+        return Ok(());
+    };
+    let started = Instant::now();
+    if let Err(e) = bucket.upload(&last_backup.archive).await {
+        let e = format!("Failed to upload backup to {}: {}", bucket.object_url(), e);
+        error!("{}", e);
+        return Err(e);
+    }
+    last_backup.uploaded = true;
+    debug!("uploaded backup to {} in {:?}", bucket.object_url(), started.elapsed());
+    Ok(())
+}
+
+/// Runs a backup cycle: changed shards are serialized again, the others copied from the
+/// archive of `last_backup`, which the cycle replaces once its archive is verified. Every
+/// `full_every` cycle serializes all of them. A failed or cancelled cycle keeps the current
+/// backup, failures are logged and in the stats.
+async fn run_backup(
+    storage: &Storage,
+    path: &str,
+    options: FileOptions,
+    semaphore: &Arc<Semaphore>,
+    full_every: u32,
+    last_backup: &mut Option<LastBackup>,
+) -> Result<(), String> {
+    let started = Instant::now();
+    let snapshot = storage.snapshot().await.map_err(|e| e.to_string())?;
+    let header = MdbHeader::new(snapshot.shards.len());
+
+    // shards untouched since the last backup are copied from its archive,
+    // a layout change invalidates all of them
+    let previous = last_backup
+        .as_ref()
+        .filter(|previous| previous.layout == snapshot.layout);
+    let unchanged: Vec<usize> = match previous {
+        Some(previous) => (0..snapshot.versions.len())
+            .filter(|i| previous.versions.get(*i) == snapshot.versions.get(*i))
+            .collect(),
+        None => Vec::new(),
+    };
+    if unchanged.len() == snapshot.shards.len() && previous.is_some() {
+        debug!("nothing changed since the last backup, skipping");
+        return Ok(());
     }
+
+    // copied entries are not decompressed or checked against the current format, a full
+    // cycle now and then rewrites them
+    let previous = previous.filter(|previous| full_every == 0 || previous.incremental + 1 < full_every);
+    let unchanged = if previous.is_some() { unchanged } else { Vec::new() };
+    let incremental = previous.map_or(0, |previous| previous.incremental + 1);
+
+    let changed = snapshot.shards.len() - unchanged.len();
+    let operation = Arc::new(
+        storage
+            .operations
+            .start("backup", format!("backing up {} changed shards", changed)),
+    );
+    operation.progress(0, changed as u64);
+
+    // Backup changed shards first, serializing up to `parallelism` of them at once,
+    // a cancelled cycle stops before the next shard and keeps the current backup
+    let shard_backups: Vec<_> = snapshot
+        .shards
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !unchanged.contains(i))
+        .map(|(i, records)| {
+            let records = records.clone();
+            let path = path.to_string();
+            let semaphore = semaphore.clone();
+            let operation = operation.clone();
+            smol::spawn(async move {
+                let _permit = semaphore.acquire_arc().await;
+                if operation.is_cancelled() {
+                    return Ok(());
+                }
+                let ser_content =
+                    smol::unblock(move || backup_format::encode_shard(&records, header))
+                        .await
+                        .map_err(|e| format!("Failed to serialize shard {}: {}", i, e))?;
+                write_backup(&path, ser_content, &get_mdb_shard(i))
+                    .await
+                    .map_err(|e| format!("Failed to backup shard {}: {}", i, e))
+            })
+        })
+        .collect();
+    let mut outcomes = Vec::with_capacity(changed + 3);
+    for (done, shard_backup) in shard_backups.into_iter().enumerate() {
+        outcomes.push(shard_backup.await);
+        operation.progress(done as u64 + 1, changed as u64);
+    }
+    // shards staged by a cancelled cycle would end up in the archive of the next one
+    let shard_dir_path = format!("{}/{}", path, MDB_BACKUP_DIR);
+    if operation.is_cancelled() {
+        info!("backup cancelled, keeping the current one");
+        let _ = std::fs::remove_dir_all(&shard_dir_path);
+        return Err("backup cancelled".to_string());
+    }
+
+    outcomes.push(match bincode::serialize(&snapshot.layout) {
+        Ok(ser_layout) => write_backup(path, ser_layout, LAYOUT_FILE_NAME)
+            .await
+            .map_err(|e| format!("Failed to backup slot layout: {}", e)),
+        Err(e) => Err(format!("Failed to serialize slot layout: {}", e)),
+    });
+
+    let ser_hash = snapshot.shard_hash.spec().into_bytes();
+    outcomes.push(
+        write_backup(path, ser_hash, HASH_FILE_NAME)
+            .await
+            .map_err(|e| format!("Failed to backup shard hash: {}", e)),
+    );
+
+    outcomes.push(match bincode::serialize(&snapshot.seq) {
+        Ok(ser_seq) => write_backup(path, ser_seq, CLOCK_FILE_NAME)
+            .await
+            .map_err(|e| format!("Failed to backup clock: {}", e)),
+        Err(e) => Err(format!("Failed to serialize clock: {}", e)),
+    });
+
+    // an archive missing any of them would not restore right, the current one
+    // is kept
+    let errors: Vec<String> = outcomes.into_iter().filter_map(Result::err).collect();
+    if let Some(first) = errors.first() {
+        for e in &errors {
+            error!("{}", e);
+        }
+        let _ = std::fs::remove_dir_all(&shard_dir_path);
+        storage.stats.backup_finished(next_backup_slot(path), started.elapsed(), Err(first.clone()));
+        return Err(first.clone());
+    }
+
+    // Create zip archive after all shards are backed up, in the slot not holding
+    // the current backup, and switch to it only once it has been verified
+    let slot = next_backup_slot(path);
+    let zip_path = PathBuf::from(format!("{}/{}", path, slot));
+    let reused: Vec<String> = unchanged.iter().map(|i| get_mdb_shard(*i)).collect();
+    let reused_from = previous.map(|previous| (previous.archive.as_path(), &reused[..]));
+    let outcome = create_zip_backup(&shard_dir_path, &zip_path, options, reused_from)
+        .await
+        .map_err(|e| format!("Failed to create zip backup: {}", e))
+        .and_then(|_| {
+            verify_backup(&zip_path).map_err(|e| {
+                format!("Backup {} failed the integrity check: {}", zip_path.display(), e)
+            })
+        })
+        .and_then(|_| {
+            set_current_backup_slot(path, slot)
+                .map_err(|e| format!("Failed to mark {} as the current backup: {}", slot, e))
+        })
+        .map(|_| BackupWritten {
+            bytes: std::fs::metadata(&zip_path).map_or(0, |metadata| metadata.len()),
+            shards_written: changed,
+            shards_copied: unchanged.len(),
+        });
+    let failed = outcome.as_ref().err().cloned();
+    storage.stats.backup_finished(slot, started.elapsed(), outcome);
+    if let Some(e) = failed {
+        error!("{}", e);
+        return Err(e);
+    }
+    debug!(
+        "backed up {} changed shards, {} copied from the previous backup",
+        changed,
+        unchanged.len()
+    );
+    *last_backup = Some(LastBackup {
+        versions: snapshot.versions,
+        layout: snapshot.layout,
+        archive: zip_path,
+        incremental,
+        #[cfg(feature = "s3")]
+        uploaded: false,
+    });
+    Ok(())
 }
 
 #[inline]
-fn get_mdb_shard(shard_num: usize) -> String {
+pub(crate) fn get_mdb_shard(shard_num: usize) -> String {
     format!("{}_{}.{}", MDB_FILE_NAME, shard_num, MDB_FILE_EXTENSION)
 }
 
-async fn write_backup(path: &str, content: Vec<u8>, shard_num: usize) -> std::io::Result<()> {
-    println!("{}", String::from_utf8_lossy(&content[..]));
+/// Shard number of an archive entry, `None` if the entry is not a shard file.
+pub(crate) fn parse_mdb_shard(entry: &str) -> Option<usize> {
+    let path = PathBuf::from(entry);
+    if path.extension().and_then(|s| s.to_str()) != Some(MDB_FILE_EXTENSION) {
+        return None;
+    }
+
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .and_then(|s| s.split('_').next_back())
+        .and_then(|s| s.parse().ok())
+}
+
+async fn write_backup(path: &str, content: Vec<u8>, file_name: &str) -> std::io::Result<()> {
     // Create the directory for storing shard files if it doesn't exist
     let shard_dir_path = format!("{}/{}", path, MDB_BACKUP_DIR);
     create_dir_all(&shard_dir_path).await?;
 
     // Create or overwrite the MDB file for the shard
-    let mdb_file_path = format!("{}/{}", shard_dir_path, get_mdb_shard(shard_num));
+    let mdb_file_path = format!("{}/{}", shard_dir_path, file_name);
     let mut file = OpenOptions::new()
         .create(true)
         .write(true)
@@ -171,14 +563,20 @@ async fn write_backup(path: &str, content: Vec<u8>, shard_num: usize) -> std::io
     Ok(())
 }
 
-async fn create_zip_backup(shard_dir_path: &str, zip_path: &str) -> std::io::Result<()> {
+/// Zips the staging directory into `zip_path`, `reused` entries are copied as they are
+/// from a previous archive without being decompressed.
+async fn create_zip_backup(
+    shard_dir_path: &str,
+    zip_path: &Path,
+    options: FileOptions,
+    reused: Option<(&Path, &[String])>,
+) -> std::io::Result<()> {
     let file = std::fs::OpenOptions::new()
         .create(true)
         .write(true)
         .truncate(true)
         .open(zip_path)?;
     let mut zip = ZipWriter::new(file);
-    let options = FileOptions::default();
 
     let entries = std::fs::read_dir(shard_dir_path)?;
     for entry in entries {
@@ -189,14 +587,24 @@ async fn create_zip_backup(shard_dir_path: &str, zip_path: &str) -> std::io::Res
 
             if let Some(name) = file_name.to_str() {
                 zip.start_file(name, options)
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                    .map_err(std::io::Error::other)?;
                 zip.write_all(&file_content)?;
             }
         }
     }
 
+    if let Some((previous_path, reused_entries)) = reused {
+        let mut previous = ZipArchive::new(std::fs::File::open(previous_path)?)
+            .map_err(std::io::Error::other)?;
+        for entry in reused_entries {
+            let file = previous.by_name(entry).map_err(std::io::Error::other)?;
+            zip.raw_copy_file(file).map_err(std::io::Error::other)?;
+        }
+    }
+
     zip.finish()
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        .map_err(std::io::Error::other)?
+        .sync_all()?;
 
     // Remove the original directory after successful zip creation
     std::fs::remove_dir_all(shard_dir_path)?;
@@ -204,27 +612,109 @@ async fn create_zip_backup(shard_dir_path: &str, zip_path: &str) -> std::io::Res
     Ok(())
 }
 
-async fn unzip_backup(zip_path: &str, shard_dir_path: &str) -> std::io::Result<()> {
+fn current_backup_slot(path: &str) -> Option<&'static str> {
+    let slot = std::fs::read_to_string(format!("{}/{}", path, CURRENT_BACKUP_SLOT_FILE)).ok()?;
+    ZIP_MDB_BACKUP_SLOTS
+        .into_iter()
+        .find(|candidate| *candidate == slot.trim())
+}
+
+fn next_backup_slot(path: &str) -> &'static str {
+    match current_backup_slot(path) {
+        Some(current) if current == ZIP_MDB_BACKUP_SLOTS[0] => ZIP_MDB_BACKUP_SLOTS[1],
+        _ => ZIP_MDB_BACKUP_SLOTS[0],
+    }
+}
+
+/// Atomically points the current backup to `slot`.
+fn set_current_backup_slot(path: &str, slot: &str) -> std::io::Result<()> {
+    let tmp_path = format!("{}/{}.tmp", path, CURRENT_BACKUP_SLOT_FILE);
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(slot.as_bytes())?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_path, format!("{}/{}", path, CURRENT_BACKUP_SLOT_FILE))
+}
+
+/// Reads back every entry of an archive, checking crcs and shard headers.
+fn verify_backup(zip_path: &Path) -> std::io::Result<()> {
     let zip_file = std::fs::File::open(zip_path)?;
-    let mut archive = zip::ZipArchive::new(zip_file)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    let mut archive = ZipArchive::new(zip_file).map_err(std::io::Error::other)?;
 
+    let mut buff = Vec::new();
     for i in 0..archive.len() {
-        let mut file = archive
-            .by_index(i)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let mut file = archive.by_index(i).map_err(std::io::Error::other)?;
+        buff.clear();
+        file.read_to_end(&mut buff)?;
+
+        if parse_mdb_shard(file.name()).is_some() {
+            MdbHeader::read(&buff).map_err(std::io::Error::other)?;
+        }
+    }
+    Ok(())
+}
 
-        let name = file.name().to_owned();
-        let outpath = PathBuf::from(shard_dir_path).join(name);
+/// Finds the archive to recover from: the current slot first, then the other slot,
+/// then the archive written before backup slots existed. With `strict` an unreadable
+/// or missing current archive is an error instead of falling back to the next one.
+fn find_backup(path: &str, strict: bool) -> Result<Option<(PathBuf, Vec<String>)>, String> {
+    let current = current_backup_slot(path);
+    let candidates = current
+        .into_iter()
+        .chain(ZIP_MDB_BACKUP_SLOTS.into_iter().filter(|slot| Some(*slot) != current))
+        .chain([LEGACY_ZIP_MDB_BACKUP_NAME]);
+
+    for candidate in candidates {
+        let zip_path = PathBuf::from(format!("{}/{}", path, candidate));
+        if std::fs::metadata(&zip_path).is_err() {
+            if strict && Some(candidate) == current {
+                return Err(format!("current backup archive {} is missing", zip_path.display()));
+            }
+            continue;
+        }
 
-        if let Some(p) = outpath.parent() {
-            if !p.exists() {
-                std::fs::create_dir_all(p)?;
+        match list_zip_entries(&zip_path) {
+            Ok(entries) => return Ok(Some((zip_path, entries))),
+            Err(e) if strict => {
+                return Err(format!("Failed to read backup archive {}: {}", zip_path.display(), e))
             }
+            Err(e) => error!("Failed to read backup archive {}: {}", zip_path.display(), e),
         }
+    }
+    Ok(None)
+}
+
+pub(crate) fn list_zip_entries(zip_path: &Path) -> std::io::Result<Vec<String>> {
+    let zip_file = std::fs::File::open(zip_path)?;
+    let archive = ZipArchive::new(zip_file).map_err(std::io::Error::other)?;
 
-        let mut outfile = std::fs::File::create(&outpath)?;
-        std::io::copy(&mut file, &mut outfile)?;
+    Ok(archive.file_names().map(|name| name.to_owned()).collect())
+}
+
+// reads an entry in full, checking its crc, and the header of a shard file
+fn verify_zip_entry(zip_path: &Path, entry: &str) -> Result<(), String> {
+    let buff = read_zip_entry(zip_path, entry).map_err(|e| e.to_string())?;
+    if parse_mdb_shard(entry).is_some() {
+        MdbHeader::read(&buff).map_err(|e| e.to_string())?;
     }
     Ok(())
 }
+
+/// Hash function the records of an archive were spread with.
+pub(crate) fn read_shard_hash(zip_path: &Path, entries: &[String]) -> Result<ShardHash, String> {
+    if !entries.iter().any(|entry| entry == HASH_FILE_NAME) {
+        return Ok(ShardHash::Sip);
+    }
+    let buff = read_zip_entry(zip_path, HASH_FILE_NAME).map_err(|e| e.to_string())?;
+    String::from_utf8_lossy(&buff).parse()
+}
+
+pub(crate) fn read_zip_entry(zip_path: &Path, entry: &str) -> std::io::Result<Vec<u8>> {
+    let zip_file = std::fs::File::open(zip_path)?;
+    let mut archive = ZipArchive::new(zip_file).map_err(std::io::Error::other)?;
+    let mut file = archive.by_name(entry).map_err(std::io::Error::other)?;
+
+    let mut buff = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut buff)?;
+    Ok(buff)
+}
@@ -0,0 +1,93 @@
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Read},
+};
+
+use log::info;
+use zip::ZipArchive;
+
+use crate::{
+    backup_format::{self, MdbHeader},
+    backup_handler::{get_mdb_shard, parse_mdb_shard},
+    errors::{DeserializationError, Errors, TransactionError},
+    record::Record,
+    storage::Storage,
+};
+
+/// Restores an uploaded backup archive into the running instance, returning how many
+/// records were restored. With `replace` the current records are dropped, else the
+/// archive is merged over them.
+///
+/// Every shard of the archive is decoded before anything is applied: a missing shard,
+/// a damaged entry or an unsupported format version leaves the data untouched.
+pub(crate) async fn restore(storage: &Storage, archive: Vec<u8>, replace: bool) -> Result<usize, Errors> {
+    let operation = storage.operations.start(
+        "restore",
+        format!(
+            "{} a {} bytes backup archive",
+            if replace { "replacing with" } else { "merging" },
+            archive.len()
+        ),
+    );
+    let records = smol::unblock(move || read_archive(archive))
+        .await
+        .map_err(|reason| Errors::DeserializationError(DeserializationError::InvalidBackup(reason)))?;
+    if operation.is_cancelled() {
+        return Err(Errors::TransactionError(TransactionError::OperationCancelled));
+    }
+
+    let restored = storage
+        .restore_records_from(records, replace)
+        .await
+        .map_err(Errors::TransactionError)?;
+    info!("restored {} records from an uploaded backup", restored);
+    Ok(restored)
+}
+
+fn read_archive(archive: Vec<u8>) -> Result<Vec<(String, Record)>, String> {
+    let mut zip = ZipArchive::new(Cursor::new(archive)).map_err(|e| e.to_string())?;
+    let entries: BTreeMap<usize, String> = zip
+        .file_names()
+        .filter_map(|entry| parse_mdb_shard(entry).map(|shard_num| (shard_num, entry.to_string())))
+        .collect();
+
+    if entries.is_empty() {
+        return Err("no shard in the archive".to_string());
+    }
+
+    let mut shard_count = None;
+    let mut records = Vec::new();
+    for entry in entries.values() {
+        let mut buff = Vec::new();
+        zip.by_name(entry)
+            .map_err(|e| e.to_string())
+            .and_then(|mut file| file.read_to_end(&mut buff).map_err(|e| e.to_string()))
+            .map_err(|e| format!("{}: {}", entry, e))?;
+
+        // headerless legacy shards do not record how many there are
+        let header = MdbHeader::read(&buff).map_err(|e| format!("{}: {}", entry, e))?;
+        if let Some(header) = header {
+            if shard_count.is_some_and(|shard_count| shard_count != header.shard_count as usize) {
+                return Err(format!("{}: written among {} shards", entry, header.shard_count));
+            }
+            shard_count = Some(header.shard_count as usize);
+        }
+
+        let (_, shard) = backup_format::decode_shard(&buff).map_err(|e| format!("{}: {}", entry, e))?;
+        records.extend(shard.into_iter().map(|(key, wrecord)| (key, wrecord.record)));
+    }
+
+    // a partial archive would restore only some of the keys
+    let shard_count = shard_count.unwrap_or(entries.len());
+    let missing: Vec<String> = (0..shard_count)
+        .filter(|shard_num| !entries.contains_key(shard_num))
+        .map(get_mdb_shard)
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("missing {}", missing.join(", ")));
+    }
+    if let Some(extra) = entries.range(shard_count..).next().map(|(_, entry)| entry) {
+        return Err(format!("{}: beyond the {} shards of the archive", extra, shard_count));
+    }
+    Ok(records)
+}
@@ -0,0 +1,247 @@
+use std::{
+    collections::HashMap,
+    error, fs,
+    fs::File,
+    io::{BufReader, Read, Write},
+    path::Path,
+};
+
+use log::{error, info, warn};
+use zip::{read::read_zipfile_from_stream, ZipArchive, ZipWriter};
+
+use crate::{
+    backup_format::{self, BackupCompression, MdbHeader, CURRENT_FORMAT_VERSION},
+    backup_handler::{get_mdb_shard, list_zip_entries, parse_mdb_shard, read_shard_hash, read_zip_entry, LAYOUT_FILE_NAME},
+    errors::{BackupFormatError, TransactionError},
+    storage::{MAX_SHARD_COUNT, SLOT_COUNT},
+    wrapped_record::WrappedRecord,
+};
+
+/// Rewrites every shard file of the `from` archive in the given format version into the
+/// `to` archive, other entries (like the slot layout) are copied as they are.
+pub(crate) fn migrate_backup(
+    from: &Path,
+    to: &Path,
+    version: u16,
+    compression: BackupCompression,
+) -> Result<(), Box<dyn error::Error>> {
+    if version > CURRENT_FORMAT_VERSION {
+        return Err(Box::new(BackupFormatError::UnsupportedVersion(version)));
+    }
+
+    let entries = list_zip_entries(from)?;
+    let shard_count = entries
+        .iter()
+        .filter(|entry| parse_mdb_shard(entry).is_some())
+        .count();
+
+    let mut zip = ZipWriter::new(File::create(to)?);
+    let options = compression.file_options();
+
+    for entry in entries {
+        let buff = read_zip_entry(from, &entry)?;
+        let content = if parse_mdb_shard(&entry).is_some() {
+            let (from_version, records) = backup_format::decode_shard(&buff)?;
+            let mut header = MdbHeader::new(shard_count);
+            header.version = version;
+            // keep the original creation time when the source file has one
+            if let Some(from_header) = MdbHeader::read(&buff)? {
+                header.created_at = from_header.created_at;
+            }
+
+            info!("{}: format version {} -> {}, {} records", entry, from_version, version, records.len());
+            backup_format::encode_shard(&records, header)?
+        } else {
+            buff
+        };
+
+        zip.start_file(entry, options)?;
+        zip.write_all(&content)?;
+    }
+
+    zip.finish()?;
+    info!("migrated {} shards into {}", shard_count, to.display());
+    Ok(())
+}
+
+/// Copies what can still be read of a damaged `from` archive into a new `to` archive: the
+/// records of every shard file in front of the damage, and the other entries that read
+/// back whole. Salvaged shard files are written in the current format version.
+pub(crate) fn repair_backup(
+    from: &Path,
+    to: &Path,
+    compression: BackupCompression,
+) -> Result<(), Box<dyn error::Error>> {
+    let entries = read_damaged_entries(from)?;
+    let shard_count = entries
+        .iter()
+        .filter(|(entry, _, _)| parse_mdb_shard(entry).is_some())
+        .count();
+
+    let mut zip = ZipWriter::new(File::create(to)?);
+    let options = compression.file_options();
+    let mut salvaged = 0;
+
+    for (entry, buff, whole) in entries {
+        let content = if parse_mdb_shard(&entry).is_some() {
+            let (from_version, records, complete) = match backup_format::salvage_shard(&buff) {
+                Ok(salvage) => salvage,
+                Err(e) => {
+                    error!("{}: unreadable, skipped: {}", entry, e);
+                    continue;
+                }
+            };
+            // keep the original shard count and creation time when the header survived
+            let mut header = MdbHeader::new(shard_count);
+            if let Ok(Some(from_header)) = MdbHeader::read(&buff) {
+                header.shard_count = from_header.shard_count;
+                header.created_at = from_header.created_at;
+            }
+
+            if whole && complete {
+                info!("{}: format version {}, {} records", entry, from_version, records.len());
+            } else {
+                warn!("{}: damaged, salvaged {} records", entry, records.len());
+            }
+            salvaged += 1;
+            backup_format::encode_shard(&records, header)?
+        } else if whole {
+            buff
+        } else {
+            error!("{}: damaged, skipped", entry);
+            continue;
+        };
+
+        zip.start_file(entry, options)?;
+        zip.write_all(&content)?;
+    }
+
+    zip.finish()?;
+    info!("salvaged {} shards into {}", salvaged, to.display());
+    Ok(())
+}
+
+/// Redistributes the records of the `from` archive over `shard_count` shards, with slots
+/// spread evenly over them, into the `to` archive or in place of `from`. Shard files are
+/// written in the current format version, other entries (like the clock) are copied as
+/// they are and the slot layout is replaced.
+pub(crate) fn rebalance_backup(
+    from: &Path,
+    to: Option<&Path>,
+    shard_count: usize,
+    compression: BackupCompression,
+) -> Result<(), Box<dyn error::Error>> {
+    if shard_count == 0 || shard_count > MAX_SHARD_COUNT {
+        return Err(Box::new(TransactionError::InvalidShardCount));
+    }
+
+    let entries = list_zip_entries(from)?;
+    // slots stay computed with the hash function of the archive
+    let shard_hash = read_shard_hash(from, &entries)?;
+    let mut header = MdbHeader::new(shard_count);
+    let mut from_shard_count = 0;
+    let mut shards: Vec<HashMap<String, WrappedRecord>> = vec![HashMap::new(); shard_count];
+    for entry in entries.iter().filter(|entry| parse_mdb_shard(entry).is_some()) {
+        let buff = read_zip_entry(from, entry)?;
+        // keep the original creation time when the source files have one
+        if let Some(from_header) = MdbHeader::read(&buff)? {
+            header.created_at = from_header.created_at;
+            from_shard_count = from_shard_count.max(from_header.shard_count as usize);
+        }
+
+        let (_, records) = backup_format::decode_shard(&buff)?;
+        info!("{}: {} records", entry, records.len());
+        for (key, wrecord) in records {
+            shards[shard_hash.slot(&key) % shard_count].insert(key, wrecord);
+        }
+        from_shard_count = from_shard_count.max(parse_mdb_shard(entry).map_or(0, |shard_num| shard_num + 1));
+    }
+
+    // a shard file missing from the source would silently lose its records
+    let missing: Vec<String> = (0..from_shard_count)
+        .map(get_mdb_shard)
+        .filter(|shard| !entries.contains(shard))
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("{} is missing {}, see repair-backup", from.display(), missing.join(", ")).into());
+    }
+
+    // written next to the destination, then moved over it
+    let to = to.unwrap_or(from);
+    let staging = to.with_extension("rebalance");
+    let mut zip = ZipWriter::new(File::create(&staging)?);
+    let options = compression.file_options();
+
+    for (shard_num, records) in shards.iter().enumerate() {
+        zip.start_file(get_mdb_shard(shard_num), options)?;
+        zip.write_all(&backup_format::encode_shard(records, header)?)?;
+    }
+
+    let layout: Vec<usize> = (0..SLOT_COUNT).map(|slot| slot % shard_count).collect();
+    zip.start_file(LAYOUT_FILE_NAME, options)?;
+    zip.write_all(&bincode::serialize(&layout)?)?;
+
+    for entry in entries.iter().filter(|entry| parse_mdb_shard(entry).is_none() && *entry != LAYOUT_FILE_NAME) {
+        zip.start_file(entry, options)?;
+        zip.write_all(&read_zip_entry(from, entry)?)?;
+    }
+
+    zip.finish()?.sync_all()?;
+    fs::rename(&staging, to)?;
+    info!(
+        "rebalanced {} records from {} into {} shards in {}",
+        shards.iter().map(HashMap::len).sum::<usize>(),
+        from_shard_count,
+        shard_count,
+        to.display()
+    );
+    Ok(())
+}
+
+// name of an archive entry, what could be read of it and whether it read back whole
+type DamagedEntry = (String, Vec<u8>, bool);
+
+// every entry of an archive, walking them from the start when the central directory at the
+// end is gone
+fn read_damaged_entries(path: &Path) -> Result<Vec<DamagedEntry>, Box<dyn error::Error>> {
+    let mut entries = Vec::new();
+    match ZipArchive::new(File::open(path)?) {
+        Ok(mut archive) => {
+            for i in 0..archive.len() {
+                match archive.by_index(i) {
+                    Ok(mut file) => {
+                        let name = file.name().to_owned();
+                        let mut buff = Vec::new();
+                        let whole = file.read_to_end(&mut buff).is_ok();
+                        entries.push((name, buff, whole));
+                    }
+                    Err(e) => error!("entry {}: unreadable, skipped: {}", i, e),
+                }
+            }
+        }
+        Err(e) => {
+            warn!("{}: {}, reading the entries from the start", path.display(), e);
+            let mut reader = BufReader::new(File::open(path)?);
+            loop {
+                match read_zipfile_from_stream(&mut reader) {
+                    Ok(Some(mut file)) => {
+                        let name = file.name().to_owned();
+                        let mut buff = Vec::new();
+                        let whole = file.read_to_end(&mut buff).is_ok();
+                        entries.push((name, buff, whole));
+                        // the position in the archive is lost along with the entry
+                        if !whole {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("{}: {}, stopping", path.display(), e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    Ok(entries)
+}
@@ -0,0 +1,182 @@
+//! The bench subcommand: reads and writes from concurrent tasks straight into a storage,
+//! once per shard lock, to tell which one suits a machine and a workload. The tasks run on
+//! the executor the connections of the server run on, with as many threads as it has.
+
+use std::{
+    error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use humantime::parse_duration;
+use log::info;
+use smol::Timer;
+
+use crate::{
+    journal::DEFAULT_JOURNAL_CAPACITY,
+    record::Record,
+    shard_hash::ShardHash,
+    shard_lock::ShardLockMode,
+    storage::{SetCondition, Storage, DEFAULT_KEYS_LIMIT, DEFAULT_SHARD_COUNT},
+};
+
+// one operation in this many has its latency sampled
+const LATENCY_SAMPLING: u64 = 64;
+const DEFAULT_BENCH_TASKS: usize = 64;
+
+#[derive(Args, Debug)]
+pub struct BenchParams {
+    #[arg(long, help = "Shard lock to measure, repeatable [default: rwlock and striped]")]
+    lock: Vec<ShardLockMode>,
+
+    #[arg(long, help = "Tasks running operations, like as many connections", default_value_t = DEFAULT_BENCH_TASKS)]
+    tasks: usize,
+
+    #[arg(long, help = "Distinct keys, fewer keys means fewer and hotter shards", default_value_t = 100_000)]
+    keys: usize,
+
+    #[arg(long, help = "Bytes of every value", default_value_t = 64)]
+    value_size: usize,
+
+    #[arg(long, help = "Share of the operations that are writes, from 0 to 1", default_value_t = 0.05)]
+    write_ratio: f64,
+
+    #[arg(long, help = "Time spent on every shard lock", default_value = "5s", value_parser = parse_duration)]
+    duration: Duration,
+}
+
+#[derive(Default)]
+struct Outcome {
+    reads: u64,
+    writes: u64,
+    read_latencies: Vec<Duration>,
+    write_latencies: Vec<Duration>,
+}
+
+/// Runs the workload described by `params` against a fresh storage for every shard lock,
+/// logging the throughput and tail latencies of each.
+pub(crate) fn run(params: &BenchParams, shard_hash: ShardHash) -> Result<(), Box<dyn error::Error>> {
+    if params.keys == 0 || !(0.0..=1.0).contains(&params.write_ratio) {
+        return Err("expected at least one key and a write ratio from 0 to 1".into());
+    }
+    let tasks = params.tasks.max(1);
+    let locks = match params.lock.is_empty() {
+        true => vec![ShardLockMode::RwLock, "striped".parse()?],
+        false => params.lock.clone(),
+    };
+    info!(
+        "{} tasks on {} keys of {} bytes, {}% writes, {:?} per shard lock",
+        tasks,
+        params.keys,
+        params.value_size,
+        params.write_ratio * 100.0,
+        params.duration
+    );
+
+    for lock in locks {
+        let storage = Storage::new(DEFAULT_JOURNAL_CAPACITY, Vec::new(), shard_hash, lock, DEFAULT_SHARD_COUNT, None, DEFAULT_KEYS_LIMIT);
+        let value: Arc<[u8]> = vec![b'x'; params.value_size].into();
+        let workload = Arc::new(Workload {
+            keys: params.keys as u64,
+            write_ratio: params.write_ratio,
+            value,
+            stop: AtomicBool::new(false),
+        });
+
+        let (outcomes, elapsed) = smol::block_on(async {
+            for key in 0..params.keys {
+                let _ = storage.set_record(&key.to_string(), Record::new(workload.value.to_vec(), None), SetCondition::Always).await;
+            }
+
+            let started = Instant::now();
+            // spawned like connections, on the executor of the server
+            let workers: Vec<_> = (0..tasks)
+                .map(|worker| smol::spawn(work(storage.clone(), workload.clone(), worker as u64)))
+                .collect();
+            Timer::after(params.duration).await;
+            workload.stop.store(true, Ordering::Relaxed);
+            let mut outcomes = Vec::with_capacity(tasks);
+            for worker in workers {
+                outcomes.push(worker.await);
+            }
+            (outcomes, started.elapsed())
+        });
+
+        let mut total = Outcome::default();
+        for outcome in outcomes {
+            total.reads += outcome.reads;
+            total.writes += outcome.writes;
+            total.read_latencies.extend(outcome.read_latencies);
+            total.write_latencies.extend(outcome.write_latencies);
+        }
+        info!(
+            "{}: {:.0} ops/s ({} reads, {} writes), p50/p99 read {:?}/{:?}, p50/p99 write {:?}/{:?}",
+            lock,
+            (total.reads + total.writes) as f64 / elapsed.as_secs_f64(),
+            total.reads,
+            total.writes,
+            percentile(&mut total.read_latencies, 0.5),
+            percentile(&mut total.read_latencies, 0.99),
+            percentile(&mut total.write_latencies, 0.5),
+            percentile(&mut total.write_latencies, 0.99),
+        );
+    }
+    Ok(())
+}
+
+// what every task runs, shared with the tasks
+struct Workload {
+    keys: u64,
+    write_ratio: f64,
+    value: Arc<[u8]>,
+    stop: AtomicBool,
+}
+
+async fn work(storage: Storage, workload: Arc<Workload>, worker: u64) -> Outcome {
+    let mut outcome = Outcome::default();
+    // splitmix64, a stream per worker
+    let mut seed = worker.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let mut random = move || {
+        seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+
+    let mut done = 0u64;
+    while !workload.stop.load(Ordering::Relaxed) {
+        let key = (random() % workload.keys).to_string();
+        let write = (random() as f64 / u64::MAX as f64) < workload.write_ratio;
+        let started = done.is_multiple_of(LATENCY_SAMPLING).then(Instant::now);
+        if write {
+            let _ = storage.set_record(&key, Record::new(workload.value.to_vec(), None), SetCondition::Always).await;
+            outcome.writes += 1;
+        } else {
+            let _ = storage.get_record(&key).await;
+            outcome.reads += 1;
+        }
+        if let Some(started) = started {
+            match write {
+                true => outcome.write_latencies.push(started.elapsed()),
+                false => outcome.read_latencies.push(started.elapsed()),
+            }
+        }
+        done += 1;
+        // like a connection waiting for its next request, the other tasks get to run
+        smol::future::yield_now().await;
+    }
+    outcome
+}
+
+fn percentile(latencies: &mut [Duration], share: f64) -> Duration {
+    if latencies.is_empty() {
+        return Duration::ZERO;
+    }
+    latencies.sort_unstable();
+    latencies[((latencies.len() - 1) as f64 * share) as usize]
+}
@@ -0,0 +1,251 @@
+use crate::errors::DeserializationError;
+
+// offsets are limited like in redis, to values of 512MB
+const MAX_BIT_OFFSET: u64 = 1 << 32;
+
+/// What happens when a SET or INCRBY does not fit the field.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Overflow {
+    Wrap,
+    Sat,
+    // the field is left untouched and nil returned
+    Fail,
+}
+
+/// An integer of `width` bits starting at bit `offset`, bit 0 being the most significant
+/// bit of the first byte.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Field {
+    signed: bool,
+    width: u32,
+    offset: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum BitfieldOp {
+    Get(Field),
+    Set(Field, i64, Overflow),
+    IncrBy(Field, i64, Overflow),
+}
+
+impl BitfieldOp {
+    pub(crate) fn writes(&self) -> bool {
+        !matches!(self, BitfieldOp::Get(_))
+    }
+}
+
+/// Parses redis style subcommands: `GET <type> <offset>`, `SET <type> <offset> <value>`,
+/// `INCRBY <type> <offset> <increment>` and `OVERFLOW WRAP|SAT|FAIL`, which applies to
+/// the following subcommands. Types are `i1`..`i64` and `u1`..`u63`, an offset prefixed
+/// with `#` is multiplied by the type width.
+pub(crate) fn parse(subcommands: &str) -> Result<Vec<BitfieldOp>, DeserializationError> {
+    let mut tokens = subcommands.split_whitespace();
+    let mut overflow = Overflow::Wrap;
+    let mut ops = Vec::new();
+
+    while let Some(subcommand) = tokens.next() {
+        let op = match subcommand.to_ascii_uppercase().as_str() {
+            "GET" => BitfieldOp::Get(parse_field(&mut tokens)?),
+            "SET" => BitfieldOp::Set(parse_field(&mut tokens)?, parse_int(tokens.next())?, overflow),
+            "INCRBY" => BitfieldOp::IncrBy(parse_field(&mut tokens)?, parse_int(tokens.next())?, overflow),
+            "OVERFLOW" => {
+                overflow = match tokens.next().map(str::to_ascii_uppercase).as_deref() {
+                    Some("WRAP") => Overflow::Wrap,
+                    Some("SAT") => Overflow::Sat,
+                    Some("FAIL") => Overflow::Fail,
+                    _ => return Err(DeserializationError::UnparsableQuery),
+                };
+                continue;
+            }
+            _ => return Err(DeserializationError::UnparsableQuery),
+        };
+        ops.push(op);
+    }
+    Ok(ops)
+}
+
+fn parse_field<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<Field, DeserializationError> {
+    let kind = tokens.next().ok_or(DeserializationError::UnparsableQuery)?;
+    let signed = match kind.as_bytes().first() {
+        Some(b'i') | Some(b'I') => true,
+        Some(b'u') | Some(b'U') => false,
+        _ => return Err(DeserializationError::UnparsableQuery),
+    };
+    let width: u32 = kind[1..].parse().map_err(|_| DeserializationError::UnparsableQuery)?;
+    let max_width = if signed { 64 } else { 63 };
+    if width == 0 || width > max_width {
+        return Err(DeserializationError::UnparsableQuery);
+    }
+
+    let offset = tokens.next().ok_or(DeserializationError::UnparsableQuery)?;
+    let offset = match offset.strip_prefix('#') {
+        Some(index) => index.parse::<u64>().ok().and_then(|index| index.checked_mul(width as u64)),
+        None => offset.parse::<u64>().ok(),
+    }
+    .filter(|offset| offset.checked_add(width as u64).is_some_and(|end| end <= MAX_BIT_OFFSET))
+    .ok_or(DeserializationError::UnparsableQuery)?;
+
+    Ok(Field { signed, width, offset })
+}
+
+fn parse_int(token: Option<&str>) -> Result<i64, DeserializationError> {
+    token
+        .and_then(|token| token.parse().ok())
+        .ok_or(DeserializationError::UnparsableQuery)
+}
+
+/// Runs the subcommands against `data`, growing it with zeroes as needed. Returns one
+/// result per subcommand: the value read, the previous value for SET, the new value for
+/// INCRBY or `None` when an overflow failed.
+pub(crate) fn apply(data: &mut Vec<u8>, ops: &[BitfieldOp]) -> Vec<Option<i64>> {
+    ops.iter()
+        .map(|op| match op {
+            BitfieldOp::Get(field) => Some(get(data, field)),
+            BitfieldOp::Set(field, value, overflow) => {
+                let previous = get(data, field);
+                let value = fit(field, *value as i128, *overflow)?;
+                set(data, field, value);
+                Some(previous)
+            }
+            BitfieldOp::IncrBy(field, increment, overflow) => {
+                let value = fit(field, get(data, field) as i128 + *increment as i128, *overflow)?;
+                set(data, field, value);
+                Some(value)
+            }
+        })
+        .collect()
+}
+
+fn get(data: &[u8], field: &Field) -> i64 {
+    let mut bits: u64 = 0;
+    for bit in field.offset..field.offset + field.width as u64 {
+        let byte = data.get((bit / 8) as usize).copied().unwrap_or_default();
+        bits = (bits << 1) | ((byte >> (7 - bit % 8)) & 1) as u64;
+    }
+
+    if field.signed {
+        // sign extends the top bit of the field
+        let shift = 64 - field.width;
+        ((bits << shift) as i64) >> shift
+    } else {
+        bits as i64
+    }
+}
+
+fn set(data: &mut Vec<u8>, field: &Field, value: i64) {
+    let end = field.offset + field.width as u64;
+    let len = end.div_ceil(8) as usize;
+    if data.len() < len {
+        data.resize(len, 0);
+    }
+
+    for (i, bit) in (field.offset..end).enumerate() {
+        let mask = 1 << (7 - bit % 8);
+        let byte = &mut data[(bit / 8) as usize];
+        if (value >> (field.width as usize - 1 - i)) & 1 == 1 {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+    }
+}
+
+/// Brings `value` into the range of the field according to the overflow policy.
+fn fit(field: &Field, value: i128, overflow: Overflow) -> Option<i64> {
+    let (min, max) = if field.signed {
+        (-(1i128 << (field.width - 1)), (1i128 << (field.width - 1)) - 1)
+    } else {
+        (0, (1i128 << field.width) - 1)
+    };
+    if (min..=max).contains(&value) {
+        return Some(value as i64);
+    }
+
+    match overflow {
+        Overflow::Wrap => {
+            let wrapped = value.rem_euclid(1i128 << field.width);
+            Some(if wrapped > max { wrapped - (1i128 << field.width) } else { wrapped } as i64)
+        }
+        Overflow::Sat => Some(value.clamp(min, max) as i64),
+        Overflow::Fail => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(signed: bool, width: u32, offset: u64) -> Field {
+        Field { signed, width, offset }
+    }
+
+    #[test]
+    fn fit_wraps() {
+        assert_eq!(fit(&field(false, 8, 0), 256, Overflow::Wrap), Some(0));
+        assert_eq!(fit(&field(false, 8, 0), -1, Overflow::Wrap), Some(255));
+        assert_eq!(fit(&field(true, 8, 0), 128, Overflow::Wrap), Some(-128));
+        assert_eq!(fit(&field(true, 8, 0), -129, Overflow::Wrap), Some(127));
+        assert_eq!(fit(&field(true, 64, 0), i64::MAX as i128 + 1, Overflow::Wrap), Some(i64::MIN));
+    }
+
+    #[test]
+    fn fit_saturates() {
+        assert_eq!(fit(&field(false, 8, 0), 300, Overflow::Sat), Some(255));
+        assert_eq!(fit(&field(false, 8, 0), -5, Overflow::Sat), Some(0));
+        assert_eq!(fit(&field(true, 8, 0), 200, Overflow::Sat), Some(127));
+        assert_eq!(fit(&field(true, 8, 0), -200, Overflow::Sat), Some(-128));
+        assert_eq!(fit(&field(false, 63, 0), i64::MAX as i128 + 1, Overflow::Sat), Some(i64::MAX));
+    }
+
+    #[test]
+    fn fit_fails() {
+        assert_eq!(fit(&field(false, 8, 0), 256, Overflow::Fail), None);
+        assert_eq!(fit(&field(false, 8, 0), -1, Overflow::Fail), None);
+        assert_eq!(fit(&field(true, 8, 0), 128, Overflow::Fail), None);
+        assert_eq!(fit(&field(true, 8, 0), -129, Overflow::Fail), None);
+        assert_eq!(fit(&field(true, 8, 0), -128, Overflow::Fail), Some(-128));
+    }
+
+    #[test]
+    fn set_then_get() {
+        let mut data = Vec::new();
+        set(&mut data, &field(false, 4, 4), 0b1010);
+        assert_eq!(data, vec![0b0000_1010]);
+        assert_eq!(get(&data, &field(false, 4, 4)), 10);
+        assert_eq!(get(&data, &field(true, 4, 4)), -6);
+
+        // across a byte boundary
+        set(&mut data, &field(true, 12, 6), -2);
+        assert_eq!(data.len(), 3);
+        assert_eq!(get(&data, &field(true, 12, 6)), -2);
+        assert_eq!(get(&data, &field(false, 12, 6)), 4094);
+        // the bits before the field are left untouched
+        assert_eq!(get(&data, &field(false, 4, 0)), 0);
+    }
+
+    #[test]
+    fn get_past_the_end_reads_zeroes() {
+        assert_eq!(get(&[0xff], &field(false, 8, 8)), 0);
+        assert_eq!(get(&[0xff], &field(true, 16, 0)), -256);
+    }
+
+    #[test]
+    fn apply_overflow_policies() {
+        let ops = parse("SET u8 0 250 INCRBY u8 0 10 OVERFLOW SAT INCRBY u8 0 10 OVERFLOW FAIL INCRBY u8 0 1 GET u8 0").unwrap();
+        let mut data = Vec::new();
+        assert_eq!(apply(&mut data, &ops), vec![Some(0), Some(4), Some(14), Some(15), Some(15)]);
+
+        let ops = parse("SET i8 0 127 INCRBY i8 0 1 OVERFLOW SAT INCRBY i8 0 -300 OVERFLOW FAIL INCRBY i8 0 -1").unwrap();
+        let mut data = Vec::new();
+        assert_eq!(apply(&mut data, &ops), vec![Some(0), Some(-128), Some(-128), None]);
+        assert_eq!(data, vec![0x80]);
+    }
+
+    #[test]
+    fn parse_rejects_overflowing_offsets() {
+        assert!(parse(&format!("GET u8 {}", u64::MAX)).is_err());
+        assert!(parse(&format!("GET u8 {}", MAX_BIT_OFFSET - 7)).is_err());
+        assert!(parse(&format!("GET u8 {}", MAX_BIT_OFFSET - 8)).is_ok());
+        assert!(parse(&format!("GET u8 #{}", u64::MAX / 4)).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use std::f64::consts::LN_2;
+
+use crate::item_hash;
+
+// capacity (u64), error rate (f64), hash count (u32) and items added (u64), little endian,
+// the bit array follows
+const HEADER_LEN: usize = 8 + 8 + 4 + 8;
+const HASHES_AT: usize = 16;
+const ITEMS_AT: usize = 20;
+// like bitfield offsets, filters stay within 512MB
+const MAX_BLOOM_BITS: u64 = 1 << 32;
+
+/// Filter created by a BF.ADD on a missing key.
+pub(crate) const DEFAULT_PARAMS: BloomParams = BloomParams {
+    error_rate: 0.01,
+    capacity: 100,
+};
+
+/// Sizing of a filter: holding `capacity` items, a lookup of a missing item answers yes
+/// with a probability of `error_rate`. Adding more items than that degrades the rate.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BloomParams {
+    error_rate: f64,
+    capacity: u64,
+}
+
+impl BloomParams {
+    /// `None` if the rate is not within (0, 1) or the filter would grow past 512MB.
+    pub(crate) fn new(error_rate: f64, capacity: u64) -> Option<Self> {
+        let params = Self { error_rate, capacity };
+        let sized = error_rate > 0.0 && error_rate < 1.0 && capacity > 0;
+        (sized && params.optimal_bits() <= MAX_BLOOM_BITS as f64).then_some(params)
+    }
+
+    fn optimal_bits(&self) -> f64 {
+        (-(self.capacity as f64) * self.error_rate.ln() / (LN_2 * LN_2)).ceil()
+    }
+
+    // rounded up to whole bytes
+    fn bits(&self) -> u64 {
+        (self.optimal_bits() as u64).max(8).next_multiple_of(8)
+    }
+
+    fn hashes(&self) -> u32 {
+        (-self.error_rate.log2()).ceil().max(1.0) as u32
+    }
+
+    /// An empty filter.
+    pub(crate) fn create(&self) -> Vec<u8> {
+        let mut filter = Vec::with_capacity(HEADER_LEN + (self.bits() / 8) as usize);
+        filter.extend_from_slice(&self.capacity.to_le_bytes());
+        filter.extend_from_slice(&self.error_rate.to_le_bytes());
+        filter.extend_from_slice(&self.hashes().to_le_bytes());
+        filter.extend_from_slice(&0u64.to_le_bytes());
+        filter.resize(HEADER_LEN + (self.bits() / 8) as usize, 0);
+        filter
+    }
+}
+
+/// Adds `item`, returning whether it was missing. `None` if `filter` is not one.
+pub(crate) fn add(filter: &mut [u8], item: &[u8]) -> Option<bool> {
+    let (hashes, bits) = shape(filter)?;
+    let mut added = false;
+    for bit in positions(item, hashes, bits) {
+        let byte = &mut filter[HEADER_LEN + (bit / 8) as usize];
+        let mask = 1 << (bit % 8);
+        added |= *byte & mask == 0;
+        *byte |= mask;
+    }
+
+    if added {
+        let items = read_u64(filter, ITEMS_AT) + 1;
+        filter[ITEMS_AT..HEADER_LEN].copy_from_slice(&items.to_le_bytes());
+    }
+    Some(added)
+}
+
+/// Whether `item` may have been added. `None` if `filter` is not one.
+pub(crate) fn exists(filter: &[u8], item: &[u8]) -> Option<bool> {
+    let (hashes, bits) = shape(filter)?;
+    Some(positions(item, hashes, bits).all(|bit| filter[HEADER_LEN + (bit / 8) as usize] & (1 << (bit % 8)) != 0))
+}
+
+/// `capacity`, `error_rate`, `hashes`, `bits` and `items` lines. `None` if `filter` is not one.
+pub(crate) fn info(filter: &[u8]) -> Option<String> {
+    let (hashes, bits) = shape(filter)?;
+    Some(format!(
+        "capacity:{}\nerror_rate:{}\nhashes:{}\nbits:{}\nitems:{}\n",
+        read_u64(filter, 0),
+        f64::from_bits(read_u64(filter, 8)),
+        hashes,
+        bits,
+        read_u64(filter, ITEMS_AT)
+    ))
+}
+
+fn shape(filter: &[u8]) -> Option<(u32, u64)> {
+    let hashes = u32::from_le_bytes(filter.get(HASHES_AT..ITEMS_AT)?.try_into().ok()?);
+    let bits = filter.len().checked_sub(HEADER_LEN)? as u64 * 8;
+    (hashes > 0 && bits > 0).then_some((hashes, bits))
+}
+
+fn read_u64(filter: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&filter[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Bits of `item`, by double hashing.
+fn positions(item: &[u8], hashes: u32, bits: u64) -> impl Iterator<Item = u64> {
+    let (h1, h2) = item_hash::hash_pair(item);
+    (0..hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bits)
+}
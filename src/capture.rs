@@ -0,0 +1,304 @@
+//! Recording of the HTTP requests an instance receives, and their replay against another
+//! instance at the pace they came in.
+//!
+//! A capture file starts with a `mapper-capture 1` line, then holds one entry per request:
+//! a `<microseconds since the capture started> <method> <path and query> <body length>`
+//! line followed by the body and a newline.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use http_types::{Body, Method, Request, Response, Url};
+use log::{info, warn};
+use smol::{
+    channel::{self, Sender, TrySendError},
+    io::AsyncReadExt,
+    lock::Semaphore,
+    net::TcpStream,
+    Timer,
+};
+
+use crate::{
+    http_query_parser::route_is,
+    middleware::{BodyLimit, BoxFuture, Middleware, Next},
+};
+
+const CAPTURE_HEADER: &str = "mapper-capture 1";
+// entries waiting for the writer thread, requests are not slowed down past it
+const CAPTURE_BACKLOG: usize = 16384;
+// requests in flight during a replay
+const REPLAY_CONCURRENCY: usize = 64;
+
+/// Records the requests it sees into a capture file, `sample` of them spread evenly.
+///
+/// `/IMPORT` requests are not recorded, their bodies are streamed into storage. Entries
+/// are written by a background thread: the ones it cannot keep up with are dropped.
+pub(crate) struct Capture {
+    started_at: Instant,
+    sample: f64,
+    seen: AtomicU64,
+    dropped: AtomicU64,
+    sender: Sender<Vec<u8>>,
+}
+
+impl Capture {
+    pub(crate) fn new(path: &Path, sample: f64) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "{}", CAPTURE_HEADER)?;
+        file.flush()?;
+
+        let (sender, receiver) = channel::bounded::<Vec<u8>>(CAPTURE_BACKLOG);
+        let path = path.display().to_string();
+        thread::spawn(move || {
+            while let Ok(entry) = receiver.recv_blocking() {
+                let mut written = file.write_all(&entry);
+                // flushed whenever idle, a capture cut short by a stop stays readable
+                if written.is_ok() && receiver.is_empty() {
+                    written = file.flush();
+                }
+                if let Err(e) = written {
+                    warn!("capture into {} stopped: {}", path, e);
+                    return;
+                }
+            }
+        });
+
+        info!("capturing {}% of the requests", sample * 100.0);
+        Ok(Self {
+            started_at: Instant::now(),
+            sample,
+            seen: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            sender,
+        })
+    }
+
+    // the nth request is kept when it brings the count of kept ones to the next integer
+    fn sampled(&self) -> bool {
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((seen + 1.0) * self.sample).floor() > (seen * self.sample).floor()
+    }
+
+    fn record(&self, method: Method, path: &str, body: &[u8]) {
+        let offset = self.started_at.elapsed().as_micros();
+        let mut entry = format!("{} {} {} {}\n", offset, method, path, body.len()).into_bytes();
+        entry.extend_from_slice(body);
+        entry.push(b'\n');
+
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(entry) {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("capture falling behind, {} requests not recorded", dropped);
+            }
+        }
+    }
+}
+
+impl Middleware for Capture {
+    fn handle<'a>(&'a self, mut req: Request, next: Next<'a>) -> BoxFuture<'a, http_types::Result<Response>> {
+        Box::pin(async move {
+            if BodyLimit::UNLIMITED.iter().any(|route| route_is(req.url().path(), route)) || !self.sampled() {
+                return next.run(req).await;
+            }
+
+            let path = match req.url().query() {
+                Some(query) => format!("{}?{}", req.url().path(), query),
+                None => req.url().path().to_string(),
+            };
+            if req.len() == Some(0) || req.method() == Method::Get {
+                self.record(req.method(), &path, &[]);
+                return next.run(req).await;
+            }
+
+            // read up to one byte past the limit, a body over it is refused past this point
+            let limit = req.ext().get::<BodyLimit>().map_or(u64::MAX, |limit| limit.max as u64);
+            let mut body = Vec::new();
+            req.take_body()
+                .take(limit.saturating_add(1))
+                .read_to_end(&mut body)
+                .await?;
+            if body.len() as u64 <= limit {
+                self.record(req.method(), &path, &body);
+            }
+            req.set_body(Body::from_bytes(body));
+            next.run(req).await
+        })
+    }
+}
+
+/// Pace of a replay: a multiple of the captured one, or `max` for as fast as possible.
+#[derive(Debug, Clone, Copy)]
+pub struct Speed(Option<f64>);
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("max") {
+            return Ok(Speed(None));
+        }
+        match s.trim_end_matches(['x', 'X']).parse::<f64>() {
+            Ok(speed) if speed.is_finite() && speed > 0.0 => Ok(Speed(Some(speed))),
+            _ => Err(format!("invalid speed {}, expected a multiple like 2x or max", s)),
+        }
+    }
+}
+
+struct Entry {
+    offset: Duration,
+    method: Method,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// Reads the entries of a capture file one at a time.
+struct Entries<R> {
+    reader: R,
+    line: String,
+}
+
+impl<R: BufRead> Entries<R> {
+    fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        if header.trim_end() != CAPTURE_HEADER {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a capture file"));
+        }
+        Ok(Self {
+            reader,
+            line: String::new(),
+        })
+    }
+
+    fn next_entry(&mut self) -> io::Result<Option<Entry>> {
+        self.line.clear();
+        if self.reader.read_line(&mut self.line)? == 0 {
+            return Ok(None);
+        }
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("malformed entry: {}", self.line.trim_end()));
+
+        let mut fields = self.line.split_whitespace();
+        let (Some(offset), Some(method), Some(path), Some(len), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid());
+        };
+        let offset = offset.parse::<u64>().map_err(|_| invalid())?;
+        let method = method.parse::<Method>().map_err(|_| invalid())?;
+        let len = len.parse::<usize>().map_err(|_| invalid())?;
+        let path = path.to_string();
+
+        let mut body = vec![0; len + 1];
+        self.reader.read_exact(&mut body)?;
+        if body.pop() != Some(b'\n') {
+            return Err(invalid());
+        }
+        Ok(Some(Entry {
+            offset: Duration::from_micros(offset),
+            method,
+            path,
+            body,
+        }))
+    }
+}
+
+#[derive(Default)]
+struct ReplayReport {
+    sent: AtomicU64,
+    // connection errors, and responses by status class
+    failed: AtomicU64,
+    statuses: [AtomicU64; 5],
+}
+
+/// Sends the requests of the capture at `path` to the instance at `target`, keeping the
+/// intervals between them divided by `speed`, and logs how the target answered.
+pub(crate) fn replay(path: &Path, target: &Url, speed: Speed, api_key: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    if target.scheme() != "http" {
+        return Err(format!("unsupported scheme {}, expected http://", target.scheme()).into());
+    }
+    let host = target.host_str().ok_or("target url without host")?;
+    let address = format!("{}:{}", host, target.port().unwrap_or(80));
+    let mut entries = Entries::new(BufReader::new(File::open(path)?))?;
+
+    smol::block_on(async {
+        let report = Arc::new(ReplayReport::default());
+        let in_flight = Arc::new(Semaphore::new(REPLAY_CONCURRENCY));
+        let started_at = Instant::now();
+        let mut behind = Duration::ZERO;
+        // the idle time before the first request is skipped
+        let mut first_offset = None;
+
+        while let Some(entry) = entries.next_entry()? {
+            let offset = entry.offset.saturating_sub(*first_offset.get_or_insert(entry.offset));
+            if let Speed(Some(speed)) = speed {
+                let due = started_at + offset.div_f64(speed);
+                Timer::at(due).await;
+                behind = behind.max(Instant::now().saturating_duration_since(due));
+            }
+
+            let mut request = Request::new(entry.method, target.join(&entry.path)?);
+            if let Some(api_key) = api_key {
+                request.insert_header("X-API-Key", api_key);
+            }
+            if !entry.body.is_empty() {
+                request.set_body(entry.body);
+            }
+
+            let permit = in_flight.acquire_arc().await;
+            report.sent.fetch_add(1, Ordering::Relaxed);
+            let report = report.clone();
+            let address = address.clone();
+            smol::spawn(async move {
+                match send(&address, request).await {
+                    Ok(status) => {
+                        let class = (status / 100).clamp(1, 5) as usize - 1;
+                        report.statuses[class].fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(_) => {
+                        report.failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                drop(permit);
+            })
+            .detach();
+        }
+
+        // every request answered
+        for _ in 0..REPLAY_CONCURRENCY {
+            in_flight.acquire_arc().await.forget();
+        }
+        let elapsed = started_at.elapsed();
+        let sent = report.sent.load(Ordering::Relaxed);
+        let statuses: Vec<u64> = report.statuses.iter().map(|count| count.load(Ordering::Relaxed)).collect();
+        info!(
+            "replayed {} requests in {:.1?} ({:.0}/s), at most {:.1?} behind schedule: {} 2xx, {} 3xx, {} 4xx, {} 5xx, {} failed",
+            sent,
+            elapsed,
+            sent as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+            behind,
+            statuses[1],
+            statuses[2],
+            statuses[3],
+            statuses[4],
+            report.failed.load(Ordering::Relaxed),
+        );
+        Ok(())
+    })
+}
+
+async fn send(address: &str, request: Request) -> http_types::Result<u16> {
+    let stream = TcpStream::connect(address).await?;
+    let mut response = async_h1::connect(stream, request).await?;
+    response.body_bytes().await?;
+    Ok(response.status() as u16)
+}
@@ -0,0 +1,189 @@
+//! Misbehavior injected on purpose with `--chaos`, so client retries and failover
+//! automation can be exercised: slow and failing requests, expirations that never happen
+//! and late backups.
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use http_types::{Request, Response, StatusCode};
+use humantime::{format_duration, parse_duration};
+use log::{error, warn};
+use smol::Timer;
+
+use crate::{
+    errors::TransactionError,
+    http_query_parser::route_starts_with,
+    middleware::{BoxFuture, Middleware, Next},
+};
+
+// routes of the chaos settings, never slowed down or failed
+const DEBUG_PATHS: &str = "/DEBUG/";
+
+/// Faults to inject, `key=value` pairs separated by commas: `latency`, `jitter` and
+/// `backup_delay` durations, `error_rate` and `drop_expirations` shares from 0 to 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct ChaosConfig {
+    // added to every request, plus up to `jitter` more
+    latency: Duration,
+    jitter: Duration,
+    // requests answered 503 without being run
+    error_rate: f64,
+    // ttl expirations skipped, the record stays until overwritten or deleted
+    drop_expirations: f64,
+    // wait before every backup cycle
+    backup_delay: Duration,
+}
+
+impl ChaosConfig {
+    /// Changes the settings named in `spec`, the others are kept.
+    pub(crate) fn apply(&mut self, spec: &str) -> Result<(), String> {
+        for setting in spec.split(',').map(str::trim).filter(|setting| !setting.is_empty()) {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=value, got {}", setting))?;
+            let duration = || parse_duration(value).map_err(|e| format!("{}: {}", name, e));
+            let share = || match value.parse::<f64>() {
+                Ok(share) if (0.0..=1.0).contains(&share) => Ok(share),
+                _ => Err(format!("{}: expected a share from 0 to 1, got {}", name, value)),
+            };
+            match name {
+                "latency" => self.latency = duration()?,
+                "jitter" => self.jitter = duration()?,
+                "error_rate" => self.error_rate = share()?,
+                "drop_expirations" => self.drop_expirations = share()?,
+                "backup_delay" => self.backup_delay = duration()?,
+                _ => return Err(format!("unknown chaos setting {}", name)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ChaosConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = ChaosConfig::default();
+        config.apply(s)?;
+        Ok(config)
+    }
+}
+
+impl fmt::Display for ChaosConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "latency={},jitter={},error_rate={},drop_expirations={},backup_delay={}",
+            format_duration(self.latency),
+            format_duration(self.jitter),
+            self.error_rate,
+            self.drop_expirations,
+            format_duration(self.backup_delay)
+        )
+    }
+}
+
+/// The chaos settings of the instance, shared by every clone of the storage. Nothing is
+/// injected unless the instance was started with `--chaos`.
+#[derive(Debug)]
+pub(crate) struct Chaos {
+    config: Mutex<Option<ChaosConfig>>,
+    // splitmix64 state
+    seed: AtomicU64,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        Self {
+            config: Mutex::new(None),
+            seed: AtomicU64::new(seed),
+        }
+    }
+}
+
+impl Chaos {
+    pub(crate) fn enable(&self, config: ChaosConfig) {
+        warn!("chaos mode on: {}", config);
+        *self.config.lock().unwrap() = Some(config);
+    }
+
+    pub(crate) fn config(&self) -> Result<ChaosConfig, TransactionError> {
+        self.config.lock().unwrap().ok_or(TransactionError::ChaosDisabled)
+    }
+
+    /// Changes the settings named in `spec`, checked by the parser, or clears them all
+    /// when `None`.
+    pub(crate) fn update(&self, spec: Option<&str>) -> Result<ChaosConfig, TransactionError> {
+        let mut config = self.config.lock().unwrap();
+        let config = config.as_mut().ok_or(TransactionError::ChaosDisabled)?;
+        match spec {
+            Some(spec) => {
+                if let Err(e) = config.apply(spec) {
+                    error!("{}", e);
+                }
+            }
+            None => *config = ChaosConfig::default(),
+        }
+        warn!("chaos settings now {}", config);
+        Ok(*config)
+    }
+
+    // uniform in [0, 1)
+    fn random(&self) -> f64 {
+        let mut z = self
+            .seed
+            .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        (z ^ (z >> 31)) as f64 / (u64::MAX as f64 + 1.0)
+    }
+
+    fn happens(&self, share: f64) -> bool {
+        share > 0.0 && self.random() < share
+    }
+
+    /// Whether the expiration of a record due now is skipped.
+    pub(crate) fn drop_expiration(&self) -> bool {
+        let share = self.config.lock().unwrap().map_or(0.0, |config| config.drop_expirations);
+        self.happens(share)
+    }
+
+    #[cfg(feature = "backup")]
+    pub(crate) fn backup_delay(&self) -> Duration {
+        self.config.lock().unwrap().map_or(Duration::ZERO, |config| config.backup_delay)
+    }
+}
+
+/// Slows down and fails requests as the chaos settings say, `/DEBUG/` ones excepted.
+impl Middleware for Chaos {
+    fn handle<'a>(&'a self, req: Request, next: Next<'a>) -> BoxFuture<'a, http_types::Result<Response>> {
+        Box::pin(async move {
+            let config = match self.config() {
+                Ok(config) if !route_starts_with(req.url().path(), DEBUG_PATHS) => config,
+                _ => return next.run(req).await,
+            };
+
+            let delay = config.latency + config.jitter.mul_f64(self.random());
+            if !delay.is_zero() {
+                Timer::after(delay).await;
+            }
+            if self.happens(config.error_rate) {
+                let mut response = Response::new(StatusCode::ServiceUnavailable);
+                response.set_body("chaos_injected");
+                return Ok(response);
+            }
+            next.run(req).await
+        })
+    }
+}
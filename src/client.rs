@@ -0,0 +1,256 @@
+//! Async client for the HTTP API of a mapper server.
+
+use std::{
+    net::{SocketAddr, TcpStream},
+    sync::Mutex,
+    time::Duration,
+};
+
+use http_types::{Method, Request, StatusCode, Url};
+use smol::Async;
+
+pub use crate::errors::ClientError;
+
+const API_KEY: &str = "X-API-Key";
+const RECORD_VERSION: &str = "X-Record-Version";
+// idle connections kept around for the next requests
+const MAX_IDLE_CONNECTIONS: usize = 16;
+
+type Connection = async_dup::Arc<Async<TcpStream>>;
+
+/// A reply of the server: status, record version header and body.
+struct Reply {
+    status: StatusCode,
+    version: Option<u64>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn into_result(self) -> Result<Self, ClientError> {
+        match self.status {
+            status if status.is_success() => Ok(self),
+            StatusCode::Forbidden => Err(ClientError::Forbidden),
+            status => Err(ClientError::Rejected {
+                status: status as u16,
+                reason: String::from_utf8_lossy(&self.body).into_owned(),
+            }),
+        }
+    }
+
+    /// `None` when the server answered that the record does not exist.
+    fn found(self) -> Result<Option<Self>, ClientError> {
+        if self.status == StatusCode::NotFound && self.body == b"record_not_found" {
+            return Ok(None);
+        }
+        self.into_result().map(Some)
+    }
+
+    fn version(&self) -> Result<u64, ClientError> {
+        self.version.ok_or_else(|| ClientError::Rejected {
+            status: self.status as u16,
+            reason: "missing record version".to_string(),
+        })
+    }
+}
+
+/// Typed client over the HTTP API, connections are kept alive and reused between requests.
+///
+/// The client can be shared between tasks, every in flight request uses its own connection.
+pub struct MapperClient {
+    address: SocketAddr,
+    api_key: Option<String>,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl MapperClient {
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            api_key: None,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Sends `api_key` with every request, for servers started with `--api-key`.
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, ClientError> {
+        let reply = self.send(Method::Get, &["GET", key], None).await?;
+        Ok(reply.found()?.map(|reply| reply.body))
+    }
+
+    /// Sets a record, returning its new version.
+    pub async fn set(&self, key: &str, value: impl Into<Vec<u8>>) -> Result<u64, ClientError> {
+        self.send(Method::Put, &["SET", key], Some(value.into()))
+            .await?
+            .into_result()?
+            .version()
+    }
+
+    /// Sets a record expiring after `ttl`, returning its new version.
+    pub async fn setex(
+        &self,
+        key: &str,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<u64, ClientError> {
+        let ttl = format_ttl(ttl);
+        self.send(Method::Put, &["SETEX", key, &ttl], Some(value.into()))
+            .await?
+            .into_result()?
+            .version()
+    }
+
+    pub async fn del(&self, key: &str) -> Result<(), ClientError> {
+        self.send(Method::Get, &["DEL", key], None)
+            .await?
+            .into_result()
+            .map(|_| ())
+    }
+
+    pub async fn exists(&self, key: &str) -> Result<bool, ClientError> {
+        let reply = self.send(Method::Get, &["EXISTS", key], None).await?;
+        Ok(reply.found()?.is_some())
+    }
+
+    /// Changes the ttl of a record, `false` if the record does not exist.
+    pub async fn expire(&self, key: &str, ttl: Duration) -> Result<bool, ClientError> {
+        let ttl = format_ttl(ttl);
+        let reply = self.send(Method::Get, &["EXPIRE", key, &ttl], None).await?;
+        Ok(reply.found()?.is_some())
+    }
+
+    /// Removes the ttl of a record, `false` if the record does not exist.
+    pub async fn persist(&self, key: &str) -> Result<bool, ClientError> {
+        let reply = self.send(Method::Get, &["PERSIST", key], None).await?;
+        Ok(reply.found()?.is_some())
+    }
+
+    /// Remaining time to live of a record, `None` if it has none or does not exist.
+    pub async fn ttl(&self, key: &str) -> Result<Option<Duration>, ClientError> {
+        let reply = self.send(Method::Get, &["TTL", key], None).await?;
+        if reply.status == StatusCode::NotFound && reply.body == b"ttl_not_found" {
+            return Ok(None);
+        }
+
+        match reply.found()? {
+            Some(reply) => {
+                let ttl = String::from_utf8_lossy(&reply.body).into_owned();
+                humantime::parse_duration(&ttl)
+                    .map(Some)
+                    .map_err(|_| ClientError::Rejected {
+                        status: reply.status as u16,
+                        reason: format!("unparsable ttl: {}", ttl),
+                    })
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub async fn ping(&self) -> Result<(), ClientError> {
+        self.send(Method::Get, &["PING"], None)
+            .await?
+            .into_result()
+            .map(|_| ())
+    }
+
+    pub async fn db_size(&self) -> Result<usize, ClientError> {
+        let reply = self.send(Method::Get, &["DBSIZE"], None).await?.into_result()?;
+        let size = String::from_utf8_lossy(&reply.body).into_owned();
+        size.parse().map_err(|_| ClientError::Rejected {
+            status: reply.status as u16,
+            reason: format!("unparsable size: {}", size),
+        })
+    }
+
+    pub async fn flush_all(&self) -> Result<(), ClientError> {
+        self.send(Method::Get, &["FLUSHALL"], None)
+            .await?
+            .into_result()
+            .map(|_| ())
+    }
+
+    pub async fn info(&self) -> Result<String, ClientError> {
+        let reply = self.send(Method::Get, &["INFO"], None).await?.into_result()?;
+        Ok(String::from_utf8_lossy(&reply.body).into_owned())
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        segments: &[&str],
+        body: Option<Vec<u8>>,
+    ) -> Result<Reply, ClientError> {
+        let mut url = Url::parse(&format!("http://{}/", self.address))
+            .map_err(|e| ClientError::Connection(e.to_string()))?;
+        url.path_segments_mut()
+            .map_err(|_| ClientError::Connection("invalid base url".to_string()))?
+            .pop_if_empty()
+            .extend(segments);
+
+        // an idle connection may have been closed by the server in the meantime,
+        // in that case the request is sent again over a new one
+        let idle = self.idle.lock().unwrap().pop();
+        if let Some(connection) = idle {
+            let request = self.request(method, url.clone(), body.clone());
+            if let Ok(reply) = self.send_over(connection, request).await {
+                return Ok(reply);
+            }
+        }
+
+        let stream = Async::<TcpStream>::connect(self.address)
+            .await
+            .map_err(|e| ClientError::Connection(e.to_string()))?;
+        let _ = stream.get_ref().set_nodelay(true);
+
+        let request = self.request(method, url, body);
+        self.send_over(async_dup::Arc::new(stream), request).await
+    }
+
+    fn request(&self, method: Method, url: Url, body: Option<Vec<u8>>) -> Request {
+        let mut request = Request::new(method, url);
+        if let Some(api_key) = &self.api_key {
+            request.insert_header(API_KEY, api_key.as_str());
+        }
+        if let Some(body) = body {
+            request.set_body(body);
+        }
+        request
+    }
+
+    async fn send_over(&self, connection: Connection, request: Request) -> Result<Reply, ClientError> {
+        let mut response = async_h1::connect(connection.clone(), request)
+            .await
+            .map_err(|e| ClientError::Connection(e.to_string()))?;
+        let body = response
+            .body_bytes()
+            .await
+            .map_err(|e| ClientError::Connection(e.to_string()))?;
+
+        let keep_alive = response
+            .header("Connection")
+            .is_none_or(|connection| connection.as_str() != "close");
+        if keep_alive {
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE_CONNECTIONS {
+                idle.push(connection);
+            }
+        }
+
+        Ok(Reply {
+            status: response.status(),
+            version: response
+                .header(RECORD_VERSION)
+                .and_then(|version| version.as_str().parse().ok()),
+            body,
+        })
+    }
+}
+
+/// Ttl as understood by the server, in milliseconds so no precision is lost.
+fn format_ttl(ttl: Duration) -> String {
+    format!("{}ms", ttl.as_millis())
+}
@@ -0,0 +1,221 @@
+//! Cluster mode: every node of the `--cluster` file owns ranges of the hash slots, and
+//! commands on keys of slots it does not own are sent to the node owning them.
+//!
+//! The file holds one node per line, `<node> <slots>...`, the node being the `host:port`
+//! or base url clients reach it at and the slots single ones or `first-last` ranges. Every
+//! slot has to be owned by exactly one node. Blank lines and lines starting with `#` are
+//! skipped. Nodes have to share the file and `--shard-hash`, which decides the slot of a key.
+//!
+//! With `--discover` the nodes come from DNS instead, and split the slots evenly between
+//! them in the order of their urls, again whenever they change.
+
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use crate::{errors::TransactionError, storage::SLOT_COUNT};
+
+#[derive(Debug)]
+pub(crate) struct Cluster {
+    // replaced when --discover finds other nodes
+    layout: RwLock<Arc<Layout>>,
+    // sent to the other nodes when asking them for quorum leases
+    api_key: Option<String>,
+}
+
+/// The nodes and the slots each one owns, at some point in time.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Layout {
+    // base url of every node
+    nodes: Vec<String>,
+    // index in `nodes` of the owner of every slot, indexed by slot
+    owners: Vec<usize>,
+    // index of this node
+    me: usize,
+}
+
+impl Cluster {
+    /// Reads the `--cluster` file at `path`, finding this node as `node`.
+    pub(crate) fn load(path: &Path, node: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("unable to read --cluster {}: {}", path.display(), e))?;
+        let layout = Layout::parse(&content, node).map_err(|e| format!("invalid --cluster {}: {}", path.display(), e))?;
+        Ok(Self::new(layout))
+    }
+
+    /// The cluster of `nodes` and this node `node`, the slots split evenly between them.
+    #[cfg(feature = "srv")]
+    pub(crate) fn spread(nodes: &[String], node: &str) -> Self {
+        Self::new(Layout::spread(nodes, node))
+    }
+
+    fn new(layout: Layout) -> Self {
+        Self {
+            layout: RwLock::new(Arc::new(layout)),
+            api_key: None,
+        }
+    }
+
+    #[cfg(feature = "auth")]
+    pub(crate) fn with_api_key(mut self, api_key: Option<String>) -> Self {
+        self.api_key = api_key;
+        self
+    }
+
+    /// The nodes and slots of now, one command sticks to them.
+    pub(crate) fn layout(&self) -> Arc<Layout> {
+        self.layout.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Splits the slots evenly between `nodes` and this node in place of the current
+    /// layout, `false` when they are the nodes of the current one.
+    #[cfg(feature = "srv")]
+    pub(crate) fn respread(&self, nodes: &[String]) -> bool {
+        let mut layout = self.layout.write().unwrap_or_else(|e| e.into_inner());
+        let respread = Layout::spread(nodes, &layout.nodes[layout.me]);
+        if respread == **layout {
+            return false;
+        }
+        *layout = Arc::new(respread);
+        true
+    }
+
+    pub(crate) fn api_key(&self) -> Option<&str> {
+        self.api_key.as_deref()
+    }
+
+    /// Fails with `Moved` to the node owning every one of `slots` when it is another one,
+    /// with `CrossNode` when they are owned by several.
+    pub(crate) fn check(&self, slots: impl IntoIterator<Item = usize>) -> Result<(), TransactionError> {
+        let layout = self.layout();
+        let mut slots = slots.into_iter();
+        let Some(first) = slots.next() else {
+            return Ok(());
+        };
+        let owner = layout.owners[first];
+        if slots.any(|slot| layout.owners[slot] != owner) {
+            return Err(TransactionError::CrossNode);
+        }
+        match owner == layout.me {
+            true => Ok(()),
+            false => Err(TransactionError::Moved {
+                slot: first,
+                node: layout.nodes[owner].clone(),
+            }),
+        }
+    }
+
+    /// `name:value` lines about the cluster and this node.
+    pub(crate) fn info(&self) -> String {
+        let layout = self.layout();
+        let my_slots = layout.owners.iter().filter(|owner| **owner == layout.me).count();
+        [
+            format!("cluster_known_nodes:{}", layout.nodes.len()),
+            format!("cluster_slots:{}", SLOT_COUNT),
+            format!("cluster_my_node:{}", layout.nodes[layout.me]),
+            format!("cluster_my_slots:{}", my_slots),
+        ]
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect()
+    }
+
+    /// `<first>-<last> <node>` lines, one per range of slots owned by the same node.
+    pub(crate) fn slots(&self) -> String {
+        let layout = self.layout();
+        let mut lines = String::new();
+        let mut first = 0;
+        for slot in 1..=SLOT_COUNT {
+            if slot == SLOT_COUNT || layout.owners[slot] != layout.owners[first] {
+                lines.push_str(&format!("{}-{} {}\n", first, slot - 1, layout.nodes[layout.owners[first]]));
+                first = slot;
+            }
+        }
+        lines
+    }
+}
+
+impl Layout {
+    fn parse(content: &str, node: &str) -> Result<Self, String> {
+        let mut nodes = Vec::new();
+        let mut owners = vec![None; SLOT_COUNT];
+        for (number, line) in content.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut tokens = line.split_ascii_whitespace();
+            let (Some(url), Some(_)) = (tokens.next().map(base_url), tokens.clone().next()) else {
+                return Err(format!("line {}: expected <node> <slots>...", number));
+            };
+            if nodes.contains(&url) {
+                return Err(format!("line {}: node {} listed twice", number, url));
+            }
+
+            for range in tokens {
+                let (first, last) = parse_range(range).ok_or_else(|| {
+                    format!("line {}: invalid slots {}, expected 0 to {} or a first-last range", number, range, SLOT_COUNT - 1)
+                })?;
+                for (slot, owner) in owners.iter_mut().enumerate().take(last + 1).skip(first) {
+                    if owner.replace(nodes.len()).is_some() {
+                        return Err(format!("line {}: slot {} already owned", number, slot));
+                    }
+                }
+            }
+            nodes.push(url);
+        }
+
+        let owners = owners
+            .iter()
+            .enumerate()
+            .map(|(slot, owner)| owner.ok_or(format!("slot {} owned by no node", slot)))
+            .collect::<Result<Vec<usize>, String>>()?;
+        let me = nodes
+            .iter()
+            .position(|url| *url == base_url(node))
+            .ok_or(format!("no node {}", node))?;
+        Ok(Self { nodes, owners, me })
+    }
+
+    // sorted, every node gets a range of the slots of about the same size; this node counts
+    // even before the others see it
+    #[cfg(feature = "srv")]
+    fn spread(nodes: &[String], node: &str) -> Self {
+        let mut nodes: Vec<String> = nodes.iter().map(|node| base_url(node)).collect();
+        nodes.push(base_url(node));
+        nodes.sort();
+        nodes.dedup();
+        let owners = (0..SLOT_COUNT).map(|slot| slot * nodes.len() / SLOT_COUNT).collect();
+        let me = nodes.iter().position(|url| *url == base_url(node)).unwrap_or_default();
+        Self { nodes, owners, me }
+    }
+
+    /// Base url of every node, this one at [`Layout::me`].
+    pub(crate) fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    pub(crate) fn me(&self) -> usize {
+        self.me
+    }
+}
+
+// `host:port` as `http://host:port`, without a trailing slash
+fn base_url(node: &str) -> String {
+    let node = node.trim_end_matches('/');
+    match node.contains("://") {
+        true => node.to_string(),
+        false => format!("http://{}", node),
+    }
+}
+
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let (first, last) = match range.split_once('-') {
+        Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+        None => {
+            let slot = range.parse().ok()?;
+            (slot, slot)
+        }
+    };
+    (first <= last && last < SLOT_COUNT).then_some((first, last))
+}
@@ -0,0 +1,123 @@
+use crate::{errors::TransactionError, item_hash};
+
+// width (u32), depth (u32) and total count (u64), little endian, then `depth` rows of
+// `width` u64 counters
+const HEADER_LEN: usize = 4 + 4 + 8;
+const DEPTH_AT: usize = 4;
+const COUNT_AT: usize = 8;
+// like bloom filters, sketches stay within 512MB
+const MAX_CMS_COUNTERS: u64 = 1 << 26;
+
+/// Dimensions of a count-min sketch: every item is counted in one of `width` counters
+/// on each of `depth` rows, its estimate being the smallest of them.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CmsParams {
+    width: u32,
+    depth: u32,
+}
+
+impl CmsParams {
+    /// `None` for an empty sketch or one growing past 512MB.
+    pub(crate) fn by_dim(width: u32, depth: u32) -> Option<Self> {
+        let counters = width as u64 * depth as u64;
+        (counters > 0 && counters <= MAX_CMS_COUNTERS).then_some(Self { width, depth })
+    }
+
+    /// Sized like the redis one: an estimate overshoots by more than `error` times the
+    /// total count with a probability of `probability`.
+    pub(crate) fn by_prob(error: f64, probability: f64) -> Option<Self> {
+        if !(error > 0.0 && error < 1.0 && probability > 0.0 && probability < 1.0) {
+            return None;
+        }
+        let width = (2.0 / error).ceil();
+        let depth = (probability.ln() / 0.5f64.ln()).ceil();
+        // saturating casts, the counter limit rejects them
+        Self::by_dim(width as u32, (depth as u32).max(1))
+    }
+
+    /// A sketch with every counter at zero.
+    pub(crate) fn create(&self) -> Vec<u8> {
+        let counters = self.width as usize * self.depth as usize;
+        let mut sketch = Vec::with_capacity(HEADER_LEN + counters * 8);
+        sketch.extend_from_slice(&self.width.to_le_bytes());
+        sketch.extend_from_slice(&self.depth.to_le_bytes());
+        sketch.extend_from_slice(&0u64.to_le_bytes());
+        sketch.resize(HEADER_LEN + counters * 8, 0);
+        sketch
+    }
+}
+
+/// Counts `item` `increment` more times, returning its new estimate. Counters saturate.
+/// `None` if `sketch` is not one.
+pub(crate) fn incr_by(sketch: &mut [u8], item: &[u8], increment: u64) -> Option<u64> {
+    let (width, depth) = shape(sketch)?;
+    let mut estimate = u64::MAX;
+    for at in counters(item, width, depth) {
+        let counter = read_u64(sketch, at).saturating_add(increment);
+        sketch[at..at + 8].copy_from_slice(&counter.to_le_bytes());
+        estimate = estimate.min(counter);
+    }
+
+    let count = read_u64(sketch, COUNT_AT).saturating_add(increment);
+    sketch[COUNT_AT..HEADER_LEN].copy_from_slice(&count.to_le_bytes());
+    Some(estimate)
+}
+
+/// How many times `item` was counted, never less than the truth. `None` if `sketch` is not one.
+pub(crate) fn query(sketch: &[u8], item: &[u8]) -> Option<u64> {
+    let (width, depth) = shape(sketch)?;
+    counters(item, width, depth).map(|at| read_u64(sketch, at)).min()
+}
+
+/// Replaces the counters of `sketch` by the sum of the `sources` ones, each multiplied by
+/// its weight. `sketch` is left untouched if a source has other dimensions.
+pub(crate) fn merge(sketch: &mut [u8], sources: &[(Vec<u8>, u64)]) -> Result<(), TransactionError> {
+    let dimensions = shape(sketch).ok_or(TransactionError::WrongType)?;
+    for (source, _) in sources {
+        if shape(source).ok_or(TransactionError::WrongType)? != dimensions {
+            return Err(TransactionError::SketchMismatch);
+        }
+    }
+
+    // the total count sits in front of the counters and is merged the same way
+    for at in (COUNT_AT..sketch.len()).step_by(8) {
+        let merged = sources.iter().fold(0u64, |merged, (source, weight)| {
+            merged.saturating_add(read_u64(source, at).saturating_mul(*weight))
+        });
+        sketch[at..at + 8].copy_from_slice(&merged.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// `width`, `depth` and `count` lines. `None` if `sketch` is not one.
+pub(crate) fn info(sketch: &[u8]) -> Option<String> {
+    let (width, depth) = shape(sketch)?;
+    Some(format!(
+        "width:{}\ndepth:{}\ncount:{}\n",
+        width,
+        depth,
+        read_u64(sketch, COUNT_AT)
+    ))
+}
+
+fn shape(sketch: &[u8]) -> Option<(u32, u32)> {
+    let width = u32::from_le_bytes(sketch.get(..DEPTH_AT)?.try_into().ok()?);
+    let depth = u32::from_le_bytes(sketch.get(DEPTH_AT..COUNT_AT)?.try_into().ok()?);
+    let counters = width as usize * depth as usize;
+    (counters > 0 && sketch.len() == HEADER_LEN + counters * 8).then_some((width, depth))
+}
+
+fn read_u64(sketch: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&sketch[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Offsets of the counters of `item`, one per row.
+fn counters(item: &[u8], width: u32, depth: u32) -> impl Iterator<Item = usize> {
+    let (h1, h2) = item_hash::hash_pair(item);
+    (0..depth as u64).map(move |row| {
+        let column = h1.wrapping_add(row.wrapping_mul(h2)) % width as u64;
+        HEADER_LEN + (row * width as u64 + column) as usize * 8
+    })
+}
@@ -1,60 +1,486 @@
 use std::{
     error, io,
     net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::Arc,
     time::Duration,
 };
+#[cfg(feature = "backup")]
+use std::thread;
 
-use ctrlc::Error;
+#[cfg(feature = "tls")]
+use futures_rustls::TlsAcceptor;
+use http_types::Url;
 use log::{error, info, Level};
-use smol::{future::race, Async};
-use clap::Parser;
+use smol::{
+    future::{pending, race},
+    Async,
+};
+use clap::{Parser, Subcommand};
 
+#[cfg(feature = "backup")]
+use crate::aof::{Aof, AofWriter, Fsync, DEFAULT_AOF_FSYNC, DEFAULT_AOF_REWRITE_MIN_SIZE, DEFAULT_AOF_REWRITE_PERCENTAGE};
+#[cfg(feature = "backup")]
+use crate::{
+    backup_format::{BackupCompression, CURRENT_FORMAT_VERSION, DEFAULT_BACKUP_COMPRESSION}, backup_handler::{BackupHandler, DEFAULT_BACKUP_FULL_EVERY}, backup_tools,
+};
+#[cfg(feature = "auth")]
+use crate::{
+    acl::Acl,
+    middleware::{AclAuth, AdminAuth, Auth},
+};
+#[cfg(feature = "discovery")]
+use crate::discovery::{Registration, Registry};
+#[cfg(feature = "chaos")]
+use crate::chaos::ChaosConfig;
+#[cfg(feature = "mirror")]
+use crate::mirror::{Mirror, Secondary};
+#[cfg(feature = "replication")]
+use crate::replication::{Primary, Replica};
+#[cfg(feature = "cluster")]
+use crate::cluster::Cluster;
+#[cfg(feature = "srv")]
+use crate::srv::{Discover, Rediscovery};
+#[cfg(feature = "metrics")]
+use crate::middleware::Metrics;
+#[cfg(feature = "migrate")]
+use crate::redis_migration;
+#[cfg(feature = "s3")]
+use crate::s3::{Bucket, Credentials, DEFAULT_S3_REGION};
+#[cfg(feature = "tls")]
+use crate::tls;
+#[cfg(feature = "http3")]
+use crate::http3;
 use crate::{
-    backup_handler::BackupHandler, http_handler::hadle_client, logger::setup_logger, storage::Storage,
+    bench::{self, BenchParams},
+    capture::{self, Capture, Speed},
+    http_handler::hadle_client,
+    journal::DEFAULT_JOURNAL_CAPACITY,
+    logger::setup_logger,
+    memcached::{handle_memcached_client, MemcachedSettings},
+    memory::{self, MaxMemory, MemoryPolicy, DEFAULT_MEMORY_POLICY},
+    middleware::{
+        AccessLog, BasePath, BodyLimit, Chain, Cors, Idempotency, Middleware, RateLimit, RateLimitConfig,
+        DEFAULT_BASE_PATH,
+    },
+    shard_hash::{ShardHash, DEFAULT_SHARD_HASH},
+    shard_lock::{ShardLockMode, DEFAULT_SHARD_LOCK},
+    slowlog::{DEFAULT_SLOWLOG_SIZE, DEFAULT_SLOWLOG_THRESHOLD_MS},
+    storage::{Storage, DEFAULT_KEYS_LIMIT, DEFAULT_SHARD_COUNT, MAX_SHARD_COUNT},
+    text_protocol::{handle_text_client, TextSettings},
 };
 
+// largest request body accepted by default, 64 MiB
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
 
 #[derive(Parser, Debug)]
 #[command(name = "Mapper")]
 #[command(about = "A simple and concurrent in memory database", long_about = None)]
 pub struct MapperBuilder {
+    #[cfg(feature = "auth")]
     #[arg(long, help = "Api key for authentication")]
     pub(crate) api_key: Option<String>,
 
+    #[cfg(feature = "auth")]
+    #[arg(long, help = "Key required by the admin endpoints (/ADMIN, /PATTERN, /SEARCH, /DEBUG)")]
+    pub(crate) admin_key: Option<String>,
+
+    #[cfg(feature = "auth")]
+    #[arg(long, conflicts_with_all = ["api_key", "admin_key"], help = "File of api keys, one `<api key> <read-only|read-write|admin> [<key prefix>...]` line each, in place of --api-key and --admin-key")]
+    pub(crate) acl: Option<PathBuf>,
+
     #[arg(long, help = "Socket address to bind", default_value = "127.0.0.1:6379")]
     pub(crate) address: String,
 
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_key", help = "PEM certificate chain served over HTTPS on --address, leaf first, plain HTTP without one")]
+    pub(crate) tls_cert: Option<PathBuf>,
+
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert", help = "PEM private key of --tls-cert")]
+    pub(crate) tls_key: Option<PathBuf>,
+
+    #[cfg(feature = "http3")]
+    #[arg(long, requires = "tls_cert", help = "UDP socket address of the HTTP/3 listener, serving the API over QUIC with --tls-cert, off by default")]
+    pub(crate) http3_address: Option<String>,
+
+    #[arg(long, help = "Socket address of the plain text protocol listener, off by default")]
+    pub(crate) text_address: Option<String>,
+
+    #[arg(long, help = "Socket address of the memcached text protocol listener, off by default")]
+    pub(crate) memcached_address: Option<String>,
+
+    #[cfg(feature = "discovery")]
+    #[arg(long, help = "Service registry to register into: consul:<address> or etcd:<address>")]
+    pub(crate) register: Option<Registry>,
+
+    #[cfg(feature = "discovery")]
+    #[arg(long, help = "Address registered for clients to reach the instance [default: --address]")]
+    pub(crate) advertise_address: Option<String>,
+
+    #[cfg(feature = "discovery")]
+    #[arg(long, help = "Seconds the registry keeps the instance after its last heartbeat", default_value_t = 15u64)]
+    pub(crate) register_ttl: u64,
+
+    #[cfg(feature = "mirror")]
+    #[arg(long, help = "Endpoint every write is copied to: http://[:api-key@]host:port of a mapper, or a redis:// url")]
+    pub(crate) mirror: Option<Secondary>,
+
+    #[cfg(feature = "replication")]
+    #[arg(long, help = "Primary to replicate, as host:port or http://[:api-key@]host:port: its records and writes are applied, clients may only read")]
+    pub(crate) replica_of: Option<Primary>,
+
+    #[cfg(feature = "cluster")]
+    #[arg(long, group = "nodes", help = "File of the cluster nodes, one `<node> <slot or first-last slot range>...` line each: keys of slots owned by another node are redirected there")]
+    pub(crate) cluster: Option<PathBuf>,
+
+    #[cfg(feature = "srv")]
+    #[arg(long, group = "nodes", help = "Cluster nodes to find in DNS in place of a --cluster file, srv:<name> for the targets of its SRV records, splitting the slots evenly")]
+    pub(crate) discover: Option<Discover>,
+
+    #[cfg(feature = "srv")]
+    #[arg(long, requires = "discover", help = "Seconds between two resolutions of the --discover nodes", default_value_t = 30u64)]
+    pub(crate) discover_interval: u64,
+
+    #[cfg(feature = "cluster")]
+    #[arg(long, requires = "nodes", help = "This node in the --cluster file or among the --discover nodes [default: --address]")]
+    pub(crate) cluster_node: Option<String>,
+
     #[arg(long, help = "Enable asynchronous logging", default_value_t = false, hide = true)]
     pub(crate) async_logging: bool,
 
     #[arg(long, help = "Logging level (e.g., info, debug, error)", default_value = "info")]
     pub(crate) logging_level: String,
 
+    #[cfg(feature = "backup")]
     #[arg(long, help = "Enable backup functionality", default_value_t = true)]
     pub(crate) backup: bool,
 
+    #[cfg(feature = "backup")]
     #[arg(long, help = "Backup interval in seconds", default_value_t = 240u64)]
     pub(crate) backup_interval: u64,
 
+    #[cfg(feature = "backup")]
     #[arg(long, help = "Path for backup files", default_value = ".")]
     pub(crate) backup_path: String,
+
+    #[cfg(feature = "backup")]
+    #[arg(long, help = "Restore backup shards on first access instead of at startup", default_value_t = false)]
+    pub(crate) lazy_recovery: bool,
+
+    #[cfg(feature = "backup")]
+    #[arg(long, help = "Refuse to start when the backup has missing, truncated or corrupted files", default_value_t = false)]
+    pub(crate) strict_recovery: bool,
+
+    #[cfg(feature = "backup")]
+    #[arg(long, help = "Backup compression: none, deflate[:0-9] or zstd[:1-22]", default_value = DEFAULT_BACKUP_COMPRESSION)]
+    pub(crate) backup_compression: BackupCompression,
+
+    #[cfg(feature = "backup")]
+    #[arg(long, help = "Shards serialized concurrently during a backup [default: available cores]")]
+    pub(crate) backup_parallelism: Option<usize>,
+
+    #[cfg(feature = "backup")]
+    #[arg(long, help = "Every how many backup cycles all the shards are serialized again, instead of copying the unchanged ones from the previous archive, 0 for never", default_value_t = DEFAULT_BACKUP_FULL_EVERY)]
+    pub(crate) backup_full_every: u32,
+
+    #[cfg(feature = "s3")]
+    #[arg(long, help = "S3 compatible bucket every backup is uploaded to, and recovered from when there is no local backup")]
+    pub(crate) backup_s3_bucket: Option<String>,
+
+    #[cfg(feature = "s3")]
+    #[arg(long, requires = "backup_s3_bucket", help = "Url of the S3 compatible store, such as http://minio:9000 [default: https://s3.<region>.amazonaws.com]")]
+    pub(crate) backup_s3_endpoint: Option<String>,
+
+    #[cfg(feature = "s3")]
+    #[arg(long, help = "Region of --backup-s3-bucket", default_value = DEFAULT_S3_REGION)]
+    pub(crate) backup_s3_region: String,
+
+    #[cfg(feature = "s3")]
+    #[arg(long, help = "Prefix of the backup archive key in --backup-s3-bucket, such as mapper/", default_value = "")]
+    pub(crate) backup_s3_prefix: String,
+
+    #[cfg(feature = "s3")]
+    #[arg(long, requires = "backup_s3_bucket", help = "Access key id signing the S3 requests [default: AWS_ACCESS_KEY_ID]")]
+    pub(crate) backup_s3_access_key: Option<String>,
+
+    #[cfg(feature = "s3")]
+    #[arg(long, requires = "backup_s3_bucket", help = "Secret access key signing the S3 requests [default: AWS_SECRET_ACCESS_KEY]")]
+    pub(crate) backup_s3_secret_key: Option<String>,
+
+    #[cfg(feature = "backup")]
+    #[arg(long, help = "Append-only file every change is appended to and replayed from at startup, after the backup, off by default")]
+    pub(crate) aof: Option<PathBuf>,
+
+    #[cfg(feature = "backup")]
+    #[arg(long, help = "When appended changes are synced to disk: always, before answering a write, everysec or no", default_value = DEFAULT_AOF_FSYNC)]
+    pub(crate) aof_fsync: Fsync,
+
+    #[cfg(feature = "backup")]
+    #[arg(long, help = "Growth in percent of the append-only file since its last rewrite that rewrites it, 0 for never", default_value_t = DEFAULT_AOF_REWRITE_PERCENTAGE)]
+    pub(crate) aof_rewrite_percentage: u64,
+
+    #[cfg(feature = "backup")]
+    #[arg(long, help = "Size in bytes below which the append-only file is not rewritten", default_value_t = DEFAULT_AOF_REWRITE_MIN_SIZE)]
+    pub(crate) aof_rewrite_min_size: u64,
+
+    #[cfg(feature = "backup")]
+    #[arg(long, help = "Microseconds the first write waiting for a sync of the append-only file waits for others to share it, 0 to sync right away", default_value_t = 0)]
+    pub(crate) aof_commit_window: u64,
+
+    #[arg(long, help = "Recent changes kept for the /CHANGES feed", default_value_t = DEFAULT_JOURNAL_CAPACITY)]
+    pub(crate) journal_size: usize,
+
+    #[arg(long, help = "Hash spreading keys over the shards: siphash, siphash:<32 hex digits secret key>, xxhash or fxhash", default_value = DEFAULT_SHARD_HASH)]
+    pub(crate) shard_hash: ShardHash,
+
+    #[arg(long, help = "Lock of every shard: rwlock, or striped[:<stripes>] for read heavy loads on many cores", default_value = DEFAULT_SHARD_LOCK)]
+    pub(crate) shard_lock: ShardLockMode,
+
+    #[arg(long, help = "Shards the keys are spread over at startup, up to 1024, unless the backup recovered was written with another count", default_value_t = DEFAULT_SHARD_COUNT)]
+    pub(crate) shards: usize,
+
+    #[arg(long, help = "Seconds a deleted key answers known_missing instead of record_not_found, off by default")]
+    pub(crate) tombstone_ttl: Option<u64>,
+
+    #[arg(long, help = "Keys listed by /KEYS at most, whatever the limit asked for", default_value_t = DEFAULT_KEYS_LIMIT)]
+    pub(crate) keys_limit: usize,
+
+    #[arg(long, help = "Key prefix whose plain values are indexed for /SEARCH, repeatable")]
+    pub(crate) search_prefix: Vec<String>,
+
+    #[arg(long, help = "Largest memory used, bytes such as 2GiB or a share of the container or machine memory such as 75% [default: 80% of the container limit, none outside containers]")]
+    pub(crate) max_memory: Option<MaxMemory>,
+
+    #[arg(long, help = "What happens as memory usage gets close to --max-memory: evict, snapshot or read-only", default_value = DEFAULT_MEMORY_POLICY)]
+    pub(crate) memory_policy: MemoryPolicy,
+
+    #[arg(long, help = "Path prefix the routes are also served under, empty for none", default_value = DEFAULT_BASE_PATH)]
+    pub(crate) base_path: String,
+
+    #[arg(long, help = "Largest request body accepted in bytes, /IMPORT excepted", default_value_t = DEFAULT_MAX_BODY_SIZE)]
+    pub(crate) max_body_size: usize,
+
+    #[arg(long, help = "Seconds the response to an Idempotency-Key is replayed for, 0 to ignore the header", default_value_t = 86400u64)]
+    pub(crate) idempotency_window: u64,
+
+    #[arg(long, help = "Origin browser pages may call the HTTP API from, * for any, repeatable")]
+    pub(crate) cors_origin: Vec<String>,
+
+    #[arg(long, help = "Requests a client, its api key or else its address, may make over a window, like 100/1s")]
+    pub(crate) rate_limit: Option<RateLimitConfig>,
+
+    #[arg(long, help = "Milliseconds an HTTP command takes before it is recorded to /SLOWLOG", default_value_t = DEFAULT_SLOWLOG_THRESHOLD_MS)]
+    pub(crate) slowlog_threshold: u64,
+
+    #[arg(long, help = "Slow commands kept by /SLOWLOG, the oldest dropped first, 0 for none", default_value_t = DEFAULT_SLOWLOG_SIZE)]
+    pub(crate) slowlog_size: usize,
+
+    #[arg(long, help = "File the incoming HTTP requests are recorded to, for the replay subcommand")]
+    pub(crate) capture: Option<PathBuf>,
+
+    #[arg(long, help = "Share of the requests recorded by --capture, from 0 to 1", default_value_t = 1.0)]
+    pub(crate) capture_sample: f64,
+
+    #[cfg(feature = "chaos")]
+    #[arg(long, num_args = 0..=1, default_missing_value = "", help = "Inject faults, for testing only: latency=<d>,jitter=<d>,error_rate=<0-1>,drop_expirations=<0-1>,backup_delay=<d>")]
+    pub(crate) chaos: Option<ChaosConfig>,
+
+    #[command(subcommand)]
+    pub command: Option<MapperCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MapperCommand {
+    #[cfg(feature = "backup")]
+    #[command(about = "Upgrade a backup archive to another format version, offline")]
+    MigrateBackup {
+        #[arg(long, help = "Backup archive to read")]
+        from: PathBuf,
+
+        #[arg(long, help = "Backup archive to write")]
+        to: PathBuf,
+
+        #[arg(long, help = "Target format version", default_value_t = CURRENT_FORMAT_VERSION)]
+        format_version: u16,
+
+        #[arg(long, help = "Compression of the written archive: none, deflate[:0-9] or zstd[:1-22]", default_value = DEFAULT_BACKUP_COMPRESSION)]
+        compression: BackupCompression,
+    },
+
+    #[cfg(feature = "backup")]
+    #[command(about = "Salvage the readable records of a damaged backup archive into a new one, offline")]
+    RepairBackup {
+        #[arg(long, help = "Damaged backup archive to read")]
+        from: PathBuf,
+
+        #[arg(long, help = "Backup archive to write")]
+        to: PathBuf,
+
+        #[arg(long, help = "Compression of the written archive: none, deflate[:0-9] or zstd[:1-22]", default_value = DEFAULT_BACKUP_COMPRESSION)]
+        compression: BackupCompression,
+    },
+
+    #[cfg(feature = "migrate")]
+    #[command(about = "Copy the string keys of a Redis instance, with their TTLs, into a running instance")]
+    Migrate {
+        #[arg(long, help = "Redis to copy from: redis://[user:password@]host[:port][/db]")]
+        from: Url,
+
+        #[arg(long, help = "Only copy the keys matching this Redis glob pattern")]
+        pattern: Option<String>,
+
+        #[arg(long, help = "Keep copying the keys changed in Redis, from keyspace notifications, until stopped", default_value_t = false)]
+        follow: bool,
+
+        #[arg(long, help = "Instance to copy into [default: --address]")]
+        to: Option<String>,
+    },
+
+    #[cfg(feature = "backup")]
+    #[command(about = "Redistribute the records of a backup archive over another shard count, offline")]
+    Rebalance {
+        #[arg(help = "Backup archive to read")]
+        backup: PathBuf,
+
+        #[arg(long, help = "Backup archive to write [default: in place]")]
+        to: Option<PathBuf>,
+
+        #[arg(long, help = "Shard count of the written archive")]
+        shards: usize,
+
+        #[arg(long, help = "Compression of the written archive: none, deflate[:0-9] or zstd[:1-22]", default_value = DEFAULT_BACKUP_COMPRESSION)]
+        compression: BackupCompression,
+    },
+
+    #[command(about = "Send the requests recorded by --capture to an instance, at the pace they came in")]
+    Replay {
+        #[arg(help = "Capture file to read")]
+        capture: PathBuf,
+
+        #[arg(long, help = "Instance to send the requests to, as http://host:port")]
+        target: Url,
+
+        #[arg(long, help = "Multiple of the captured pace, like 2x, or max for as fast as possible", default_value = "1x")]
+        speed: Speed,
+    },
+
+    #[command(about = "Measure the storage throughput under each shard lock, in process")]
+    Bench {
+        #[command(flatten)]
+        params: BenchParams,
+    },
+}
+
+impl MapperCommand {
+    pub fn run(&self, mapper_params: &MapperBuilder) -> Result<(), Box<dyn error::Error>> {
+        setup_logger(false, grab_logger_level(mapper_params));
+
+        match self {
+            #[cfg(feature = "backup")]
+            MapperCommand::MigrateBackup { from, to, format_version, compression } => {
+                backup_tools::migrate_backup(from, to, *format_version, *compression)
+            }
+            #[cfg(feature = "backup")]
+            MapperCommand::RepairBackup { from, to, compression } => {
+                backup_tools::repair_backup(from, to, *compression)
+            }
+            #[cfg(feature = "backup")]
+            MapperCommand::Rebalance { backup, to, shards, compression } => {
+                backup_tools::rebalance_backup(backup, to.as_deref(), *shards, *compression)
+            }
+            #[cfg(feature = "migrate")]
+            MapperCommand::Migrate { from, pattern, follow, to } => {
+                #[cfg(feature = "auth")]
+                let api_key = mapper_params.api_key.as_deref();
+                #[cfg(not(feature = "auth"))]
+                let api_key = None;
+                redis_migration::migrate(
+                    from,
+                    pattern.as_deref(),
+                    *follow,
+                    to.as_deref().unwrap_or(&mapper_params.address),
+                    api_key,
+                )
+            }
+            MapperCommand::Replay { capture, target, speed } => {
+                #[cfg(feature = "auth")]
+                let api_key = mapper_params.api_key.as_deref();
+                #[cfg(not(feature = "auth"))]
+                let api_key = None;
+                capture::replay(capture, target, *speed, api_key)
+            }
+            MapperCommand::Bench { params } => bench::run(params, mapper_params.shard_hash),
+        }
+    }
 }
 
 enum Signal {
     Quit,
     Listen(io::Result<(Async<TcpStream>, SocketAddr)>),
+    ListenText(io::Result<(Async<TcpStream>, SocketAddr)>),
+    ListenMemcached(io::Result<(Async<TcpStream>, SocketAddr)>),
 }
 
+#[cfg(feature = "backup")]
 pub struct Backup {
     backup_interval: Duration,
     backup_path: String,
+    lazy_recovery: bool,
+    strict_recovery: bool,
+    compression: BackupCompression,
+    parallelism: usize,
+    full_every: u32,
+    #[cfg(feature = "s3")]
+    bucket: Option<Arc<Bucket>>,
 }
 
 pub struct Mapper {
     ctrlc_channel: (smol::channel::Sender<()>, smol::channel::Receiver<()>),
+    #[cfg(feature = "auth")]
     password: Option<String>,
+    #[cfg(feature = "auth")]
+    admin_key: Option<String>,
+    #[cfg(feature = "auth")]
+    acl: Option<Arc<Acl>>,
     socket_address: SocketAddr,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+    #[cfg(feature = "http3")]
+    http3: Option<(SocketAddr, quinn::ServerConfig)>,
+    text_address: Option<SocketAddr>,
+    memcached_address: Option<SocketAddr>,
+    journal_size: usize,
+    search_prefixes: Vec<String>,
+    shard_hash: ShardHash,
+    shard_lock: ShardLockMode,
+    shard_count: usize,
+    tombstone_ttl: Option<Duration>,
+    keys_limit: usize,
+    idempotency_window: Duration,
+    cors_origins: Vec<String>,
+    rate_limit: Option<RateLimitConfig>,
+    base_path: Option<Arc<BasePath>>,
+    memory: Option<(u64, MemoryPolicy)>,
+    max_body_size: usize,
+    slowlog: Option<(Duration, usize)>,
+    capture: Option<(PathBuf, f64)>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<ChaosConfig>,
+    #[cfg(feature = "backup")]
     backup: Option<Backup>,
+    #[cfg(feature = "backup")]
+    aof: Option<Arc<Aof>>,
+    #[cfg(feature = "discovery")]
+    registration: Option<(Registry, SocketAddr, Duration)>,
+    #[cfg(feature = "mirror")]
+    mirror: Option<Secondary>,
+    #[cfg(feature = "replication")]
+    replica_of: Option<Primary>,
+    #[cfg(feature = "cluster")]
+    cluster: Option<Arc<Cluster>>,
+    #[cfg(feature = "srv")]
+    rediscover: Option<(Discover, Duration)>,
 }
 
 impl Mapper {
@@ -68,22 +494,214 @@ impl Mapper {
             .parse::<SocketAddr>()
             .expect("unable to parse socket address");
 
+        let text_address = mapper_params.text_address.as_ref().map(|address| {
+            address
+                .parse::<SocketAddr>()
+                .expect("unable to parse text protocol socket address")
+        });
+        let memcached_address = mapper_params.memcached_address.as_ref().map(|address| {
+            address
+                .parse::<SocketAddr>()
+                .expect("unable to parse memcached socket address")
+        });
+
+        #[cfg(feature = "discovery")]
+        let advertise_address = match &mapper_params.advertise_address {
+            Some(address) => address
+                .parse::<SocketAddr>()
+                .expect("unable to parse advertised socket address"),
+            None => socket_address,
+        };
+
+        if !(mapper_params.capture_sample > 0.0 && mapper_params.capture_sample <= 1.0) {
+            return Err("--capture-sample must be above 0 and at most 1".into());
+        }
+
+        if mapper_params.shards == 0 || mapper_params.shards > MAX_SHARD_COUNT {
+            return Err(format!("--shards must be between 1 and {}", MAX_SHARD_COUNT).into());
+        }
+
+        if mapper_params.keys_limit == 0 {
+            return Err("--keys-limit must be above 0".into());
+        }
+
+        let base_path = match mapper_params.base_path.is_empty() {
+            true => None,
+            false => Some(BasePath::new(&mapper_params.base_path)?),
+        };
+
+        let max_memory = match mapper_params.max_memory {
+            Some(max_memory) => Some(max_memory.resolve().ok_or("unable to read the total memory for --max-memory")?),
+            None => MaxMemory::default_limit(),
+        };
+        #[cfg(feature = "backup")]
+        if mapper_params.memory_policy == MemoryPolicy::Snapshot && !mapper_params.backup {
+            return Err("--memory-policy snapshot needs backups enabled".into());
+        }
+
+        // writes not copied yet wait in the journal
+        #[cfg(feature = "mirror")]
+        if mapper_params.mirror.is_some() && mapper_params.journal_size == 0 {
+            return Err("--mirror needs a --journal-size above 0".into());
+        }
+
+        // changes not appended yet wait in the journal
+        #[cfg(feature = "backup")]
+        if mapper_params.aof.is_some() && mapper_params.journal_size == 0 {
+            return Err("--aof needs a --journal-size above 0".into());
+        }
+
+        // credentials come from the environment like for the AWS tools, unless given
+        #[cfg(feature = "s3")]
+        let bucket = match &mapper_params.backup_s3_bucket {
+            Some(_) if !mapper_params.backup => return Err("--backup-s3-bucket needs backups enabled".into()),
+            Some(name) => {
+                let env = |name| std::env::var(name).ok().filter(|value: &String| !value.is_empty());
+                let credentials = Credentials {
+                    access_key: mapper_params
+                        .backup_s3_access_key
+                        .clone()
+                        .or_else(|| env("AWS_ACCESS_KEY_ID"))
+                        .ok_or("--backup-s3-bucket needs --backup-s3-access-key or AWS_ACCESS_KEY_ID")?,
+                    secret_key: mapper_params
+                        .backup_s3_secret_key
+                        .clone()
+                        .or_else(|| env("AWS_SECRET_ACCESS_KEY"))
+                        .ok_or("--backup-s3-bucket needs --backup-s3-secret-key or AWS_SECRET_ACCESS_KEY")?,
+                    session_token: env("AWS_SESSION_TOKEN"),
+                };
+                Some(Arc::new(Bucket::new(
+                    mapper_params.backup_s3_endpoint.as_deref(),
+                    name,
+                    &mapper_params.backup_s3_region,
+                    &mapper_params.backup_s3_prefix,
+                    credentials,
+                )?))
+            }
+            None => None,
+        };
+
+        #[cfg(feature = "auth")]
+        let acl = match &mapper_params.acl {
+            Some(path) => Some(Arc::new(Acl::load(path)?)),
+            None => None,
+        };
+
+        #[cfg(feature = "cluster")]
+        let node = mapper_params.cluster_node.as_deref().unwrap_or(&mapper_params.address);
+        #[cfg(feature = "cluster")]
+        let cluster = match &mapper_params.cluster {
+            Some(path) => Some(Cluster::load(path, node)?),
+            None => None,
+        };
+        // the nodes of now, found again while running
+        #[cfg(feature = "srv")]
+        let cluster = match &mapper_params.discover {
+            Some(discover) => Some(Cluster::spread(&discover.resolve()?, node)),
+            None => cluster,
+        };
+        #[cfg(feature = "cluster")]
+        let cluster = cluster.map(|cluster| {
+            #[cfg(feature = "auth")]
+            let cluster = cluster.with_api_key(mapper_params.api_key.clone());
+            Arc::new(cluster)
+        });
+
+        #[cfg(feature = "tls")]
+        let tls = match (&mapper_params.tls_cert, &mapper_params.tls_key) {
+            (Some(cert), Some(key)) => Some(tls::acceptor(cert, key)?),
+            _ => None,
+        };
+
+        #[cfg(feature = "http3")]
+        let http3 = match (&mapper_params.http3_address, &mapper_params.tls_cert, &mapper_params.tls_key) {
+            (Some(address), Some(cert), Some(key)) => Some((
+                address.parse::<SocketAddr>().expect("unable to parse http3 socket address"),
+                http3::server_config(cert, key)?,
+            )),
+            _ => None,
+        };
+
         let (ctrlc_tx, ctrlc_rx) = smol::channel::bounded::<()>(1);
 
         Ok(Mapper {
-            password: mapper_params.api_key.map(|s| s.to_string()),
+            #[cfg(feature = "auth")]
+            password: mapper_params.api_key,
+            #[cfg(feature = "auth")]
+            admin_key: mapper_params.admin_key,
+            #[cfg(feature = "auth")]
+            acl,
             ctrlc_channel: (ctrlc_tx, ctrlc_rx),
             socket_address,
+            #[cfg(feature = "tls")]
+            tls,
+            #[cfg(feature = "http3")]
+            http3,
+            text_address,
+            memcached_address,
+            journal_size: mapper_params.journal_size,
+            search_prefixes: mapper_params.search_prefix,
+            shard_hash: mapper_params.shard_hash,
+            shard_lock: mapper_params.shard_lock,
+            shard_count: mapper_params.shards,
+            tombstone_ttl: mapper_params.tombstone_ttl.map(Duration::from_secs),
+            keys_limit: mapper_params.keys_limit,
+            idempotency_window: Duration::from_secs(mapper_params.idempotency_window),
+            cors_origins: mapper_params.cors_origin,
+            rate_limit: mapper_params.rate_limit,
+            base_path: base_path.map(Arc::new),
+            memory: max_memory.map(|limit| (limit, mapper_params.memory_policy)),
+            max_body_size: mapper_params.max_body_size,
+            slowlog: (mapper_params.slowlog_size > 0)
+                .then(|| (Duration::from_millis(mapper_params.slowlog_threshold), mapper_params.slowlog_size)),
+            capture: mapper_params.capture.map(|path| (path, mapper_params.capture_sample)),
+            #[cfg(feature = "chaos")]
+            chaos: mapper_params.chaos,
+            #[cfg(feature = "discovery")]
+            registration: mapper_params.register.map(|registry| {
+                (registry, advertise_address, Duration::from_secs(mapper_params.register_ttl.max(1)))
+            }),
+            #[cfg(feature = "mirror")]
+            mirror: mapper_params.mirror,
+            #[cfg(feature = "replication")]
+            replica_of: mapper_params.replica_of,
+            #[cfg(feature = "cluster")]
+            cluster,
+            #[cfg(feature = "srv")]
+            rediscover: mapper_params
+                .discover
+                .map(|discover| (discover, Duration::from_secs(mapper_params.discover_interval.max(1)))),
+            #[cfg(feature = "backup")]
+            aof: mapper_params.aof.map(|path| {
+                Arc::new(Aof::new(
+                    path,
+                    mapper_params.aof_fsync,
+                    mapper_params.aof_rewrite_percentage,
+                    mapper_params.aof_rewrite_min_size,
+                    Duration::from_micros(mapper_params.aof_commit_window),
+                    mapper_params.strict_recovery,
+                ))
+            }),
+            #[cfg(feature = "backup")]
             backup: mapper_params
                 .backup
                 .then(|| Backup {
                     backup_interval: Duration::from_secs(mapper_params.backup_interval),
-                    backup_path: mapper_params.backup_path
+                    backup_path: mapper_params.backup_path,
+                    lazy_recovery: mapper_params.lazy_recovery,
+                    strict_recovery: mapper_params.strict_recovery,
+                    compression: mapper_params.backup_compression,
+                    parallelism: mapper_params.backup_parallelism.unwrap_or_else(|| {
+                        thread::available_parallelism().map_or(1, |cores| cores.get())
+                    }),
+                    full_every: mapper_params.backup_full_every,
+                    #[cfg(feature = "s3")]
+                    bucket,
                 }),
         })
     }
 
-    pub fn start(&self) -> Result<(), Error> {
+    pub fn start(&self) -> Result<(), Box<dyn error::Error>> {
         ctrlc::set_handler({
             let s = self.ctrlc_channel.0.clone();
             move || {
@@ -92,48 +710,303 @@ impl Mapper {
             }
         })?;
 
-        let storage = Storage::default();
+        smol::block_on(self.serve())?;
 
-        smol::block_on(async {
-            if let Some(backup_params) = &self.backup {
-                BackupHandler::new(backup_params.backup_interval, backup_params.backup_path.clone(), storage.clone())
-                .recover_and_backup()
-                .await;
+        Ok(())
+    }
+
+    /// Recovers the last backup and serves requests until a termination signal arrives, then
+    /// backs up the writes made since the last backup.
+    ///
+    /// Unlike [`Mapper::start`] it installs no signal handler and runs on the caller's
+    /// executor, so the server can be embedded in another program or in tests. Fails when
+    /// a strict recovery finds the backup damaged, or when the final backup fails.
+    pub async fn serve(&self) -> io::Result<()> {
+        let storage = Storage::new(
+            self.journal_size,
+            self.search_prefixes.clone(),
+            self.shard_hash,
+            self.shard_lock,
+            self.shard_count,
+            self.tombstone_ttl,
+            self.keys_limit,
+        );
+        #[cfg(feature = "cluster")]
+        let storage = storage.with_cluster(self.cluster.clone());
+        #[cfg(feature = "backup")]
+        let storage = storage.with_aof(self.aof.clone());
+
+        if let Some((threshold, size)) = self.slowlog {
+            storage.slowlog.enable(threshold, size);
+        }
+
+        // the access log comes first so rejected requests get logged too
+        let mut middlewares: Vec<Arc<dyn Middleware>> = vec![Arc::new(AccessLog)];
+        // like the access log, rejected requests count too
+        #[cfg(feature = "metrics")]
+        middlewares.push(Arc::new(Metrics::new(storage.stats.clone())));
+        // logged as requested, seen unprefixed by every other layer
+        if let Some(base_path) = &self.base_path {
+            middlewares.push(base_path.clone());
+        }
+        // before authentication, preflight requests carry no key and rejections need the
+        // headers for the page to read them
+        if !self.cors_origins.is_empty() {
+            middlewares.push(Arc::new(Cors::new(self.cors_origins.clone())));
+        }
+        // slowed down and failed requests show in the access log
+        #[cfg(feature = "chaos")]
+        if let Some(config) = self.chaos {
+            storage.chaos.enable(config);
+            middlewares.push(storage.chaos.clone());
+        }
+        middlewares.push(Arc::new(BodyLimit {
+            max: self.max_body_size,
+        }));
+        #[cfg(feature = "auth")]
+        if let Some(api_key) = &self.password {
+            middlewares.push(Arc::new(Auth::new(api_key.clone())));
+        }
+        #[cfg(feature = "auth")]
+        if let Some(admin_key) = &self.admin_key {
+            middlewares.push(Arc::new(AdminAuth::new(admin_key.clone())));
+        }
+        #[cfg(feature = "auth")]
+        if let Some(acl) = &self.acl {
+            middlewares.push(Arc::new(AclAuth::new(acl.clone())));
+        }
+        // after authentication, only the api keys checked count as clients, the addresses
+        // of the clients otherwise
+        if let Some(rate_limit) = self.rate_limit {
+            #[cfg(feature = "auth")]
+            let keys_checked = self.password.is_some() || self.acl.is_some();
+            #[cfg(not(feature = "auth"))]
+            let keys_checked = false;
+            middlewares.push(Arc::new(RateLimit::new(rate_limit, keys_checked)));
+        }
+        // after authentication, rejected requests are not recorded
+        if let Some((path, sample)) = &self.capture {
+            let capture = Capture::new(path, *sample)
+                .map_err(|e| io::Error::other(format!("unable to capture into {}: {}", path.display(), e)))?;
+            middlewares.push(Arc::new(capture));
+        }
+        // after authentication, rejected requests are not replayed
+        if !self.idempotency_window.is_zero() {
+            middlewares.push(Arc::new(Idempotency::new(self.idempotency_window)));
+        }
+        let middlewares = Chain::new(middlewares);
+
+        #[cfg(feature = "backup")]
+        let backup_handler = match &self.backup {
+            Some(backup_params) => {
+                let backup_handler = BackupHandler::new(
+                    backup_params.backup_interval,
+                    backup_params.backup_path.clone(),
+                    backup_params.lazy_recovery,
+                    backup_params.strict_recovery,
+                    backup_params.compression,
+                    backup_params.parallelism,
+                    storage.clone(),
+                )
+                .with_full_every(backup_params.full_every);
+                #[cfg(feature = "s3")]
+                let backup_handler = backup_handler.with_bucket(backup_params.bucket.clone());
+                backup_handler
+                    .recover_and_backup()
+                    .await
+                    .map_err(|e| io::Error::other(format!("refusing to start: {}", e)))?;
+                Some(backup_handler)
             }
+            None => None,
+        };
+        // the changes made since the backup, then the ones to come
+        #[cfg(feature = "backup")]
+        let aof_writer = match &self.aof {
+            Some(aof) => Some(
+                AofWriter::start(aof.clone(), storage.clone())
+                    .await
+                    .map_err(|e| io::Error::other(format!("refusing to start: {}", e)))?,
+            ),
+            None => None,
+        };
+
+        // once recovered, a restored dataset counts
+        if let Some((limit, policy)) = self.memory {
+            memory::monitor(storage.clone(), limit, policy);
+        }
 
-            let listener = Async::<TcpListener>::bind(self.socket_address)
-                .expect("unable to start tcplistener");
-
-            info!("listening on {}", self.socket_address);
-
-            loop {
-                let signal = race(async { Signal::Listen(listener.accept().await) }, async {
-                    match self.ctrlc_channel.1.recv().await {
-                        Ok(_) | Err(_) => Signal::Quit
-                    }
-                })
-                .await;
-
-                match signal {
-                    Signal::Quit => break,
-                    Signal::Listen(maybe_stream) => match maybe_stream {
-                        Ok(stream) => smol::spawn(hadle_client(
-                            stream.0,
-                            stream.1,
-                            storage.clone(),
-                            self.password.clone()
-                        ))
-                        .detach(),
-                        Err(e) => error!("async tcpstream error: {}", e),
-                    },
+        let listener = Async::<TcpListener>::bind(self.socket_address)
+            .expect("unable to start tcplistener");
+
+        #[cfg(feature = "tls")]
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        #[cfg(not(feature = "tls"))]
+        let scheme = "http";
+        info!("listening on {} over {}", self.socket_address, scheme);
+
+        let text_listener = self.text_address.map(|address| {
+            let listener = Async::<TcpListener>::bind(address).expect("unable to start text protocol tcplistener");
+            info!("text protocol listening on {}", address);
+            listener
+        });
+        // its own task, closed with the endpoint when the instance stops
+        #[cfg(feature = "http3")]
+        let http3_endpoint = self.http3.clone().map(|(address, config)| {
+            let endpoint = quinn::Endpoint::server(config, address).expect("unable to start http3 udp listener");
+            info!("http3 listening on {}", address);
+            smol::spawn(http3::listen(endpoint.clone(), storage.clone(), middlewares.clone())).detach();
+            endpoint
+        });
+        let memcached_listener = self.memcached_address.map(|address| {
+            let listener = Async::<TcpListener>::bind(address).expect("unable to start memcached tcplistener");
+            info!("memcached protocol listening on {}", address);
+            listener
+        });
+        let text_settings = Arc::new(TextSettings {
+            max_line: self.max_body_size,
+            #[cfg(feature = "auth")]
+            api_key: self.password.clone(),
+            #[cfg(feature = "auth")]
+            admin_key: self.admin_key.clone(),
+            #[cfg(not(feature = "auth"))]
+            api_key: None,
+            #[cfg(not(feature = "auth"))]
+            admin_key: None,
+            #[cfg(feature = "auth")]
+            acl: self.acl.clone(),
+        });
+        let memcached_settings = Arc::new(MemcachedSettings {
+            max_value: self.max_body_size,
+            #[cfg(feature = "auth")]
+            api_key: self.password.clone(),
+            #[cfg(not(feature = "auth"))]
+            api_key: None,
+            #[cfg(feature = "auth")]
+            acl: self.acl.clone(),
+        });
+
+        // once listening, so the instance is reachable when it shows up
+        #[cfg(feature = "discovery")]
+        let registration = self
+            .registration
+            .map(|(registry, address, ttl)| Registration::start(registry, address, ttl, storage.clone()));
+        // the recovered records are the secondary's business, only later writes are copied
+        #[cfg(feature = "mirror")]
+        let mirror = self
+            .mirror
+            .clone()
+            .map(|secondary| Mirror::start(secondary, storage.clone()));
+        #[cfg(feature = "replication")]
+        let replica = self
+            .replica_of
+            .clone()
+            .map(|primary| Replica::start(primary, storage.clone()));
+        #[cfg(feature = "srv")]
+        let rediscovery = match (&self.rediscover, &self.cluster) {
+            (Some((discover, interval)), Some(cluster)) => {
+                Some(Rediscovery::start(discover.clone(), cluster.clone(), *interval))
+            }
+            _ => None,
+        };
+
+        loop {
+            let accept = race(
+                async { Signal::Listen(listener.accept().await) },
+                race(
+                    async { Signal::ListenText(accept_optional(&text_listener).await) },
+                    async { Signal::ListenMemcached(accept_optional(&memcached_listener).await) },
+                ),
+            );
+            let signal = race(accept, async {
+                match self.ctrlc_channel.1.recv().await {
+                    Ok(_) | Err(_) => Signal::Quit
                 }
+            })
+            .await;
+
+            match signal {
+                Signal::Quit => break,
+                Signal::Listen(maybe_stream) => match maybe_stream {
+                    Ok(stream) => smol::spawn(hadle_client(
+                        stream.0,
+                        stream.1,
+                        storage.clone(),
+                        middlewares.clone(),
+                        #[cfg(feature = "tls")]
+                        self.tls.clone(),
+                    ))
+                    .detach(),
+                    Err(e) => error!("async tcpstream error: {}", e),
+                },
+                Signal::ListenText(maybe_stream) => match maybe_stream {
+                    Ok(stream) => smol::spawn(handle_text_client(
+                        stream.0,
+                        stream.1,
+                        storage.clone(),
+                        text_settings.clone(),
+                    ))
+                    .detach(),
+                    Err(e) => error!("async tcpstream error: {}", e),
+                },
+                Signal::ListenMemcached(maybe_stream) => match maybe_stream {
+                    Ok(stream) => smol::spawn(handle_memcached_client(
+                        stream.0,
+                        stream.1,
+                        storage.clone(),
+                        memcached_settings.clone(),
+                    ))
+                    .detach(),
+                    Err(e) => error!("async tcpstream error: {}", e),
+                },
             }
-        });
+        }
 
+        #[cfg(feature = "discovery")]
+        if let Some(registration) = registration {
+            registration.stop().await;
+        }
+        // clients are told, not left to time out
+        #[cfg(feature = "http3")]
+        if let Some(endpoint) = http3_endpoint {
+            endpoint.close(0u32.into(), b"shutting down");
+        }
+        #[cfg(feature = "mirror")]
+        if let Some(mirror) = mirror {
+            mirror.stop().await;
+        }
+        #[cfg(feature = "replication")]
+        if let Some(replica) = replica {
+            replica.stop().await;
+        }
+        #[cfg(feature = "srv")]
+        if let Some(rediscovery) = rediscovery {
+            rediscovery.stop().await;
+        }
+        // after the writes of the replica
+        #[cfg(feature = "backup")]
+        if let Some(aof_writer) = aof_writer {
+            aof_writer.stop().await;
+        }
+        // the writes since the last tick, a shutdown losing none of them
+        #[cfg(feature = "backup")]
+        if let Some(backup_handler) = backup_handler {
+            backup_handler
+                .final_backup()
+                .await
+                .map_err(|e| io::Error::other(format!("final backup failed: {}", e)))?;
+        }
         Ok(())
     }
 }
 
+// connections of a listener that may be off, never any when it is
+async fn accept_optional(listener: &Option<Async<TcpListener>>) -> io::Result<(Async<TcpStream>, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => pending().await,
+    }
+}
+
 fn grab_logger_level(mapper_params: &MapperBuilder) -> Level {
     let logging_level = mapper_params.logging_level.clone();
     Level::iter()
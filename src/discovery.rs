@@ -0,0 +1,258 @@
+//! Self registration of the instance into a service registry, kept alive by heartbeats
+//! and removed on shutdown.
+
+use std::{
+    net::{SocketAddr, TcpStream},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use http_types::{mime, Method, Request, Url};
+use log::{info, warn};
+use serde_json::{json, Value};
+use smol::{lock::Mutex, Async, Task, Timer};
+
+use crate::storage::Storage;
+
+// name the instances are registered under
+const SERVICE_NAME: &str = "mapper";
+// a standalone instance, replication would bring replicas
+const ROLE: &str = "primary";
+// etcd key prefix of the instances
+const ETCD_PREFIX: &str = "/services/mapper/";
+
+/// A service registry, `consul:<address>` or `etcd:<address>` of its HTTP API.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Registry {
+    Consul(SocketAddr),
+    Etcd(SocketAddr),
+}
+
+impl FromStr for Registry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, address) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected consul:<address> or etcd:<address>, got {}", s))?;
+        let address = address
+            .parse::<SocketAddr>()
+            .map_err(|e| format!("invalid registry address {}: {}", address, e))?;
+
+        match kind.to_lowercase().as_str() {
+            "consul" => Ok(Registry::Consul(address)),
+            "etcd" => Ok(Registry::Etcd(address)),
+            _ => Err(format!("unknown registry: {}", kind)),
+        }
+    }
+}
+
+/// The registration of the instance, heartbeats run until [`Registration::stop`].
+pub(crate) struct Registration {
+    registrar: Arc<Registrar>,
+    heartbeats: Task<()>,
+}
+
+struct Registrar {
+    registry: Registry,
+    id: String,
+    address: SocketAddr,
+    ttl: Duration,
+    storage: Storage,
+    // etcd lease holding the key and the value put, none while not registered
+    lease: Mutex<Option<(String, String)>>,
+}
+
+impl Registration {
+    /// Registers the instance reachable at `address` and keeps it registered, the registry
+    /// drops it `ttl` after the heartbeats stop. A registry that cannot be reached is
+    /// retried at every heartbeat.
+    pub(crate) fn start(registry: Registry, address: SocketAddr, ttl: Duration, storage: Storage) -> Self {
+        let registrar = Arc::new(Registrar {
+            registry,
+            id: format!("{}-{}", SERVICE_NAME, address).replace([':', '[', ']'], "-"),
+            address,
+            ttl,
+            storage,
+            lease: Mutex::new(None),
+        });
+
+        let heartbeats = smol::spawn({
+            let registrar = registrar.clone();
+            async move {
+                let mut registered = false;
+                loop {
+                    let status = registrar.status();
+                    let outcome = if registered {
+                        registrar.heartbeat(&status).await
+                    } else {
+                        registrar.register(&status).await
+                    };
+                    match outcome {
+                        Ok(()) if !registered => {
+                            info!("registered as {} into {:?}", registrar.id, registrar.registry);
+                            registered = true;
+                        }
+                        Ok(()) => {}
+                        Err(e) => {
+                            warn!("registration into {:?} failed: {}", registrar.registry, e);
+                            registered = false;
+                        }
+                    }
+                    // a few beats per ttl so a lost one does not expire the registration
+                    Timer::after(registrar.ttl / 3).await;
+                }
+            }
+        });
+
+        Self { registrar, heartbeats }
+    }
+
+    /// Stops the heartbeats and removes the instance from the registry.
+    pub(crate) async fn stop(self) {
+        self.heartbeats.cancel().await;
+        match self.registrar.deregister().await {
+            Ok(()) => info!("deregistered {} from {:?}", self.registrar.id, self.registrar.registry),
+            Err(e) => warn!("deregistration from {:?} failed: {}", self.registrar.registry, e),
+        }
+    }
+}
+
+// health reported with every heartbeat: passing, or warning with the reason
+struct Status {
+    health: &'static str,
+    output: String,
+}
+
+impl Registrar {
+    fn status(&self) -> Status {
+        if let Some(error) = self.storage.stats.last_backup().and_then(|report| report.error) {
+            return Status {
+                health: "warning",
+                output: format!("last backup failed: {}", error),
+            };
+        }
+        Status {
+            health: "passing",
+            output: String::new(),
+        }
+    }
+
+    async fn register(&self, status: &Status) -> Result<(), String> {
+        match self.registry {
+            Registry::Consul(consul) => {
+                let service = json!({
+                    "ID": self.id,
+                    "Name": SERVICE_NAME,
+                    "Address": self.address.ip().to_string(),
+                    "Port": self.address.port(),
+                    "Tags": [ROLE],
+                    "Meta": { "role": ROLE },
+                    "Check": {
+                        "TTL": format!("{}s", self.ttl.as_secs()),
+                        "Status": status.health,
+                        "Notes": status.output,
+                        // an instance gone without deregistering does not linger
+                        "DeregisterCriticalServiceAfter": format!("{}s", self.ttl.as_secs() * 10),
+                    },
+                });
+                call(consul, Method::Put, "/v1/agent/service/register", Some(service)).await?;
+                Ok(())
+            }
+            Registry::Etcd(etcd) => {
+                let grant = call(etcd, Method::Post, "/v3/lease/grant", Some(json!({ "TTL": self.ttl.as_secs() }))).await?;
+                let lease = grant["ID"].as_str().ok_or("lease grant without id")?.to_owned();
+                let value = self.put_etcd(etcd, &lease, status).await?;
+                *self.lease.lock().await = Some((lease, value));
+                Ok(())
+            }
+        }
+    }
+
+    async fn heartbeat(&self, status: &Status) -> Result<(), String> {
+        match self.registry {
+            Registry::Consul(consul) => {
+                let path = format!("/v1/agent/check/update/service:{}", self.id);
+                let update = json!({ "Status": status.health, "Output": status.output });
+                call(consul, Method::Put, &path, Some(update)).await?;
+                Ok(())
+            }
+            Registry::Etcd(etcd) => {
+                let mut registered = self.lease.lock().await;
+                let (lease, put) = registered.as_mut().ok_or("no lease")?;
+                let renewed = call(etcd, Method::Post, "/v3/lease/keepalive", Some(json!({ "ID": lease }))).await?;
+                // an expired lease is renewed with no ttl, its key is gone
+                if renewed["result"]["TTL"].as_str().is_none_or(|ttl| ttl == "0") {
+                    return Err("lease expired".to_string());
+                }
+                if *put != self.etcd_value(status) {
+                    *put = self.put_etcd(etcd, lease, status).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn etcd_value(&self, status: &Status) -> String {
+        json!({
+            "address": self.address.to_string(),
+            "role": ROLE,
+            "status": status.health,
+            "output": status.output,
+        })
+        .to_string()
+    }
+
+    // puts the key of the instance under `lease`, returning the value put
+    async fn put_etcd(&self, etcd: SocketAddr, lease: &str, status: &Status) -> Result<String, String> {
+        let value = self.etcd_value(status);
+        let put = json!({
+            "key": base64::encode(format!("{}{}", ETCD_PREFIX, self.id)),
+            "value": base64::encode(&value),
+            "lease": lease,
+        });
+        call(etcd, Method::Post, "/v3/kv/put", Some(put)).await?;
+        Ok(value)
+    }
+
+    async fn deregister(&self) -> Result<(), String> {
+        match self.registry {
+            Registry::Consul(consul) => {
+                let path = format!("/v1/agent/service/deregister/{}", self.id);
+                call(consul, Method::Put, &path, None).await?;
+                Ok(())
+            }
+            Registry::Etcd(etcd) => match self.lease.lock().await.take() {
+                // the key goes with its lease
+                Some((lease, _)) => {
+                    call(etcd, Method::Post, "/v3/lease/revoke", Some(json!({ "ID": lease }))).await?;
+                    Ok(())
+                }
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+// sends a request to the HTTP API of a registry, returning its json response
+async fn call(registry: SocketAddr, method: Method, path: &str, body: Option<Value>) -> Result<Value, String> {
+    let url = Url::parse(&format!("http://{}{}", registry, path)).map_err(|e| e.to_string())?;
+    let mut request = Request::new(method, url);
+    if let Some(body) = body {
+        request.set_body(body.to_string());
+        request.set_content_type(mime::JSON);
+    }
+
+    let stream = Async::<TcpStream>::connect(registry)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut response = async_h1::connect(async_dup::Arc::new(stream), request)
+        .await
+        .map_err(|e| e.to_string())?;
+    let body = response.body_string().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("{} {}", response.status(), body.trim()));
+    }
+    Ok(serde_json::from_str(&body).unwrap_or(Value::Null))
+}
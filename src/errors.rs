@@ -23,6 +23,58 @@ pub enum TransactionError {
     ShardNotFound,
     RecordNotFound,
     TTLNotFound,
+    ShardLimitReached,
+    UnsplittableShard,
+    ReshardingInProgress,
+    InvalidShardCount,
+    ChangesTruncated,
+    DeadlineExceeded,
+    OperationNotFound,
+    ScheduleNotFound,
+    OperationCancelled,
+    ValueNotAFloat,
+    IncrementOverflow,
+    WrongType,
+    KeyExists,
+    SketchMismatch,
+    SampleTooOld,
+    InvalidVector,
+    LockHeld,
+    LockNotHeld,
+    SemaphoreFull,
+    PermitNotHeld,
+    QueueEmpty,
+    QueueItemNotFound,
+    MemberNotFound,
+    FieldNotFound,
+    SeqNotReached,
+    VersionMismatch,
+    ValueNotAnInteger,
+    KnownMissing,
+    TombstonesDisabled,
+    OutOfMemory,
+    #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+    HeapProfilingUnavailable,
+    #[cfg(feature = "chaos")]
+    ChaosDisabled,
+    #[cfg(feature = "scripting")]
+    ScriptFailed(String),
+    #[cfg(feature = "replication")]
+    ReadOnlyReplica,
+    #[cfg(feature = "backup")]
+    AofWriteFailed,
+    #[cfg(feature = "backup")]
+    ShardNotLoaded,
+    #[cfg(feature = "backup")]
+    AofDisabled,
+    #[cfg(feature = "cluster")]
+    Moved { slot: usize, node: String },
+    #[cfg(feature = "cluster")]
+    CrossNode,
+    #[cfg(feature = "cluster")]
+    ClusterDisabled,
+    #[cfg(feature = "cluster")]
+    QuorumNotReached,
 }
 
 impl error::Error for TransactionError {}
@@ -32,6 +84,58 @@ impl fmt::Display for TransactionError {
                 TransactionError::ShardNotFound => write!(f, "shard_not_found"),
                 TransactionError::RecordNotFound => write!(f, "record_not_found"),
                 TransactionError::TTLNotFound => write!(f, "ttl_not_found"),
+                TransactionError::ShardLimitReached => write!(f, "shard_limit_reached"),
+                TransactionError::UnsplittableShard => write!(f, "unsplittable_shard"),
+                TransactionError::ReshardingInProgress => write!(f, "resharding_in_progress"),
+                TransactionError::InvalidShardCount => write!(f, "invalid_shard_count"),
+                TransactionError::ChangesTruncated => write!(f, "changes_truncated"),
+                TransactionError::DeadlineExceeded => write!(f, "deadline_exceeded"),
+                TransactionError::OperationNotFound => write!(f, "operation_not_found"),
+                TransactionError::ScheduleNotFound => write!(f, "schedule_not_found"),
+                TransactionError::OperationCancelled => write!(f, "operation_cancelled"),
+                TransactionError::ValueNotAFloat => write!(f, "value_not_a_float"),
+                TransactionError::IncrementOverflow => write!(f, "increment_overflow"),
+                TransactionError::WrongType => write!(f, "wrong_type"),
+                TransactionError::KeyExists => write!(f, "key_exists"),
+                TransactionError::SketchMismatch => write!(f, "sketch_mismatch"),
+                TransactionError::SampleTooOld => write!(f, "sample_too_old"),
+                TransactionError::InvalidVector => write!(f, "invalid_vector"),
+                TransactionError::LockHeld => write!(f, "lock_held"),
+                TransactionError::LockNotHeld => write!(f, "lock_not_held"),
+                TransactionError::SemaphoreFull => write!(f, "semaphore_full"),
+                TransactionError::PermitNotHeld => write!(f, "permit_not_held"),
+                TransactionError::QueueEmpty => write!(f, "queue_empty"),
+                TransactionError::QueueItemNotFound => write!(f, "queue_item_not_found"),
+                TransactionError::MemberNotFound => write!(f, "member_not_found"),
+                TransactionError::FieldNotFound => write!(f, "field_not_found"),
+                TransactionError::SeqNotReached => write!(f, "seq_not_reached"),
+                TransactionError::VersionMismatch => write!(f, "version_mismatch"),
+                TransactionError::ValueNotAnInteger => write!(f, "value_not_an_integer"),
+                TransactionError::KnownMissing => write!(f, "known_missing"),
+                TransactionError::TombstonesDisabled => write!(f, "tombstones_disabled"),
+                TransactionError::OutOfMemory => write!(f, "out_of_memory"),
+                #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+                TransactionError::HeapProfilingUnavailable => write!(f, "heap_profiling_unavailable"),
+                #[cfg(feature = "chaos")]
+                TransactionError::ChaosDisabled => write!(f, "chaos_disabled"),
+                #[cfg(feature = "scripting")]
+                TransactionError::ScriptFailed(reason) => write!(f, "script_failed: {}", reason),
+                #[cfg(feature = "replication")]
+                TransactionError::ReadOnlyReplica => write!(f, "read_only_replica"),
+                #[cfg(feature = "backup")]
+                TransactionError::AofWriteFailed => write!(f, "aof_write_failed"),
+                #[cfg(feature = "backup")]
+                TransactionError::ShardNotLoaded => write!(f, "shard_not_loaded"),
+                #[cfg(feature = "backup")]
+                TransactionError::AofDisabled => write!(f, "aof_disabled"),
+                #[cfg(feature = "cluster")]
+                TransactionError::Moved { slot, node } => write!(f, "moved {} {}", slot, node),
+                #[cfg(feature = "cluster")]
+                TransactionError::CrossNode => write!(f, "cross_node"),
+                #[cfg(feature = "cluster")]
+                TransactionError::ClusterDisabled => write!(f, "cluster_disabled"),
+                #[cfg(feature = "cluster")]
+                TransactionError::QuorumNotReached => write!(f, "quorum_not_reached"),
         }
     }
 }
@@ -42,6 +146,10 @@ pub enum DeserializationError {
     UnparsableQuery,
     UnparsableDuration,
     UnparsableBytes,
+    UnparsableEntry(u64),
+    BodyTooLarge,
+    #[cfg(feature = "backup")]
+    InvalidBackup(String),
 }
 
 impl error::Error for DeserializationError {}
@@ -52,6 +160,67 @@ impl fmt::Display for DeserializationError {
             DeserializationError::UnparsableQuery => write!(f, "unparsable_query"),
             DeserializationError::UnparsableDuration => write!(f, "unparsable_duration"),
             DeserializationError::UnparsableBytes => write!(f, "unparsable_bytes"),
+            DeserializationError::UnparsableEntry(position) => {
+                write!(f, "unparsable_entry: {}", position)
+            }
+            DeserializationError::BodyTooLarge => write!(f, "body_too_large"),
+            #[cfg(feature = "backup")]
+            DeserializationError::InvalidBackup(reason) => write!(f, "invalid_backup: {}", reason),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(feature = "backup")]
+#[derive(Debug)]
+pub enum BackupFormatError {
+    Truncated,
+    UnsupportedVersion(u16),
+    Undecodable(String),
+    UnsupportedRecordKind(u16),
+    UnsupportedTags(u16),
+    UnsupportedSlidingTtl(u16),
+}
+
+#[cfg(feature = "backup")]
+impl error::Error for BackupFormatError {}
+#[cfg(feature = "backup")]
+impl fmt::Display for BackupFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackupFormatError::Truncated => write!(f, "truncated_backup_file"),
+            BackupFormatError::UnsupportedVersion(version) => {
+                write!(f, "unsupported_backup_version: {}", version)
+            }
+            BackupFormatError::Undecodable(err) => write!(f, "undecodable_backup_file: {}", err),
+            BackupFormatError::UnsupportedRecordKind(version) => {
+                write!(f, "record_kind_unsupported_by_version: {}", version)
+            }
+            BackupFormatError::UnsupportedTags(version) => {
+                write!(f, "tags_unsupported_by_version: {}", version)
+            }
+            BackupFormatError::UnsupportedSlidingTtl(version) => {
+                write!(f, "sliding_ttl_unsupported_by_version: {}", version)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+#[derive(Debug)]
+pub enum ClientError {
+    Connection(String),
+    Forbidden,
+    Rejected { status: u16, reason: String },
+}
+
+#[cfg(feature = "client")]
+impl error::Error for ClientError {}
+#[cfg(feature = "client")]
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Connection(err) => write!(f, "connection_error: {}", err),
+            ClientError::Forbidden => write!(f, "forbidden"),
+            ClientError::Rejected { status, reason } => write!(f, "rejected ({}): {}", status, reason),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use http_types::Body;
+use log::{debug, error};
+#[cfg(feature = "json")]
+use serde::Serialize;
+use smol::{
+    channel::{self, Receiver, Sender},
+    io::{AsyncRead, BufReader},
+    stream::Stream,
+};
+
+use crate::{
+    import::{self, BulkFormat},
+    record::RecordKind,
+    storage::Storage,
+    wrapped_record::WrappedRecord,
+};
+
+// encoded bytes sent to the connection at once
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+// chunks encoded ahead of a slow client
+const EXPORT_CHUNKS_AHEAD: usize = 4;
+
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    key: &'a str,
+    value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<String>,
+}
+
+/// Streams the records whose key starts with `prefix`, in the format `/IMPORT` reads back.
+///
+/// Shards are encoded one at a time by a background task, a few chunks ahead of the
+/// connection. The layout is held for the whole export, so slot migrations wait for it
+/// instead of moving keys under it. The export stops if the client goes away or it is
+/// cancelled through `/ADMIN/OPS`.
+pub(crate) fn export(storage: &Storage, prefix: Option<String>, format: BulkFormat) -> Body {
+    let (sender, receiver) = channel::bounded(EXPORT_CHUNKS_AHEAD);
+    smol::spawn(encode_shards(storage.clone(), prefix, format, sender)).detach();
+
+    let mut body = Body::from_reader(BufReader::new(ChunkReader::new(receiver)), None);
+    body.set_mime(format.content_type());
+    body
+}
+
+async fn encode_shards(
+    storage: Storage,
+    prefix: Option<String>,
+    format: BulkFormat,
+    sender: Sender<io::Result<Vec<u8>>>,
+) {
+    let operation = storage.operations.start(
+        "export",
+        format!(
+            "exporting {} records with prefix {:?}",
+            format.name(),
+            prefix.as_deref().unwrap_or_default()
+        ),
+    );
+    let _layout = storage.layout_lock.read().await;
+
+    let shard_count = storage.shard_count();
+    let mut exported = 0;
+    let mut chunk = Vec::with_capacity(EXPORT_CHUNK_BYTES);
+    for shard_index in 0..shard_count {
+        // the map is shared, not copied: writers copy it while the export holds it
+        let records = match storage.read_shard(shard_index).await {
+            Ok(locked_shard) => locked_shard.records.clone(),
+            // an export missing a shard would look complete
+            Err(e) => {
+                error!("export failed: {}", e);
+                let _ = sender.send(Err(io::Error::other(e.to_string()))).await;
+                return;
+            }
+        };
+
+        // typed records, like bloom filters, have no representation in the bulk formats
+        let matching = records.iter().filter(|(key, wrecord)| {
+            wrecord.record.kind == RecordKind::Bytes
+                && prefix.as_ref().is_none_or(|prefix| key.starts_with(prefix.as_str()))
+        });
+        for (key, wrecord) in matching {
+            encode(&mut chunk, key, wrecord, format);
+            exported += 1;
+
+            if chunk.len() >= EXPORT_CHUNK_BYTES {
+                let full = std::mem::replace(&mut chunk, Vec::with_capacity(EXPORT_CHUNK_BYTES));
+                if sender.send(Ok(full)).await.is_err() || operation.is_cancelled() {
+                    debug!("export stopped after {} records", exported);
+                    return;
+                }
+            }
+        }
+        operation.progress(shard_index as u64 + 1, shard_count as u64);
+    }
+
+    if !chunk.is_empty() {
+        let _ = sender.send(Ok(chunk)).await;
+    }
+    debug!("exported {} records", exported);
+}
+
+fn encode(chunk: &mut Vec<u8>, key: &str, wrecord: &WrappedRecord, format: BulkFormat) {
+    let ttl = wrecord
+        .record
+        .ttl_policy
+        .as_ref()
+        .map(|ttl_policy| ttl_policy.expire_in().as_millis() as u64);
+
+    match format {
+        #[cfg(feature = "json")]
+        BulkFormat::NdJson => {
+            let entry = JsonEntry {
+                key,
+                value: String::from_utf8_lossy(&wrecord.record.data).into_owned(),
+                ttl: ttl.map(|ttl| format!("{}ms", ttl.max(1))),
+            };
+            // strings only, always serializes
+            if serde_json::to_writer(&mut *chunk, &entry).is_ok() {
+                chunk.push(b'\n');
+            }
+        }
+        BulkFormat::Binary => {
+            // 0 means no ttl, an expiring record keeps at least a millisecond
+            import::encode_binary_entry(chunk, key, &wrecord.record.data, ttl.map_or(0, |ttl| ttl.max(1)));
+        }
+    }
+}
+
+/// Reads the chunks of an encoding task, ending once it is done.
+///
+/// An error sent by the task fails the read, so the response is cut short instead of
+/// ending like a complete one.
+pub(crate) struct ChunkReader {
+    chunks: Pin<Box<Receiver<io::Result<Vec<u8>>>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChunkReader {
+    pub(crate) fn new(chunks: Receiver<io::Result<Vec<u8>>>) -> Self {
+        Self {
+            chunks: Box::pin(chunks),
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl AsyncRead for ChunkReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        while self.pos == self.chunk.len() {
+            match self.chunks.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let read = buf.len().min(self.chunk.len() - self.pos);
+        buf[..read].copy_from_slice(&self.chunk[self.pos..self.pos + read]);
+        self.pos += read;
+        Poll::Ready(Ok(read))
+    }
+}
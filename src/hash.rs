@@ -0,0 +1,125 @@
+use std::{collections::BTreeMap, fmt::Write, time::Duration};
+
+use crate::errors::TransactionError;
+
+/// Field values listed in name order, each field with its own optional deadline: stats
+/// kept per user age out field by field. Fields past their deadline are left out of
+/// reads and dropped by the next write.
+#[derive(Debug, Default)]
+pub(crate) struct Hash {
+    // value and deadline, milliseconds since the unix epoch
+    fields: BTreeMap<String, (String, Option<u64>)>,
+}
+
+impl Hash {
+    /// Sets `field` to `value` without a deadline, returning whether it is a new one.
+    pub(crate) fn set(&mut self, field: &str, value: String) -> bool {
+        self.fields.insert(field.to_string(), (value, None)).is_none()
+    }
+
+    pub(crate) fn get(&self, field: &str) -> Option<&str> {
+        self.fields.get(field).map(|(value, _)| value.as_str())
+    }
+
+    /// Removes `field`, returning whether it was in.
+    pub(crate) fn remove(&mut self, field: &str) -> bool {
+        self.fields.remove(field).is_some()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Adds `delta` to the integer value of `field`, 0 when it is not in, keeping its
+    /// deadline. Returns the new value.
+    pub(crate) fn incr(&mut self, field: &str, delta: i64) -> Result<i64, TransactionError> {
+        let (value, _) = self.fields.entry(field.to_string()).or_insert(("0".to_string(), None));
+        let incremented = value
+            .parse::<i64>()
+            .map_err(|_| TransactionError::ValueNotAnInteger)?
+            .checked_add(delta)
+            .ok_or(TransactionError::IncrementOverflow)?;
+        *value = incremented.to_string();
+        Ok(incremented)
+    }
+
+    /// Gives `field` a deadline `ttl` after `now`, milliseconds since the unix epoch, or
+    /// none, returning whether it is in.
+    pub(crate) fn expire(&mut self, field: &str, ttl: Option<Duration>, now: u64) -> bool {
+        match self.fields.get_mut(field) {
+            Some((_, deadline)) => {
+                *deadline = ttl.map(|ttl| now.saturating_add(ttl.as_millis() as u64));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// What is left of the ttl of `field` at `now`, `None` for a field without one.
+    pub(crate) fn ttl(&self, field: &str, now: u64) -> Option<Option<Duration>> {
+        let (_, deadline) = self.fields.get(field)?;
+        Some(deadline.map(|deadline| Duration::from_millis(deadline.saturating_sub(now))))
+    }
+
+    /// `<field> <value>` lines.
+    pub(crate) fn render(&self) -> String {
+        self.fields.iter().fold(String::new(), |mut list, (field, (value, _))| {
+            let _ = writeln!(list, "{} {}", field, value);
+            list
+        })
+    }
+
+    /// Field count (u32), little endian, then every field in name order as its deadline
+    /// (u64, 0 for none), name length (u32), name, value length (u32) and value.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.fields.len() as u32).to_le_bytes());
+        for (field, (value, deadline)) in &self.fields {
+            bytes.extend_from_slice(&deadline.unwrap_or_default().to_le_bytes());
+            bytes.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(field.as_bytes());
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(value.as_bytes());
+        }
+        bytes
+    }
+
+    /// The fields of `bytes` not past their deadline at `now`, milliseconds since the unix
+    /// epoch, with whether some were; `None` if `bytes` do not hold a hash.
+    pub(crate) fn decode(bytes: &[u8], now: u64) -> Option<(Self, bool)> {
+        let mut reader = Reader(bytes);
+        let mut hash = Hash::default();
+        let mut expired = false;
+        for _ in 0..reader.u32()? {
+            let deadline = Some(reader.u64()?).filter(|deadline| *deadline > 0);
+            let len = reader.u32()? as usize;
+            let field = String::from_utf8(reader.take(len)?.to_vec()).ok()?;
+            let len = reader.u32()? as usize;
+            let value = String::from_utf8(reader.take(len)?.to_vec()).ok()?;
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                expired = true;
+                continue;
+            }
+            hash.fields.insert(field, (value, deadline));
+        }
+        reader.0.is_empty().then_some((hash, expired))
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = self.0.split_at_checked(len)?;
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
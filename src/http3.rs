@@ -0,0 +1,187 @@
+//! HTTP/3 over QUIC on `--http3-address`, for clients on lossy links or paying for every
+//! connection setup: a lost packet only holds up the request it belongs to, and connections
+//! resume across address changes. Requests go through the same middlewares and handlers as
+//! those of `--address`, with the certificate of `--tls-cert`.
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use h3::server::RequestStream;
+use h3_quinn::{BidiStream, RecvStream};
+use http_types::{Body, Method, Request, Response, StatusCode, Url};
+use log::{debug, error, warn};
+use quinn::{crypto::rustls::QuicServerConfig, Endpoint, Incoming, ServerConfig};
+use smol::io::{AsyncRead, AsyncReadExt, BufReader};
+
+use crate::{middleware::Chain, storage::Storage, tls};
+
+// size of the chunks response bodies are sent in
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Configuration presenting the certificate chain at `cert` with the key at `key` to QUIC
+/// clients, offering HTTP/3 only.
+pub(crate) fn server_config(cert: &Path, key: &Path) -> Result<ServerConfig, String> {
+    let mut config = tls::server_config(cert, key)?;
+    config.alpn_protocols = vec![b"h3".to_vec()];
+    let config = QuicServerConfig::try_from(config).map_err(|e| format!("invalid --tls-cert for quic: {}", e))?;
+    Ok(ServerConfig::with_crypto(Arc::new(config)))
+}
+
+/// Serves the connections of `endpoint` until it is closed.
+pub(crate) async fn listen(endpoint: Endpoint, storage: Storage, middlewares: Chain) {
+    while let Some(incoming) = endpoint.accept().await {
+        smol::spawn(serve(incoming, storage.clone(), middlewares.clone())).detach();
+    }
+}
+
+// every request of the connection at the same time, each on its own stream
+async fn serve(incoming: Incoming, storage: Storage, middlewares: Chain) {
+    let address = incoming.remote_address();
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(e) => return warn!("quic handshake with {} failed: {}", address, e),
+    };
+    let _connection = storage.stats.connection_opened();
+    let mut connection = match h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection)).await {
+        Ok(connection) => connection,
+        Err(e) => return error!("{} from {}", e, address),
+    };
+
+    loop {
+        match connection.accept().await {
+            Ok(Some(resolver)) => {
+                let (storage, middlewares) = (storage.clone(), middlewares.clone());
+                smol::spawn(async move {
+                    match resolver.resolve_request().await {
+                        Ok((req, stream)) => respond(req, stream, address, storage, middlewares).await,
+                        Err(e) => error!("{} from {}", e, address),
+                    }
+                })
+                .detach();
+            }
+            Ok(None) => break,
+            // closed by the client, or idle for too long
+            Err(e) => {
+                debug!("connection of {} closed: {}", address, e);
+                break;
+            }
+        }
+    }
+}
+
+async fn respond(
+    req: http::Request<()>,
+    stream: RequestStream<BidiStream<Bytes>, Bytes>,
+    address: SocketAddr,
+    storage: Storage,
+    middlewares: Chain,
+) {
+    let (mut sender, receiver) = stream.split();
+    let mut response = match to_request(&req, receiver) {
+        Ok(mut req) => {
+            // for the slow log
+            req.set_peer_addr(Some(address));
+            match middlewares.handle(req, &storage).await {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("{} from {}", e, address);
+                    Response::new(e.status())
+                }
+            }
+        }
+        Err(reason) => {
+            let mut response = Response::new(StatusCode::BadRequest);
+            response.set_body(reason);
+            response
+        }
+    };
+
+    let mut head = http::Response::builder().status(u16::from(response.status()));
+    for (name, values) in response.iter() {
+        for value in values {
+            head = head.header(name.as_str(), value.as_str());
+        }
+    }
+    if let Some(len) = response.len() {
+        head = head.header(http::header::CONTENT_LENGTH, len);
+    }
+    let head = match head.body(()) {
+        Ok(head) => head,
+        Err(e) => return error!("{} for {}", e, address),
+    };
+
+    // bodies are streamed, those of watches and subscriptions have no end known upfront
+    let mut body = response.take_body();
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let sent = async {
+        sender.send_response(head).await.map_err(|e| e.to_string())?;
+        loop {
+            match body.read(&mut chunk).await.map_err(|e| e.to_string())? {
+                0 => break,
+                read => sender
+                    .send_data(Bytes::copy_from_slice(&chunk[..read]))
+                    .await
+                    .map_err(|e| e.to_string())?,
+            }
+        }
+        sender.finish().await.map_err(|e| e.to_string())
+    };
+    if let Err(e) = sent.await {
+        warn!("unable to answer {}: {}", address, e);
+    }
+}
+
+// the request of the handlers, reading the body from `receiver` as it arrives
+fn to_request(req: &http::Request<()>, receiver: RequestStream<RecvStream, Bytes>) -> Result<Request, String> {
+    let method: Method = req.method().as_str().parse().map_err(|_| format!("unsupported method {}", req.method()))?;
+    let url = Url::parse(&req.uri().to_string()).map_err(|e| format!("invalid uri {}: {}", req.uri(), e))?;
+    let mut request = Request::new(method, url);
+    for (name, value) in req.headers() {
+        if let Ok(value) = value.to_str() {
+            request.append_header(name.as_str(), value);
+        }
+    }
+
+    let len = req
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse().ok());
+    let body = RequestBody {
+        receiver: Mutex::new(receiver),
+        chunk: Bytes::new(),
+    };
+    request.set_body(Body::from_reader(BufReader::new(body), len));
+    Ok(request)
+}
+
+// bodies have to be Sync, the stream is only locked for that
+struct RequestBody {
+    receiver: Mutex<RequestStream<RecvStream, Bytes>>,
+    // what is left of the last data frame
+    chunk: Bytes,
+}
+
+impl AsyncRead for RequestBody {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        while this.chunk.is_empty() {
+            let receiver = this.receiver.get_mut().map_err(|_| io::Error::other("poisoned request body"))?;
+            match ready!(receiver.poll_recv_data(cx)) {
+                Ok(Some(mut data)) => this.chunk = data.copy_to_bytes(data.remaining()),
+                Ok(None) => return Poll::Ready(Ok(0)),
+                Err(e) => return Poll::Ready(Err(io::Error::other(e.to_string()))),
+            }
+        }
+        let read = buf.len().min(this.chunk.len());
+        buf[..read].copy_from_slice(&this.chunk[..read]);
+        this.chunk.advance(read);
+        Poll::Ready(Ok(read))
+    }
+}
@@ -1,22 +1,65 @@
-use std::net::{SocketAddr, TcpStream};
+#[cfg(feature = "auth")]
+use std::sync::Arc;
+use std::{
+    net::{SocketAddr, TcpStream},
+    time::{Duration, Instant},
+};
 
+#[cfg(feature = "tls")]
+use futures_rustls::TlsAcceptor;
 use http_types::{Method, Request, Response, StatusCode};
+#[cfg(feature = "tls")]
+use log::warn;
 use log::error;
-use smol::Async;
+use smol::{
+    io::{AsyncRead, AsyncWrite},
+    Async,
+};
 
-use crate::{query_handler, http_query_parser::Query, storage::Storage};
-
-const API_KEY: &'static str = "X-API-Key";
+#[cfg(feature = "auth")]
+use crate::{acl::Grant, middleware::is_admin_path};
+#[cfg(feature = "replication")]
+use crate::http_query_parser::request_strong;
+use crate::{
+    errors::{DeserializationError, Errors, TransactionError},
+    query_handler,
+    http_query_parser::{near_misses, request_min_seq, request_timeout, Query},
+    middleware::Chain,
+    storage::Storage,
+};
+const RECORD_VERSION: &str = "X-Record-Version";
+const SEQ: &str = "X-Seq";
 
 pub(crate) async fn hadle_client(
     stream: Async<TcpStream>,
     address: SocketAddr,
     storage: Storage,
-    maybe_api_key: Option<String>,
+    middlewares: Chain,
+    #[cfg(feature = "tls")] tls: Option<TlsAcceptor>,
 ) {
-    let stream = async_dup::Arc::new(stream);
-    if let Err(e) = async_h1::accept(stream, move |req| {
-        handle_http_request(req, storage.clone(), maybe_api_key.clone())
+    let _connection = storage.stats.connection_opened();
+    #[cfg(feature = "tls")]
+    if let Some(tls) = tls {
+        match tls.accept(stream).await {
+            // the reads and writes of async_h1 share the session
+            Ok(stream) => serve(async_dup::Arc::new(async_dup::Mutex::new(stream)), address, storage, middlewares).await,
+            Err(e) => warn!("tls handshake with {} failed: {}", address, e),
+        }
+        return;
+    }
+    serve(async_dup::Arc::new(stream), address, storage, middlewares).await;
+}
+
+async fn serve<RW>(stream: RW, address: SocketAddr, storage: Storage, middlewares: Chain)
+where
+    RW: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+{
+    if let Err(e) = async_h1::accept(stream, move |mut req| {
+        let storage = storage.clone();
+        let middlewares = middlewares.clone();
+        // for the slow log
+        req.set_peer_addr(Some(address));
+        async move { middlewares.handle(req, &storage).await }
     })
     .await
     {
@@ -24,67 +67,254 @@ pub(crate) async fn hadle_client(
     }
 }
 
-async fn handle_http_request(
+pub(crate) async fn handle_http_request(
     req: Request,
     storage: Storage,
-    maybe_api_key: Option<String>,
 ) -> http_types::Result<Response> {
-    if let Some(password) = maybe_api_key {
-        match req.header(API_KEY) {
-            Some(password_from_header) => {
-                if password_from_header != password.as_str() {
-                    return Ok(Response::new(StatusCode::Forbidden));
-                }
+    match req.method() {
+        Method::Get | Method::Put | Method::Delete => {
+            // a replica sends the requests wanting strong consistency to its primary
+            #[cfg(feature = "replication")]
+            match (request_strong(&req), storage.primary.get()) {
+                (Ok(true), Some(primary)) => return Ok(to_primary(&req, primary)),
+                (Err(e), _) => return Ok(unparsable_request(e)),
+                _ => {}
+            }
+            match request_timeout(&req).and_then(|timeout| Ok((timeout, request_min_seq(&req)?))) {
+                Ok((timeout, min_seq)) => handle_query_request(req, storage, timeout, min_seq).await,
+                Err(e) => Ok(unparsable_request(e)),
             }
-            None => return Ok(Response::new(StatusCode::Forbidden)),
         }
+        _ => Ok(Response::new(StatusCode::NotFound)),
     }
+}
 
-    match req.method() {
-        Method::Get | Method::Put => match Query::try_from(req).await {
-            Ok(query) => {
-                Ok(match query_handler::handle_query(query, storage).await {
-                    Ok(query_data) => {
-                        let mut http_res = Response::new(StatusCode::Ok);
-                        http_res.set_body(query_data);
-                        http_res
+async fn handle_query_request(
+    req: Request,
+    storage: Storage,
+    timeout: Option<Duration>,
+    min_seq: Option<u64>,
+) -> http_types::Result<Response> {
+    let (method, path) = (req.method(), req.url().path().to_string());
+    let client = req.peer_addr().map(str::to_owned);
+    // followed by the node a key of another one is redirected to
+    #[cfg(feature = "cluster")]
+    let target = match req.url().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.clone(),
+    };
+    #[cfg(feature = "auth")]
+    let grant = req.ext().get::<Arc<Grant>>().cloned();
+    match Query::try_from(req).await {
+        Ok(query) => {
+            #[cfg(feature = "auth")]
+            if let Some(grant) = grant {
+                if let Err(reason) = grant.check(is_admin_path(&path), query.is_write(), &query.keys()) {
+                    let mut http_res = Response::new(StatusCode::Forbidden);
+                    http_res.set_body(reason);
+                    return Ok(http_res);
+                }
+            }
+            // the timeout of a watch is how long it waits for a change, not a deadline
+            let (timeout, waits) = match query {
+                Query::Watch { .. } => (None, true),
+                _ => (timeout, false),
+            };
+            let (name, key) = (query.name(), query.key().map(str::to_owned));
+            storage.stats.command(name);
+            if let Some(key) = &key {
+                storage.stats.key_requested(key);
+            }
+            // a store behind the last write seen by the client would read stale state
+            let start = Instant::now();
+            let outcome = match min_seq {
+                Some(min_seq) if min_seq > storage.journal.last_seq() => {
+                    Err(Errors::TransactionError(TransactionError::SeqNotReached))
+                }
+                _ => query_handler::handle_query_within(query, storage.clone(), timeout).await,
+            };
+            // a watch waiting for a change is not slow
+            if !waits {
+                storage.slowlog.command(name, key.as_deref(), start.elapsed(), client.as_deref());
+            }
+            let mut http_res = match outcome {
+                Ok(query_data) => {
+                    let mut http_res = Response::new(StatusCode::Ok);
+                    if let Some(version) = query_data.version {
+                        http_res.insert_header(RECORD_VERSION, version.to_string());
+                    }
+                    http_res.set_body(query_data.body);
+                    if let Some(content_type) = query_data.content_type {
+                        http_res.set_content_type(content_type);
                     }
-                    Err(error) => {
-                        let status = match &error {
-                            crate::errors::Errors::TransactionError(transaction_error) => {
-                                match transaction_error {
-                                    crate::errors::TransactionError::ShardNotFound
-                                    | crate::errors::TransactionError::RecordNotFound
-                                    | crate::errors::TransactionError::TTLNotFound => {
-                                        StatusCode::NotFound
-                                    }
+                    http_res
+                }
+                Err(error) => {
+                    let status = match &error {
+                        crate::errors::Errors::TransactionError(transaction_error) => {
+                            match transaction_error {
+                                crate::errors::TransactionError::ShardNotFound
+                                | crate::errors::TransactionError::RecordNotFound
+                                | crate::errors::TransactionError::KnownMissing
+                                | crate::errors::TransactionError::OperationNotFound
+                                | crate::errors::TransactionError::ScheduleNotFound
+                                | crate::errors::TransactionError::QueueEmpty
+                                | crate::errors::TransactionError::QueueItemNotFound
+                                | crate::errors::TransactionError::MemberNotFound
+                                | crate::errors::TransactionError::FieldNotFound
+                                | crate::errors::TransactionError::TTLNotFound => {
+                                    StatusCode::NotFound
+                                }
+                                crate::errors::TransactionError::UnsplittableShard
+                                | crate::errors::TransactionError::InvalidShardCount
+                                | crate::errors::TransactionError::ValueNotAFloat
+                                | crate::errors::TransactionError::ValueNotAnInteger
+                                | crate::errors::TransactionError::IncrementOverflow
+                                | crate::errors::TransactionError::SketchMismatch
+                                | crate::errors::TransactionError::SampleTooOld
+                                | crate::errors::TransactionError::InvalidVector => {
+                                    StatusCode::BadRequest
+                                }
+                                crate::errors::TransactionError::ShardLimitReached
+                                | crate::errors::TransactionError::ReshardingInProgress
+                                | crate::errors::TransactionError::OperationCancelled
+                                | crate::errors::TransactionError::WrongType
+                                | crate::errors::TransactionError::KeyExists
+                                | crate::errors::TransactionError::LockHeld
+                                | crate::errors::TransactionError::LockNotHeld
+                                | crate::errors::TransactionError::SemaphoreFull
+                                | crate::errors::TransactionError::PermitNotHeld
+                                | crate::errors::TransactionError::TombstonesDisabled => {
+                                    StatusCode::Conflict
+                                }
+                                #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+                                crate::errors::TransactionError::HeapProfilingUnavailable => {
+                                    StatusCode::Conflict
+                                }
+                                #[cfg(feature = "chaos")]
+                                crate::errors::TransactionError::ChaosDisabled => {
+                                    StatusCode::Conflict
+                                }
+                                #[cfg(feature = "backup")]
+                                crate::errors::TransactionError::AofDisabled => {
+                                    StatusCode::Conflict
+                                }
+                                #[cfg(feature = "scripting")]
+                                crate::errors::TransactionError::ScriptFailed(_) => {
+                                    StatusCode::BadRequest
+                                }
+                                #[cfg(feature = "replication")]
+                                crate::errors::TransactionError::ReadOnlyReplica => {
+                                    StatusCode::Forbidden
+                                }
+                                #[cfg(feature = "cluster")]
+                                crate::errors::TransactionError::Moved { .. } => {
+                                    StatusCode::TemporaryRedirect
+                                }
+                                #[cfg(feature = "cluster")]
+                                crate::errors::TransactionError::CrossNode => {
+                                    StatusCode::BadRequest
+                                }
+                                #[cfg(feature = "cluster")]
+                                crate::errors::TransactionError::ClusterDisabled => {
+                                    StatusCode::Conflict
+                                }
+                                #[cfg(feature = "cluster")]
+                                crate::errors::TransactionError::QuorumNotReached => {
+                                    StatusCode::ServiceUnavailable
+                                }
+                                crate::errors::TransactionError::ChangesTruncated => {
+                                    StatusCode::Gone
+                                }
+                                crate::errors::TransactionError::DeadlineExceeded => {
+                                    StatusCode::GatewayTimeout
+                                }
+                                crate::errors::TransactionError::OutOfMemory => {
+                                    StatusCode::InsufficientStorage
+                                }
+                                #[cfg(feature = "backup")]
+                                crate::errors::TransactionError::AofWriteFailed => {
+                                    StatusCode::InsufficientStorage
+                                }
+                                #[cfg(feature = "backup")]
+                                crate::errors::TransactionError::ShardNotLoaded => {
+                                    StatusCode::ServiceUnavailable
+                                }
+                                crate::errors::TransactionError::SeqNotReached
+                                | crate::errors::TransactionError::VersionMismatch => {
+                                    StatusCode::PreconditionFailed
                                 }
                             }
-                            crate::errors::Errors::DeserializationError(deserialization_error) => {
-                                match deserialization_error {
-                                    crate::errors::DeserializationError::QueryNotFound => {
-                                        StatusCode::NotFound
-                                    }
-                                    crate::errors::DeserializationError::UnparsableQuery
-                                    | crate::errors::DeserializationError::UnparsableDuration
-                                    | crate::errors::DeserializationError::UnparsableBytes => {
-                                        StatusCode::InternalServerError
-                                    }
+                        }
+                        crate::errors::Errors::DeserializationError(deserialization_error) => {
+                            match deserialization_error {
+                                crate::errors::DeserializationError::QueryNotFound
+                                | crate::errors::DeserializationError::UnparsableQuery
+                                | crate::errors::DeserializationError::UnparsableDuration
+                                | crate::errors::DeserializationError::UnparsableBytes
+                                | crate::errors::DeserializationError::UnparsableEntry(_) => {
+                                    StatusCode::BadRequest
+                                }
+                                crate::errors::DeserializationError::BodyTooLarge => {
+                                    StatusCode::PayloadTooLarge
+                                }
+                                #[cfg(feature = "backup")]
+                                crate::errors::DeserializationError::InvalidBackup(_) => {
+                                    StatusCode::BadRequest
                                 }
                             }
-                        };
-                        let mut http_res = Response::new(status);
-                        http_res.set_body(error.to_string());
-                        http_res
+                        }
+                    };
+                    let mut http_res = Response::new(status);
+                    #[cfg(feature = "cluster")]
+                    if let Errors::TransactionError(TransactionError::Moved { node, .. }) = &error {
+                        http_res.insert_header("Location", format!("{}{}", node, target));
                     }
-                })
-            }
-            Err(e) => {
-                let mut http_res = Response::new(StatusCode::InternalServerError);
-                http_res.set_body(e.to_string());
-                Ok(http_res)
-            }
-        },
-        _ => Ok(Response::new(StatusCode::NotFound)),
+                    http_res.set_body(error.to_string());
+                    http_res
+                }
+            };
+            // after the query, at least the sequence number of a write it made
+            http_res.insert_header(SEQ, storage.journal.last_seq().to_string());
+            Ok(http_res)
+        }
+        Err(DeserializationError::QueryNotFound) => Ok(query_not_found(method, &path)),
+        Err(e) => Ok(unparsable_request(e)),
     }
 }
+
+/// `307 Temporary Redirect` to the same path on `primary`, with a `primary <address>` body.
+#[cfg(feature = "replication")]
+fn to_primary(req: &Request, primary: &str) -> Response {
+    let target = match req.url().query() {
+        Some(query) => format!("{}?{}", req.url().path(), query),
+        None => req.url().path().to_string(),
+    };
+    let mut http_res = Response::new(StatusCode::TemporaryRedirect);
+    http_res.insert_header("Location", format!("http://{}{}", primary, target));
+    http_res.set_body(format!("primary {}", primary));
+    http_res
+}
+
+/// A request matching no route, its body the error followed by the routes it was likely
+/// meant to be, one per line.
+fn query_not_found(method: Method, path: &str) -> Response {
+    let mut http_res = Response::new(StatusCode::BadRequest);
+    let mut body = DeserializationError::QueryNotFound.to_string();
+    for route in near_misses(method, path) {
+        body.push('\n');
+        body.push_str(&route);
+    }
+    http_res.set_body(body);
+    http_res
+}
+
+fn unparsable_request(error: DeserializationError) -> Response {
+    let status = match error {
+        DeserializationError::BodyTooLarge => StatusCode::PayloadTooLarge,
+        _ => StatusCode::BadRequest,
+    };
+    let mut http_res = Response::new(status);
+    http_res.set_body(error.to_string());
+    http_res
+}
@@ -1,11 +1,64 @@
-use std::time::Duration;
+use std::{
+    cell::RefCell,
+    ops::Bound,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use http_types::Request;
+use http_types::{Body, Method, Request, Url};
 use humantime::parse_duration;
 use log::error;
+use percent_encoding::percent_decode_str;
 use regex::Regex;
+use smol::io::AsyncReadExt;
 
-use crate::errors::DeserializationError;
+#[cfg(feature = "backup")]
+use crate::backup_format::{BackupCompression, DEFAULT_BACKUP_COMPRESSION};
+#[cfg(feature = "chaos")]
+use crate::chaos::ChaosConfig;
+#[cfg(feature = "cluster")]
+use crate::quorum::LeaseOp;
+use crate::{
+    bitfield::{self, BitfieldOp},
+    bloom::BloomParams,
+    cms::CmsParams,
+    scan::{Cursor, DEFAULT_SCAN_COUNT},
+    list::End,
+    search::DEFAULT_SEARCH_LIMIT,
+    set::SetOp,
+    stats::InfoSection,
+    sorted_set,
+    storage::SetCondition,
+    timeseries::Aggregation,
+    topk::{TopK, MAX_TOPK},
+    transaction::{self, TxCommand},
+    vector::{self, Metric, MAX_DIMENSION},
+    errors::DeserializationError,
+    import::BulkFormat,
+    middleware::BodyLimit,
+    pattern::Pattern,
+};
+
+const TIMEOUT_HEADER: &str = "X-Timeout";
+const MIN_SEQ_HEADER: &str = "X-Min-Seq";
+#[cfg(feature = "replication")]
+const CONSISTENCY_HEADER: &str = "X-Consistency";
+// keys listed by /EXPIRING without a limit
+const DEFAULT_EXPIRING_LIMIT: usize = 100;
+// how long /WATCH waits for a change without a timeout
+const DEFAULT_WATCH_TIMEOUT: Duration = Duration::from_secs(30);
+// routes suggested for an unknown one at most
+const MAX_NEAR_MISSES: usize = 5;
+// edits from an unknown command to one it was likely meant to be at most, half of a short
+// command at most
+const MAX_TYPO_DISTANCE: usize = 2;
+// read at a time from a request body, the limit is checked after every read
+const BODY_CHUNK_BYTES: usize = 64 * 1024;
+
+thread_local! {
+    // the routes tried by the api functions while they are listed, none while parsing
+    static LISTED_ROUTES: RefCell<Option<Vec<&'static str>>> = const { RefCell::new(None) };
+}
 
 #[derive(Debug)]
 pub enum Query {
@@ -15,11 +68,16 @@ pub enum Query {
     Set {
         key: String,
         data: Vec<u8>,
+        tags: Vec<String>,
+        condition: SetCondition,
     },
     SetEx {
         key: String,
         data: Vec<u8>,
         ttl: Duration,
+        sliding: bool,
+        tags: Vec<String>,
+        condition: SetCondition,
     },
     Del {
         key: String,
@@ -37,10 +95,454 @@ pub enum Query {
     Persist {
         key: String,
     },
-    Info,
+    // every section without one
+    Info {
+        section: Option<InfoSection>,
+    },
     FlushAll,
     DbSize,
     Ping,
+    SplitShard {
+        shard: usize,
+    },
+    Reshard {
+        shards: usize,
+    },
+    Changes {
+        since: Option<u64>,
+    },
+    Watch {
+        key: String,
+        version: Option<u64>,
+        timeout: Duration,
+    },
+    Tombstone {
+        key: String,
+        ttl: Option<Duration>,
+    },
+    Untombstone {
+        key: String,
+    },
+    ExpireMany {
+        keys: Vec<String>,
+        ttl: Duration,
+    },
+    SnapGet {
+        keys: Vec<String>,
+    },
+    MGet {
+        keys: Vec<String>,
+    },
+    MSet {
+        pairs: Vec<(String, Vec<u8>)>,
+    },
+    Exec {
+        commands: Vec<TxCommand>,
+    },
+    #[cfg(feature = "scripting")]
+    Eval {
+        script: String,
+        keys: Vec<String>,
+        args: Vec<String>,
+    },
+    PersistMany {
+        keys: Vec<String>,
+    },
+    DelPattern {
+        pattern: Pattern,
+        dry_run: bool,
+    },
+    Keys {
+        pattern: Pattern,
+        limit: Option<usize>,
+    },
+    Subscribe {
+        channel: String,
+    },
+    Publish {
+        channel: String,
+        message: Vec<u8>,
+    },
+    PubSubChannels,
+    Scan {
+        cursor: Cursor,
+        count: usize,
+        pattern: Option<Pattern>,
+    },
+    Import {
+        format: BulkFormat,
+        body: Body,
+    },
+    Export {
+        prefix: Option<String>,
+        format: BulkFormat,
+    },
+    #[cfg(feature = "replication")]
+    Replicate {
+        // run id and journal sequence a reconnecting replica reached
+        resume: Option<(u64, u64)>,
+    },
+    #[cfg(feature = "cluster")]
+    ClusterInfo,
+    #[cfg(feature = "cluster")]
+    ClusterSlots,
+    // a lease command with quorum=true, run on every node
+    #[cfg(feature = "cluster")]
+    QuorumLease {
+        name: String,
+        op: LeaseOp,
+    },
+    // one of the requests of the node gathering a quorum, run on this node whatever the slot
+    #[cfg(feature = "cluster")]
+    MemberLease {
+        name: String,
+        op: LeaseOp,
+    },
+    Operations,
+    CancelOperation {
+        id: u64,
+    },
+    Bitfield {
+        key: String,
+        ops: Vec<BitfieldOp>,
+    },
+    IncrByFloat {
+        key: String,
+        increment: f64,
+    },
+    IncrBy {
+        key: String,
+        delta: i64,
+    },
+    Append {
+        key: String,
+        data: Vec<u8>,
+    },
+    GetDel {
+        key: String,
+    },
+    GetSet {
+        key: String,
+        data: Vec<u8>,
+    },
+    Object {
+        key: String,
+    },
+    BloomReserve {
+        key: String,
+        params: BloomParams,
+    },
+    BloomAdd {
+        key: String,
+        item: String,
+    },
+    BloomExists {
+        key: String,
+        item: String,
+    },
+    BloomInfo {
+        key: String,
+    },
+    CmsInit {
+        key: String,
+        params: CmsParams,
+    },
+    CmsIncrBy {
+        key: String,
+        increments: Vec<(String, u64)>,
+    },
+    CmsCount {
+        key: String,
+        item: String,
+    },
+    CmsMerge {
+        key: String,
+        sources: Vec<(String, u64)>,
+    },
+    CmsInfo {
+        key: String,
+    },
+    TopKReserve {
+        key: String,
+        topk: TopK,
+    },
+    TopKAdd {
+        key: String,
+        items: Vec<(String, u64)>,
+    },
+    TopKList {
+        key: String,
+    },
+    TopKInfo {
+        key: String,
+    },
+    TsCreate {
+        key: String,
+        retention: Option<Duration>,
+    },
+    TsAdd {
+        key: String,
+        timestamp: u64,
+        value: f64,
+    },
+    TsAddMany {
+        key: String,
+        samples: Vec<(u64, f64)>,
+    },
+    TsRange {
+        key: String,
+        from: u64,
+        to: u64,
+        aggregation: Option<(Aggregation, u64)>,
+    },
+    TsInfo {
+        key: String,
+    },
+    ZAdd {
+        key: String,
+        members: Vec<(String, f64)>,
+    },
+    ZRange {
+        key: String,
+        start: i64,
+        stop: i64,
+        rev: bool,
+    },
+    ZRangeByScore {
+        key: String,
+        min: Bound<f64>,
+        max: Bound<f64>,
+        offset: usize,
+        limit: Option<usize>,
+    },
+    ZRem {
+        key: String,
+        members: Vec<String>,
+    },
+    ZIncrBy {
+        key: String,
+        member: String,
+        increment: f64,
+    },
+    ZRank {
+        key: String,
+        member: String,
+        rev: bool,
+    },
+    ZRemRangeByScore {
+        key: String,
+        min: Bound<f64>,
+        max: Bound<f64>,
+    },
+    SAdd {
+        key: String,
+        members: Vec<String>,
+    },
+    SRem {
+        key: String,
+        members: Vec<String>,
+    },
+    SMembers {
+        key: String,
+    },
+    SIsMember {
+        key: String,
+        member: String,
+    },
+    SCard {
+        key: String,
+    },
+    SCombine {
+        op: SetOp,
+        keys: Vec<String>,
+    },
+    SCombineStore {
+        op: SetOp,
+        destination: String,
+        keys: Vec<String>,
+    },
+    ListPush {
+        key: String,
+        end: End,
+        items: Vec<String>,
+    },
+    ListPop {
+        key: String,
+        end: End,
+        count: usize,
+    },
+    LRange {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    LLen {
+        key: String,
+    },
+    LMove {
+        source: String,
+        destination: String,
+        from: End,
+        to: End,
+    },
+    LMPop {
+        keys: Vec<String>,
+        end: End,
+        count: usize,
+    },
+    HSet {
+        key: String,
+        fields: Vec<(String, String)>,
+    },
+    HGet {
+        key: String,
+        field: String,
+    },
+    HDel {
+        key: String,
+        fields: Vec<String>,
+    },
+    HGetAll {
+        key: String,
+    },
+    HIncrBy {
+        key: String,
+        field: String,
+        delta: i64,
+    },
+    // no ttl to make the field persistent again
+    HExpire {
+        key: String,
+        field: String,
+        ttl: Option<Duration>,
+    },
+    HTtl {
+        key: String,
+        field: String,
+    },
+    VectorCreate {
+        index: String,
+        dimension: u32,
+        metric: Metric,
+    },
+    VectorAdd {
+        index: String,
+        id: String,
+        vector: Vec<f32>,
+    },
+    VectorRemove {
+        index: String,
+        id: String,
+    },
+    VectorSearch {
+        index: String,
+        vector: Vec<f32>,
+        k: usize,
+    },
+    VectorInfo {
+        index: String,
+    },
+    HotKeys,
+    // the most recent entries, all of them without a count
+    SlowLog {
+        count: Option<usize>,
+    },
+    SlowLogReset,
+    RateLimit {
+        key: String,
+        limit: u64,
+        window: Duration,
+    },
+    Lock {
+        name: String,
+        ttl: Duration,
+    },
+    Renew {
+        name: String,
+        token: u64,
+        ttl: Duration,
+    },
+    Unlock {
+        name: String,
+        token: u64,
+    },
+    QueuePush {
+        key: String,
+        item: String,
+    },
+    QueuePop {
+        key: String,
+        timeout: Duration,
+    },
+    QueueAck {
+        key: String,
+        id: u64,
+    },
+    SemAcquire {
+        name: String,
+        max: u64,
+        ttl: Duration,
+    },
+    SemRelease {
+        name: String,
+        token: u64,
+    },
+    SetAt {
+        key: String,
+        data: Vec<u8>,
+        at: u64,
+        tags: Vec<String>,
+    },
+    DelAt {
+        key: String,
+        at: u64,
+    },
+    Scheduled,
+    CancelSchedule {
+        id: u64,
+    },
+    Expiring {
+        within: Duration,
+        limit: usize,
+    },
+    GetByTag {
+        tag: String,
+    },
+    DelByTag {
+        tag: String,
+    },
+    Search {
+        query: String,
+        limit: usize,
+    },
+    #[cfg(feature = "metrics")]
+    Stats,
+    #[cfg(feature = "backup")]
+    Backup {
+        compression: BackupCompression,
+    },
+    #[cfg(feature = "backup")]
+    BackupStatus,
+    #[cfg(feature = "backup")]
+    AofRewrite,
+    #[cfg(feature = "backup")]
+    Restore {
+        archive: Vec<u8>,
+        replace: bool,
+    },
+    #[cfg(feature = "chaos")]
+    Chaos,
+    // the settings named in the spec, or all of them cleared
+    #[cfg(feature = "chaos")]
+    ChaosUpdate {
+        spec: Option<String>,
+    },
+    #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+    MemStats,
+    #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+    HeapProfile {
+        path: Option<String>,
+    },
 }
 
 impl Query {
@@ -48,47 +550,721 @@ impl Query {
         let path = req.url().path().to_string();
         let method = req.method();
         match method {
-            http_types::Method::Get => get_api(&path),
-            http_types::Method::Put => match req.body_bytes().await {
-                Ok(body) => put_api(&*path, body),
-                Err(e) => {
-                    error!("put without body: {}", e);
-                    Err(DeserializationError::UnparsableQuery)
-                }
-            },
+            http_types::Method::Get => get_api(req.url()),
+            http_types::Method::Delete => delete_api(req.url()),
+            // streamed, the body is not read upfront
+            http_types::Method::Put if route_is(&path, "/IMPORT") => import_api(&mut req),
+            http_types::Method::Put => {
+                let body = read_body(&mut req).await?;
+                put_api(req.url(), body)
+            }
             _ => Err(DeserializationError::QueryNotFound),
         }
     }
+
+    // text commands whose last argument is the body of their route, the value running to
+    // the end of the line, with the arguments before it
+    const TEXT_BODY_COMMANDS: [(&'static str, usize); 5] =
+        [("SET", 1), ("SETEX", 2), ("SETAT", 2), ("QPUSH", 1), ("PUBLISH", 1)];
+
+    /// Parses a command of the text protocol as the route it mirrors: `SET foo bar` is
+    /// `PUT /SET/foo` with `bar` as body, `GET foo` is `GET /GET/foo`. Returns the url of
+    /// the route along with the query.
+    pub(crate) fn from_text(line: &str) -> Result<(Url, Self), DeserializationError> {
+        let line = line.trim();
+        let (command, arguments) = line.split_once(' ').unwrap_or((line, ""));
+        let command = command.to_ascii_uppercase();
+        let body_command = Self::TEXT_BODY_COMMANDS
+            .iter()
+            .find(|(name, _)| *name == command);
+
+        let mut url = Url::parse("http://localhost/").map_err(|_| DeserializationError::UnparsableQuery)?;
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| DeserializationError::UnparsableQuery)?;
+        segments.pop_if_empty().push(&command);
+        let body = match body_command {
+            Some((_, leading)) => {
+                let mut arguments = arguments.trim_start().splitn(leading + 1, ' ');
+                segments.extend(arguments.by_ref().take(*leading));
+                let body = arguments.next().ok_or(DeserializationError::UnparsableQuery)?;
+                Some(body.as_bytes().to_vec())
+            }
+            None => {
+                segments.extend(arguments.split_whitespace());
+                None
+            }
+        };
+        drop(segments);
+
+        let query = match body {
+            Some(body) => put_api(&url, body)?,
+            None => get_api(&url)?,
+        };
+        Ok((url, query))
+    }
+}
+
+/// Reads the body chunk by chunk into a buffer sized upfront from its length, failing
+/// before reading anything when the declared length is over the limit the [`BodyLimit`]
+/// middleware put on the request, else as soon as a chunk takes it past the limit.
+async fn read_body(req: &mut Request) -> Result<Vec<u8>, DeserializationError> {
+    let limit = req.ext().get::<BodyLimit>().map_or(usize::MAX, |limit| limit.max);
+    if req.len().is_some_and(|len| len > limit) {
+        return Err(DeserializationError::BodyTooLarge);
+    }
+    let mut buff = Vec::with_capacity(req.len().unwrap_or_default());
+
+    let mut body = req.take_body();
+    let mut chunk = vec![0; BODY_CHUNK_BYTES];
+    loop {
+        let read = match body.read(&mut chunk).await {
+            Ok(read) => read,
+            Err(e) => {
+                error!("put without body: {}", e);
+                return Err(DeserializationError::UnparsableQuery);
+            }
+        };
+        if read == 0 {
+            return Ok(buff);
+        }
+        if buff.len() + read > limit {
+            return Err(DeserializationError::BodyTooLarge);
+        }
+        buff.extend_from_slice(&chunk[..read]);
+    }
+}
+
+/// Deadline of a request, from the `X-Timeout` header or else the `timeout` url parameter.
+pub(crate) fn request_timeout(req: &Request) -> Result<Option<Duration>, DeserializationError> {
+    let timeout = match req.header(TIMEOUT_HEADER) {
+        Some(timeout) => Some(timeout.as_str().to_string()),
+        None => query_param(req.url(), "timeout"),
+    };
+    timeout
+        .map(|timeout| parse_duration(&timeout).map_err(|_| DeserializationError::UnparsableDuration))
+        .transpose()
+}
+
+/// Whether a request asks for strong consistency, `strong` in the `X-Consistency` header or
+/// else the `consistency` url parameter, over the default `eventual`.
+#[cfg(feature = "replication")]
+pub(crate) fn request_strong(req: &Request) -> Result<bool, DeserializationError> {
+    let consistency = match req.header(CONSISTENCY_HEADER) {
+        Some(consistency) => Some(consistency.as_str().to_string()),
+        None => query_param(req.url(), "consistency"),
+    };
+    match consistency.as_deref() {
+        None | Some("eventual") => Ok(false),
+        Some("strong") => Ok(true),
+        Some(_) => Err(DeserializationError::UnparsableQuery),
+    }
+}
+
+/// Sequence number a request wants the store to have reached, from the `X-Min-Seq` header
+/// or else the `min_seq` url parameter.
+pub(crate) fn request_min_seq(req: &Request) -> Result<Option<u64>, DeserializationError> {
+    let min_seq = match req.header(MIN_SEQ_HEADER) {
+        Some(min_seq) => Some(min_seq.as_str().to_string()),
+        None => query_param(req.url(), "min_seq"),
+    };
+    min_seq
+        .map(|min_seq| min_seq.parse().map_err(|_| DeserializationError::UnparsableQuery))
+        .transpose()
+}
+
+impl Query {
+    /// Name of the command, as used in the urls.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Query::Get { .. } => "GET",
+            Query::Set { .. } => "SET",
+            Query::SetEx { .. } => "SETEX",
+            Query::Del { .. } => "DEL",
+            Query::Exists { .. } => "EXISTS",
+            Query::Expire { .. } => "EXPIRE",
+            Query::Ttl { .. } => "TTL",
+            Query::Persist { .. } => "PERSIST",
+            Query::Info { .. } => "INFO",
+            Query::FlushAll => "FLUSHALL",
+            Query::DbSize => "DBSIZE",
+            Query::Ping => "PING",
+            Query::SplitShard { .. } => "SPLITSHARD",
+            Query::Reshard { .. } => "RESHARD",
+            Query::Changes { .. } => "CHANGES",
+            Query::Watch { .. } => "WATCH",
+            Query::Tombstone { .. } | Query::Untombstone { .. } => "TOMBSTONE",
+            Query::ExpireMany { .. } => "EXPIRE",
+            Query::PersistMany { .. } => "PERSIST",
+            Query::SnapGet { .. } => "SNAPGET",
+            Query::MGet { .. } => "MGET",
+            Query::MSet { .. } => "MSET",
+            Query::Exec { .. } => "EXEC",
+            #[cfg(feature = "scripting")]
+            Query::Eval { .. } => "EVAL",
+            Query::DelPattern { .. } => "DEL/PATTERN",
+            Query::Keys { .. } => "KEYS",
+            Query::Scan { .. } => "SCAN",
+            Query::Subscribe { .. } => "SUBSCRIBE",
+            Query::Publish { .. } => "PUBLISH",
+            Query::PubSubChannels => "PUBSUB/CHANNELS",
+            Query::Import { .. } => "IMPORT",
+            Query::Export { .. } => "EXPORT",
+            #[cfg(feature = "replication")]
+            Query::Replicate { .. } => "REPLICATE",
+            #[cfg(feature = "cluster")]
+            Query::ClusterInfo => "CLUSTER/INFO",
+            #[cfg(feature = "cluster")]
+            Query::ClusterSlots => "CLUSTER/SLOTS",
+            #[cfg(feature = "cluster")]
+            Query::QuorumLease { op, .. } | Query::MemberLease { op, .. } => op.command(),
+            Query::Operations => "ADMIN/OPS",
+            Query::CancelOperation { .. } => "ADMIN/OPS/CANCEL",
+            Query::Object { .. } => "OBJECT",
+            Query::IncrByFloat { .. } => "INCRBYFLOAT",
+            Query::IncrBy { .. } => "INCRBY",
+            Query::Append { .. } => "APPEND",
+            Query::GetDel { .. } => "GETDEL",
+            Query::GetSet { .. } => "GETSET",
+            Query::Bitfield { .. } => "BITFIELD",
+            Query::BloomReserve { .. } => "BF.RESERVE",
+            Query::BloomAdd { .. } => "BF.ADD",
+            Query::BloomExists { .. } => "BF.EXISTS",
+            Query::BloomInfo { .. } => "BF.INFO",
+            Query::CmsInit { .. } => "CMS.INIT",
+            Query::CmsIncrBy { .. } => "CMS.INCRBY",
+            Query::CmsCount { .. } => "CMS.QUERY",
+            Query::CmsMerge { .. } => "CMS.MERGE",
+            Query::CmsInfo { .. } => "CMS.INFO",
+            Query::TopKReserve { .. } => "TOPK.RESERVE",
+            Query::TopKAdd { .. } => "TOPK.ADD",
+            Query::TopKList { .. } => "TOPK.LIST",
+            Query::TopKInfo { .. } => "TOPK.INFO",
+            Query::TsCreate { .. } => "TS.CREATE",
+            Query::TsAdd { .. } => "TS.ADD",
+            Query::TsAddMany { .. } => "TS.ADD",
+            Query::TsRange { .. } => "TS.RANGE",
+            Query::TsInfo { .. } => "TS.INFO",
+            Query::ZAdd { .. } => "ZADD",
+            Query::ZRange { .. } => "ZRANGE",
+            Query::ZRangeByScore { .. } => "ZRANGEBYSCORE",
+            Query::ZRem { .. } => "ZREM",
+            Query::ZIncrBy { .. } => "ZINCRBY",
+            Query::ZRank { .. } => "ZRANK",
+            Query::ZRemRangeByScore { .. } => "ZREMRANGEBYSCORE",
+            Query::SAdd { .. } => "SADD",
+            Query::SRem { .. } => "SREM",
+            Query::SMembers { .. } => "SMEMBERS",
+            Query::SIsMember { .. } => "SISMEMBER",
+            Query::SCard { .. } => "SCARD",
+            Query::SCombine { op: SetOp::Inter, .. } => "SINTER",
+            Query::SCombine { op: SetOp::Union, .. } => "SUNION",
+            Query::SCombine { op: SetOp::Diff, .. } => "SDIFF",
+            Query::SCombineStore { op: SetOp::Inter, .. } => "SINTERSTORE",
+            Query::SCombineStore { op: SetOp::Union, .. } => "SUNIONSTORE",
+            Query::SCombineStore { op: SetOp::Diff, .. } => "SDIFFSTORE",
+            Query::ListPush { end: End::Left, .. } => "LPUSH",
+            Query::ListPush { end: End::Right, .. } => "RPUSH",
+            Query::ListPop { end: End::Left, .. } => "LPOP",
+            Query::ListPop { end: End::Right, .. } => "RPOP",
+            Query::LRange { .. } => "LRANGE",
+            Query::LLen { .. } => "LLEN",
+            Query::LMove { .. } => "LMOVE",
+            Query::LMPop { .. } => "LMPOP",
+            Query::HSet { .. } => "HSET",
+            Query::HGet { .. } => "HGET",
+            Query::HDel { .. } => "HDEL",
+            Query::HGetAll { .. } => "HGETALL",
+            Query::HIncrBy { .. } => "HINCRBY",
+            Query::HExpire { ttl: Some(_), .. } => "HEXPIRE",
+            Query::HExpire { ttl: None, .. } => "HPERSIST",
+            Query::HTtl { .. } => "HTTL",
+            Query::VectorCreate { .. } => "VCREATE",
+            Query::VectorAdd { .. } => "VADD",
+            Query::VectorRemove { .. } => "VREM",
+            Query::VectorSearch { .. } => "VSEARCH",
+            Query::VectorInfo { .. } => "VINFO",
+            Query::HotKeys => "ADMIN/HOTKEYS",
+            Query::SlowLog { .. } | Query::SlowLogReset => "SLOWLOG",
+            #[cfg(feature = "chaos")]
+            Query::Chaos | Query::ChaosUpdate { .. } => "DEBUG/CHAOS",
+            #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+            Query::MemStats => "ADMIN/MEMSTATS",
+            #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+            Query::HeapProfile { .. } => "ADMIN/MEMSTATS/HEAPPROFILE",
+            Query::RateLimit { .. } => "RATELIMIT",
+            Query::Lock { .. } => "LOCK",
+            Query::Renew { .. } => "RENEW",
+            Query::Unlock { .. } => "UNLOCK",
+            Query::SemAcquire { .. } => "SEMACQUIRE",
+            Query::QueuePush { .. } => "QPUSH",
+            Query::QueuePop { .. } => "QPOP",
+            Query::QueueAck { .. } => "QACK",
+            Query::SemRelease { .. } => "SEMRELEASE",
+            Query::SetAt { .. } => "SETAT",
+            Query::DelAt { .. } => "DELAT",
+            Query::Scheduled => "SCHEDULED",
+            Query::CancelSchedule { .. } => "SCHEDULED/CANCEL",
+            Query::Expiring { .. } => "EXPIRING",
+            Query::GetByTag { .. } => "GETBYTAG",
+            Query::DelByTag { .. } => "DELBYTAG",
+            Query::Search { .. } => "SEARCH",
+            #[cfg(feature = "metrics")]
+            Query::Stats => "STATS",
+            #[cfg(feature = "backup")]
+            Query::Backup { .. } => "ADMIN/BACKUP",
+            #[cfg(feature = "backup")]
+            Query::BackupStatus => "ADMIN/BACKUP/STATUS",
+            #[cfg(feature = "backup")]
+            Query::AofRewrite => "ADMIN/AOF/REWRITE",
+            #[cfg(feature = "backup")]
+            Query::Restore { .. } => "ADMIN/RESTORE",
+        }
+    }
+
+    /// Whether the command changes the dataset or the state of the instance, refused to
+    /// read-only api keys.
+    #[cfg(feature = "auth")]
+    pub(crate) fn is_write(&self) -> bool {
+        #[cfg(feature = "chaos")]
+        if matches!(self, Query::ChaosUpdate { .. }) {
+            return true;
+        }
+        self.changes_dataset()
+            || matches!(
+                self,
+                Query::SplitShard { .. }
+                    | Query::Reshard { .. }
+                    | Query::Publish { .. }
+                    | Query::CancelOperation { .. }
+                    | Query::SlowLogReset
+            )
+    }
+
+    /// Whether the command writes records, refused on replicas as well.
+    #[cfg(any(feature = "auth", feature = "replication", feature = "backup"))]
+    pub(crate) fn changes_dataset(&self) -> bool {
+        match self {
+            Query::Set { .. }
+            | Query::SetEx { .. }
+            | Query::SetAt { .. }
+            | Query::Del { .. }
+            | Query::DelAt { .. }
+            | Query::Expire { .. }
+            | Query::Persist { .. }
+            | Query::FlushAll
+            | Query::Tombstone { .. }
+            | Query::Untombstone { .. }
+            | Query::GetDel { .. }
+            | Query::GetSet { .. }
+            | Query::ExpireMany { .. }
+            | Query::PersistMany { .. }
+            | Query::Exec { .. }
+            | Query::MSet { .. }
+            | Query::DelPattern { .. }
+            | Query::DelByTag { .. }
+            | Query::Import { .. }
+            | Query::IncrByFloat { .. }
+            | Query::IncrBy { .. }
+            | Query::Append { .. }
+            | Query::Bitfield { .. }
+            | Query::BloomReserve { .. }
+            | Query::BloomAdd { .. }
+            | Query::CmsInit { .. }
+            | Query::CmsIncrBy { .. }
+            | Query::CmsMerge { .. }
+            | Query::TopKReserve { .. }
+            | Query::TopKAdd { .. }
+            | Query::TsCreate { .. }
+            | Query::TsAdd { .. }
+            | Query::TsAddMany { .. }
+            | Query::ZAdd { .. }
+            | Query::ZRem { .. }
+            | Query::ZIncrBy { .. }
+            | Query::ZRemRangeByScore { .. }
+            | Query::SAdd { .. }
+            | Query::SRem { .. }
+            | Query::SCombineStore { .. }
+            | Query::ListPush { .. }
+            | Query::ListPop { .. }
+            | Query::LMove { .. }
+            | Query::LMPop { .. }
+            | Query::HSet { .. }
+            | Query::HDel { .. }
+            | Query::HIncrBy { .. }
+            | Query::HExpire { .. }
+            | Query::VectorCreate { .. }
+            | Query::VectorAdd { .. }
+            | Query::VectorRemove { .. }
+            | Query::RateLimit { .. }
+            | Query::Lock { .. }
+            | Query::Renew { .. }
+            | Query::Unlock { .. }
+            | Query::SemAcquire { .. }
+            | Query::SemRelease { .. }
+            | Query::QueuePush { .. }
+            | Query::QueuePop { .. }
+            | Query::QueueAck { .. }
+            | Query::CancelSchedule { .. } => true,
+            #[cfg(feature = "scripting")]
+            Query::Eval { .. } => true,
+            #[cfg(feature = "backup")]
+            Query::Restore { .. } => true,
+            Query::Get { .. }
+            | Query::Exists { .. }
+            | Query::Ttl { .. }
+            | Query::Info { .. }
+            | Query::DbSize
+            | Query::Ping
+            | Query::Changes { .. }
+            | Query::Watch { .. }
+            | Query::SnapGet { .. }
+            | Query::MGet { .. }
+            | Query::Keys { .. }
+            | Query::Scan { .. }
+            | Query::Subscribe { .. }
+            | Query::PubSubChannels
+            | Query::Export { .. }
+            | Query::Operations
+            | Query::Object { .. }
+            | Query::BloomExists { .. }
+            | Query::BloomInfo { .. }
+            | Query::CmsCount { .. }
+            | Query::CmsInfo { .. }
+            | Query::TopKList { .. }
+            | Query::TopKInfo { .. }
+            | Query::TsRange { .. }
+            | Query::TsInfo { .. }
+            | Query::ZRange { .. }
+            | Query::ZRangeByScore { .. }
+            | Query::ZRank { .. }
+            | Query::SMembers { .. }
+            | Query::SIsMember { .. }
+            | Query::SCard { .. }
+            | Query::SCombine { .. }
+            | Query::LRange { .. }
+            | Query::LLen { .. }
+            | Query::HGet { .. }
+            | Query::HGetAll { .. }
+            | Query::HTtl { .. }
+            | Query::VectorSearch { .. }
+            | Query::VectorInfo { .. }
+            | Query::HotKeys
+            | Query::SlowLog { .. }
+            | Query::Scheduled
+            | Query::Expiring { .. }
+            | Query::GetByTag { .. }
+            | Query::Search { .. }
+            | Query::SplitShard { .. }
+            | Query::Reshard { .. }
+            | Query::Publish { .. }
+            | Query::CancelOperation { .. }
+            | Query::SlowLogReset => false,
+            #[cfg(feature = "chaos")]
+            Query::Chaos | Query::ChaosUpdate { .. } => false,
+            #[cfg(feature = "replication")]
+            Query::Replicate { .. } => false,
+            #[cfg(feature = "cluster")]
+            Query::ClusterInfo | Query::ClusterSlots => false,
+            #[cfg(feature = "cluster")]
+            Query::QuorumLease { .. } | Query::MemberLease { .. } => true,
+            #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+            Query::MemStats | Query::HeapProfile { .. } => false,
+            #[cfg(feature = "metrics")]
+            Query::Stats => false,
+            #[cfg(feature = "backup")]
+            Query::Backup { .. } | Query::BackupStatus | Query::AofRewrite => false,
+        }
+    }
+
+    /// Whether the command can add data, refused while memory is short: writes creating or
+    /// growing records, imports and restores, and reshards copying shards.
+    pub(crate) fn grows_dataset(&self) -> bool {
+        match self {
+            Query::Set { .. }
+            | Query::SetEx { .. }
+            | Query::SplitShard { .. }
+            | Query::Reshard { .. }
+            | Query::Tombstone { .. }
+            | Query::Exec { .. }
+            | Query::MSet { .. }
+            | Query::Import { .. }
+            | Query::IncrByFloat { .. }
+            | Query::IncrBy { .. }
+            | Query::Append { .. }
+            | Query::GetSet { .. }
+            | Query::Bitfield { .. }
+            | Query::BloomReserve { .. }
+            | Query::BloomAdd { .. }
+            | Query::CmsInit { .. }
+            | Query::CmsIncrBy { .. }
+            | Query::CmsMerge { .. }
+            | Query::TopKReserve { .. }
+            | Query::TopKAdd { .. }
+            | Query::TsCreate { .. }
+            | Query::TsAdd { .. }
+            | Query::TsAddMany { .. }
+            | Query::ZAdd { .. }
+            | Query::ZIncrBy { .. }
+            | Query::SAdd { .. }
+            | Query::SCombineStore { .. }
+            | Query::ListPush { .. }
+            | Query::HSet { .. }
+            | Query::HIncrBy { .. }
+            | Query::VectorCreate { .. }
+            | Query::VectorAdd { .. }
+            | Query::RateLimit { .. }
+            | Query::Lock { .. }
+            | Query::SemAcquire { .. }
+            | Query::QueuePush { .. }
+            | Query::SetAt { .. } => true,
+            #[cfg(feature = "scripting")]
+            Query::Eval { .. } => true,
+            #[cfg(feature = "backup")]
+            Query::Restore { .. } => true,
+            Query::Get { .. }
+            | Query::Del { .. }
+            | Query::Exists { .. }
+            | Query::Expire { .. }
+            | Query::Ttl { .. }
+            | Query::Persist { .. }
+            | Query::Info { .. }
+            | Query::FlushAll
+            | Query::DbSize
+            | Query::Ping
+            | Query::Changes { .. }
+            | Query::Watch { .. }
+            | Query::Untombstone { .. }
+            | Query::GetDel { .. }
+            | Query::ExpireMany { .. }
+            | Query::PersistMany { .. }
+            | Query::SnapGet { .. }
+            | Query::MGet { .. }
+            | Query::DelPattern { .. }
+            | Query::Keys { .. }
+            | Query::Scan { .. }
+            | Query::Subscribe { .. }
+            | Query::Publish { .. }
+            | Query::PubSubChannels
+            | Query::Export { .. }
+            | Query::Operations
+            | Query::CancelOperation { .. }
+            | Query::Object { .. }
+            | Query::BloomExists { .. }
+            | Query::BloomInfo { .. }
+            | Query::CmsCount { .. }
+            | Query::CmsInfo { .. }
+            | Query::TopKList { .. }
+            | Query::TopKInfo { .. }
+            | Query::TsRange { .. }
+            | Query::TsInfo { .. }
+            | Query::ZRange { .. }
+            | Query::ZRangeByScore { .. }
+            | Query::ZRem { .. }
+            | Query::ZRank { .. }
+            | Query::ZRemRangeByScore { .. }
+            | Query::SRem { .. }
+            | Query::SMembers { .. }
+            | Query::SIsMember { .. }
+            | Query::SCard { .. }
+            | Query::SCombine { .. }
+            | Query::ListPop { .. }
+            | Query::LRange { .. }
+            | Query::LLen { .. }
+            | Query::LMove { .. }
+            | Query::LMPop { .. }
+            | Query::HGet { .. }
+            | Query::HDel { .. }
+            | Query::HGetAll { .. }
+            | Query::HExpire { .. }
+            | Query::HTtl { .. }
+            | Query::VectorRemove { .. }
+            | Query::VectorSearch { .. }
+            | Query::VectorInfo { .. }
+            | Query::HotKeys
+            | Query::SlowLog { .. }
+            | Query::SlowLogReset
+            | Query::Renew { .. }
+            | Query::Unlock { .. }
+            | Query::QueuePop { .. }
+            | Query::QueueAck { .. }
+            | Query::SemRelease { .. }
+            | Query::DelAt { .. }
+            | Query::Scheduled
+            | Query::CancelSchedule { .. }
+            | Query::Expiring { .. }
+            | Query::GetByTag { .. }
+            | Query::DelByTag { .. }
+            | Query::Search { .. } => false,
+            #[cfg(feature = "chaos")]
+            Query::Chaos | Query::ChaosUpdate { .. } => false,
+            #[cfg(feature = "replication")]
+            Query::Replicate { .. } => false,
+            #[cfg(feature = "cluster")]
+            Query::ClusterInfo | Query::ClusterSlots => false,
+            #[cfg(feature = "cluster")]
+            Query::QuorumLease { op, .. } | Query::MemberLease { op, .. } => matches!(op, LeaseOp::Lock { .. }),
+            #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+            Query::MemStats | Query::HeapProfile { .. } => false,
+            #[cfg(feature = "metrics")]
+            Query::Stats => false,
+            #[cfg(feature = "backup")]
+            Query::Backup { .. } | Query::BackupStatus | Query::AofRewrite => false,
+        }
+    }
+
+    /// The record a single key command works on.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Query::Get { key }
+            | Query::Watch { key, .. }
+            | Query::Tombstone { key, .. }
+            | Query::Untombstone { key }
+            | Query::Set { key, .. }
+            | Query::RateLimit { key, .. }
+            | Query::Lock { name: key, .. }
+            | Query::Renew { name: key, .. }
+            | Query::Unlock { name: key, .. }
+            | Query::SemAcquire { name: key, .. }
+            | Query::QueuePush { key, .. }
+            | Query::QueuePop { key, .. }
+            | Query::QueueAck { key, .. }
+            | Query::SemRelease { name: key, .. }
+            | Query::SetAt { key, .. }
+            | Query::DelAt { key, .. }
+            | Query::SetEx { key, .. }
+            | Query::Del { key }
+            | Query::Exists { key }
+            | Query::Expire { key, .. }
+            | Query::Ttl { key }
+            | Query::Persist { key }
+            | Query::Bitfield { key, .. }
+            | Query::IncrByFloat { key, .. }
+            | Query::IncrBy { key, .. }
+            | Query::Append { key, .. }
+            | Query::GetDel { key }
+            | Query::GetSet { key, .. }
+            | Query::Object { key }
+            | Query::BloomReserve { key, .. }
+            | Query::BloomAdd { key, .. }
+            | Query::BloomExists { key, .. }
+            | Query::BloomInfo { key }
+            | Query::CmsInit { key, .. }
+            | Query::CmsIncrBy { key, .. }
+            | Query::CmsCount { key, .. }
+            | Query::CmsMerge { key, .. }
+            | Query::CmsInfo { key }
+            | Query::TopKReserve { key, .. }
+            | Query::TopKAdd { key, .. }
+            | Query::TopKList { key }
+            | Query::TopKInfo { key }
+            | Query::TsCreate { key, .. }
+            | Query::TsAdd { key, .. }
+            | Query::TsAddMany { key, .. }
+            | Query::TsRange { key, .. }
+            | Query::TsInfo { key }
+            | Query::ZAdd { key, .. }
+            | Query::ZRange { key, .. }
+            | Query::ZRangeByScore { key, .. }
+            | Query::ZRem { key, .. }
+            | Query::ZIncrBy { key, .. }
+            | Query::ZRank { key, .. }
+            | Query::ZRemRangeByScore { key, .. }
+            | Query::SAdd { key, .. }
+            | Query::SRem { key, .. }
+            | Query::SMembers { key }
+            | Query::SIsMember { key, .. }
+            | Query::SCard { key }
+            | Query::ListPush { key, .. }
+            | Query::ListPop { key, .. }
+            | Query::LRange { key, .. }
+            | Query::LLen { key }
+            | Query::HSet { key, .. }
+            | Query::HGet { key, .. }
+            | Query::HDel { key, .. }
+            | Query::HGetAll { key }
+            | Query::HIncrBy { key, .. }
+            | Query::HExpire { key, .. }
+            | Query::HTtl { key, .. } => Some(key),
+            Query::VectorCreate { index, .. }
+            | Query::VectorAdd { index, .. }
+            | Query::VectorRemove { index, .. }
+            | Query::VectorSearch { index, .. }
+            | Query::VectorInfo { index } => Some(index),
+            #[cfg(feature = "cluster")]
+            Query::QuorumLease { name, .. } | Query::MemberLease { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Every record the command works on, for the key prefixes of a grant and to find the
+    /// node owning them in a cluster.
+    #[cfg(any(feature = "auth", feature = "cluster"))]
+    pub(crate) fn keys(&self) -> Vec<&str> {
+        match self {
+            Query::ExpireMany { keys, .. }
+            | Query::SnapGet { keys }
+            | Query::MGet { keys }
+            | Query::PersistMany { keys }
+            | Query::SCombine { keys, .. }
+            | Query::LMPop { keys, .. } => keys.iter().map(String::as_str).collect(),
+            #[cfg(feature = "scripting")]
+            Query::Eval { keys, .. } => keys.iter().map(String::as_str).collect(),
+            Query::MSet { pairs } => pairs.iter().map(|(key, _)| key.as_str()).collect(),
+            Query::Exec { commands } => commands.iter().map(TxCommand::key).collect(),
+            Query::CmsMerge { key, sources, .. } => std::iter::once(key.as_str())
+                .chain(sources.iter().map(|(source, _)| source.as_str()))
+                .collect(),
+            Query::SCombineStore { destination, keys, .. } => std::iter::once(destination.as_str())
+                .chain(keys.iter().map(String::as_str))
+                .collect(),
+            Query::LMove { source, destination, .. } => vec![source.as_str(), destination.as_str()],
+            _ => self.key().into_iter().collect(),
+        }
+    }
 }
 
 macro_rules! match_api {
-    ($path:expr, $pattern:expr, $query:expr) => {
-        if let Some(captures) = extract_wildcards($path, $pattern) {
+    ($path:expr, $pattern:expr, $query:expr) => {{
+        LISTED_ROUTES.with_borrow_mut(|routes| {
+            if let Some(routes) = routes {
+                routes.push($pattern);
+            }
+        });
+        // compiled by the first request reaching the route
+        static ROUTE: OnceLock<Regex> = OnceLock::new();
+        if let Some(captures) = extract_wildcards($path, ROUTE.get_or_init(|| route_regex($pattern))) {
             return $query(captures);
         }
-    };
+    }};
 }
 
-fn put_api(path: &str, body: Vec<u8>) -> Result<Query, DeserializationError> {
+fn put_api(url: &Url, body: Vec<u8>) -> Result<Query, DeserializationError> {
+    let path = url.path();
+
     match_api!(path, "/SET/*", |captures: Vec<String>| {
-        println!("body: {:?}", String::from_utf8(body.clone()));
         captures
-            .get(0)
+            .first()
             .map_or(Err(DeserializationError::UnparsableQuery), |key| {
                 Ok(Query::Set {
                     key: key.clone(),
                     data: body,
+                    tags: tags(url),
+                    condition: set_condition(url)?,
                 })
             })
     });
 
     match_api!(path, "/SETEX/*/*", |captures: Vec<String>| {
-        if let (Some(key), Some(dur)) = (captures.get(0), captures.get(1)) {
+        if let (Some(key), Some(dur)) = (captures.first(), captures.get(1)) {
             match parse_duration(dur.as_str()) {
                 Ok(dur) => Ok(Query::SetEx {
                     key: key.clone(),
                     data: body,
-                    ttl: Duration::from(dur),
+                    ttl: dur,
+                    sliding: flag(url, "sliding")?,
+                    tags: tags(url),
+                    condition: set_condition(url)?,
                 }),
                 Err(_) => Err(DeserializationError::UnparsableDuration),
             }
@@ -97,95 +1273,1638 @@ fn put_api(path: &str, body: Vec<u8>) -> Result<Query, DeserializationError> {
         }
     });
 
-    Err(DeserializationError::QueryNotFound)
-}
-
-fn get_api(path: &str) -> Result<Query, DeserializationError> {
-    match_api!(path, "/GET/*", |captures: Vec<String>| {
-        captures
-            .get(0)
-            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
-                Ok(Query::Get { key: el.clone() })
-            })
+    match_api!(path, "/APPEND/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::Append { key: key.clone(), data: body })
     });
 
-    match_api!(path, "/DEL/*", |captures: Vec<String>| {
-        captures
-            .get(0)
-            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
-                Ok(Query::Del { key: el.clone() })
-            })
+    match_api!(path, "/PUBLISH/*", |captures: Vec<String>| {
+        let channel = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::Publish {
+            channel: channel.clone(),
+            message: body,
+        })
     });
 
-    match_api!(path, "/EXISTS/*", |captures: Vec<String>| {
-        captures
-            .get(0)
-            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
-                Ok(Query::Exists { key: el.clone() })
-            })
+    match_api!(path, "/GETSET/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::GetSet { key: key.clone(), data: body })
     });
 
-    match_api!(path, "/EXPIRE/*/*", |captures: Vec<String>| {
-        if let (Some(key), Some(dur)) = (captures.get(0), captures.get(1)) {
-            match parse_duration(dur.as_str()) {
-                Ok(dur) => Ok(Query::Expire {
-                    key: key.clone(),
-                    ttl: Duration::from(dur),
-                }),
-                Err(_) => Err(DeserializationError::UnparsableDuration),
-            }
-        } else {
-            Err(DeserializationError::UnparsableQuery)
+    match_api!(path, "/SETAT/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(at)) => Ok(Query::SetAt {
+                key: key.clone(),
+                data: body,
+                at: parse_timestamp(at)?,
+                tags: tags(url),
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
         }
     });
 
-    match_api!(path, "/TTL/*", |captures: Vec<String>| {
-        captures
-            .get(0)
-            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
-                Ok(Query::Ttl { key: el.clone() })
-            })
+    match_api!(path, "/QPUSH/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::QueuePush {
+            key: key.clone(),
+            item: String::from_utf8(body).map_err(|_| DeserializationError::UnparsableBytes)?,
+        })
     });
 
-    match_api!(path, "/PERSIST/*", |captures: Vec<String>| {
-        captures
-            .get(0)
-            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
-                Ok(Query::Persist { key: el.clone() })
+    match_api!(path, "/EXPIRE/*", |captures: Vec<String>| {
+        let ttl = captures
+            .first()
+            .ok_or(DeserializationError::UnparsableQuery)
+            .and_then(|dur| parse_duration(dur).map_err(|_| DeserializationError::UnparsableDuration))?;
+        Ok(Query::ExpireMany {
+            keys: key_list(body)?,
+            ttl,
+        })
+    });
+
+    match_api!(path, "/BITFIELD/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        let subcommands = String::from_utf8(body).map_err(|_| DeserializationError::UnparsableBytes)?;
+        Ok(Query::Bitfield {
+            key: key.clone(),
+            ops: bitfield::parse(&subcommands)?,
+        })
+    });
+
+    match_api!(path, "/PERSIST", |_| Ok(Query::PersistMany { keys: key_list(body)? }));
+
+    match_api!(path, "/SNAPGET", |_| Ok(Query::SnapGet { keys: key_list(body)? }));
+
+    match_api!(path, "/MGET", |_| Ok(Query::MGet { keys: key_list(body)? }));
+
+    match_api!(path, "/MSET", |_| Ok(Query::MSet { pairs: key_value_list(body)? }));
+
+    match_api!(path, "/EXEC", |_| {
+        let commands = String::from_utf8(body).map_err(|_| DeserializationError::UnparsableBytes)?;
+        Ok(Query::Exec {
+            commands: transaction::parse(&commands)?,
+        })
+    });
+
+    #[cfg(feature = "scripting")]
+    match_api!(path, "/EVAL", |_| {
+        let keys = comma_list(url, "keys");
+        if keys.is_empty() {
+            return Err(DeserializationError::UnparsableQuery);
+        }
+        Ok(Query::Eval {
+            script: String::from_utf8(body).map_err(|_| DeserializationError::UnparsableBytes)?,
+            keys,
+            args: comma_list(url, "args"),
+        })
+    });
+
+    match_api!(path, "/CMS.INCRBY/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        let increments = weighted_list(body, None)?;
+        if increments.is_empty() {
+            return Err(DeserializationError::UnparsableQuery);
+        }
+        Ok(Query::CmsIncrBy {
+            key: key.clone(),
+            increments,
+        })
+    });
+
+    match_api!(path, "/TOPK.ADD/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        let items = weighted_list(body, Some(1))?;
+        if items.is_empty() {
+            return Err(DeserializationError::UnparsableQuery);
+        }
+        Ok(Query::TopKAdd {
+            key: key.clone(),
+            items,
+        })
+    });
+
+    match_api!(path, "/ZADD/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        let body = String::from_utf8(body).map_err(|_| DeserializationError::UnparsableBytes)?;
+        let members = body
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [member, score] => match score.parse::<f64>() {
+                    Ok(score) if !score.is_nan() => Ok((member.to_string(), score)),
+                    _ => Err(DeserializationError::UnparsableQuery),
+                },
+                _ => Err(DeserializationError::UnparsableQuery),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if members.is_empty() {
+            return Err(DeserializationError::UnparsableQuery);
+        }
+        Ok(Query::ZAdd {
+            key: key.clone(),
+            members,
+        })
+    });
+
+    match_api!(path, "/ZREM/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        let members = key_list(body)?;
+        if members.is_empty() {
+            return Err(DeserializationError::UnparsableQuery);
+        }
+        Ok(Query::ZRem {
+            key: key.clone(),
+            members,
+        })
+    });
+
+    match_api!(path, "/SADD/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        let members = key_list(body)?;
+        if members.is_empty() {
+            return Err(DeserializationError::UnparsableQuery);
+        }
+        Ok(Query::SAdd {
+            key: key.clone(),
+            members,
+        })
+    });
+
+    match_api!(path, "/SREM/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        let members = key_list(body)?;
+        if members.is_empty() {
+            return Err(DeserializationError::UnparsableQuery);
+        }
+        Ok(Query::SRem {
+            key: key.clone(),
+            members,
+        })
+    });
+
+    match_api!(path, "/LPUSH/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::ListPush {
+            key: key.clone(),
+            end: End::Left,
+            items: non_empty_list(body)?,
+        })
+    });
+
+    match_api!(path, "/RPUSH/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::ListPush {
+            key: key.clone(),
+            end: End::Right,
+            items: non_empty_list(body)?,
+        })
+    });
+
+    match_api!(path, "/LMPOP/*", |captures: Vec<String>| {
+        let end = captures.first().and_then(|el| el.parse::<End>().ok());
+        let keys = non_empty_list(body)?;
+        match end {
+            Some(end) => Ok(Query::LMPop {
+                keys,
+                end,
+                count: pop_count(url)?,
+            }),
+            None => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/HSET/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        let fields = key_value_list(body)?
+            .into_iter()
+            .map(|(field, value)| Ok((field, String::from_utf8(value).map_err(|_| DeserializationError::UnparsableBytes)?)))
+            .collect::<Result<_, _>>()?;
+        Ok(Query::HSet {
+            key: key.clone(),
+            fields,
+        })
+    });
+
+    match_api!(path, "/HDEL/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::HDel {
+            key: key.clone(),
+            fields: non_empty_list(body)?,
+        })
+    });
+
+    match_api!(path, "/SINTER", |_| Ok(Query::SCombine {
+        op: SetOp::Inter,
+        keys: non_empty_list(body)?,
+    }));
+    match_api!(path, "/SUNION", |_| Ok(Query::SCombine {
+        op: SetOp::Union,
+        keys: non_empty_list(body)?,
+    }));
+    match_api!(path, "/SDIFF", |_| Ok(Query::SCombine {
+        op: SetOp::Diff,
+        keys: non_empty_list(body)?,
+    }));
+
+    match_api!(path, "/SINTERSTORE/*", |captures: Vec<String>| {
+        let destination = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::SCombineStore {
+            op: SetOp::Inter,
+            destination: destination.clone(),
+            keys: non_empty_list(body)?,
+        })
+    });
+
+    match_api!(path, "/SUNIONSTORE/*", |captures: Vec<String>| {
+        let destination = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::SCombineStore {
+            op: SetOp::Union,
+            destination: destination.clone(),
+            keys: non_empty_list(body)?,
+        })
+    });
+
+    match_api!(path, "/SDIFFSTORE/*", |captures: Vec<String>| {
+        let destination = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::SCombineStore {
+            op: SetOp::Diff,
+            destination: destination.clone(),
+            keys: non_empty_list(body)?,
+        })
+    });
+
+    match_api!(path, "/TS.ADD/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        let body = String::from_utf8(body).map_err(|_| DeserializationError::UnparsableBytes)?;
+        let samples = body
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [timestamp, value] => Ok((parse_timestamp(timestamp)?, parse_sample_value(value)?)),
+                _ => Err(DeserializationError::UnparsableQuery),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if samples.is_empty() {
+            return Err(DeserializationError::UnparsableQuery);
+        }
+        Ok(Query::TsAddMany {
+            key: key.clone(),
+            samples,
+        })
+    });
+
+    match_api!(path, "/VADD/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(index), Some(id)) => Ok(Query::VectorAdd {
+                index: index.clone(),
+                id: id.clone(),
+                vector: vector_body(body)?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/VSEARCH/*/*", |captures: Vec<String>| {
+        let k = captures.get(1).and_then(|el| el.parse::<usize>().ok()).filter(|k| *k > 0);
+        match (captures.first(), k) {
+            (Some(index), Some(k)) => Ok(Query::VectorSearch {
+                index: index.clone(),
+                vector: vector_body(body)?,
+                k,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/CMS.MERGE/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        let sources = weighted_list(body, Some(1))?;
+        if sources.is_empty() {
+            return Err(DeserializationError::UnparsableQuery);
+        }
+        Ok(Query::CmsMerge {
+            key: key.clone(),
+            sources,
+        })
+    });
+
+    // checked here, applied over the current settings
+    #[cfg(feature = "chaos")]
+    match_api!(path, "/DEBUG/CHAOS", |_| {
+        let spec = String::from_utf8(body).map_err(|_| DeserializationError::UnparsableBytes)?;
+        spec.parse::<ChaosConfig>().map_err(|e| {
+            error!("{}", e);
+            DeserializationError::UnparsableQuery
+        })?;
+        Ok(Query::ChaosUpdate { spec: Some(spec) })
+    });
+
+    #[cfg(feature = "backup")]
+    match_api!(path, "/ADMIN/RESTORE", |_| {
+        let replace = match query_param(url, "mode").as_deref() {
+            None | Some("merge") => false,
+            Some("replace") => true,
+            Some(_) => return Err(DeserializationError::UnparsableQuery),
+        };
+        Ok(Query::Restore { archive: body, replace })
+    });
+
+    Err(DeserializationError::QueryNotFound)
+}
+
+/// Milliseconds since the unix epoch, `*` for now.
+fn parse_timestamp(timestamp: &str) -> Result<u64, DeserializationError> {
+    match timestamp {
+        "*" => Ok(SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64)),
+        timestamp => timestamp.parse().map_err(|_| DeserializationError::UnparsableQuery),
+    }
+}
+
+fn parse_sample_value(value: &str) -> Result<f64, DeserializationError> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|value| value.is_finite())
+        .ok_or(DeserializationError::UnparsableQuery)
+}
+
+fn vector_create(
+    index: Option<&String>,
+    dimension: Option<&String>,
+    metric: Option<&String>,
+) -> Result<Query, DeserializationError> {
+    let index = index.ok_or(DeserializationError::UnparsableQuery)?;
+    let dimension = dimension
+        .and_then(|dimension| dimension.parse::<u32>().ok())
+        .filter(|dimension| (1..=MAX_DIMENSION).contains(dimension))
+        .ok_or(DeserializationError::UnparsableQuery)?;
+    let metric = match metric {
+        Some(metric) => Metric::from_name(metric).ok_or(DeserializationError::UnparsableQuery)?,
+        None => Metric::Cosine,
+    };
+    Ok(Query::VectorCreate {
+        index: index.clone(),
+        dimension,
+        metric,
+    })
+}
+
+fn vector_body(body: Vec<u8>) -> Result<Vec<f32>, DeserializationError> {
+    let body = String::from_utf8(body).map_err(|_| DeserializationError::UnparsableBytes)?;
+    vector::parse(&body).ok_or(DeserializationError::UnparsableQuery)
+}
+
+fn topk_reserve(key: Option<&String>, k: Option<&String>, window: Option<&String>) -> Result<Query, DeserializationError> {
+    let key = key.ok_or(DeserializationError::UnparsableQuery)?;
+    let k = k
+        .and_then(|k| k.parse::<u32>().ok())
+        .filter(|k| (1..=MAX_TOPK).contains(k))
+        .ok_or(DeserializationError::UnparsableQuery)?;
+    let window = window
+        .map(|window| parse_duration(window).map_err(|_| DeserializationError::UnparsableDuration))
+        .transpose()?;
+    Ok(Query::TopKReserve {
+        key: key.clone(),
+        topk: TopK::new(k, window),
+    })
+}
+
+/// `<item> <number>` lines of a request body, the number falling back to `default`.
+fn weighted_list(body: Vec<u8>, default: Option<u64>) -> Result<Vec<(String, u64)>, DeserializationError> {
+    let body = String::from_utf8(body).map_err(|_| DeserializationError::UnparsableBytes)?;
+    body.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let item = fields.next().ok_or(DeserializationError::UnparsableQuery)?;
+            let number = match fields.next() {
+                Some(number) => number.parse().ok(),
+                None => default,
+            };
+            match (number, fields.next()) {
+                (Some(number), None) => Ok((item.to_string(), number)),
+                _ => Err(DeserializationError::UnparsableQuery),
+            }
+        })
+        .collect()
+}
+
+/// Keys of a multi key request body, one per line.
+/// A boolean url parameter, set by `true` or no value at all.
+fn flag(url: &Url, name: &str) -> Result<bool, DeserializationError> {
+    match query_param(url, name).as_deref() {
+        None | Some("false") => Ok(false),
+        Some("true") | Some("") => Ok(true),
+        Some(_) => Err(DeserializationError::UnparsableQuery),
+    }
+}
+
+/// The `nx` or `xx` flag of a SET, both at once being refused.
+fn set_condition(url: &Url) -> Result<SetCondition, DeserializationError> {
+    match (flag(url, "nx")?, flag(url, "xx")?) {
+        (false, false) => Ok(SetCondition::Always),
+        (true, false) => Ok(SetCondition::IfAbsent),
+        (false, true) => Ok(SetCondition::IfExists),
+        (true, true) => Err(DeserializationError::UnparsableQuery),
+    }
+}
+
+/// Distinct tags of the comma separated `tags` url parameter.
+fn tags(url: &Url) -> Vec<String> {
+    let mut tags: Vec<String> = query_param(url, "tags")
+        .iter()
+        .flat_map(|tags| tags.split(','))
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_owned)
+        .collect();
+    tags.sort_unstable();
+    tags.dedup();
+    tags
+}
+
+/// Values of the comma separated url parameter `name`, in order.
+#[cfg(feature = "scripting")]
+fn comma_list(url: &Url, name: &str) -> Vec<String> {
+    query_param(url, name)
+        .iter()
+        .flat_map(|values| values.split(','))
+        .filter(|value| !value.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn key_list(body: Vec<u8>) -> Result<Vec<String>, DeserializationError> {
+    let body = String::from_utf8(body).map_err(|_| DeserializationError::UnparsableBytes)?;
+    Ok(body
+        .lines()
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+// one item or key per line, at least one
+fn non_empty_list(body: Vec<u8>) -> Result<Vec<String>, DeserializationError> {
+    let items = key_list(body)?;
+    if items.is_empty() {
+        return Err(DeserializationError::UnparsableQuery);
+    }
+    Ok(items)
+}
+
+// how many items a pop takes, `?count=`, one by default
+fn pop_count(url: &Url) -> Result<usize, DeserializationError> {
+    match query_param(url, "count") {
+        Some(count) => count
+            .parse()
+            .ok()
+            .filter(|count| *count > 0)
+            .ok_or(DeserializationError::UnparsableQuery),
+        None => Ok(1),
+    }
+}
+
+// one `<key> <value>` pair per line, the value running to the end of the line
+fn key_value_list(body: Vec<u8>) -> Result<Vec<(String, Vec<u8>)>, DeserializationError> {
+    let body = String::from_utf8(body).map_err(|_| DeserializationError::UnparsableBytes)?;
+    let pairs: Vec<(String, Vec<u8>)> = body
+        .lines()
+        .map(str::trim_start)
+        .filter(|line| !line.trim_end().is_empty())
+        .map(|line| match line.split_once(' ') {
+            Some((key, value)) => Ok((key.to_owned(), value.as_bytes().to_vec())),
+            None => Err(DeserializationError::UnparsableQuery),
+        })
+        .collect::<Result<_, _>>()?;
+    match pairs.is_empty() {
+        true => Err(DeserializationError::UnparsableQuery),
+        false => Ok(pairs),
+    }
+}
+
+fn delete_api(url: &Url) -> Result<Query, DeserializationError> {
+    let path = url.path();
+
+    // the `?` and `[]` of the glob come percent-encoded
+    match_api!(path, "/PATTERN/*", |captures: Vec<String>| {
+        let glob = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::DelPattern {
+            pattern: Pattern::new(glob),
+            dry_run: flag(url, "dry_run")?,
+        })
+    });
+
+    match_api!(path, "/SLOWLOG", |_| Ok(Query::SlowLogReset));
+
+    match_api!(path, "/TOMBSTONE/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::Untombstone { key: key.clone() })
+    });
+
+    #[cfg(feature = "chaos")]
+    match_api!(path, "/DEBUG/CHAOS", |_| Ok(Query::ChaosUpdate { spec: None }));
+
+    Err(DeserializationError::QueryNotFound)
+}
+
+fn import_api(req: &mut Request) -> Result<Query, DeserializationError> {
+    let format = req
+        .content_type()
+        .and_then(|content_type| BulkFormat::from_content_type(content_type.essence()))
+        .ok_or(DeserializationError::UnparsableQuery)?;
+
+    Ok(Query::Import {
+        format,
+        body: req.take_body(),
+    })
+}
+
+fn get_api(url: &Url) -> Result<Query, DeserializationError> {
+    let path = url.path();
+
+    match_api!(path, "/SET/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(value)) => Ok(Query::Set {
+                key: key.clone(),
+                data: value.clone().into_bytes(),
+                tags: tags(url),
+                condition: set_condition(url)?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/GET/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::Get { key: el.clone() })
+            })
+    });
+
+    match_api!(path, "/WATCH/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        let version = query_param(url, "version")
+            .map(|version| version.parse().map_err(|_| DeserializationError::UnparsableQuery))
+            .transpose()?;
+        let timeout = query_param(url, "timeout")
+            .map(|timeout| parse_duration(&timeout).map_err(|_| DeserializationError::UnparsableDuration))
+            .transpose()?;
+        Ok(Query::Watch {
+            key: key.clone(),
+            version,
+            timeout: timeout.unwrap_or(DEFAULT_WATCH_TIMEOUT),
+        })
+    });
+
+    match_api!(path, "/TOMBSTONE/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        let ttl = query_param(url, "ttl")
+            .map(|ttl| parse_duration(&ttl).map_err(|_| DeserializationError::UnparsableDuration))
+            .transpose()?;
+        Ok(Query::Tombstone { key: key.clone(), ttl })
+    });
+
+    match_api!(path, "/DEL/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::Del { key: el.clone() })
+            })
+    });
+
+    match_api!(path, "/GETDEL/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::GetDel { key: el.clone() })
+            })
+    });
+
+    match_api!(path, "/EXISTS/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::Exists { key: el.clone() })
             })
     });
 
-    match_api!(path, "/INFO", |_| Ok(Query::Info));
+    match_api!(path, "/EXPIRE/*/*", |captures: Vec<String>| {
+        if let (Some(key), Some(dur)) = (captures.first(), captures.get(1)) {
+            match parse_duration(dur.as_str()) {
+                Ok(dur) => Ok(Query::Expire {
+                    key: key.clone(),
+                    ttl: dur,
+                }),
+                Err(_) => Err(DeserializationError::UnparsableDuration),
+            }
+        } else {
+            Err(DeserializationError::UnparsableQuery)
+        }
+    });
+
+    match_api!(path, "/TTL/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::Ttl { key: el.clone() })
+            })
+    });
+
+    match_api!(path, "/PERSIST/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::Persist { key: el.clone() })
+            })
+    });
+
+    match_api!(path, "/OBJECT/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::Object { key: el.clone() })
+            })
+    });
+
+    match_api!(path, "/INCRBYFLOAT/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1).and_then(|el| el.parse::<f64>().ok())) {
+            (Some(key), Some(increment)) if increment.is_finite() => Ok(Query::IncrByFloat {
+                key: key.clone(),
+                increment,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/INCR/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::IncrBy { key: key.clone(), delta: 1 })
+    });
+
+    match_api!(path, "/DECR/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::IncrBy { key: key.clone(), delta: -1 })
+    });
+
+    match_api!(path, "/INCRBY/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1).and_then(|el| el.parse::<i64>().ok())) {
+            (Some(key), Some(delta)) => Ok(Query::IncrBy { key: key.clone(), delta }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/DECRBY/*/*", |captures: Vec<String>| {
+        let delta = captures.get(1).and_then(|el| el.parse::<i64>().ok()).and_then(i64::checked_neg);
+        match (captures.first(), delta) {
+            (Some(key), Some(delta)) => Ok(Query::IncrBy { key: key.clone(), delta }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/BF.RESERVE/*/*/*", |captures: Vec<String>| {
+        let error_rate = captures.get(1).and_then(|el| el.parse::<f64>().ok());
+        let capacity = captures.get(2).and_then(|el| el.parse::<u64>().ok());
+        match (captures.first(), error_rate, capacity) {
+            (Some(key), Some(error_rate), Some(capacity)) => Ok(Query::BloomReserve {
+                key: key.clone(),
+                params: BloomParams::new(error_rate, capacity).ok_or(DeserializationError::UnparsableQuery)?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/BF.ADD/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(item)) => Ok(Query::BloomAdd {
+                key: key.clone(),
+                item: item.clone(),
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/BF.EXISTS/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(item)) => Ok(Query::BloomExists {
+                key: key.clone(),
+                item: item.clone(),
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/BF.INFO/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::BloomInfo { key: el.clone() })
+            })
+    });
+
+    match_api!(path, "/CMS.INITBYDIM/*/*/*", |captures: Vec<String>| {
+        let width = captures.get(1).and_then(|el| el.parse::<u32>().ok());
+        let depth = captures.get(2).and_then(|el| el.parse::<u32>().ok());
+        match (captures.first(), width, depth) {
+            (Some(key), Some(width), Some(depth)) => Ok(Query::CmsInit {
+                key: key.clone(),
+                params: CmsParams::by_dim(width, depth).ok_or(DeserializationError::UnparsableQuery)?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/CMS.INITBYPROB/*/*/*", |captures: Vec<String>| {
+        let error = captures.get(1).and_then(|el| el.parse::<f64>().ok());
+        let probability = captures.get(2).and_then(|el| el.parse::<f64>().ok());
+        match (captures.first(), error, probability) {
+            (Some(key), Some(error), Some(probability)) => Ok(Query::CmsInit {
+                key: key.clone(),
+                params: CmsParams::by_prob(error, probability).ok_or(DeserializationError::UnparsableQuery)?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/CMS.INCRBY/*/*/*", |captures: Vec<String>| {
+        let increment = captures.get(2).and_then(|el| el.parse::<u64>().ok());
+        match (captures.first(), captures.get(1), increment) {
+            (Some(key), Some(item), Some(increment)) => Ok(Query::CmsIncrBy {
+                key: key.clone(),
+                increments: vec![(item.clone(), increment)],
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/CMS.QUERY/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(item)) => Ok(Query::CmsCount {
+                key: key.clone(),
+                item: item.clone(),
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/CMS.INFO/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::CmsInfo { key: el.clone() })
+            })
+    });
+
+    match_api!(path, "/TOPK.RESERVE/*/*", |captures: Vec<String>| {
+        topk_reserve(captures.first(), captures.get(1), None)
+    });
+
+    match_api!(path, "/TOPK.RESERVE/*/*/*", |captures: Vec<String>| {
+        topk_reserve(captures.first(), captures.get(1), captures.get(2))
+    });
+
+    match_api!(path, "/TOPK.ADD/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(item)) => Ok(Query::TopKAdd {
+                key: key.clone(),
+                items: vec![(item.clone(), 1)],
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/TOPK.LIST/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::TopKList { key: el.clone() })
+            })
+    });
+
+    match_api!(path, "/TOPK.INFO/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::TopKInfo { key: el.clone() })
+            })
+    });
+
+    match_api!(path, "/TS.CREATE/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::TsCreate {
+                    key: el.clone(),
+                    retention: None,
+                })
+            })
+    });
+
+    match_api!(path, "/TS.CREATE/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(retention)) => Ok(Query::TsCreate {
+                key: key.clone(),
+                retention: Some(parse_duration(retention).map_err(|_| DeserializationError::UnparsableDuration)?),
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/TS.ADD/*/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1), captures.get(2)) {
+            (Some(key), Some(timestamp), Some(value)) => Ok(Query::TsAdd {
+                key: key.clone(),
+                timestamp: parse_timestamp(timestamp)?,
+                value: parse_sample_value(value)?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/ZRANGE/*/*/*", |captures: Vec<String>| {
+        let start = captures.get(1).and_then(|el| el.parse::<i64>().ok());
+        let stop = captures.get(2).and_then(|el| el.parse::<i64>().ok());
+        match (captures.first(), start, stop) {
+            (Some(key), Some(start), Some(stop)) => Ok(Query::ZRange {
+                key: key.clone(),
+                start,
+                stop,
+                rev: flag(url, "rev")?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    // the `(` of an excluded bound may come percent-encoded
+    match_api!(path, "/ZRANGEBYSCORE/*/*/*", |captures: Vec<String>| {
+        let min = captures.get(1).and_then(|el| sorted_set::parse_bound(el));
+        let max = captures.get(2).and_then(|el| sorted_set::parse_bound(el));
+        let limit = match query_param(url, "limit") {
+            Some(limit) => Some(
+                limit
+                    .parse()
+                    .ok()
+                    .filter(|limit| *limit > 0)
+                    .ok_or(DeserializationError::UnparsableQuery)?,
+            ),
+            None => None,
+        };
+        let offset = match query_param(url, "offset") {
+            Some(offset) => offset.parse().map_err(|_| DeserializationError::UnparsableQuery)?,
+            None => 0,
+        };
+        match (captures.first(), min, max) {
+            (Some(key), Some(min), Some(max)) => Ok(Query::ZRangeByScore {
+                key: key.clone(),
+                min,
+                max,
+                offset,
+                limit,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/ZREMRANGEBYSCORE/*/*/*", |captures: Vec<String>| {
+        let min = captures.get(1).and_then(|el| sorted_set::parse_bound(el));
+        let max = captures.get(2).and_then(|el| sorted_set::parse_bound(el));
+        match (captures.first(), min, max) {
+            (Some(key), Some(min), Some(max)) => Ok(Query::ZRemRangeByScore {
+                key: key.clone(),
+                min,
+                max,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/ZINCRBY/*/*/*", |captures: Vec<String>| {
+        let increment = captures.get(2).and_then(|el| el.parse::<f64>().ok());
+        match (captures.first(), captures.get(1), increment) {
+            (Some(key), Some(member), Some(increment)) if !increment.is_nan() => Ok(Query::ZIncrBy {
+                key: key.clone(),
+                member: member.clone(),
+                increment,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/ZRANK/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(member)) => Ok(Query::ZRank {
+                key: key.clone(),
+                member: member.clone(),
+                rev: flag(url, "rev")?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/LPOP/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::ListPop {
+            key: key.clone(),
+            end: End::Left,
+            count: pop_count(url)?,
+        })
+    });
+
+    match_api!(path, "/RPOP/*", |captures: Vec<String>| {
+        let key = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::ListPop {
+            key: key.clone(),
+            end: End::Right,
+            count: pop_count(url)?,
+        })
+    });
+
+    match_api!(path, "/LRANGE/*/*/*", |captures: Vec<String>| {
+        let start = captures.get(1).and_then(|el| el.parse::<i64>().ok());
+        let stop = captures.get(2).and_then(|el| el.parse::<i64>().ok());
+        match (captures.first(), start, stop) {
+            (Some(key), Some(start), Some(stop)) => Ok(Query::LRange {
+                key: key.clone(),
+                start,
+                stop,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/LLEN/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |key| {
+                Ok(Query::LLen { key: key.clone() })
+            })
+    });
+
+    match_api!(path, "/RPOPLPUSH/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(source), Some(destination)) => Ok(Query::LMove {
+                source: source.clone(),
+                destination: destination.clone(),
+                from: End::Right,
+                to: End::Left,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/LMOVE/*/*/*/*", |captures: Vec<String>| {
+        let from = captures.get(2).and_then(|el| el.parse::<End>().ok());
+        let to = captures.get(3).and_then(|el| el.parse::<End>().ok());
+        match (captures.first(), captures.get(1), from, to) {
+            (Some(source), Some(destination), Some(from), Some(to)) => Ok(Query::LMove {
+                source: source.clone(),
+                destination: destination.clone(),
+                from,
+                to,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/HGET/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(field)) => Ok(Query::HGet {
+                key: key.clone(),
+                field: field.clone(),
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/HGETALL/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |key| {
+                Ok(Query::HGetAll { key: key.clone() })
+            })
+    });
+
+    match_api!(path, "/HINCRBY/*/*/*", |captures: Vec<String>| {
+        let delta = captures.get(2).and_then(|el| el.parse::<i64>().ok());
+        match (captures.first(), captures.get(1), delta) {
+            (Some(key), Some(field), Some(delta)) => Ok(Query::HIncrBy {
+                key: key.clone(),
+                field: field.clone(),
+                delta,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/HEXPIRE/*/*/*", |captures: Vec<String>| {
+        let (Some(key), Some(field), Some(ttl)) = (captures.first(), captures.get(1), captures.get(2)) else {
+            return Err(DeserializationError::UnparsableQuery);
+        };
+        Ok(Query::HExpire {
+            key: key.clone(),
+            field: field.clone(),
+            ttl: Some(parse_duration(ttl).map_err(|_| DeserializationError::UnparsableDuration)?),
+        })
+    });
+
+    match_api!(path, "/HPERSIST/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(field)) => Ok(Query::HExpire {
+                key: key.clone(),
+                field: field.clone(),
+                ttl: None,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/HTTL/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(field)) => Ok(Query::HTtl {
+                key: key.clone(),
+                field: field.clone(),
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/SMEMBERS/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |key| {
+                Ok(Query::SMembers { key: key.clone() })
+            })
+    });
+
+    match_api!(path, "/SISMEMBER/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(member)) => Ok(Query::SIsMember {
+                key: key.clone(),
+                member: member.clone(),
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/SCARD/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |key| {
+                Ok(Query::SCard { key: key.clone() })
+            })
+    });
+
+    match_api!(path, "/TS.RANGE/*/*/*", |captures: Vec<String>| {
+        let (Some(key), Some(from), Some(to)) = (captures.first(), captures.get(1), captures.get(2)) else {
+            return Err(DeserializationError::UnparsableQuery);
+        };
+        let from = match from.as_str() {
+            "-" => 0,
+            from => from.parse().map_err(|_| DeserializationError::UnparsableQuery)?,
+        };
+        let to = match to.as_str() {
+            "+" => u64::MAX,
+            to => to.parse().map_err(|_| DeserializationError::UnparsableQuery)?,
+        };
+        let aggregation = match (query_param(url, "aggregation"), query_param(url, "bucket")) {
+            (Some(aggregation), Some(bucket)) => {
+                let aggregation = Aggregation::from_name(&aggregation).ok_or(DeserializationError::UnparsableQuery)?;
+                let bucket = parse_duration(&bucket).map_err(|_| DeserializationError::UnparsableDuration)?;
+                let bucket = bucket.as_millis() as u64;
+                if bucket == 0 {
+                    return Err(DeserializationError::UnparsableDuration);
+                }
+                Some((aggregation, bucket))
+            }
+            (None, None) => None,
+            _ => return Err(DeserializationError::UnparsableQuery),
+        };
+        Ok(Query::TsRange {
+            key: key.clone(),
+            from,
+            to,
+            aggregation,
+        })
+    });
+
+    match_api!(path, "/TS.INFO/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::TsInfo { key: el.clone() })
+            })
+    });
+
+    match_api!(path, "/VCREATE/*/*", |captures: Vec<String>| {
+        vector_create(captures.first(), captures.get(1), None)
+    });
+
+    match_api!(path, "/VCREATE/*/*/*", |captures: Vec<String>| {
+        vector_create(captures.first(), captures.get(1), captures.get(2))
+    });
+
+    match_api!(path, "/VREM/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(index), Some(id)) => Ok(Query::VectorRemove {
+                index: index.clone(),
+                id: id.clone(),
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/VINFO/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::VectorInfo { index: el.clone() })
+            })
+    });
+
+    match_api!(path, "/ADMIN/HOTKEYS", |_| Ok(Query::HotKeys));
+
+    match_api!(path, "/SLOWLOG", |_| {
+        let count = query_param(url, "count")
+            .map(|count| count.parse::<usize>().map_err(|_| DeserializationError::UnparsableQuery))
+            .transpose()?;
+        Ok(Query::SlowLog { count })
+    });
+
+    #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+    match_api!(path, "/ADMIN/MEMSTATS", |_| Ok(Query::MemStats));
+
+    #[cfg(feature = "chaos")]
+    match_api!(path, "/DEBUG/CHAOS", |_| Ok(Query::Chaos));
+
+    #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+    match_api!(path, "/ADMIN/MEMSTATS/HEAPPROFILE", |_| Ok(Query::HeapProfile {
+        path: query_param(url, "path"),
+    }));
+
+    match_api!(path, "/RATELIMIT/*/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1), captures.get(2)) {
+            (Some(key), Some(limit), Some(window)) => Ok(Query::RateLimit {
+                key: key.clone(),
+                limit: limit
+                    .parse()
+                    .ok()
+                    .filter(|limit| *limit > 0)
+                    .ok_or(DeserializationError::UnparsableQuery)?,
+                window: parse_duration(window)
+                    .ok()
+                    .filter(|window| !window.is_zero())
+                    .ok_or(DeserializationError::UnparsableDuration)?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/LOCK/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(name), Some(ttl)) => {
+                let ttl = parse_duration(ttl).map_err(|_| DeserializationError::UnparsableDuration)?;
+                #[cfg(feature = "cluster")]
+                if let Some(quorum) = query_param(url, "quorum") {
+                    let token = query_param(url, "token")
+                        .map(|token| token.parse())
+                        .transpose()
+                        .map_err(|_| DeserializationError::UnparsableQuery)?;
+                    return lease_query(name.clone(), LeaseOp::Lock { ttl, token }, &quorum);
+                }
+                Ok(Query::Lock { name: name.clone(), ttl })
+            }
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/RENEW/*/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1), captures.get(2)) {
+            (Some(name), Some(token), Some(ttl)) => {
+                let token = token.parse().map_err(|_| DeserializationError::UnparsableQuery)?;
+                let ttl = parse_duration(ttl).map_err(|_| DeserializationError::UnparsableDuration)?;
+                #[cfg(feature = "cluster")]
+                if let Some(quorum) = query_param(url, "quorum") {
+                    return lease_query(name.clone(), LeaseOp::Renew { token, ttl }, &quorum);
+                }
+                Ok(Query::Renew { name: name.clone(), token, ttl })
+            }
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/UNLOCK/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(name), Some(token)) => {
+                let token = token.parse().map_err(|_| DeserializationError::UnparsableQuery)?;
+                #[cfg(feature = "cluster")]
+                if let Some(quorum) = query_param(url, "quorum") {
+                    return lease_query(name.clone(), LeaseOp::Unlock { token }, &quorum);
+                }
+                Ok(Query::Unlock { name: name.clone(), token })
+            }
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/QPOP/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(timeout)) => Ok(Query::QueuePop {
+                key: key.clone(),
+                timeout: parse_duration(timeout).map_err(|_| DeserializationError::UnparsableDuration)?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/QACK/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(id)) => Ok(Query::QueueAck {
+                key: key.clone(),
+                id: id.parse().map_err(|_| DeserializationError::UnparsableQuery)?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/SEMACQUIRE/*/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1), captures.get(2)) {
+            (Some(name), Some(max), Some(ttl)) => Ok(Query::SemAcquire {
+                name: name.clone(),
+                max: max
+                    .parse()
+                    .ok()
+                    .filter(|max| *max > 0)
+                    .ok_or(DeserializationError::UnparsableQuery)?,
+                ttl: parse_duration(ttl).map_err(|_| DeserializationError::UnparsableDuration)?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/SEMRELEASE/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(name), Some(token)) => Ok(Query::SemRelease {
+                name: name.clone(),
+                token: token.parse().map_err(|_| DeserializationError::UnparsableQuery)?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/DELAT/*/*", |captures: Vec<String>| {
+        match (captures.first(), captures.get(1)) {
+            (Some(key), Some(at)) => Ok(Query::DelAt {
+                key: key.clone(),
+                at: parse_timestamp(at)?,
+            }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    match_api!(path, "/SCHEDULED", |_| Ok(Query::Scheduled));
+
+    match_api!(path, "/SCHEDULED/*/CANCEL", |captures: Vec<String>| {
+        captures
+            .first()
+            .and_then(|el| el.parse().ok())
+            .map_or(Err(DeserializationError::UnparsableQuery), |id| {
+                Ok(Query::CancelSchedule { id })
+            })
+    });
+
+    // the `?` and `[]` of the glob come percent-encoded
+    match_api!(path, "/KEYS/*", |captures: Vec<String>| {
+        let glob = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        let limit = match query_param(url, "limit") {
+            Some(limit) => Some(
+                limit
+                    .parse()
+                    .ok()
+                    .filter(|limit| *limit > 0)
+                    .ok_or(DeserializationError::UnparsableQuery)?,
+            ),
+            None => None,
+        };
+        Ok(Query::Keys {
+            pattern: Pattern::new(glob),
+            limit,
+        })
+    });
+
+    match_api!(path, "/SUBSCRIBE/*", |captures: Vec<String>| {
+        let channel = captures.first().ok_or(DeserializationError::UnparsableQuery)?;
+        Ok(Query::Subscribe { channel: channel.clone() })
+    });
+
+    match_api!(path, "/PUBSUB/CHANNELS", |_| Ok(Query::PubSubChannels));
+
+    match_api!(path, "/SCAN/*", |captures: Vec<String>| {
+        let cursor = captures
+            .first()
+            .and_then(|cursor| cursor.parse().ok())
+            .ok_or(DeserializationError::UnparsableQuery)?;
+        let count = match query_param(url, "count") {
+            Some(count) => count
+                .parse()
+                .ok()
+                .filter(|count| *count > 0)
+                .ok_or(DeserializationError::UnparsableQuery)?,
+            None => DEFAULT_SCAN_COUNT,
+        };
+        Ok(Query::Scan {
+            cursor,
+            count,
+            pattern: query_param(url, "match").map(|glob| Pattern::new(&glob)),
+        })
+    });
+
+    match_api!(path, "/EXPIRING", |_| {
+        let within = query_param(url, "within")
+            .ok_or(DeserializationError::UnparsableQuery)
+            .and_then(|within| parse_duration(&within).map_err(|_| DeserializationError::UnparsableDuration))?;
+        let limit = match query_param(url, "limit") {
+            Some(limit) => limit
+                .parse()
+                .ok()
+                .filter(|limit| *limit > 0)
+                .ok_or(DeserializationError::UnparsableQuery)?,
+            None => DEFAULT_EXPIRING_LIMIT,
+        };
+        Ok(Query::Expiring { within, limit })
+    });
+
+    match_api!(path, "/GETBYTAG/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::GetByTag { tag: el.clone() })
+            })
+    });
+
+    match_api!(path, "/DELBYTAG/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .map_or(Err(DeserializationError::UnparsableQuery), |el| {
+                Ok(Query::DelByTag { tag: el.clone() })
+            })
+    });
+
+    match_api!(path, "/SEARCH", |_| {
+        let limit = match query_param(url, "limit") {
+            Some(limit) => limit
+                .parse()
+                .ok()
+                .filter(|limit| *limit > 0)
+                .ok_or(DeserializationError::UnparsableQuery)?,
+            None => DEFAULT_SEARCH_LIMIT,
+        };
+        query_param(url, "q")
+            .map_or(Err(DeserializationError::UnparsableQuery), |query| {
+                Ok(Query::Search { query, limit })
+            })
+    });
+
+    match_api!(path, "/INFO", |_| Ok(Query::Info { section: None }));
+
+    match_api!(path, "/INFO/*", |captures: Vec<String>| {
+        let section = captures.first().and_then(|section| section.parse().ok());
+        match section {
+            Some(section) => Ok(Query::Info { section: Some(section) }),
+            None => Err(DeserializationError::UnparsableQuery),
+        }
+    });
 
     match_api!(path, "/FLUSHALL", |_| Ok(Query::FlushAll));
 
     match_api!(path, "/DBSIZE", |_| Ok(Query::DbSize));
 
+    #[cfg(feature = "metrics")]
+    match_api!(path, "/STATS", |_| Ok(Query::Stats));
+
     match_api!(path, "/PING", |_| Ok(Query::Ping));
 
+    match_api!(path, "/SPLITSHARD/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .and_then(|el| el.parse().ok())
+            .map_or(Err(DeserializationError::UnparsableQuery), |shard| {
+                Ok(Query::SplitShard { shard })
+            })
+    });
+
+    match_api!(path, "/RESHARD/*", |captures: Vec<String>| {
+        captures
+            .first()
+            .and_then(|el| el.parse().ok())
+            .map_or(Err(DeserializationError::UnparsableQuery), |shards| {
+                Ok(Query::Reshard { shards })
+            })
+    });
+
+    match_api!(path, "/EXPORT", |_| {
+        let format = match query_param(url, "format") {
+            Some(format) => BulkFormat::from_name(&format).ok_or(DeserializationError::UnparsableQuery)?,
+            #[cfg(feature = "json")]
+            None => BulkFormat::NdJson,
+            #[cfg(not(feature = "json"))]
+            None => BulkFormat::Binary,
+        };
+        Ok(Query::Export {
+            prefix: query_param(url, "prefix"),
+            format,
+        })
+    });
+
+    #[cfg(feature = "replication")]
+    match_api!(path, "/REPLICATE", |_| {
+        let number = |name| query_param(url, name).map(|value| value.parse::<u64>());
+        match (number("run"), number("since")) {
+            (Some(Ok(run)), Some(Ok(since))) => Ok(Query::Replicate {
+                resume: Some((run, since)),
+            }),
+            (None, None) => Ok(Query::Replicate { resume: None }),
+            _ => Err(DeserializationError::UnparsableQuery),
+        }
+    });
+
+    #[cfg(feature = "cluster")]
+    match_api!(path, "/CLUSTER/INFO", |_| Ok(Query::ClusterInfo));
+
+    #[cfg(feature = "cluster")]
+    match_api!(path, "/CLUSTER/SLOTS", |_| Ok(Query::ClusterSlots));
+
+    match_api!(path, "/ADMIN/OPS", |_| Ok(Query::Operations));
+
+    #[cfg(feature = "backup")]
+    match_api!(path, "/ADMIN/BACKUP", |_| {
+        let compression = query_param(url, "compression")
+            .unwrap_or_else(|| DEFAULT_BACKUP_COMPRESSION.to_string())
+            .parse()
+            .map_err(|_| DeserializationError::UnparsableQuery)?;
+        Ok(Query::Backup { compression })
+    });
+
+    #[cfg(feature = "backup")]
+    match_api!(path, "/ADMIN/BACKUP/STATUS", |_| Ok(Query::BackupStatus));
+    #[cfg(feature = "backup")]
+    match_api!(path, "/ADMIN/AOF/REWRITE", |_| Ok(Query::AofRewrite));
+
+    match_api!(path, "/ADMIN/OPS/*/CANCEL", |captures: Vec<String>| {
+        captures
+            .first()
+            .and_then(|el| el.parse().ok())
+            .map_or(Err(DeserializationError::UnparsableQuery), |id| {
+                Ok(Query::CancelOperation { id })
+            })
+    });
+
+    match_api!(path, "/CHANGES", |_| {
+        match query_param(url, "since") {
+            Some(since) => since
+                .parse()
+                .map_or(Err(DeserializationError::UnparsableQuery), |since| {
+                    Ok(Query::Changes { since: Some(since) })
+                }),
+            None => Ok(Query::Changes { since: None }),
+        }
+    });
+
     Err(DeserializationError::QueryNotFound)
 }
 
-fn extract_wildcards(url: &str, pattern: &str) -> Option<Vec<String>> {
-    // Create a regex pattern, replacing `*` with a capture group for wildcards
-    let mut regex_pattern = pattern.replace("*", r"([^/]+)");
+// a lease command taken on a quorum of nodes with `quorum=true`, or by one of them for the
+// node gathering it with `quorum=member`, the token of its lease given
+#[cfg(feature = "cluster")]
+fn lease_query(name: String, op: LeaseOp, quorum: &str) -> Result<Query, DeserializationError> {
+    match quorum {
+        "true" => Ok(Query::QuorumLease { name, op }),
+        "member" if !matches!(op, LeaseOp::Lock { token: None, .. }) => Ok(Query::MemberLease { name, op }),
+        _ => Err(DeserializationError::UnparsableQuery),
+    }
+}
+
+fn query_param(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
 
-    // Add start (^) and end ($) anchors to match the whole URL
-    regex_pattern = format!("^{}$", regex_pattern);
-    let maybe_re = Regex::new(&regex_pattern);
+/// Whether `path` is the route `route`, matched like every route: ignoring case and
+/// trailing slashes.
+pub(crate) fn route_is(path: &str, route: &str) -> bool {
+    path.trim_end_matches('/').eq_ignore_ascii_case(route)
+}
 
-    if let Ok(re) = maybe_re {
-        if let Some(captures) = re.captures(url) {
-            let wildcards = captures
-                .iter()
-                .skip(1) // Skip the full match
-                .filter_map(|cap| cap.map(|m| m.as_str().to_string()))
+/// Whether `path` is under the route prefix `prefix`, ignoring case.
+pub(crate) fn route_starts_with(path: &str, prefix: &str) -> bool {
+    path.get(..prefix.len())
+        .is_some_and(|head| head.eq_ignore_ascii_case(prefix))
+}
+
+/// Every route as its method and pattern, listed once by running the api functions on a
+/// path none of them matches.
+fn routes() -> &'static [(Method, &'static str)] {
+    static ROUTES: OnceLock<Vec<(Method, &'static str)>> = OnceLock::new();
+    ROUTES.get_or_init(|| {
+        let Ok(url) = Url::parse("http://localhost/") else {
+            return Vec::new();
+        };
+        let listed = |method: Method, api: &dyn Fn()| {
+            LISTED_ROUTES.set(Some(Vec::new()));
+            api();
+            let patterns = LISTED_ROUTES.take().unwrap_or_default();
+            patterns.into_iter().map(move |pattern| (method, pattern))
+        };
+        let mut routes: Vec<_> = listed(Method::Get, &|| drop(get_api(&url)))
+            .chain(listed(Method::Put, &|| drop(put_api(&url, Vec::new()))))
+            .chain(listed(Method::Delete, &|| drop(delete_api(&url))))
+            .collect();
+        routes.push((Method::Put, "/IMPORT"));
+        routes
+    })
+}
+
+/// The routes `path` was likely meant to be, closest first: the right command with another
+/// method or number of segments, else commands a typo away.
+pub(crate) fn near_misses(method: Method, path: &str) -> Vec<String> {
+    let segments: Vec<String> = path
+        .trim_matches('/')
+        .split('/')
+        .map(|segment| segment.to_ascii_uppercase())
+        .collect();
+
+    let mut misses: Vec<_> = routes()
+        .iter()
+        .filter_map(|(route_method, pattern)| {
+            let literals: Vec<_> = pattern
+                .trim_start_matches('/')
+                .split('/')
+                .enumerate()
+                .filter(|(_, literal)| *literal != "*")
                 .collect();
+            let command = literals.first().map_or("", |(_, literal)| literal);
+            let typo = edit_distance(&segments[0], command);
+            if typo > MAX_TYPO_DISTANCE.min(command.len() / 2) {
+                return None;
+            }
+            let distance: usize = literals
+                .iter()
+                .map(|(i, literal)| edit_distance(segments.get(*i).map_or("", String::as_str), literal))
+                .sum();
+            let arity = pattern.split('/').count().abs_diff(segments.len() + 1);
+            Some(((typo, distance, *route_method != method, arity), *route_method, *pattern))
+        })
+        .collect();
+    misses.sort_by_key(|(rank, _, _)| *rank);
+    // typos of other commands are noise once the command is known
+    if misses.first().is_some_and(|((typo, ..), _, _)| *typo == 0) {
+        misses.retain(|((typo, ..), _, _)| *typo == 0);
+    }
+    misses
+        .into_iter()
+        .take(MAX_NEAR_MISSES)
+        .map(|(_, method, pattern)| format!("{} {}", method, pattern))
+        .collect()
+}
 
-            return Some(wildcards);
-        } else {
-            return None;
+// edits turning `a` into `b`, swapping two neighbours being one
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>(); a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = rows[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = substitution.min(rows[i - 1][j] + 1).min(rows[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = distance;
         }
     }
-    None
+    rows[a.len()][b.len()]
+}
+
+/// Matches a route `pattern`, `*` capturing a segment. Routes match whatever the case.
+fn route_regex(pattern: &str) -> Regex {
+    Regex::new(&format!("(?i)^{}$", pattern.replace("*", r"([^/]+)")))
+        .expect("route patterns are valid regexes")
+}
+
+/// Captures of the `*` of the route `re` in `url`, percent-decoded so they can hold `/`,
+/// spaces and any other character. `None` if the url does not match or a capture is not
+/// utf-8. Routes match with trailing slashes.
+fn extract_wildcards(url: &str, re: &Regex) -> Option<Vec<String>> {
+    let url = url.trim_end_matches('/');
+    re.captures(url)?
+        .iter()
+        .skip(1) // Skip the full match
+        .flatten()
+        .map(|m| percent_decode_str(m.as_str()).decode_utf8().ok().map(|cap| cap.into_owned()))
+        .collect()
 }
@@ -0,0 +1,218 @@
+use std::time::Duration;
+
+use http_types::Body;
+use log::debug;
+#[cfg(feature = "json")]
+use serde::Deserialize;
+use smol::io::{AsyncBufRead, AsyncReadExt};
+#[cfg(feature = "json")]
+use smol::io::AsyncBufReadExt;
+
+use crate::{
+    errors::{DeserializationError, Errors, TransactionError},
+    record::Record,
+    storage::Storage,
+};
+
+// entries applied under a single round of shard write locks
+const IMPORT_BATCH_ENTRIES: usize = 4096;
+
+/// Encoding of `/IMPORT` and `/EXPORT` bodies.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum BulkFormat {
+    /// `application/x-ndjson`: one `{"key": .., "value": .., "ttl": ..}` object per line,
+    /// `ttl` is optional and written like the urls ones (`60s`, `2h`).
+    #[cfg(feature = "json")]
+    NdJson,
+    /// `application/octet-stream`: entries of a big endian u32 key length, the key,
+    /// a u32 value length, the value and a u64 ttl in milliseconds, 0 for none.
+    /// Lossless, unlike ndjson values which have to be utf-8.
+    Binary,
+}
+
+impl BulkFormat {
+    pub(crate) fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type {
+            #[cfg(feature = "json")]
+            "application/x-ndjson" => Some(BulkFormat::NdJson),
+            "application/octet-stream" => Some(BulkFormat::Binary),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            #[cfg(feature = "json")]
+            "ndjson" => Some(BulkFormat::NdJson),
+            "binary" => Some(BulkFormat::Binary),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "json")]
+            BulkFormat::NdJson => "ndjson",
+            BulkFormat::Binary => "binary",
+        }
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "json")]
+            BulkFormat::NdJson => "application/x-ndjson",
+            BulkFormat::Binary => "application/octet-stream",
+        }
+    }
+}
+
+/// Appends an entry in the [`BulkFormat::Binary`] encoding, `ttl_ms` 0 for none.
+pub(crate) fn encode_binary_entry(buff: &mut Vec<u8>, key: &str, value: &[u8], ttl_ms: u64) {
+    buff.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    buff.extend_from_slice(key.as_bytes());
+    buff.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    buff.extend_from_slice(value);
+    buff.extend_from_slice(&ttl_ms.to_be_bytes());
+}
+
+#[cfg(feature = "json")]
+#[derive(Deserialize)]
+struct JsonEntry {
+    key: String,
+    value: String,
+    #[serde(default)]
+    ttl: Option<String>,
+}
+
+/// Reads the entries of `body` as they arrive and applies them in shard grouped batches,
+/// returning how many were imported. Progress is reported and cancellation checked
+/// between batches: entries of the batches already applied stay, also on errors.
+pub(crate) async fn import(storage: &Storage, format: BulkFormat, body: Body) -> Result<u64, Errors> {
+    let operation = storage.operations.start("import", format!("importing {} entries", format.name()));
+    let total_bytes = body.len().unwrap_or_default() as u64;
+    let mut reader = EntryReader { body, bytes_read: 0 };
+
+    let mut imported = 0;
+    loop {
+        let mut batch = Vec::with_capacity(IMPORT_BATCH_ENTRIES);
+        while batch.len() < IMPORT_BATCH_ENTRIES {
+            let entry = reader
+                .next_entry(format)
+                .await
+                .map_err(|_| {
+                    let position = imported + batch.len() as u64 + 1;
+                    Errors::DeserializationError(DeserializationError::UnparsableEntry(position))
+                })?;
+            match entry {
+                Some(entry) => batch.push(entry),
+                None => break,
+            }
+        }
+        if batch.is_empty() {
+            break;
+        }
+
+        let batch_len = batch.len() as u64;
+        storage
+            .set_many(batch)
+            .await
+            .map_err(Errors::TransactionError)?;
+        imported += batch_len;
+        operation.progress(reader.bytes_read, total_bytes);
+        debug!("imported {} entries, {} bytes read", imported, reader.bytes_read);
+
+        if operation.is_cancelled() {
+            return Err(Errors::TransactionError(TransactionError::OperationCancelled));
+        }
+        if batch_len < IMPORT_BATCH_ENTRIES as u64 {
+            break;
+        }
+    }
+    Ok(imported)
+}
+
+struct EntryReader<R> {
+    body: R,
+    bytes_read: u64,
+}
+
+impl<R: AsyncBufRead + Unpin> EntryReader<R> {
+    /// The next entry, `None` at the end of the body.
+    async fn next_entry(&mut self, format: BulkFormat) -> Result<Option<(String, Record)>, ()> {
+        match format {
+            #[cfg(feature = "json")]
+            BulkFormat::NdJson => self.next_json_entry().await,
+            BulkFormat::Binary => self.next_binary_entry().await,
+        }
+    }
+
+    #[cfg(feature = "json")]
+    async fn next_json_entry(&mut self) -> Result<Option<(String, Record)>, ()> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = self.body.read_line(&mut line).await.map_err(|_| ())?;
+            if read == 0 {
+                return Ok(None);
+            }
+            self.bytes_read += read as u64;
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+
+        let entry: JsonEntry = serde_json::from_str(&line).map_err(|_| ())?;
+        let ttl = entry
+            .ttl
+            .map(|ttl| humantime::parse_duration(&ttl))
+            .transpose()
+            .map_err(|_| ())?;
+        Ok(Some((entry.key, Record::new(entry.value.into_bytes(), ttl))))
+    }
+
+    async fn next_binary_entry(&mut self) -> Result<Option<(String, Record)>, ()> {
+        let mut key_len = [0; 4];
+        // a clean end of the body is only allowed between entries
+        match self.body.read(&mut key_len[..1]).await.map_err(|_| ())? {
+            0 => return Ok(None),
+            _ => self.read_exact(&mut key_len[1..]).await?,
+        }
+        self.bytes_read += 1;
+
+        let key = self.read_bytes(u32::from_be_bytes(key_len)).await?;
+        let key = String::from_utf8(key).map_err(|_| ())?;
+
+        let mut value_len = [0; 4];
+        self.read_exact(&mut value_len).await?;
+        let value = self.read_bytes(u32::from_be_bytes(value_len)).await?;
+
+        let mut ttl_ms = [0; 8];
+        self.read_exact(&mut ttl_ms).await?;
+        let ttl = match u64::from_be_bytes(ttl_ms) {
+            0 => None,
+            ttl_ms => Some(Duration::from_millis(ttl_ms)),
+        };
+        Ok(Some((key, Record::new(value, ttl))))
+    }
+
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), ()> {
+        self.body.read_exact(buf).await.map_err(|_| ())?;
+        self.bytes_read += buf.len() as u64;
+        Ok(())
+    }
+
+    // grows with the data actually received, not with the announced length
+    async fn read_bytes(&mut self, len: u32) -> Result<Vec<u8>, ()> {
+        let mut bytes = Vec::new();
+        (&mut self.body)
+            .take(len as u64)
+            .read_to_end(&mut bytes)
+            .await
+            .map_err(|_| ())?;
+        if bytes.len() != len as usize {
+            return Err(());
+        }
+        self.bytes_read += bytes.len() as u64;
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,20 @@
+/// Two independent 64 bit hashes of an item, for structures probing several positions
+/// like `h1 + i * h2`. The second one is odd, so it never cycles early on a power of two.
+///
+/// Spelled out instead of the std hasher, whose output may change between releases:
+/// filters and sketches restored from a backup have to keep answering the same.
+pub(crate) fn hash_pair(item: &[u8]) -> (u64, u64) {
+    // fnv-1a
+    let fnv = item.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    let h1 = mix(fnv);
+    (h1, mix(h1 ^ 0x9e3779b97f4a7c15) | 1)
+}
+
+// splitmix64 finalizer, spreads the fnv bits of short items
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
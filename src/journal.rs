@@ -0,0 +1,167 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::Mutex,
+};
+
+use smol::channel::{self, Receiver, Sender};
+
+use crate::errors::TransactionError;
+
+pub(crate) const DEFAULT_JOURNAL_CAPACITY: usize = 65536;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ChangeKind {
+    Set,
+    Del,
+    Expire,
+    Persist,
+    Expired,
+    FlushAll,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangeKind::Set => write!(f, "set"),
+            ChangeKind::Del => write!(f, "del"),
+            ChangeKind::Expire => write!(f, "expire"),
+            ChangeKind::Persist => write!(f, "persist"),
+            ChangeKind::Expired => write!(f, "expired"),
+            ChangeKind::FlushAll => write!(f, "flushall"),
+        }
+    }
+}
+
+/// A mutation of the dataset, only the key is kept: consumers read the value back if needed.
+#[derive(Debug, Clone)]
+pub(crate) struct Change {
+    pub(crate) seq: u64,
+    pub(crate) kind: ChangeKind,
+    pub(crate) key: Option<String>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.key {
+            Some(key) => write!(f, "{} {} {}", self.seq, self.kind, key),
+            None => write!(f, "{} {}", self.seq, self.kind),
+        }
+    }
+}
+
+/// Bounded in memory log of the last mutations, numbered by a monotonically increasing
+/// sequence. The oldest changes are dropped once `capacity` is reached.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    capacity: usize,
+    inner: Mutex<JournalInner>,
+}
+
+#[derive(Debug, Default)]
+struct JournalInner {
+    last_seq: u64,
+    changes: VecDeque<Change>,
+    // woken once by the next change of the key, a flush wakes them all
+    watches: HashMap<String, Vec<Sender<()>>>,
+}
+
+impl Journal {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(JournalInner::default()),
+        }
+    }
+
+    /// Appends a change, returning its sequence number.
+    ///
+    /// Callers hold the write lock of the shard they changed, so changes of the same key
+    /// are numbered in the order they were applied.
+    pub(crate) fn record(&self, kind: ChangeKind, key: Option<&str>) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_seq += 1;
+        let seq = inner.last_seq;
+
+        if self.capacity > 0 {
+            if inner.changes.len() == self.capacity {
+                inner.changes.pop_front();
+            }
+            inner.changes.push_back(Change {
+                seq,
+                kind,
+                key: key.map(str::to_owned),
+            });
+        }
+
+        let woken = match key {
+            _ if inner.watches.is_empty() => Vec::new(),
+            Some(key) => inner.watches.remove(key).unwrap_or_default(),
+            None => inner.watches.drain().flat_map(|(_, watches)| watches).collect(),
+        };
+        for watch in woken {
+            let _ = watch.try_send(());
+        }
+        seq
+    }
+
+    /// Receives a message on the next change of `key`, recorded after this call.
+    pub(crate) fn watch(&self, key: &str) -> Receiver<()> {
+        let (sender, receiver) = channel::bounded(1);
+        let mut inner = self.inner.lock().unwrap();
+        inner.watches.entry(key.to_string()).or_default().push(sender);
+        receiver
+    }
+
+    /// Forgets the watches of `key` whose receiver is gone.
+    pub(crate) fn unwatch(&self, key: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(watches) = inner.watches.get_mut(key) {
+            watches.retain(|watch| !watch.is_closed());
+            if watches.is_empty() {
+                inner.watches.remove(key);
+            }
+        }
+    }
+
+    /// Moves the sequence forward to at least `seq`, used for clocks coming from a backup.
+    #[cfg(feature = "backup")]
+    pub(crate) fn observe(&self, seq: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_seq = inner.last_seq.max(seq);
+    }
+
+    pub(crate) fn last_seq(&self) -> u64 {
+        self.inner.lock().unwrap().last_seq
+    }
+
+    /// Changes numbered after `seq`, failing when some of them have already been dropped.
+    pub(crate) fn since(&self, seq: u64) -> Result<Vec<Change>, TransactionError> {
+        let inner = self.inner.lock().unwrap();
+        let oldest = inner
+            .changes
+            .front()
+            .map_or(inner.last_seq + 1, |change| change.seq);
+        if seq + 1 < oldest {
+            return Err(TransactionError::ChangesTruncated);
+        }
+
+        Ok(inner
+            .changes
+            .iter()
+            .filter(|change| change.seq > seq)
+            .cloned()
+            .collect())
+    }
+
+    /// Every change still retained.
+    pub(crate) fn retained(&self) -> Vec<Change> {
+        self.inner.lock().unwrap().changes.iter().cloned().collect()
+    }
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self::new(DEFAULT_JOURNAL_CAPACITY)
+    }
+}
@@ -0,0 +1,93 @@
+mod core;
+mod logger;
+mod record;
+mod storage;
+mod shard_hash;
+mod shard_lock;
+mod bench;
+mod wrapped_record;
+mod http_handler;
+mod text_protocol;
+mod memcached;
+#[cfg(feature = "discovery")]
+mod discovery;
+mod middleware;
+#[cfg(feature = "auth")]
+mod acl;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "http3")]
+mod http3;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod capture;
+mod journal;
+mod pattern;
+mod bitfield;
+mod bloom;
+mod cms;
+mod item_hash;
+mod timeseries;
+mod topk;
+mod set;
+mod list;
+mod hash;
+mod sorted_set;
+mod vector;
+mod search;
+mod tags;
+mod tombstones;
+mod scan;
+mod pubsub;
+mod memory;
+mod transaction;
+#[cfg(feature = "scripting")]
+mod script;
+mod export;
+mod import;
+mod operations;
+mod queue;
+mod ratelimit;
+mod schedule;
+mod semaphore;
+mod http_query_parser;
+mod errors;
+mod query_handler;
+#[cfg(feature = "backup")]
+mod backup_handler;
+#[cfg(feature = "backup")]
+mod backup_format;
+#[cfg(feature = "backup")]
+mod backup_tools;
+#[cfg(feature = "backup")]
+mod backup_download;
+#[cfg(feature = "backup")]
+mod backup_restore;
+#[cfg(feature = "backup")]
+mod aof;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "migrate")]
+mod redis_migration;
+#[cfg(feature = "mirror")]
+mod mirror;
+#[cfg(feature = "replication")]
+mod replication;
+#[cfg(any(feature = "migrate", feature = "mirror", feature = "replication"))]
+mod remote;
+#[cfg(feature = "cluster")]
+mod cluster;
+#[cfg(feature = "cluster")]
+mod quorum;
+#[cfg(feature = "srv")]
+mod srv;
+#[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+mod allocator;
+mod resharding;
+mod stats;
+mod slowlog;
+
+#[cfg(feature = "client")]
+pub mod client;
+
+pub use crate::core::{Mapper, MapperBuilder, MapperCommand};
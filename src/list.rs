@@ -0,0 +1,114 @@
+use std::{collections::VecDeque, fmt::Write, str::FromStr};
+
+/// Items in push order, pushed and popped at either end, for queues and stacks: a consumer
+/// moves what it pops to an in-flight list in the same step with LMOVE.
+#[derive(Debug, Default)]
+pub(crate) struct List {
+    items: VecDeque<String>,
+}
+
+/// An end of a list, `LEFT` being the head.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum End {
+    Left,
+    Right,
+}
+
+impl FromStr for End {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "LEFT" => Ok(End::Left),
+            "RIGHT" => Ok(End::Right),
+            _ => Err(()),
+        }
+    }
+}
+
+impl List {
+    /// Pushes `item` at `end`.
+    pub(crate) fn push(&mut self, end: End, item: String) {
+        match end {
+            End::Left => self.items.push_front(item),
+            End::Right => self.items.push_back(item),
+        }
+    }
+
+    /// Pops the item at `end`, `None` once empty.
+    pub(crate) fn pop(&mut self, end: End) -> Option<String> {
+        match end {
+            End::Left => self.items.pop_front(),
+            End::Right => self.items.pop_back(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Items from index `start` to index `stop` included, head first. Negative indexes
+    /// count from the tail, -1 being the last item.
+    pub(crate) fn range(&self, start: i64, stop: i64) -> Vec<&str> {
+        let len = self.items.len() as i64;
+        let index = |index: i64| if index < 0 { len + index } else { index };
+        let (start, stop) = (index(start).max(0), index(stop).min(len - 1));
+        if start > stop {
+            return Vec::new();
+        }
+        self.items
+            .range(start as usize..=stop as usize)
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Item count (u32), little endian, then every item head first as its length (u32) and
+    /// bytes.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.items.len() as u32).to_le_bytes());
+        for item in &self.items {
+            bytes.extend_from_slice(&(item.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(item.as_bytes());
+        }
+        bytes
+    }
+
+    /// `None` if `bytes` do not hold one.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        let mut list = List::default();
+        for _ in 0..reader.u32()? {
+            let len = reader.u32()? as usize;
+            let item = String::from_utf8(reader.take(len)?.to_vec()).ok()?;
+            list.items.push_back(item);
+        }
+        reader.0.is_empty().then_some(list)
+    }
+}
+
+/// One item per line.
+pub(crate) fn render<S: AsRef<str>>(items: &[S]) -> String {
+    items.iter().fold(String::new(), |mut list, item| {
+        let _ = writeln!(list, "{}", item.as_ref());
+        list
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = self.0.split_at_checked(len)?;
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+}
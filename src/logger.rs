@@ -44,11 +44,10 @@ pub fn setup_logger(enable_async_logging: bool, max_level: Level) {
     if enable_async_logging {
         LAZY_ASYNC_LOGGER.get_or_init(|| -> mpsc::Sender<String> {
             let (s, r) = channel::<String>();
-            thread::spawn(move || loop {
-                match r.recv() {
-                    //todo implement log rotation logic
-                    Ok(msg) => println!("{}", msg),
-                    Err(_) => break,
+            thread::spawn(move || {
+                //todo implement log rotation logic
+                while let Ok(msg) = r.recv() {
+                    println!("{}", msg);
                 }
             });
 
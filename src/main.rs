@@ -1,21 +1,19 @@
-mod core;
-mod logger;
-mod record;
-mod storage;
-mod wrapped_record;
-mod http_handler;
-mod http_query_parser;
-mod errors;
-mod query_handler;
-mod backup_handler;
-
-use core::{Mapper, MapperBuilder};
+use mapper::{Mapper, MapperBuilder};
 
 use clap::Parser;
 
 fn main() {
-    Mapper::new(MapperBuilder::parse())
-        .unwrap()
-        .start()
-        .unwrap();
-}
\ No newline at end of file
+    let mapper_params = MapperBuilder::parse();
+    if let Some(command) = &mapper_params.command {
+        if let Err(e) = command.run(&mapper_params) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(e) = Mapper::new(mapper_params).and_then(|mapper| mapper.start()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
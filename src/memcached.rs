@@ -0,0 +1,455 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+use smol::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    Async,
+};
+
+#[cfg(feature = "auth")]
+use crate::acl::{Acl, Grant};
+use crate::{
+    errors::TransactionError,
+    record::{Record, RecordKind},
+    storage::Storage,
+};
+
+// longest command line, a `get` of many keys included
+const MAX_LINE: usize = 64 * 1024;
+// longest key memcached accepts
+const MAX_KEY_LEN: usize = 250;
+// expiration times past 30 days are unix times
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+const BAD_FORMAT: &str = "CLIENT_ERROR bad command line format";
+
+/// Settings shared by the connections of the memcached listener.
+#[derive(Debug)]
+pub(crate) struct MemcachedSettings {
+    pub(crate) max_value: usize,
+    // checked like memcached does without SASL: the first command is a `set` of
+    // `<user> <password>`, any user
+    pub(crate) api_key: Option<String>,
+    // sent the same way, the password being one of its api keys
+    #[cfg(feature = "auth")]
+    pub(crate) acl: Option<Arc<Acl>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Store {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+    Cas(u64),
+}
+
+#[derive(Debug)]
+enum Command {
+    Get {
+        keys: Vec<String>,
+        cas: bool,
+    },
+    Store {
+        mode: Store,
+        key: String,
+        flags: u32,
+        exptime: i64,
+        bytes: usize,
+        noreply: bool,
+    },
+    Delete {
+        key: String,
+        noreply: bool,
+    },
+    Incr {
+        key: String,
+        delta: u64,
+        decr: bool,
+        noreply: bool,
+    },
+    Touch {
+        key: String,
+        exptime: i64,
+        noreply: bool,
+    },
+    FlushAll {
+        noreply: bool,
+    },
+    Version,
+    Quit,
+}
+
+// whether `grant` allows `command`, a `get` needing every key it reads
+#[cfg(feature = "auth")]
+fn allowed(grant: &Grant, command: &Command) -> bool {
+    match command {
+        Command::Get { keys, .. } => {
+            let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+            grant.check(false, false, &keys).is_ok()
+        }
+        Command::Store { key, .. }
+        | Command::Delete { key, .. }
+        | Command::Incr { key, .. }
+        | Command::Touch { key, .. } => grant.check(false, true, &[key]).is_ok(),
+        // like /FLUSHALL, an admin endpoint
+        Command::FlushAll { .. } => grant.check(true, true, &[]).is_ok(),
+        Command::Version | Command::Quit => true,
+    }
+}
+
+// fails unless this node owns the slots of every key of `command`
+#[cfg(feature = "cluster")]
+fn owned(storage: &Storage, command: &Command) -> Result<(), TransactionError> {
+    let Some(cluster) = &storage.cluster else {
+        return Ok(());
+    };
+    let keys = match command {
+        Command::Get { keys, .. } => keys.iter().map(String::as_str).collect(),
+        Command::Store { key, .. }
+        | Command::Delete { key, .. }
+        | Command::Incr { key, .. }
+        | Command::Touch { key, .. } => vec![key.as_str()],
+        Command::FlushAll { .. } | Command::Version | Command::Quit => Vec::new(),
+    };
+    cluster.check(keys.into_iter().map(|key| storage.key_slot(key)))
+}
+
+/// Parses a command line of the memcached text protocol, failing with the reply to send.
+fn parse(line: &str) -> Result<Command, &'static str> {
+    let mut tokens = line.split_ascii_whitespace();
+    let command = tokens.next().unwrap_or_default();
+    let args: Vec<&str> = tokens.collect();
+
+    let key = |i: usize| {
+        args.get(i)
+            .filter(|key| key.len() <= MAX_KEY_LEN)
+            .map(|key| key.to_string())
+            .ok_or(BAD_FORMAT)
+    };
+    let noreply = |i: usize| match args.get(i) {
+        None => Ok(false),
+        Some(&"noreply") if args.len() == i + 1 => Ok(true),
+        Some(_) => Err(BAD_FORMAT),
+    };
+
+    Ok(match command {
+        "get" | "gets" if !args.is_empty() => Command::Get {
+            keys: (0..args.len()).map(key).collect::<Result<_, _>>()?,
+            cas: command == "gets",
+        },
+        "set" | "add" | "replace" | "append" | "prepend" | "cas" => {
+            let mode = match command {
+                "set" => Store::Set,
+                "add" => Store::Add,
+                "replace" => Store::Replace,
+                "append" => Store::Append,
+                "prepend" => Store::Prepend,
+                _ => Store::Cas(number(&args, 4)?),
+            };
+            Command::Store {
+                mode,
+                key: key(0)?,
+                flags: number(&args, 1)?,
+                exptime: number(&args, 2)?,
+                bytes: number(&args, 3)?,
+                noreply: noreply(if matches!(mode, Store::Cas(_)) { 5 } else { 4 })?,
+            }
+        }
+        // the delay of old clients is only accepted when 0
+        "delete" => match args.get(1) {
+            Some(&"0") => Command::Delete {
+                key: key(0)?,
+                noreply: noreply(2)?,
+            },
+            _ => Command::Delete {
+                key: key(0)?,
+                noreply: noreply(1)?,
+            },
+        },
+        "incr" | "decr" => Command::Incr {
+            key: key(0)?,
+            delta: number(&args, 1)?,
+            decr: command == "decr",
+            noreply: noreply(2)?,
+        },
+        "touch" => Command::Touch {
+            key: key(0)?,
+            exptime: number(&args, 1)?,
+            noreply: noreply(2)?,
+        },
+        "flush_all" => match args.first() {
+            Some(&"0") => Command::FlushAll { noreply: noreply(1)? },
+            Some(&"noreply") | None => Command::FlushAll { noreply: noreply(0)? },
+            Some(_) => return Err("CLIENT_ERROR delayed flush_all is not supported"),
+        },
+        "version" => Command::Version,
+        "quit" => Command::Quit,
+        _ => return Err("ERROR"),
+    })
+}
+
+fn number<T: std::str::FromStr>(args: &[&str], i: usize) -> Result<T, &'static str> {
+    args.get(i)
+        .and_then(|number| number.parse().ok())
+        .ok_or(BAD_FORMAT)
+}
+
+// ttl of a memcached exptime: 0 never expires, up to 30 days it counts seconds from now,
+// beyond that it is a unix time, negative or past ones are already expired
+fn ttl(exptime: i64) -> Option<Duration> {
+    match exptime {
+        0 => None,
+        exptime if exptime < 0 => Some(Duration::ZERO),
+        exptime if exptime <= MAX_RELATIVE_EXPTIME => Some(Duration::from_secs(exptime as u64)),
+        exptime => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            Some(Duration::from_secs((exptime as u64).saturating_sub(now)))
+        }
+    }
+}
+
+async fn get(storage: &Storage, keys: Vec<String>, cas: bool) -> Vec<u8> {
+    let mut reply = Vec::new();
+    for key in keys {
+        // values of other record types are misses, like expired ones not removed yet
+        let Ok((record, version)) = storage.get_versioned_record(&key).await else {
+            continue;
+        };
+        let Ok(value) = record.value(RecordKind::Bytes) else {
+            continue;
+        };
+        if record.ttl_policy.as_ref().is_some_and(|ttl_policy| ttl_policy.expire_in().is_zero()) {
+            continue;
+        }
+        let header = if cas {
+            format!("VALUE {} 0 {} {}\r\n", key, value.len(), version)
+        } else {
+            format!("VALUE {} 0 {}\r\n", key, value.len())
+        };
+        reply.extend_from_slice(header.as_bytes());
+        reply.extend_from_slice(value);
+        reply.extend_from_slice(b"\r\n");
+    }
+    reply.extend_from_slice(b"END\r\n");
+    reply
+}
+
+async fn store(storage: &Storage, mode: Store, key: &str, data: Vec<u8>, ttl: Option<Duration>) -> String {
+    if storage.memory.is_read_only() {
+        return "SERVER_ERROR out of memory storing object".to_string();
+    }
+    let stored = storage
+        .write_record(key, |current| match (mode, current) {
+            (Store::Set, _) | (Store::Add, None) | (Store::Replace, Some(_)) => Ok(Record::new(data, ttl)),
+            (Store::Add, Some(_)) => Err(TransactionError::KeyExists),
+            (Store::Cas(cas), Some((_, version))) if cas != version => Err(TransactionError::VersionMismatch),
+            (Store::Cas(_), Some(_)) => Ok(Record::new(data, ttl)),
+            // appending keeps the expiration of the record
+            (Store::Append, Some((record, _))) => {
+                let mut appended = record.clone();
+                appended.data = [record.value(RecordKind::Bytes)?, &data].concat();
+                Ok(appended)
+            }
+            (Store::Prepend, Some((record, _))) => {
+                let mut prepended = record.clone();
+                prepended.data = [&data, record.value(RecordKind::Bytes)?].concat();
+                Ok(prepended)
+            }
+            (_, None) => Err(TransactionError::RecordNotFound),
+        })
+        .await;
+
+    match stored {
+        Ok(_) => "STORED".to_string(),
+        Err(TransactionError::RecordNotFound) if matches!(mode, Store::Cas(_)) => "NOT_FOUND".to_string(),
+        Err(TransactionError::KeyExists | TransactionError::RecordNotFound) => "NOT_STORED".to_string(),
+        Err(TransactionError::VersionMismatch) => "EXISTS".to_string(),
+        Err(e) => format!("SERVER_ERROR {}", e),
+    }
+}
+
+async fn incr(storage: &Storage, key: &str, delta: u64, decr: bool) -> String {
+    let mut value = 0;
+    let incremented = storage
+        .write_record(key, |current| {
+            let (record, _) = current.ok_or(TransactionError::RecordNotFound)?;
+            let current = std::str::from_utf8(record.value(RecordKind::Bytes)?)
+                .ok()
+                .and_then(|current| current.trim().parse::<u64>().ok())
+                .ok_or(TransactionError::ValueNotAnInteger)?;
+            // increments wrap around, decrements stop at 0
+            value = if decr {
+                current.saturating_sub(delta)
+            } else {
+                current.wrapping_add(delta)
+            };
+            let mut record = record.clone();
+            record.data = value.to_string().into_bytes();
+            Ok(record)
+        })
+        .await;
+
+    match incremented {
+        Ok(_) => value.to_string(),
+        Err(TransactionError::RecordNotFound) => "NOT_FOUND".to_string(),
+        Err(TransactionError::ValueNotAnInteger) => {
+            "CLIENT_ERROR cannot increment or decrement non-numeric value".to_string()
+        }
+        Err(e) => format!("SERVER_ERROR {}", e),
+    }
+}
+
+async fn run(storage: &Storage, command: Command, data: Vec<u8>) -> Vec<u8> {
+    #[cfg(feature = "cluster")]
+    if let Err(e) = owned(storage, &command) {
+        return format!("SERVER_ERROR {}\r\n", e).into_bytes();
+    }
+    let reply = match command {
+        Command::Get { keys, cas } => return get(storage, keys, cas).await,
+        #[cfg(feature = "replication")]
+        Command::Store { .. }
+        | Command::Delete { .. }
+        | Command::Incr { .. }
+        | Command::Touch { .. }
+        | Command::FlushAll { .. }
+            if storage.is_replica() =>
+        {
+            format!("SERVER_ERROR {}", TransactionError::ReadOnlyReplica)
+        }
+        Command::Store { flags, .. } if flags != 0 => "CLIENT_ERROR flags are not supported".to_string(),
+        Command::Store {
+            mode, key, exptime, ..
+        } => store(storage, mode, &key, data, ttl(exptime)).await,
+        Command::Delete { key, .. } => match storage.remove_record(&key).await {
+            Ok(true) => "DELETED".to_string(),
+            Ok(false) => "NOT_FOUND".to_string(),
+            Err(e) => format!("SERVER_ERROR {}", e),
+        },
+        Command::Incr { key, delta, decr, .. } => incr(storage, &key, delta, decr).await,
+        Command::Touch { key, exptime, .. } => match storage.update_ttl(&key, ttl(exptime)).await {
+            Ok(_) => "TOUCHED".to_string(),
+            Err(TransactionError::RecordNotFound) => "NOT_FOUND".to_string(),
+            Err(e) => format!("SERVER_ERROR {}", e),
+        },
+        Command::FlushAll { .. } => match storage.flush_all().await {
+            Ok(_) => "OK".to_string(),
+            Err(e) => format!("SERVER_ERROR {}", e),
+        },
+        Command::Version => format!("VERSION {}", env!("CARGO_PKG_VERSION")),
+        Command::Quit => String::new(),
+    };
+    format!("{}\r\n", reply).into_bytes()
+}
+
+/// Serves a connection speaking the memcached text protocol: `get`, `gets`, `set`, `add`,
+/// `replace`, `append`, `prepend`, `cas`, `delete`, `incr`, `decr`, `touch`, `flush_all`,
+/// `version` and `quit`, over the records of the HTTP API.
+pub(crate) async fn handle_memcached_client(
+    stream: Async<TcpStream>,
+    address: SocketAddr,
+    storage: Storage,
+    settings: Arc<MemcachedSettings>,
+) {
+    let _connection = storage.stats.connection_opened();
+    if let Err(e) = serve_commands(&stream, &storage, &settings).await {
+        error!("memcached {} from {}", e, address);
+    }
+}
+
+async fn serve_commands(stream: &Async<TcpStream>, storage: &Storage, settings: &MemcachedSettings) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut writer = stream;
+    #[cfg(feature = "auth")]
+    let mut grant: Option<Arc<Grant>> = None;
+    #[cfg(feature = "auth")]
+    let mut authenticated = settings.api_key.is_none() && settings.acl.is_none();
+    #[cfg(not(feature = "auth"))]
+    let mut authenticated = settings.api_key.is_none();
+
+    let mut buff = Vec::new();
+    loop {
+        buff.clear();
+        let read = (&mut reader)
+            .take(MAX_LINE as u64)
+            .read_until(b'\n', &mut buff)
+            .await?;
+        if read == 0 {
+            return Ok(());
+        }
+        if read == MAX_LINE && buff.last() != Some(&b'\n') {
+            writer.write_all(b"CLIENT_ERROR line too long\r\n").await?;
+            return Ok(());
+        }
+
+        let command = match std::str::from_utf8(&buff).map_err(|_| BAD_FORMAT).and_then(parse) {
+            Ok(command) => command,
+            Err(reply) => {
+                writer.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+                continue;
+            }
+        };
+
+        // the data block of a storage command follows its line
+        let (data, noreply) = match &command {
+            Command::Store { bytes, noreply, .. } => {
+                // the block of a length this large cannot be told apart from what follows
+                if *bytes > settings.max_value {
+                    writer.write_all(b"SERVER_ERROR object too large for cache\r\n").await?;
+                    return Ok(());
+                }
+                let mut block = (&mut reader).take(*bytes as u64 + 2);
+                let mut data = Vec::with_capacity(bytes + 2);
+                block.read_to_end(&mut data).await?;
+                if data.len() != bytes + 2 || !data.ends_with(b"\r\n") {
+                    writer.write_all(b"CLIENT_ERROR bad data chunk\r\n").await?;
+                    return Ok(());
+                }
+                data.truncate(*bytes);
+                (data, *noreply)
+            }
+            Command::Delete { noreply, .. }
+            | Command::Incr { noreply, .. }
+            | Command::Touch { noreply, .. }
+            | Command::FlushAll { noreply } => (Vec::new(), *noreply),
+            Command::Quit => return Ok(()),
+            Command::Get { .. } | Command::Version => (Vec::new(), false),
+        };
+
+        let reply = match command {
+            Command::Store { .. } if !authenticated => {
+                let credentials = String::from_utf8_lossy(&data);
+                let password = credentials.split_once(' ').map(|(_, password)| password.trim());
+                authenticated = password.is_some() && password == settings.api_key.as_deref();
+                #[cfg(feature = "auth")]
+                if let (Some(acl), Some(password)) = (&settings.acl, password) {
+                    grant = acl.grant(password);
+                    authenticated = grant.is_some();
+                }
+                if authenticated {
+                    b"STORED\r\n".to_vec()
+                } else {
+                    b"CLIENT_ERROR authentication failure\r\n".to_vec()
+                }
+            }
+            _ if !authenticated => b"CLIENT_ERROR unauthenticated\r\n".to_vec(),
+            #[cfg(feature = "auth")]
+            ref command if grant.as_ref().is_some_and(|grant| !allowed(grant, command)) => {
+                b"CLIENT_ERROR forbidden\r\n".to_vec()
+            }
+            command => run(storage, command, data).await,
+        };
+        if !noreply {
+            writer.write_all(&reply).await?;
+        }
+    }
+}
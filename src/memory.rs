@@ -0,0 +1,226 @@
+//! Memory limit of the instance and what it does as its usage gets close, picked with
+//! `--max-memory` and `--memory-policy`.
+//!
+//! Usage is the resident memory of the process. In a container the limit defaults to a
+//! share of the cgroup one, so the instance gives way before the kernel kills it, possibly
+//! in the middle of a backup.
+
+use std::{
+    fmt, fs,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use smol::{stream::StreamExt, Timer};
+
+use crate::{stats::MemoryReport, storage::Storage};
+
+pub(crate) const DEFAULT_MEMORY_POLICY: &str = "read-only";
+// share of the cgroup limit taken without --max-memory
+const DEFAULT_CGROUP_SHARE: f64 = 0.8;
+// under pressure from this share of the limit, until below the second one
+const PRESSURE_START: f64 = 0.9;
+const PRESSURE_END: f64 = 0.8;
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// cgroup v1 reports no limit as the largest page aligned i64
+const CGROUP_V1_UNLIMITED: u64 = 1 << 62;
+
+/// Largest memory the instance uses: a size such as `2GiB`, or a share of the cgroup limit,
+/// of the physical memory outside containers, such as `75%`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum MaxMemory {
+    Bytes(u64),
+    Share(f64),
+}
+
+impl MaxMemory {
+    /// The limit in bytes, `None` for a share of a total that cannot be read.
+    pub(crate) fn resolve(&self) -> Option<u64> {
+        match self {
+            MaxMemory::Bytes(bytes) => Some(*bytes),
+            MaxMemory::Share(share) => {
+                let total = cgroup_limit().or_else(physical_memory)?;
+                Some((total as f64 * share) as u64)
+            }
+        }
+    }
+
+    /// The limit applying without `--max-memory`: a share of the cgroup limit, none
+    /// outside containers.
+    pub(crate) fn default_limit() -> Option<u64> {
+        cgroup_limit().map(|limit| (limit as f64 * DEFAULT_CGROUP_SHARE) as u64)
+    }
+}
+
+impl FromStr for MaxMemory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(percent) = s.strip_suffix('%') {
+            return match percent.parse::<f64>() {
+                Ok(percent) if percent > 0.0 && percent <= 100.0 => Ok(MaxMemory::Share(percent / 100.0)),
+                _ => Err(format!("invalid share {}, expected above 0% and at most 100%", s)),
+            };
+        }
+        match parse_size(s) {
+            Some(bytes) if bytes > 0 => Ok(MaxMemory::Bytes(bytes)),
+            _ => Err(format!("invalid memory size {}, expected bytes such as 512MiB or 2GiB, or a share such as 75%", s)),
+        }
+    }
+}
+
+// bytes with an optional binary unit, `k`, `kb` and `kib` all being 1024
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let shift = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 10,
+        "m" | "mb" | "mib" => 20,
+        "g" | "gb" | "gib" => 30,
+        "t" | "tb" | "tib" => 40,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// What happens once the usage gets close to the limit, undone once it is back down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MemoryPolicy {
+    // records removed, the ones expiring first before the others
+    Evict,
+    // a backup written right away, while there is still room for it
+    #[cfg(feature = "backup")]
+    Snapshot,
+    // writes adding data refused, reads and deletes still served
+    ReadOnly,
+}
+
+impl FromStr for MemoryPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "evict" => Ok(MemoryPolicy::Evict),
+            #[cfg(feature = "backup")]
+            "snapshot" => Ok(MemoryPolicy::Snapshot),
+            "read-only" => Ok(MemoryPolicy::ReadOnly),
+            _ => Err(format!("unknown memory policy {}, expected evict, snapshot or read-only", s)),
+        }
+    }
+}
+
+impl fmt::Display for MemoryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryPolicy::Evict => write!(f, "evict"),
+            #[cfg(feature = "backup")]
+            MemoryPolicy::Snapshot => write!(f, "snapshot"),
+            MemoryPolicy::ReadOnly => write!(f, "read-only"),
+        }
+    }
+}
+
+/// Whether writes adding data are refused, shared by every clone of the storage.
+#[derive(Debug, Default)]
+pub(crate) struct Memory {
+    read_only: AtomicBool,
+}
+
+impl Memory {
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+}
+
+/// Measures the memory usage of the instance every second, applying `policy` while it is
+/// under pressure.
+pub(crate) fn monitor(storage: Storage, limit: u64, policy: MemoryPolicy) {
+    let (start, end) = ((limit as f64 * PRESSURE_START) as u64, (limit as f64 * PRESSURE_END) as u64);
+    info!("memory limited to {} bytes, {} from {} bytes on", limit, policy, start);
+
+    smol::spawn(async move {
+        let mut pressure = false;
+        // logged once, usage can stay up with nothing left to evict
+        let mut exhausted = false;
+        let mut ticker = Timer::interval(CHECK_INTERVAL);
+        while ticker.next().await.is_some() {
+            let dataset = || async { storage.shard_stats().await.iter().map(|shard| shard.bytes as u64).sum::<u64>() };
+            // the dataset size where the resident memory cannot be read
+            let usage = match resident_memory() {
+                Some(usage) => usage,
+                None => dataset().await,
+            };
+
+            if !pressure && usage >= start {
+                warn!("memory usage of {} bytes is close to the limit of {}, applying {}", usage, limit, policy);
+                pressure = true;
+                match policy {
+                    MemoryPolicy::Evict => {}
+                    #[cfg(feature = "backup")]
+                    MemoryPolicy::Snapshot => storage.request_backup(),
+                    MemoryPolicy::ReadOnly => storage.memory.read_only.store(true, Ordering::Relaxed),
+                }
+            } else if pressure && usage < end {
+                info!("memory usage back down to {} bytes, lifting {}", usage, policy);
+                pressure = false;
+                exhausted = false;
+                storage.memory.read_only.store(false, Ordering::Relaxed);
+            }
+            storage.stats.memory_usage(MemoryReport {
+                limit,
+                usage,
+                pressure,
+                policy: policy.to_string(),
+            });
+
+            if pressure && policy == MemoryPolicy::Evict {
+                // the dataset is only part of the usage, it shrinks by the same share
+                let excess = usage.saturating_sub(end) as f64 / usage.max(1) as f64;
+                let bytes = (dataset().await as f64 * excess).ceil() as u64;
+                match storage.evict(bytes).await {
+                    0 if !exhausted => {
+                        error!("nothing left to evict, memory usage stays at {} bytes", usage);
+                        exhausted = true;
+                    }
+                    0 => {}
+                    evicted => warn!("evicted {} records to free memory", evicted),
+                }
+            }
+        }
+    })
+    .detach();
+}
+
+/// Memory limit of the cgroup of the process, v2 first, `None` without one.
+fn cgroup_limit() -> Option<u64> {
+    if let Ok(limit) = fs::read_to_string("/sys/fs/cgroup/memory.max") {
+        // "max" without a limit
+        return limit.trim().parse().ok();
+    }
+    fs::read_to_string("/sys/fs/cgroup/memory/memory.limit_in_bytes")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .filter(|limit| *limit < CGROUP_V1_UNLIMITED)
+}
+
+fn physical_memory() -> Option<u64> {
+    meminfo_kib("/proc/meminfo", "MemTotal:")
+}
+
+fn resident_memory() -> Option<u64> {
+    meminfo_kib("/proc/self/status", "VmRSS:")
+}
+
+// a `<name> <n> kB` line of a proc file, in bytes
+fn meminfo_kib(path: &str, name: &str) -> Option<u64> {
+    let content = fs::read_to_string(path).ok()?;
+    let line = content.lines().find(|line| line.starts_with(name))?;
+    let kib: u64 = line[name.len()..].trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kib * 1024)
+}
@@ -0,0 +1,572 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use http_types::{Body, Method, Request, Response, StatusCode};
+use log::debug;
+
+#[cfg(feature = "auth")]
+use crate::acl::Acl;
+#[cfg(feature = "metrics")]
+use crate::stats::Stats;
+use crate::{
+    http_handler::handle_http_request,
+    http_query_parser::{route_is, route_starts_with},
+    ratelimit,
+    storage::Storage,
+};
+
+pub(crate) type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A layer wrapped around the request handler: it can answer on its own or hand the
+/// request to the next layer, looking at or changing the request and the response.
+pub(crate) trait Middleware: Send + Sync {
+    fn handle<'a>(&'a self, req: Request, next: Next<'a>) -> BoxFuture<'a, http_types::Result<Response>>;
+}
+
+/// The rest of the chain after a middleware, ending with the request handler.
+pub(crate) struct Next<'a> {
+    middlewares: &'a [Arc<dyn Middleware>],
+    storage: &'a Storage,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn run(self, req: Request) -> BoxFuture<'a, http_types::Result<Response>> {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => middleware.handle(
+                req,
+                Next {
+                    middlewares,
+                    storage: self.storage,
+                },
+            ),
+            None => Box::pin(handle_http_request(req, self.storage.clone())),
+        }
+    }
+}
+
+/// Middlewares applied around every request, the first one is the outermost.
+#[derive(Clone)]
+pub(crate) struct Chain {
+    middlewares: Arc<[Arc<dyn Middleware>]>,
+}
+
+impl Chain {
+    pub(crate) fn new(middlewares: Vec<Arc<dyn Middleware>>) -> Self {
+        Self {
+            middlewares: middlewares.into(),
+        }
+    }
+
+    pub(crate) async fn handle(&self, req: Request, storage: &Storage) -> http_types::Result<Response> {
+        Next {
+            middlewares: &self.middlewares,
+            storage,
+        }
+        .run(req)
+        .await
+    }
+}
+
+/// Logs every request with its status and duration.
+pub(crate) struct AccessLog;
+
+impl Middleware for AccessLog {
+    fn handle<'a>(&'a self, req: Request, next: Next<'a>) -> BoxFuture<'a, http_types::Result<Response>> {
+        Box::pin(async move {
+            let start = Instant::now();
+            let method = req.method();
+            let path = req.url().path().to_string();
+
+            let res = next.run(req).await;
+            match &res {
+                Ok(response) => debug!("{} {} {} {:?}", method, path, response.status(), start.elapsed()),
+                Err(e) => debug!("{} {} failed: {} {:?}", method, path, e, start.elapsed()),
+            }
+            res
+        })
+    }
+}
+
+pub(crate) const DEFAULT_BASE_PATH: &str = "/v1";
+
+/// Serves the routes under a base path, `/v1/GET/foo` as `/GET/foo`, the unprefixed ones
+/// staying available, so a later `/v2` can change them while `/v1` keeps working.
+pub(crate) struct BasePath {
+    prefix: String,
+}
+
+impl BasePath {
+    /// Fails unless `prefix` is an absolute path, its trailing `/` is dropped.
+    pub(crate) fn new(prefix: &str) -> Result<Self, String> {
+        let prefix = prefix.trim_end_matches('/');
+        if !prefix.starts_with('/') {
+            return Err(format!("invalid base path {}, expected it to start with /", prefix));
+        }
+        Ok(Self {
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn strip<'p>(&self, path: &'p str) -> Option<&'p str> {
+        match path.strip_prefix(self.prefix.as_str()) {
+            Some("") => Some("/"),
+            Some(route) if route.starts_with('/') => Some(route),
+            _ => None,
+        }
+    }
+}
+
+impl Middleware for BasePath {
+    fn handle<'a>(&'a self, mut req: Request, next: Next<'a>) -> BoxFuture<'a, http_types::Result<Response>> {
+        Box::pin(async move {
+            if let Some(route) = self.strip(req.url().path()).map(str::to_string) {
+                req.url_mut().set_path(&route);
+            }
+            next.run(req).await
+        })
+    }
+}
+
+/// Replays the response of a request carrying an `Idempotency-Key` to the retries reusing
+/// the key within the window, so a retried write applies once.
+pub(crate) struct Idempotency {
+    window: Duration,
+    outcomes: Mutex<Outcomes>,
+}
+
+#[derive(Default)]
+struct Outcomes {
+    by_key: HashMap<String, Outcome>,
+    // keys by first use, oldest first
+    used: VecDeque<(Instant, String)>,
+}
+
+struct Outcome {
+    request: String,
+    used_at: Instant,
+    // none while in flight
+    response: Option<(Response, Vec<u8>)>,
+}
+
+enum Seen {
+    First,
+    Replay(Box<Response>),
+    Rejected(StatusCode, &'static str),
+}
+
+impl Idempotency {
+    const HEADER: &'static str = "Idempotency-Key";
+    const REPLAYED: &'static str = "Idempotent-Replayed";
+
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            outcomes: Mutex::new(Outcomes::default()),
+        }
+    }
+
+    fn see(&self, key: &str, request: &str) -> Seen {
+        let mut outcomes = self.outcomes.lock().unwrap();
+        while let Some((used_at, _)) = outcomes.used.front() {
+            if used_at.elapsed() < self.window {
+                break;
+            }
+            if let Some((used_at, expired)) = outcomes.used.pop_front() {
+                // the key may have been forgotten then used again since
+                if outcomes.by_key.get(&expired).is_some_and(|outcome| outcome.used_at == used_at) {
+                    outcomes.by_key.remove(&expired);
+                }
+            }
+        }
+
+        match outcomes.by_key.get(key).map(|outcome| (&outcome.request, &outcome.response)) {
+            Some((used_for, _)) if used_for != request => {
+                Seen::Rejected(StatusCode::UnprocessableEntity, "idempotency_key_reused")
+            }
+            Some((_, None)) => Seen::Rejected(StatusCode::Conflict, "idempotency_key_in_flight"),
+            Some((_, Some((response, body)))) => {
+                let mut replay = response.clone();
+                replay.set_body(Body::from_bytes(body.clone()));
+                replay.insert_header(Self::REPLAYED, "true");
+                Seen::Replay(Box::new(replay))
+            }
+            None => {
+                let used_at = Instant::now();
+                let outcome = Outcome {
+                    request: request.to_owned(),
+                    used_at,
+                    response: None,
+                };
+                outcomes.by_key.insert(key.to_owned(), outcome);
+                outcomes.used.push_back((used_at, key.to_owned()));
+                Seen::First
+            }
+        }
+    }
+}
+
+// forgets a key whose request failed or was dropped before it got a response to replay
+struct InFlight<'a> {
+    idempotency: &'a Idempotency,
+    key: Option<String>,
+}
+
+impl InFlight<'_> {
+    fn complete(mut self, response: Response, body: Vec<u8>) {
+        if let Some(key) = self.key.take() {
+            let mut outcomes = self.idempotency.outcomes.lock().unwrap();
+            if let Some(outcome) = outcomes.by_key.get_mut(&key) {
+                outcome.response = Some((response, body));
+            }
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.idempotency.outcomes.lock().unwrap().by_key.remove(&key);
+        }
+    }
+}
+
+impl Middleware for Idempotency {
+    fn handle<'a>(&'a self, req: Request, next: Next<'a>) -> BoxFuture<'a, http_types::Result<Response>> {
+        Box::pin(async move {
+            let Some(key) = req.header(Self::HEADER).map(|key| key.as_str().to_owned()) else {
+                return next.run(req).await;
+            };
+            match self.see(&key, &format!("{} {}", req.method(), req.url())) {
+                Seen::First => {}
+                Seen::Replay(response) => return Ok(*response),
+                Seen::Rejected(status, reason) => {
+                    let mut response = Response::new(status);
+                    response.set_body(reason);
+                    return Ok(response);
+                }
+            }
+
+            let in_flight = InFlight {
+                idempotency: self,
+                key: Some(key),
+            };
+            let mut response = next.run(req).await?;
+            let body = response.take_body().into_bytes().await?;
+            in_flight.complete(response.clone(), body.clone());
+            response.set_body(body);
+            Ok(response)
+        })
+    }
+}
+
+/// Rejects bodies over `max` bytes, upfront when they declare their length, else while they
+/// are read. `/IMPORT` streams its body into storage and takes any size.
+#[derive(Clone, Copy)]
+pub(crate) struct BodyLimit {
+    pub(crate) max: usize,
+}
+
+impl BodyLimit {
+    pub(crate) const UNLIMITED: [&'static str; 1] = ["/IMPORT"];
+}
+
+impl Middleware for BodyLimit {
+    fn handle<'a>(&'a self, mut req: Request, next: Next<'a>) -> BoxFuture<'a, http_types::Result<Response>> {
+        Box::pin(async move {
+            if Self::UNLIMITED.iter().any(|route| route_is(req.url().path(), route)) {
+                return next.run(req).await;
+            }
+            if req.len().is_some_and(|len| len > self.max) {
+                let mut response = Response::new(StatusCode::PayloadTooLarge);
+                response.set_body("body_too_large");
+                return Ok(response);
+            }
+            // for bodies of unknown length, enforced by the request parser
+            req.ext_mut().insert(*self);
+            next.run(req).await
+        })
+    }
+}
+
+/// Lets browser pages of `origins`, `*` for any, call the api: their requests get the
+/// `Access-Control-*` headers, their preflight requests are answered without going further,
+/// so before authentication as they carry no key.
+pub(crate) struct Cors {
+    origins: Vec<String>,
+}
+
+impl Cors {
+    const ALLOWED_METHODS: &'static str = "GET, PUT, DELETE";
+    // readable by the page, on top of the safelisted ones
+    const EXPOSED_HEADERS: &'static str = "X-Record-Version, X-Seq, Idempotent-Replayed, Retry-After";
+    // seconds a browser keeps the answer to a preflight request
+    const MAX_AGE: &'static str = "600";
+
+    pub(crate) fn new(origins: Vec<String>) -> Self {
+        Self { origins }
+    }
+
+    fn allowed(&self, origin: &str) -> bool {
+        self.origins.iter().any(|allowed| allowed == "*" || allowed == origin)
+    }
+}
+
+impl Middleware for Cors {
+    fn handle<'a>(&'a self, req: Request, next: Next<'a>) -> BoxFuture<'a, http_types::Result<Response>> {
+        Box::pin(async move {
+            let Some(origin) = req.header("Origin").map(|origin| origin.as_str().to_owned()) else {
+                return next.run(req).await;
+            };
+            if !self.allowed(&origin) {
+                return next.run(req).await;
+            }
+
+            let preflight = req.method() == Method::Options && req.header("Access-Control-Request-Method").is_some();
+            let mut response = match preflight {
+                true => {
+                    let mut response = Response::new(StatusCode::NoContent);
+                    response.insert_header("Access-Control-Allow-Methods", Self::ALLOWED_METHODS);
+                    if let Some(headers) = req.header("Access-Control-Request-Headers") {
+                        response.insert_header("Access-Control-Allow-Headers", headers.as_str());
+                    }
+                    response.insert_header("Access-Control-Max-Age", Self::MAX_AGE);
+                    response
+                }
+                false => next.run(req).await?,
+            };
+            response.insert_header("Access-Control-Allow-Origin", origin);
+            response.insert_header("Access-Control-Expose-Headers", Self::EXPOSED_HEADERS);
+            response.append_header("Vary", "Origin");
+            Ok(response)
+        })
+    }
+}
+
+/// Requests a client may make over a window, with `--rate-limit <requests>/<window>`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RateLimitConfig {
+    limit: u64,
+    window: Duration,
+}
+
+impl FromStr for RateLimitConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate limit {}, expected <requests>/<window> like 100/1s", s);
+        let (limit, window) = s.split_once('/').ok_or_else(invalid)?;
+        let limit = limit.parse::<u64>().ok().filter(|limit| *limit > 0).ok_or_else(invalid)?;
+        let window = humantime::parse_duration(window)
+            .ok()
+            .filter(|window| !window.is_zero())
+            .ok_or_else(invalid)?;
+        Ok(Self { limit, window })
+    }
+}
+
+/// Answers `429 rate_limited` to the clients over their limit, a client being its api key
+/// when api keys are checked, else its address. Counted like `/RATELIMIT`, over a sliding
+/// window, in memory.
+pub(crate) struct RateLimit {
+    config: RateLimitConfig,
+    // unchecked keys would let a client pick a new one per request
+    keyed_on_api_key: bool,
+    // limiter of every client, with its last request
+    clients: Mutex<HashMap<String, (Vec<u8>, Instant)>>,
+}
+
+impl RateLimit {
+    // clients are looked at for idle ones once there are this many
+    const PRUNE_AT: usize = 10_000;
+
+    /// Counts the requests of every api key with `keyed_on_api_key`, for chains checking them
+    /// with `Auth` or `AclAuth` before.
+    pub(crate) fn new(config: RateLimitConfig, keyed_on_api_key: bool) -> Self {
+        Self {
+            config,
+            keyed_on_api_key,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check(&self, client: String) -> ratelimit::Verdict {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let mut clients = self.clients.lock().unwrap();
+        // idle for two windows, a limiter holds no count anymore
+        if clients.len() >= Self::PRUNE_AT {
+            let idle = self.config.window.saturating_mul(2);
+            clients.retain(|_, (_, last_request)| last_request.elapsed() < idle);
+        }
+        let (limiter, last_request) = clients
+            .entry(client)
+            .or_insert_with(|| (ratelimit::create(), Instant::now()));
+        *last_request = Instant::now();
+        ratelimit::check(limiter, self.config.limit, self.config.window, now)
+            .expect("limiters are created with their length")
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle<'a>(&'a self, req: Request, next: Next<'a>) -> BoxFuture<'a, http_types::Result<Response>> {
+        Box::pin(async move {
+            let client = match req.header("X-API-Key").filter(|_| self.keyed_on_api_key) {
+                Some(api_key) => format!("key {}", api_key),
+                // the port changes with every connection
+                None => req
+                    .peer_addr()
+                    .map(|address| address.rsplit_once(':').map_or(address, |(host, _)| host).to_owned())
+                    .unwrap_or_default(),
+            };
+            let verdict = self.check(client);
+            if !verdict.allowed {
+                let mut response = Response::new(StatusCode::TooManyRequests);
+                response.insert_header("Retry-After", verdict.reset.as_secs().max(1).to_string());
+                response.set_body("rate_limited");
+                return Ok(response);
+            }
+            next.run(req).await
+        })
+    }
+}
+
+/// Counts the responses by status class with their durations, for `/STATS`.
+#[cfg(feature = "metrics")]
+pub(crate) struct Metrics {
+    stats: Arc<Stats>,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    pub(crate) fn new(stats: Arc<Stats>) -> Self {
+        Self { stats }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Middleware for Metrics {
+    fn handle<'a>(&'a self, req: Request, next: Next<'a>) -> BoxFuture<'a, http_types::Result<Response>> {
+        Box::pin(async move {
+            let start = Instant::now();
+            let res = next.run(req).await;
+            // a failed request gets a 500 from async_h1
+            let status = res.as_ref().map_or(StatusCode::InternalServerError, |response| response.status());
+            self.stats.http_response(status, start.elapsed());
+            res
+        })
+    }
+}
+
+// routes of the admin endpoints
+const ADMIN_PATHS: [&str; 10] = [
+    "/ADMIN/",
+    "/PATTERN/",
+    "/KEYS/",
+    "/SEARCH",
+    "/DEBUG/",
+    "/SLOWLOG",
+    "/FLUSHALL",
+    "/SPLITSHARD/",
+    "/RESHARD/",
+    "/REPLICATE",
+];
+
+/// Whether the route at `path` is an admin endpoint.
+pub(crate) fn is_admin_path(path: &str) -> bool {
+    ADMIN_PATHS.iter().any(|admin| route_starts_with(path, admin))
+}
+
+/// Rejects requests not carrying the api key.
+#[cfg(feature = "auth")]
+pub(crate) struct Auth {
+    api_key: String,
+}
+
+#[cfg(feature = "auth")]
+impl Auth {
+    const HEADER: &'static str = "X-API-Key";
+
+    pub(crate) fn new(api_key: String) -> Self {
+        Self { api_key }
+    }
+}
+
+/// Rejects requests to admin endpoints not carrying the admin key, on top of [`Auth`].
+#[cfg(feature = "auth")]
+pub(crate) struct AdminAuth {
+    admin_key: String,
+}
+
+#[cfg(feature = "auth")]
+impl AdminAuth {
+    const HEADER: &'static str = "X-Admin-Key";
+
+    pub(crate) fn new(admin_key: String) -> Self {
+        Self { admin_key }
+    }
+}
+
+#[cfg(feature = "auth")]
+impl Middleware for AdminAuth {
+    fn handle<'a>(&'a self, req: Request, next: Next<'a>) -> BoxFuture<'a, http_types::Result<Response>> {
+        Box::pin(async move {
+            let admin = is_admin_path(req.url().path());
+            match req.header(Self::HEADER) {
+                _ if !admin => next.run(req).await,
+                Some(admin_key) if admin_key == self.admin_key.as_str() => next.run(req).await,
+                _ => Ok(Response::new(http_types::StatusCode::Forbidden)),
+            }
+        })
+    }
+}
+
+#[cfg(feature = "auth")]
+impl Middleware for Auth {
+    fn handle<'a>(&'a self, req: Request, next: Next<'a>) -> BoxFuture<'a, http_types::Result<Response>> {
+        Box::pin(async move {
+            match req.header(Self::HEADER) {
+                Some(api_key) if api_key == self.api_key.as_str() => next.run(req).await,
+                _ => Ok(Response::new(http_types::StatusCode::Forbidden)),
+            }
+        })
+    }
+}
+
+/// Rejects requests not carrying one of the api keys of `--acl`, the grant of the key going
+/// along with the request, checked against the command once parsed.
+#[cfg(feature = "auth")]
+pub(crate) struct AclAuth {
+    acl: Arc<Acl>,
+}
+
+#[cfg(feature = "auth")]
+impl AclAuth {
+    pub(crate) fn new(acl: Arc<Acl>) -> Self {
+        Self { acl }
+    }
+}
+
+#[cfg(feature = "auth")]
+impl Middleware for AclAuth {
+    fn handle<'a>(&'a self, mut req: Request, next: Next<'a>) -> BoxFuture<'a, http_types::Result<Response>> {
+        Box::pin(async move {
+            let grant = req
+                .header(Auth::HEADER)
+                .and_then(|api_key| self.acl.grant(api_key.as_str()));
+            match grant {
+                Some(grant) => {
+                    req.ext_mut().insert(grant);
+                    next.run(req).await
+                }
+                None => Ok(Response::new(http_types::StatusCode::Forbidden)),
+            }
+        })
+    }
+}
@@ -0,0 +1,306 @@
+//! Asynchronous copy of every write to a secondary endpoint, another mapper or Redis,
+//! following the journal.
+
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use http_types::Url;
+use log::{info, warn};
+use percent_encoding::percent_decode_str;
+use smol::{Task, Timer};
+
+use crate::{
+    import::encode_binary_entry,
+    journal::{Change, ChangeKind},
+    record::RecordKind,
+    remote::{MapperTarget, Redis, Reply},
+    stats::MirrorReport,
+    storage::Storage,
+};
+
+// changes copied at once, their keys deduplicated
+const MIRROR_BATCH: usize = 512;
+// how often an idle mirror looks for new changes
+const MIRROR_POLL: Duration = Duration::from_millis(50);
+const RETRY_MIN: Duration = Duration::from_millis(100);
+const RETRY_MAX: Duration = Duration::from_secs(10);
+// time given on shutdown to copy the last writes
+const MIRROR_DRAIN: Duration = Duration::from_secs(5);
+
+/// Endpoint writes are copied to: `http://[:api-key@]host:port` of a mapper instance or a
+/// `redis://[user:password@]host[:port][/db]` url.
+#[derive(Debug, Clone)]
+pub(crate) enum Secondary {
+    Mapper(MapperTarget),
+    Redis(Url),
+}
+
+impl FromStr for Secondary {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|e| format!("invalid mirror url {}: {}", s, e))?;
+        match url.scheme() {
+            "http" => {
+                let host = url.host_str().ok_or("mirror url without host")?;
+                let address = format!("{}:{}", host, url.port().unwrap_or(80));
+                let api_key = url
+                    .password()
+                    .map(|api_key| percent_decode_str(api_key).decode_utf8_lossy().into_owned());
+                Ok(Secondary::Mapper(MapperTarget::new(&address, api_key.as_deref())))
+            }
+            "redis" => Ok(Secondary::Redis(url)),
+            scheme => Err(format!("unsupported mirror scheme {}, expected http:// or redis://", scheme)),
+        }
+    }
+}
+
+// credentials left out, it ends up in logs and /STATS
+impl fmt::Display for Secondary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Secondary::Mapper(target) => write!(f, "http://{}", target.address()),
+            Secondary::Redis(url) => write!(
+                f,
+                "redis://{}:{}{}",
+                url.host_str().unwrap_or_default(),
+                url.port().unwrap_or(6379),
+                url.path()
+            ),
+        }
+    }
+}
+
+/// A write as the secondary receives it, the value read back when it is copied.
+enum Write {
+    // 0 means no ttl
+    Set { key: String, value: Vec<u8>, ttl_ms: u64 },
+    Del(String),
+    FlushAll,
+}
+
+impl Write {
+    fn redis_command(&self) -> Vec<Vec<u8>> {
+        match self {
+            Write::Set { key, value, ttl_ms: 0 } => vec![b"SET".to_vec(), key.clone().into_bytes(), value.clone()],
+            Write::Set { key, value, ttl_ms } => vec![
+                b"SET".to_vec(),
+                key.clone().into_bytes(),
+                value.clone(),
+                b"PX".to_vec(),
+                ttl_ms.to_string().into_bytes(),
+            ],
+            Write::Del(key) => vec![b"DEL".to_vec(), key.clone().into_bytes()],
+            Write::FlushAll => vec![b"FLUSHDB".to_vec()],
+        }
+    }
+}
+
+enum Connection {
+    Mapper(MapperTarget),
+    // connected on first use, and again after an error
+    Redis { url: Url, redis: Option<Redis> },
+}
+
+impl Connection {
+    async fn apply(&mut self, writes: &[Write]) -> Result<(), String> {
+        match self {
+            Connection::Mapper(target) => {
+                let mut import = Vec::new();
+                for write in writes {
+                    match write {
+                        Write::Set { key, value, ttl_ms } => encode_binary_entry(&mut import, key, value, *ttl_ms),
+                        Write::Del(key) => target.delete(key).await?,
+                        Write::FlushAll => target.flush_all().await?,
+                    }
+                }
+                if !import.is_empty() {
+                    target.import(import).await?;
+                }
+                Ok(())
+            }
+            Connection::Redis { url, redis } => {
+                let connection = match redis {
+                    Some(connection) => connection,
+                    None => redis.insert(Redis::connect(url).await.map_err(|e| e.to_string())?),
+                };
+
+                let commands: Vec<Vec<Vec<u8>>> = writes.iter().map(Write::redis_command).collect();
+                let commands: Vec<Vec<&[u8]>> = commands
+                    .iter()
+                    .map(|command| command.iter().map(Vec::as_slice).collect())
+                    .collect();
+                let commands: Vec<&[&[u8]]> = commands.iter().map(Vec::as_slice).collect();
+
+                let replies = connection.call(&commands).await.inspect_err(|_| *redis = None)?;
+                match replies.into_iter().find_map(|reply| match reply {
+                    Reply::Error(e) => Some(e),
+                    _ => None,
+                }) {
+                    Some(e) => Err(e),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// The copy of writes to a secondary, running until [`Mirror::stop`].
+pub(crate) struct Mirror {
+    storage: Storage,
+    // last journal change copied
+    mirrored: Arc<AtomicU64>,
+    task: Task<()>,
+}
+
+impl Mirror {
+    /// Copies the writes made from now on to `secondary`, in batches read back from the
+    /// storage. A secondary that cannot be reached is retried with a growing delay while
+    /// the journal holds the changes not copied yet: the ones it drops are counted, and
+    /// their keys copied again only once written again.
+    pub(crate) fn start(secondary: Secondary, storage: Storage) -> Self {
+        let mirrored = Arc::new(AtomicU64::new(storage.journal.last_seq()));
+        let task = smol::spawn(follow(secondary, storage.clone(), mirrored.clone()));
+        Self { storage, mirrored, task }
+    }
+
+    /// Gives the mirror a few seconds to copy the last writes, then stops it.
+    pub(crate) async fn stop(self) {
+        let deadline = Instant::now() + MIRROR_DRAIN;
+        while self.mirrored.load(Ordering::Acquire) < self.storage.journal.last_seq() && Instant::now() < deadline {
+            Timer::after(MIRROR_POLL).await;
+        }
+        self.task.cancel().await;
+
+        let lag = self.storage.journal.last_seq().saturating_sub(self.mirrored.load(Ordering::Acquire));
+        if lag > 0 {
+            warn!("mirror stopped with {} changes not copied", lag);
+        }
+    }
+}
+
+async fn follow(secondary: Secondary, storage: Storage, mirrored: Arc<AtomicU64>) {
+    let mut report = MirrorReport {
+        target: secondary.to_string(),
+        ..Default::default()
+    };
+    info!("mirroring writes to {}", report.target);
+    let mut connection = match secondary {
+        Secondary::Mapper(target) => Connection::Mapper(target),
+        Secondary::Redis(url) => Connection::Redis { url, redis: None },
+    };
+
+    let mut position = mirrored.load(Ordering::Acquire);
+    // journal positions and when they were first seen, the oldest not copied tells the lag
+    let mut seen: VecDeque<(u64, Instant)> = VecDeque::new();
+    let mut retry = RETRY_MIN;
+    loop {
+        let changes = match storage.journal.since(position) {
+            Ok(changes) => changes,
+            Err(_) => {
+                let oldest = storage
+                    .journal
+                    .retained()
+                    .first()
+                    .map_or_else(|| storage.journal.last_seq(), |change| change.seq - 1);
+                warn!("mirror fell behind the journal, {} changes dropped", oldest - position);
+                report.dropped += oldest - position;
+                position = oldest;
+                mirrored.store(position, Ordering::Release);
+                continue;
+            }
+        };
+
+        if let Some(last) = changes.last() {
+            if seen.back().is_none_or(|(seq, _)| *seq < last.seq) {
+                seen.push_back((last.seq, Instant::now()));
+            }
+        }
+
+        let batch = next_batch(&changes);
+        let mut failed = false;
+        if let Some(last) = batch.last() {
+            let writes = read_writes(&storage, batch, &mut report.skipped).await;
+            match connection.apply(&writes).await {
+                Ok(()) => {
+                    report.mirrored += batch.len() as u64;
+                    position = last.seq;
+                    mirrored.store(position, Ordering::Release);
+                    retry = RETRY_MIN;
+                }
+                Err(e) => {
+                    warn!("mirroring to {} failed, retrying in {:?}: {}", report.target, retry, e);
+                    report.retries += 1;
+                    failed = true;
+                    report.last_error = Some(e);
+                }
+            }
+        }
+
+        while seen.front().is_some_and(|(seq, _)| *seq <= position) {
+            seen.pop_front();
+        }
+        report.lag_changes = storage.journal.last_seq().saturating_sub(position);
+        report.lag_ms = seen.front().map_or(0, |(_, at)| at.elapsed().as_millis() as u64);
+        storage.stats.mirror_progress(report.clone());
+
+        if failed {
+            Timer::after(retry).await;
+            retry = (retry * 2).min(RETRY_MAX);
+        } else if batch.is_empty() {
+            Timer::after(MIRROR_POLL).await;
+        }
+    }
+}
+
+// a flush is copied on its own, the keys changed around it are read before or after it
+fn next_batch(changes: &[Change]) -> &[Change] {
+    let changes = &changes[..changes.len().min(MIRROR_BATCH)];
+    match changes.iter().position(|change| matches!(change.kind, ChangeKind::FlushAll)) {
+        Some(0) => &changes[..1],
+        Some(flush) => &changes[..flush],
+        None => changes,
+    }
+}
+
+// the keys as they are now, a key changed several times is copied once
+async fn read_writes(storage: &Storage, batch: &[Change], skipped: &mut u64) -> Vec<Write> {
+    if let [Change { kind: ChangeKind::FlushAll, .. }] = batch {
+        return vec![Write::FlushAll];
+    }
+
+    let keys: BTreeSet<&str> = batch.iter().filter_map(|change| change.key.as_deref()).collect();
+    let mut writes = Vec::with_capacity(keys.len());
+    for key in keys {
+        let Ok((_, shard)) = storage.read_key_shard(key).await else {
+            continue;
+        };
+        let write = match shard.records.get(key).map(|wrecord| &wrecord.record) {
+            // typed records, like bloom filters, have no counterpart on the secondary
+            Some(record) if record.kind != RecordKind::Bytes => {
+                *skipped += 1;
+                continue;
+            }
+            Some(record) => match record.ttl_policy.as_ref().map(|ttl_policy| ttl_policy.expire_in()) {
+                Some(ttl) if ttl.is_zero() => Write::Del(key.to_string()),
+                ttl => Write::Set {
+                    key: key.to_string(),
+                    value: record.data.clone(),
+                    // an expiring record keeps at least a millisecond
+                    ttl_ms: ttl.map_or(0, |ttl| (ttl.as_millis() as u64).max(1)),
+                },
+            },
+            None => Write::Del(key.to_string()),
+        };
+        writes.push(write);
+    }
+    writes
+}
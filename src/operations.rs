@@ -0,0 +1,128 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use crate::errors::TransactionError;
+
+/// Long running operations in flight, listed and cancelled through `/ADMIN/OPS`.
+#[derive(Debug, Default)]
+pub(crate) struct Operations {
+    next_id: AtomicU64,
+    running: Mutex<BTreeMap<u64, Arc<OperationState>>>,
+}
+
+#[derive(Debug)]
+struct OperationState {
+    id: u64,
+    kind: &'static str,
+    description: String,
+    started_at: Instant,
+    done: AtomicU64,
+    total: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl fmt::Display for OperationState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}/{} {}ms {}",
+            self.id,
+            self.kind,
+            self.done.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+            self.started_at.elapsed().as_millis(),
+            self.description
+        )?;
+        if self.cancelled.load(Ordering::Relaxed) {
+            write!(f, " (cancelling)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Handle of a registered operation, unregistered when dropped.
+///
+/// Cancellation is cooperative: the operation polls [`Operation::is_cancelled`]
+/// between units of work and stops on its own.
+pub(crate) struct Operation {
+    state: Arc<OperationState>,
+    registry: Arc<Operations>,
+}
+
+impl Operation {
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn progress(&self, done: u64, total: u64) {
+        self.state.done.store(done, Ordering::Relaxed);
+        self.state.total.store(total, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        self.registry.running.lock().unwrap().remove(&self.state.id);
+    }
+}
+
+impl Operations {
+    pub(crate) fn start(self: &Arc<Self>, kind: &'static str, description: String) -> Operation {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let state = Arc::new(OperationState {
+            id,
+            kind,
+            description,
+            started_at: Instant::now(),
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+        });
+        self.running.lock().unwrap().insert(id, state.clone());
+
+        Operation {
+            state,
+            registry: self.clone(),
+        }
+    }
+
+    /// Asks a running operation to stop.
+    pub(crate) fn cancel(&self, id: u64) -> Result<(), TransactionError> {
+        match self.running.lock().unwrap().get(&id) {
+            Some(state) => {
+                state.cancelled.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+            None => Err(TransactionError::OperationNotFound),
+        }
+    }
+
+    /// One `<id> <kind> <done>/<total> <elapsed> <description>` line per running operation.
+    pub(crate) fn list(&self) -> String {
+        self.running
+            .lock()
+            .unwrap()
+            .values()
+            .map(|state| format!("{}\n", state))
+            .collect()
+    }
+
+    /// The lines of [`Operations::list`] of the running operations of one of `kinds`.
+    #[cfg(feature = "backup")]
+    pub(crate) fn list_kinds(&self, kinds: &[&str]) -> Vec<String> {
+        self.running
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|state| kinds.contains(&state.kind))
+            .map(|state| state.to_string())
+            .collect()
+    }
+}
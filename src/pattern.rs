@@ -0,0 +1,95 @@
+use std::fmt;
+
+/// Glob style key pattern, matching like the redis one: `*` any run of characters,
+/// `?` a single character, `[abc]`, `[^a-z]` a class and `\` escapes the next character.
+#[derive(Debug, Clone)]
+pub(crate) struct Pattern {
+    source: String,
+    glob: Vec<char>,
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Pattern {
+    pub(crate) fn new(glob: &str) -> Self {
+        Self {
+            source: glob.to_string(),
+            glob: glob.chars().collect(),
+        }
+    }
+
+    pub(crate) fn matches(&self, key: &str) -> bool {
+        let key: Vec<char> = key.chars().collect();
+        let (mut p, mut k) = (0, 0);
+        // position after the last `*` and the key position it is matching up to
+        let mut backtrack: Option<(usize, usize)> = None;
+
+        while k < key.len() {
+            let step = match self.glob.get(p) {
+                Some('*') => {
+                    backtrack = Some((p + 1, k));
+                    p += 1;
+                    continue;
+                }
+                Some('?') => Some(p + 1),
+                Some('[') => self.match_class(p, key[k]),
+                Some('\\') if p + 1 < self.glob.len() => (self.glob[p + 1] == key[k]).then_some(p + 2),
+                Some(c) => (*c == key[k]).then_some(p + 1),
+                None => None,
+            };
+
+            match (step, backtrack) {
+                (Some(next), _) => {
+                    p = next;
+                    k += 1;
+                }
+                // let the last `*` swallow one more character
+                (None, Some((star_next, star_k))) => {
+                    backtrack = Some((star_next, star_k + 1));
+                    p = star_next;
+                    k = star_k + 1;
+                }
+                (None, None) => return false,
+            }
+        }
+
+        self.glob[p..].iter().all(|c| *c == '*')
+    }
+
+    /// Matches `c` against the class opening at `start`, returning the position after it.
+    fn match_class(&self, start: usize, c: char) -> Option<usize> {
+        let mut p = start + 1;
+        let negated = matches!(self.glob.get(p), Some('^') | Some('!'));
+        if negated {
+            p += 1;
+        }
+
+        let mut matched = false;
+        loop {
+            match self.glob.get(p) {
+                // unterminated, the bracket is taken literally
+                None => return (c == '[').then_some(start + 1),
+                Some(']') => break,
+                Some('\\') if p + 1 < self.glob.len() => {
+                    matched |= self.glob[p + 1] == c;
+                    p += 2;
+                }
+                Some(low) if self.glob.get(p + 1) == Some(&'-') && p + 2 < self.glob.len() && self.glob[p + 2] != ']' => {
+                    let high = self.glob[p + 2];
+                    matched |= (*low.min(&high)..=*low.max(&high)).contains(&c);
+                    p += 3;
+                }
+                Some(literal) => {
+                    matched |= *literal == c;
+                    p += 1;
+                }
+            }
+        }
+
+        (matched != negated).then_some(p + 1)
+    }
+}
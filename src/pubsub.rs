@@ -0,0 +1,122 @@
+//! Publish/subscribe over channels, apart from the keys: a message published to a channel
+//! goes to the subscribers connected at that time and is not stored.
+//!
+//! Subscribers hold an HTTP response streamed as server-sent events. One that falls too far
+//! behind is dropped, ending its response, rather than holding messages for it.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use http_types::{mime, Body};
+use log::warn;
+use smol::{
+    channel::{self, Sender, TrySendError},
+    io::BufReader,
+};
+
+use crate::export::ChunkReader;
+
+// messages waiting for a subscriber before it is dropped
+const SUBSCRIBER_BACKLOG: usize = 1024;
+
+/// Subscribers of every channel with at least one.
+#[derive(Debug, Default)]
+pub(crate) struct PubSub {
+    channels: Mutex<HashMap<String, Vec<Subscriber>>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    id: u64,
+    events: Sender<io::Result<Vec<u8>>>,
+}
+
+impl PubSub {
+    /// Subscribes to `channel`, returning the body of the response streaming its messages.
+    /// The subscription lasts until the client goes away.
+    pub(crate) fn subscribe(&self, channel: &str) -> Body {
+        let (sender, receiver) = channel::bounded(SUBSCRIBER_BACKLOG);
+        // sent right away, so the client knows it is subscribed before any message
+        let _ = sender.try_send(Ok(format!(": subscribed to {}\n\n", channel).into_bytes()));
+
+        let subscriber = Subscriber {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            events: sender,
+        };
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(channel.to_string())
+            .or_default()
+            .push(subscriber);
+
+        let mut body = Body::from_reader(BufReader::new(ChunkReader::new(receiver)), None);
+        body.set_mime(mime::SSE);
+        body
+    }
+
+    /// Sends `message` to the subscribers of `channel`, returning how many got it.
+    /// Subscribers gone or too far behind are dropped.
+    pub(crate) fn publish(&self, channel: &str, message: &[u8]) -> usize {
+        let event = event(message);
+        let mut channels = self.channels.lock().unwrap();
+        let Some(subscribers) = channels.get_mut(channel) else {
+            return 0;
+        };
+
+        let mut reached = 0;
+        subscribers.retain(|subscriber| match subscriber.events.try_send(Ok(event.clone())) {
+            Ok(()) => {
+                reached += 1;
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                warn!("subscriber {} of {} fell {} messages behind, dropping it", subscriber.id, channel, SUBSCRIBER_BACKLOG);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        });
+        if subscribers.is_empty() {
+            channels.remove(channel);
+        }
+        reached
+    }
+
+    /// `<channel> <subscribers>` lines, by channel name.
+    pub(crate) fn channels(&self) -> String {
+        let mut channels = self.channels.lock().unwrap();
+        channels.retain(|_, subscribers| {
+            subscribers.retain(|subscriber| !subscriber.events.is_closed());
+            !subscribers.is_empty()
+        });
+
+        let mut listed: Vec<(&String, usize)> = channels
+            .iter()
+            .map(|(channel, subscribers)| (channel, subscribers.len()))
+            .collect();
+        listed.sort_unstable();
+        listed
+            .iter()
+            .map(|(channel, subscribers)| format!("{} {}\n", channel, subscribers))
+            .collect()
+    }
+}
+
+// a server-sent event, one `data` field per line of the message
+fn event(message: &[u8]) -> Vec<u8> {
+    let mut event = Vec::with_capacity(message.len() + 8);
+    for line in message.split(|byte| *byte == b'\n') {
+        event.extend_from_slice(b"data: ");
+        event.extend_from_slice(line.strip_suffix(b"\r").unwrap_or(line));
+        event.push(b'\n');
+    }
+    event.push(b'\n');
+    event
+}
@@ -1,10 +1,46 @@
+#[cfg(any(feature = "metrics", feature = "jemalloc", feature = "mimalloc"))]
+use http_types::mime;
+use std::{
+    fmt::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use http_types::{Body, Mime};
 use log::error;
+use smol::{future::FutureExt, Timer};
 
-use crate::{errors::{self}, http_query_parser::Query, record::Record, storage::Storage};
+#[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+use crate::allocator;
+#[cfg(feature = "backup")]
+use crate::{backup_download, backup_restore};
+#[cfg(feature = "replication")]
+use crate::replication;
+#[cfg(feature = "cluster")]
+use crate::quorum::{self, LeaseOp};
+use crate::{
+    bloom, cms,
+    errors::{self},
+    export,
+    http_query_parser::Query,
+    hash::Hash,
+    import,
+    list::{self, End, List},
+    record::{Record, RecordKind},
+    queue, ratelimit, resharding,
+    schedule::{self, ScheduledWrite},
+    search,
+    set::{Set, SetOp},
+    stats::{self, InfoSection},
+    sorted_set::{self, SortedSet},
+    storage::Storage,
+    timeseries,
+    topk::{self, TopK},
+    vector,
+};
 
-fn handle_ok_result<T, F>(result: Result<T, errors::TransactionError>, handler: F) -> Result<String, errors::Errors>
+fn handle_ok_result<T, R, F>(result: Result<T, errors::TransactionError>, handler: F) -> Result<R, errors::Errors>
 where
-    F: FnOnce(T) -> Result<String, errors::Errors>,
+    F: FnOnce(T) -> Result<R, errors::Errors>,
 {
     match result {
         Ok(value) => handler(value),
@@ -16,6 +52,9 @@ where
 }
 
 fn record_to_string(record: Record) -> Result<String, errors::Errors> {
+    if let Err(err) = record.value(RecordKind::Bytes) {
+        return Err(errors::Errors::TransactionError(err));
+    }
     match String::from_utf8(record.data) {
         Ok(rec_string) => Ok(rec_string),
         Err(err) => {
@@ -25,27 +64,835 @@ fn record_to_string(record: Record) -> Result<String, errors::Errors> {
     }
 }
 
-pub(crate) async fn handle_query(query: Query, storage: Storage) -> Result<String, errors::Errors> {
+/// Successful outcome of a query, with the logical clock of the record it read or wrote.
+pub(crate) struct QueryOutput {
+    pub(crate) body: Body,
+    pub(crate) version: Option<u64>,
+    pub(crate) content_type: Option<Mime>,
+}
+
+impl QueryOutput {
+    fn versioned(body: String, version: u64) -> Self {
+        Self {
+            body: body.into(),
+            version: Some(version),
+            content_type: None,
+        }
+    }
+
+    // a version only for records that exist
+    fn optionally_versioned(body: String, version: Option<u64>) -> Self {
+        Self {
+            body: body.into(),
+            version,
+            content_type: None,
+        }
+    }
+
+    #[cfg(any(feature = "metrics", feature = "jemalloc", feature = "mimalloc"))]
+    fn json(body: String) -> Self {
+        Self {
+            body: body.into(),
+            version: None,
+            content_type: Some(mime::JSON),
+        }
+    }
+}
+
+impl From<String> for QueryOutput {
+    fn from(body: String) -> Self {
+        Self {
+            body: body.into(),
+            version: None,
+            content_type: None,
+        }
+    }
+}
+
+/// Runs the query, giving up with `DeadlineExceeded` once `timeout` has elapsed.
+///
+/// The query is dropped at its next await point: a FLUSHALL interrupted this way has
+/// only flushed part of the shards.
+pub(crate) async fn handle_query_within(
+    query: Query,
+    storage: Storage,
+    timeout: Option<Duration>,
+) -> Result<QueryOutput, errors::Errors> {
+    match timeout {
+        Some(timeout) => {
+            let name = query.name();
+            handle_query(query, storage)
+                .or(async {
+                    Timer::after(timeout).await;
+                    error!("{} exceeded its deadline of {:?}", name, timeout);
+                    Err(errors::Errors::TransactionError(errors::TransactionError::DeadlineExceeded))
+                })
+                .await
+        }
+        None => handle_query(query, storage).await,
+    }
+}
+
+pub(crate) async fn handle_query(query: Query, storage: Storage) -> Result<QueryOutput, errors::Errors> {
+    // deletes still go through, they make room
+    if query.grows_dataset() && storage.memory.is_read_only() {
+        return Err(errors::Errors::TransactionError(errors::TransactionError::OutOfMemory));
+    }
+    // a replica only applies the writes of its primary
+    #[cfg(feature = "replication")]
+    if query.changes_dataset() && storage.is_replica() {
+        return Err(errors::Errors::TransactionError(errors::TransactionError::ReadOnlyReplica));
+    }
+    // keys of slots owned by another node are served there, quorum leases on every node
+    #[cfg(feature = "cluster")]
+    if let Some(cluster) = storage
+        .cluster
+        .as_ref()
+        .filter(|_| !matches!(query, Query::QuorumLease { .. } | Query::MemberLease { .. }))
+    {
+        let slots = query.keys().into_iter().map(|key| storage.key_slot(key));
+        cluster.check(slots).map_err(errors::Errors::TransactionError)?;
+    }
+    // with --aof-fsync always, a write is answered once on disk
+    #[cfg(feature = "backup")]
+    if let Some(aof) = storage.aof.clone().filter(|aof| aof.syncs_every_write() && query.changes_dataset()) {
+        let output = handle_allowed_query(query, storage.clone()).await?;
+        aof.synced(storage.journal.last_seq()).await.map_err(errors::Errors::TransactionError)?;
+        return Ok(output);
+    }
+    handle_allowed_query(query, storage).await
+}
+
+async fn handle_allowed_query(query: Query, storage: Storage) -> Result<QueryOutput, errors::Errors> {
     match query {
-        Query::Get { key } => handle_ok_result(storage.get_record(&key).await, record_to_string),
-        Query::Set { key, data } => handle_ok_result(
-            storage.set_record(&key, Record::new(data, None)).await,
-            |_| Ok(String::new()),
+        Query::Get { key } => handle_ok_result(
+            storage.get_versioned_record(&key).await,
+            |(record, version)| {
+                record_to_string(record).map(|body| QueryOutput::versioned(body, version))
+            },
         ),
-        Query::SetEx { key, data, ttl } => handle_ok_result(
-            storage.set_record(&key, Record::new(data, Some(ttl))).await,
-            |_| Ok(String::new()),
+        Query::Watch { key, version, timeout } => handle_ok_result(
+            storage.watch_record(&key, version, timeout).await,
+            |(record, version)| {
+                record_to_string(record).map(|body| QueryOutput::versioned(body, version))
+            },
+        ),
+        Query::Set { key, data, tags, condition } => handle_ok_result(
+            storage.set_record(&key, Record::new(data, None).with_tags(tags), condition).await,
+            |version| Ok(QueryOutput::versioned(String::new(), version)),
+        ),
+        Query::SetEx { key, data, ttl, sliding, tags, condition } => {
+            let mut record = Record::new(data, Some(ttl)).with_tags(tags);
+            if sliding {
+                record = record.with_sliding_ttl();
+            }
+            handle_ok_result(
+                storage.set_record(&key, record, condition).await,
+                |version| Ok(QueryOutput::versioned(String::new(), version)),
+            )
+        }
+        Query::Expire { key, ttl } => handle_ok_result(
+            storage.update_ttl(&key, Some(ttl)).await,
+            |version| Ok(QueryOutput::versioned(String::new(), version)),
+        ),
+        Query::Persist { key } => handle_ok_result(
+            storage.update_ttl(&key, None).await,
+            |version| Ok(QueryOutput::versioned(String::new(), version)),
+        ),
+        Query::IncrByFloat { key, increment } => handle_ok_result(
+            storage.incr_by_float(&key, increment).await,
+            |(value, version)| Ok(QueryOutput::versioned(value.to_string(), version)),
+        ),
+        Query::IncrBy { key, delta } => handle_ok_result(
+            storage.incr_by(&key, delta).await,
+            |(value, version)| Ok(QueryOutput::versioned(value.to_string(), version)),
+        ),
+        Query::Append { key, data } => handle_ok_result(
+            storage.append(&key, &data).await,
+            |(len, version)| Ok(QueryOutput::versioned(len.to_string(), version)),
+        ),
+        Query::GetSet { key, data } => handle_ok_result(
+            storage.replace_record(&key, Record::new(data, None)).await,
+            |(previous, version)| {
+                let body = previous.map_or(Ok(String::new()), record_to_string)?;
+                Ok(QueryOutput::versioned(body, version))
+            },
+        ),
+        Query::Bitfield { key, ops } => handle_ok_result(
+            storage.bitfield(&key, &ops).await,
+            |(results, version)| {
+                let body: String = results
+                    .iter()
+                    .map(|result| match result {
+                        Some(value) => format!("{}\n", value),
+                        None => "nil\n".to_string(),
+                    })
+                    .collect();
+                Ok(QueryOutput::optionally_versioned(body, version))
+            },
+        ),
+        Query::Object { key } => handle_ok_result(
+            storage.get_versioned_record(&key).await,
+            |(record, version)| Ok(QueryOutput::versioned(object(&record, version), version)),
+        ),
+        Query::BloomReserve { key, params } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::Bloom, |value| match value {
+                    Some(_) => Err(errors::TransactionError::KeyExists),
+                    None => {
+                        *value = Some(params.create());
+                        Ok(((), true))
+                    }
+                })
+                .await,
+            |(_, version)| Ok(QueryOutput::optionally_versioned(String::new(), version)),
+        ),
+        Query::BloomAdd { key, item } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::Bloom, |value| {
+                    let filter = value.get_or_insert_with(|| bloom::DEFAULT_PARAMS.create());
+                    let added = bloom::add(filter, item.as_bytes()).ok_or(errors::TransactionError::WrongType)?;
+                    Ok((added, added))
+                })
+                .await,
+            |(added, version)| Ok(QueryOutput::optionally_versioned((added as u8).to_string(), version)),
+        ),
+        Query::BloomExists { key, item } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::Bloom, |filter| match filter {
+                    Some(filter) => bloom::exists(filter, item.as_bytes()).ok_or(errors::TransactionError::WrongType),
+                    None => Ok(false),
+                })
+                .await,
+            |(exists, version)| Ok(QueryOutput::optionally_versioned((exists as u8).to_string(), version)),
+        ),
+        Query::BloomInfo { key } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::Bloom, |filter| {
+                    let filter = filter.ok_or(errors::TransactionError::RecordNotFound)?;
+                    bloom::info(filter).ok_or(errors::TransactionError::WrongType)
+                })
+                .await,
+            |(info, version)| Ok(QueryOutput::optionally_versioned(info, version)),
+        ),
+        Query::CmsInit { key, params } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::CountMin, |value| match value {
+                    Some(_) => Err(errors::TransactionError::KeyExists),
+                    None => {
+                        *value = Some(params.create());
+                        Ok(((), true))
+                    }
+                })
+                .await,
+            |(_, version)| Ok(QueryOutput::optionally_versioned(String::new(), version)),
+        ),
+        Query::CmsIncrBy { key, increments } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::CountMin, |value| {
+                    let sketch = value.as_mut().ok_or(errors::TransactionError::RecordNotFound)?;
+                    // a sketch that is not one fails on the first item, before any change
+                    let estimates: Option<String> = increments
+                        .iter()
+                        .map(|(item, increment)| {
+                            cms::incr_by(sketch, item.as_bytes(), *increment).map(|estimate| format!("{}\n", estimate))
+                        })
+                        .collect();
+                    Ok((estimates.ok_or(errors::TransactionError::WrongType)?, true))
+                })
+                .await,
+            |(estimates, version)| Ok(QueryOutput::optionally_versioned(estimates, version)),
+        ),
+        Query::CmsCount { key, item } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::CountMin, |sketch| {
+                    let sketch = sketch.ok_or(errors::TransactionError::RecordNotFound)?;
+                    cms::query(sketch, item.as_bytes()).ok_or(errors::TransactionError::WrongType)
+                })
+                .await,
+            |(estimate, version)| Ok(QueryOutput::optionally_versioned(estimate.to_string(), version)),
+        ),
+        Query::CmsMerge { key, sources } => handle_ok_result(
+            merge_sketches(&storage, &key, sources).await,
+            |version| Ok(QueryOutput::optionally_versioned(String::new(), version)),
         ),
+        Query::TopKReserve { key, topk } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::TopK, |value| match value {
+                    Some(_) => Err(errors::TransactionError::KeyExists),
+                    None => {
+                        *value = Some(topk.encode());
+                        Ok(((), true))
+                    }
+                })
+                .await,
+            |(_, version)| Ok(QueryOutput::optionally_versioned(String::new(), version)),
+        ),
+        Query::TopKAdd { key, items } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::TopK, |value| {
+                    let encoded = value.as_mut().ok_or(errors::TransactionError::RecordNotFound)?;
+                    let mut topk = TopK::decode(encoded).ok_or(errors::TransactionError::WrongType)?;
+                    let expelled: String = items
+                        .iter()
+                        .map(|(item, increment)| match topk.add(item, *increment) {
+                            Some(expelled) => format!("{}\n", expelled),
+                            None => "nil\n".to_string(),
+                        })
+                        .collect();
+                    *encoded = topk.encode();
+                    Ok((expelled, true))
+                })
+                .await,
+            |(expelled, version)| Ok(QueryOutput::optionally_versioned(expelled, version)),
+        ),
+        Query::TopKList { key } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::TopK, |encoded| {
+                    let encoded = encoded.ok_or(errors::TransactionError::RecordNotFound)?;
+                    let mut topk = TopK::decode(encoded).ok_or(errors::TransactionError::WrongType)?;
+                    Ok(topk::render(topk.list()))
+                })
+                .await,
+            |(list, version)| Ok(QueryOutput::optionally_versioned(list, version)),
+        ),
+        Query::TopKInfo { key } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::TopK, |encoded| {
+                    let encoded = encoded.ok_or(errors::TransactionError::RecordNotFound)?;
+                    TopK::decode(encoded)
+                        .map(|topk| topk.info())
+                        .ok_or(errors::TransactionError::WrongType)
+                })
+                .await,
+            |(info, version)| Ok(QueryOutput::optionally_versioned(info, version)),
+        ),
+        Query::TsCreate { key, retention } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::TimeSeries, |value| match value {
+                    Some(_) => Err(errors::TransactionError::KeyExists),
+                    None => {
+                        *value = Some(timeseries::create(retention));
+                        Ok(((), true))
+                    }
+                })
+                .await,
+            |(_, version)| Ok(QueryOutput::optionally_versioned(String::new(), version)),
+        ),
+        Query::TsAdd { key, timestamp, value } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::TimeSeries, |series| {
+                    let series = series.get_or_insert_with(|| timeseries::create(None));
+                    match timeseries::add(series, timestamp, value) {
+                        Some(true) => Ok((timestamp, true)),
+                        Some(false) => Err(errors::TransactionError::SampleTooOld),
+                        None => Err(errors::TransactionError::WrongType),
+                    }
+                })
+                .await,
+            |(timestamp, version)| Ok(QueryOutput::optionally_versioned(timestamp.to_string(), version)),
+        ),
+        Query::TsAddMany { key, samples } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::TimeSeries, |series| {
+                    let series = series.get_or_insert_with(|| timeseries::create(None));
+                    // a series that is not one fails on the first sample, before any change
+                    let mut kept = 0;
+                    for (timestamp, value) in samples {
+                        kept += timeseries::add(series, timestamp, value).ok_or(errors::TransactionError::WrongType)? as u64;
+                    }
+                    Ok((kept, kept > 0))
+                })
+                .await,
+            |(kept, version)| Ok(QueryOutput::optionally_versioned(kept.to_string(), version)),
+        ),
+        Query::TsRange { key, from, to, aggregation } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::TimeSeries, |series| {
+                    let series = series.ok_or(errors::TransactionError::RecordNotFound)?;
+                    timeseries::range(series, from, to, aggregation)
+                        .map(|samples| timeseries::render(&samples))
+                        .ok_or(errors::TransactionError::WrongType)
+                })
+                .await,
+            |(samples, version)| Ok(QueryOutput::optionally_versioned(samples, version)),
+        ),
+        Query::TsInfo { key } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::TimeSeries, |series| {
+                    let series = series.ok_or(errors::TransactionError::RecordNotFound)?;
+                    timeseries::info(series).ok_or(errors::TransactionError::WrongType)
+                })
+                .await,
+            |(info, version)| Ok(QueryOutput::optionally_versioned(info, version)),
+        ),
+        Query::VectorCreate { index, dimension, metric } => handle_ok_result(
+            storage
+                .update_value(&index, RecordKind::VectorIndex, |value| match value {
+                    Some(_) => Err(errors::TransactionError::KeyExists),
+                    None => {
+                        *value = Some(vector::create(dimension, metric));
+                        Ok(((), true))
+                    }
+                })
+                .await,
+            |(_, version)| Ok(QueryOutput::optionally_versioned(String::new(), version)),
+        ),
+        Query::VectorAdd { index, id, vector } => handle_ok_result(
+            storage
+                .update_value(&index, RecordKind::VectorIndex, |value| {
+                    let stored = value.as_mut().ok_or(errors::TransactionError::RecordNotFound)?;
+                    let added = vector::add(stored, &id, vector).ok_or(errors::TransactionError::InvalidVector)?;
+                    Ok((added, true))
+                })
+                .await,
+            |(added, version)| Ok(QueryOutput::optionally_versioned((added as u8).to_string(), version)),
+        ),
+        Query::VectorRemove { index, id } => handle_ok_result(
+            storage
+                .update_value(&index, RecordKind::VectorIndex, |value| {
+                    let stored = value.as_mut().ok_or(errors::TransactionError::RecordNotFound)?;
+                    let removed = vector::remove(stored, &id).ok_or(errors::TransactionError::WrongType)?;
+                    Ok((removed, removed))
+                })
+                .await,
+            |(removed, version)| Ok(QueryOutput::optionally_versioned((removed as u8).to_string(), version)),
+        ),
+        Query::VectorSearch { index, vector, k } => handle_ok_result(
+            storage
+                .read_value(&index, RecordKind::VectorIndex, |stored| {
+                    let stored = stored.ok_or(errors::TransactionError::RecordNotFound)?;
+                    vector::search(stored, vector, k)
+                        .map(|neighbours| vector::render(&neighbours))
+                        .ok_or(errors::TransactionError::InvalidVector)
+                })
+                .await,
+            |(neighbours, version)| Ok(QueryOutput::optionally_versioned(neighbours, version)),
+        ),
+        Query::RateLimit { key, limit, window } => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64);
+            // idle for two windows, a limiter holds no count anymore
+            let idle_ttl = window.saturating_mul(2);
+            handle_ok_result(
+                storage
+                    .update_idle_value(&key, RecordKind::RateLimit, idle_ttl, |value| {
+                        let limiter = value.get_or_insert_with(ratelimit::create);
+                        let verdict = ratelimit::check(limiter, limit, window, now)
+                            .ok_or(errors::TransactionError::WrongType)?;
+                        Ok((verdict, true))
+                    })
+                    .await,
+                |(verdict, version)| Ok(QueryOutput::optionally_versioned(verdict.render(), version)),
+            )
+        }
+        Query::ZAdd { key, members } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::SortedSet, |value| {
+                    let mut set = match value {
+                        Some(encoded) => SortedSet::decode(encoded).ok_or(errors::TransactionError::WrongType)?,
+                        None => SortedSet::default(),
+                    };
+                    let added = members.iter().filter(|(member, score)| set.add(member, *score)).count();
+                    *value = Some(set.encode());
+                    Ok((added, true))
+                })
+                .await,
+            |(added, version)| Ok(QueryOutput::optionally_versioned(added.to_string(), version)),
+        ),
+        Query::ZRem { key, members } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::SortedSet, |value| {
+                    let Some(encoded) = value.as_deref() else {
+                        return Ok((0, false));
+                    };
+                    let mut set = SortedSet::decode(encoded).ok_or(errors::TransactionError::WrongType)?;
+                    let removed = members.iter().filter(|member| set.remove(member)).count();
+                    if removed > 0 {
+                        *value = encode_sorted_set(&set);
+                    }
+                    Ok((removed, removed > 0))
+                })
+                .await,
+            |(removed, version)| Ok(QueryOutput::optionally_versioned(removed.to_string(), version)),
+        ),
+        Query::ZRange { key, start, stop, rev } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::SortedSet, |encoded| match encoded {
+                    Some(encoded) => {
+                        let set = SortedSet::decode(encoded).ok_or(errors::TransactionError::WrongType)?;
+                        Ok(sorted_set::render(&set.range(start, stop, rev)))
+                    }
+                    None => Ok(String::new()),
+                })
+                .await,
+            |(list, version)| Ok(QueryOutput::optionally_versioned(list, version)),
+        ),
+        Query::ZRangeByScore { key, min, max, offset, limit } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::SortedSet, |encoded| match encoded {
+                    Some(encoded) => {
+                        let set = SortedSet::decode(encoded).ok_or(errors::TransactionError::WrongType)?;
+                        Ok(sorted_set::render(&set.range_by_score(min, max, offset, limit)))
+                    }
+                    None => Ok(String::new()),
+                })
+                .await,
+            |(list, version)| Ok(QueryOutput::optionally_versioned(list, version)),
+        ),
+        Query::ZIncrBy { key, member, increment } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::SortedSet, |value| {
+                    let mut set = match value {
+                        Some(encoded) => SortedSet::decode(encoded).ok_or(errors::TransactionError::WrongType)?,
+                        None => SortedSet::default(),
+                    };
+                    let score = set
+                        .incr(&member, increment)
+                        .ok_or(errors::TransactionError::IncrementOverflow)?;
+                    *value = Some(set.encode());
+                    Ok((score, true))
+                })
+                .await,
+            |(score, version)| Ok(QueryOutput::optionally_versioned(score.to_string(), version)),
+        ),
+        Query::ZRank { key, member, rev } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::SortedSet, |encoded| {
+                    let encoded = encoded.ok_or(errors::TransactionError::RecordNotFound)?;
+                    let set = SortedSet::decode(encoded).ok_or(errors::TransactionError::WrongType)?;
+                    set.rank(&member, rev).ok_or(errors::TransactionError::MemberNotFound)
+                })
+                .await,
+            |(rank, version)| Ok(QueryOutput::optionally_versioned(rank.to_string(), version)),
+        ),
+        Query::ZRemRangeByScore { key, min, max } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::SortedSet, |value| {
+                    let Some(encoded) = value.as_deref() else {
+                        return Ok((0, false));
+                    };
+                    let mut set = SortedSet::decode(encoded).ok_or(errors::TransactionError::WrongType)?;
+                    let removed = set.remove_range_by_score(min, max);
+                    if removed > 0 {
+                        *value = encode_sorted_set(&set);
+                    }
+                    Ok((removed, removed > 0))
+                })
+                .await,
+            |(removed, version)| Ok(QueryOutput::optionally_versioned(removed.to_string(), version)),
+        ),
+        Query::SAdd { key, members } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::Set, |value| {
+                    let mut set = decode_set(value.as_deref())?;
+                    let added = members.iter().filter(|member| set.add(member)).count();
+                    *value = Some(set.encode());
+                    Ok((added, added > 0))
+                })
+                .await,
+            |(added, version)| Ok(QueryOutput::optionally_versioned(added.to_string(), version)),
+        ),
+        Query::SRem { key, members } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::Set, |value| {
+                    if value.is_none() {
+                        return Ok((0, false));
+                    }
+                    let mut set = decode_set(value.as_deref())?;
+                    let removed = members.iter().filter(|member| set.remove(member)).count();
+                    if removed > 0 {
+                        *value = encode_set(&set);
+                    }
+                    Ok((removed, removed > 0))
+                })
+                .await,
+            |(removed, version)| Ok(QueryOutput::optionally_versioned(removed.to_string(), version)),
+        ),
+        Query::SMembers { key } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::Set, |encoded| decode_set(encoded).map(|set| set.render()))
+                .await,
+            |(list, version)| Ok(QueryOutput::optionally_versioned(list, version)),
+        ),
+        Query::SIsMember { key, member } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::Set, |encoded| decode_set(encoded).map(|set| set.contains(&member)))
+                .await,
+            |(member, version)| Ok(QueryOutput::optionally_versioned((member as u8).to_string(), version)),
+        ),
+        Query::SCard { key } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::Set, |encoded| decode_set(encoded).map(|set| set.len()))
+                .await,
+            |(len, version)| Ok(QueryOutput::optionally_versioned(len.to_string(), version)),
+        ),
+        Query::SCombineStore { op, destination, keys } => handle_ok_result(
+            store_sets(&storage, op, &destination, &keys).await,
+            |(len, version)| Ok(QueryOutput::optionally_versioned(len.to_string(), version)),
+        ),
+        Query::ListPush { key, end, items } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::List, |value| {
+                    let mut list = decode_list(value.as_deref())?;
+                    for item in items {
+                        list.push(end, item);
+                    }
+                    *value = Some(list.encode());
+                    Ok((list.len(), true))
+                })
+                .await,
+            |(len, version)| Ok(QueryOutput::optionally_versioned(len.to_string(), version)),
+        ),
+        Query::ListPop { key, end, count } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::List, |value| {
+                    let encoded = value.as_deref().ok_or(errors::TransactionError::RecordNotFound)?;
+                    let mut list = decode_list(Some(encoded))?;
+                    let popped = pop(&mut list, end, count)?;
+                    *value = encode_list(&list);
+                    Ok((popped, true))
+                })
+                .await,
+            |(popped, version)| Ok(QueryOutput::optionally_versioned(list::render(&popped), version)),
+        ),
+        Query::LRange { key, start, stop } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::List, |encoded| {
+                    decode_list(encoded).map(|list| list::render(&list.range(start, stop)))
+                })
+                .await,
+            |(items, version)| Ok(QueryOutput::optionally_versioned(items, version)),
+        ),
+        Query::LLen { key } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::List, |encoded| decode_list(encoded).map(|list| list.len()))
+                .await,
+            |(len, version)| Ok(QueryOutput::optionally_versioned(len.to_string(), version)),
+        ),
+        Query::LMove { source, destination, from, to } => handle_ok_result(
+            move_item(&storage, &source, &destination, from, to).await,
+            |(item, version)| Ok(QueryOutput::optionally_versioned(item, version)),
+        ),
+        Query::HSet { key, fields } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::Hash, |value| {
+                    let (mut hash, _) = decode_hash(value.as_deref())?;
+                    let added = fields
+                        .into_iter()
+                        .map(|(field, value)| hash.set(&field, value))
+                        .filter(|new| *new)
+                        .count();
+                    *value = Some(hash.encode());
+                    Ok((added, true))
+                })
+                .await,
+            |(added, version)| Ok(QueryOutput::optionally_versioned(added.to_string(), version)),
+        ),
+        Query::HGet { key, field } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::Hash, |encoded| {
+                    let encoded = encoded.ok_or(errors::TransactionError::RecordNotFound)?;
+                    let (hash, _) = decode_hash(Some(encoded))?;
+                    hash.get(&field)
+                        .map(str::to_owned)
+                        .ok_or(errors::TransactionError::FieldNotFound)
+                })
+                .await,
+            |(value, version)| Ok(QueryOutput::optionally_versioned(value, version)),
+        ),
+        Query::HDel { key, fields } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::Hash, |value| {
+                    if value.is_none() {
+                        return Ok((0, false));
+                    }
+                    let (mut hash, expired) = decode_hash(value.as_deref())?;
+                    let removed = fields.iter().filter(|field| hash.remove(field)).count();
+                    if removed > 0 || expired {
+                        *value = encode_hash(&hash);
+                    }
+                    Ok((removed, removed > 0 || expired))
+                })
+                .await,
+            |(removed, version)| Ok(QueryOutput::optionally_versioned(removed.to_string(), version)),
+        ),
+        Query::HGetAll { key } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::Hash, |encoded| decode_hash(encoded).map(|(hash, _)| hash.render()))
+                .await,
+            |(fields, version)| Ok(QueryOutput::optionally_versioned(fields, version)),
+        ),
+        Query::HIncrBy { key, field, delta } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::Hash, |value| {
+                    let (mut hash, _) = decode_hash(value.as_deref())?;
+                    let incremented = hash.incr(&field, delta)?;
+                    *value = Some(hash.encode());
+                    Ok((incremented, true))
+                })
+                .await,
+            |(value, version)| Ok(QueryOutput::optionally_versioned(value.to_string(), version)),
+        ),
+        Query::HExpire { key, field, ttl } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::Hash, |value| {
+                    if value.is_none() {
+                        return Ok((false, false));
+                    }
+                    let (mut hash, expired) = decode_hash(value.as_deref())?;
+                    let found = hash.expire(&field, ttl, now_ms());
+                    if found || expired {
+                        *value = encode_hash(&hash);
+                    }
+                    Ok((found, found || expired))
+                })
+                .await,
+            |(found, version)| Ok(QueryOutput::optionally_versioned((found as u8).to_string(), version)),
+        ),
+        Query::HTtl { key, field } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::Hash, |encoded| {
+                    let encoded = encoded.ok_or(errors::TransactionError::RecordNotFound)?;
+                    let (hash, _) = decode_hash(Some(encoded))?;
+                    let ttl = hash
+                        .ttl(&field, now_ms())
+                        .ok_or(errors::TransactionError::FieldNotFound)?
+                        .ok_or(errors::TransactionError::TTLNotFound)?;
+                    Ok(format!("{}s", ttl.as_secs()))
+                })
+                .await,
+            |(ttl, version)| Ok(QueryOutput::optionally_versioned(ttl, version)),
+        ),
+        Query::QueuePush { key, item } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::Queue, |value| {
+                    let queue = value.get_or_insert_with(queue::create);
+                    if !queue::is_valid(queue) {
+                        return Err(errors::TransactionError::WrongType);
+                    }
+                    Ok((queue::push(queue, item.as_bytes()), true))
+                })
+                .await,
+            |(id, version)| Ok(QueryOutput::optionally_versioned(id.to_string(), version)),
+        ),
+        Query::QueuePop { key, timeout } => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64);
+            handle_ok_result(
+                storage
+                    .update_value(&key, RecordKind::Queue, |value| {
+                        let queue = value.as_mut().ok_or(errors::TransactionError::QueueEmpty)?;
+                        if !queue::is_valid(queue) {
+                            return Err(errors::TransactionError::WrongType);
+                        }
+                        let delivery =
+                            queue::pop(queue, timeout, now).ok_or(errors::TransactionError::QueueEmpty)?;
+                        Ok((delivery, true))
+                    })
+                    .await,
+                |(delivery, version)| match delivery.render() {
+                    Some(delivery) => Ok(QueryOutput::optionally_versioned(delivery, version)),
+                    None => Err(errors::Errors::DeserializationError(
+                        errors::DeserializationError::UnparsableBytes,
+                    )),
+                },
+            )
+        }
+        Query::QueueAck { key, id } => handle_ok_result(
+            storage
+                .update_value(&key, RecordKind::Queue, |value| {
+                    let queue = value.as_mut().ok_or(errors::TransactionError::QueueItemNotFound)?;
+                    if !queue::is_valid(queue) {
+                        return Err(errors::TransactionError::WrongType);
+                    }
+                    if !queue::ack(queue, id) {
+                        return Err(errors::TransactionError::QueueItemNotFound);
+                    }
+                    Ok(((), true))
+                })
+                .await,
+            |(_, version)| Ok(QueryOutput::optionally_versioned(String::new(), version)),
+        ),
+        Query::VectorInfo { index } => handle_ok_result(
+            storage
+                .read_value(&index, RecordKind::VectorIndex, |stored| {
+                    let stored = stored.ok_or(errors::TransactionError::RecordNotFound)?;
+                    vector::info(stored).ok_or(errors::TransactionError::WrongType)
+                })
+                .await,
+            |(info, version)| Ok(QueryOutput::optionally_versioned(info, version)),
+        ),
+        Query::CmsInfo { key } => handle_ok_result(
+            storage
+                .read_value(&key, RecordKind::CountMin, |sketch| {
+                    let sketch = sketch.ok_or(errors::TransactionError::RecordNotFound)?;
+                    cms::info(sketch).ok_or(errors::TransactionError::WrongType)
+                })
+                .await,
+            |(info, version)| Ok(QueryOutput::optionally_versioned(info, version)),
+        ),
+        // streamed, the body carries its own content type
+        Query::Subscribe { channel } => Ok(QueryOutput {
+            body: storage.pubsub.subscribe(&channel),
+            version: None,
+            content_type: None,
+        }),
+        Query::Export { prefix, format } => Ok(QueryOutput {
+            body: export::export(&storage, prefix, format),
+            version: None,
+            content_type: None,
+        }),
+        #[cfg(feature = "replication")]
+        Query::Replicate { resume } => Ok(QueryOutput {
+            body: replication::stream(&storage, resume),
+            version: None,
+            content_type: None,
+        }),
+        #[cfg(feature = "backup")]
+        Query::Backup { compression } => Ok(QueryOutput {
+            body: backup_download::download(&storage, compression)
+                .await
+                .map_err(errors::Errors::TransactionError)?,
+            version: None,
+            content_type: None,
+        }),
+        #[cfg(feature = "metrics")]
+        Query::Stats => {
+            let shard_stats = storage.shard_stats().await;
+            Ok(QueryOutput::json(storage.stats.to_json(&shard_stats)))
+        }
+        #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+        Query::MemStats => {
+            let dataset = storage.shard_stats().await.iter().map(|shard| shard.bytes).sum();
+            // numbers and strings only, always serializes
+            Ok(QueryOutput::json(serde_json::to_string(&allocator::stats(dataset)).unwrap_or_default()))
+        }
+        query => handle_unversioned_query(query, storage)
+            .await
+            .map(QueryOutput::from),
+    }
+}
+
+async fn handle_unversioned_query(query: Query, storage: Storage) -> Result<String, errors::Errors> {
+    match query {
         Query::Del { key } => handle_ok_result(
             storage.remove_record(&key).await,
             |_| Ok(String::new()),
         ),
-        Query::Exists { key } => handle_ok_result(
-            storage.get_record(&key).await,
+        Query::GetDel { key } => handle_ok_result(storage.take_record(&key).await, record_to_string),
+        Query::Tombstone { key, ttl } => handle_ok_result(
+            storage.bury(&key, ttl).await,
             |_| Ok(String::new()),
         ),
-        Query::Expire { key, ttl } => handle_ok_result(
-            storage.update_ttl(&key, Some(ttl)).await,
+        Query::Untombstone { key } => handle_ok_result(
+            storage.unbury(&key),
+            |_| Ok(String::new()),
+        ),
+        Query::Exists { key } => handle_ok_result(
+            storage.get_record(&key).await,
             |_| Ok(String::new()),
         ),
         Query::Ttl { key } => handle_ok_result(storage.get_record(&key).await, |rec: Record| {
@@ -54,16 +901,588 @@ pub(crate) async fn handle_query(query: Query, storage: Storage) -> Result<Strin
                 None => Err(errors::Errors::TransactionError(errors::TransactionError::TTLNotFound)),
             }
         }),
-        Query::Info => Ok("mapper".to_string()),
-        Query::FlushAll => {
-            storage.flush_all().await;
-            Ok(String::new())
-        }
+        Query::Info { section } => Ok(info(&storage, section).await),
+        Query::FlushAll => handle_ok_result(storage.flush_all().await, |_| Ok(String::new())),
         Query::DbSize => Ok(storage.db_size().await.to_string()),
         Query::Ping => Ok("pong".to_string()),
-        Query::Persist { key } => handle_ok_result(
-            storage.update_ttl(&key, None).await,
+        Query::SplitShard { shard } => handle_ok_result(
+            resharding::split_shard(&storage, shard),
+            |new_shard| Ok(new_shard.to_string()),
+        ),
+        Query::Reshard { shards } => handle_ok_result(
+            resharding::resize(&storage, shards),
+            |moving_slots| Ok(moving_slots.to_string()),
+        ),
+        Query::Changes { since } => {
+            let changes = match since {
+                Some(since) => storage.journal.since(since),
+                // without a starting point, whatever is still retained
+                None => Ok(storage.journal.retained()),
+            };
+            handle_ok_result(changes, |changes| {
+                Ok(changes.iter().map(|change| format!("{}\n", change)).collect())
+            })
+        }
+        Query::ExpireMany { keys, ttl } => handle_ok_result(
+            storage.update_ttls(keys, Some(ttl)).await,
+            |updated| Ok(updated.to_string()),
+        ),
+        Query::PersistMany { keys } => handle_ok_result(
+            storage.update_ttls(keys, None).await,
+            |updated| Ok(updated.to_string()),
+        ),
+        Query::Exec { commands } => handle_ok_result(
+            storage.execute(&commands).await,
+            |results| Ok(results.iter().map(|result| format!("{}\n", result)).collect()),
+        ),
+        #[cfg(feature = "scripting")]
+        Query::Eval { script, keys, args } => handle_ok_result(storage.eval(&script, &keys, &args).await, Ok),
+        Query::SnapGet { keys } => handle_ok_result(
+            storage.get_snapshot(&keys).await,
+            |records| snapshot(&keys, records),
+        ),
+        // values that cannot be read as text are missing too, rather than failing the batch
+        Query::MGet { keys } => handle_ok_result(storage.get_many(&keys).await, |records| {
+            let records = records
+                .into_iter()
+                .map(|record| {
+                    record.filter(|(record, _)| {
+                        record
+                            .value(RecordKind::Bytes)
+                            .is_ok_and(|value| std::str::from_utf8(value).is_ok())
+                    })
+                })
+                .collect();
+            snapshot(&keys, records)
+        }),
+        Query::SCombine { op, keys } => handle_ok_result(storage.get_snapshot(&keys).await, |records| {
+            let values = records
+                .iter()
+                .map(|record| record.as_ref().map(|(record, _)| record.value(RecordKind::Set)).transpose())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(errors::Errors::TransactionError)?;
+            let sets = values
+                .into_iter()
+                .map(decode_set)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(errors::Errors::TransactionError)?;
+            Ok(Set::combine(op, &sets).render())
+        }),
+        Query::LMPop { keys, end, count } => handle_ok_result(
+            storage
+                .update_values(&keys, RecordKind::List, |values| {
+                    // the first list with an item, in the order of the keys
+                    for key in &keys {
+                        let mut list = decode_list(values[key].as_deref())?;
+                        if list.is_empty() {
+                            continue;
+                        }
+                        let popped = pop(&mut list, end, count)?;
+                        values.insert(key.clone(), encode_list(&list));
+                        return Ok(format!("{}\n{}", key, list::render(&popped)));
+                    }
+                    Err(errors::TransactionError::RecordNotFound)
+                })
+                .await,
+            |(popped, _)| Ok(popped),
+        ),
+        Query::MSet { pairs } => handle_ok_result(
+            storage
+                .set_many(pairs.into_iter().map(|(key, value)| (key, Record::new(value, None))).collect())
+                .await,
+            |versions| Ok(versions.iter().map(|version| format!("{}\n", version)).collect()),
+        ),
+        Query::DelPattern { pattern, dry_run } => handle_ok_result(
+            storage.remove_matching(&pattern, dry_run).await,
+            |removed| Ok(removed.to_string()),
+        ),
+        Query::Import { format, body } => import::import(&storage, format, body)
+            .await
+            .map(|imported| imported.to_string()),
+        #[cfg(feature = "backup")]
+        Query::Restore { archive, replace } => backup_restore::restore(&storage, archive, replace)
+            .await
+            .map(|restored| restored.to_string()),
+        #[cfg(feature = "backup")]
+        Query::BackupStatus => {
+            let mut status = storage.stats.backup_status();
+            for operation in storage.operations.list_kinds(&["backup", "restore"]) {
+                status.push_str(&format!("in_flight {}\n", operation));
+            }
+            Ok(status)
+        }
+        #[cfg(feature = "backup")]
+        Query::AofRewrite => handle_ok_result(
+            storage.aof.as_ref().ok_or(errors::TransactionError::AofDisabled),
+            |aof| {
+                aof.request_rewrite();
+                Ok(String::new())
+            },
+        ),
+        Query::Operations => Ok(storage.operations.list()),
+        Query::HotKeys => Ok(storage.stats.hot_keys()),
+        Query::SlowLog { count } => Ok(storage.slowlog.entries(count)),
+        Query::SlowLogReset => Ok(storage.slowlog.reset().to_string()),
+        #[cfg(feature = "cluster")]
+        Query::ClusterInfo => handle_ok_result(
+            storage.cluster.as_ref().ok_or(errors::TransactionError::ClusterDisabled),
+            |cluster| Ok(cluster.info()),
+        ),
+        #[cfg(feature = "cluster")]
+        Query::ClusterSlots => handle_ok_result(
+            storage.cluster.as_ref().ok_or(errors::TransactionError::ClusterDisabled),
+            |cluster| Ok(cluster.slots()),
+        ),
+        #[cfg(feature = "chaos")]
+        Query::Chaos => handle_ok_result(storage.chaos.config(), |config| Ok(config.to_string())),
+        #[cfg(feature = "chaos")]
+        Query::ChaosUpdate { spec } => handle_ok_result(
+            storage.chaos.update(spec.as_deref()),
+            |config| Ok(config.to_string()),
+        ),
+        #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+        Query::HeapProfile { path } => handle_ok_result(
+            smol::unblock(move || allocator::dump_heap_profile(path)).await,
+            Ok,
+        ),
+        Query::Lock { name, ttl } => handle_ok_result(
+            storage.acquire_lock(&name, ttl).await,
+            |token| Ok(token.to_string()),
+        ),
+        Query::Renew { name, token, ttl } => handle_ok_result(
+            storage.renew_lock(&name, token, ttl).await,
+            |_| Ok(String::new()),
+        ),
+        Query::Unlock { name, token } => handle_ok_result(
+            storage.release_lock(&name, token).await,
+            |_| Ok(String::new()),
+        ),
+        #[cfg(feature = "cluster")]
+        Query::QuorumLease { name, op } => {
+            let Some(cluster) = storage.cluster.clone() else {
+                return Err(errors::Errors::TransactionError(errors::TransactionError::ClusterDisabled));
+            };
+            match op {
+                LeaseOp::Lock { ttl, .. } => handle_ok_result(
+                    quorum::lock(&storage, &cluster, &name, ttl).await,
+                    |token| Ok(token.to_string()),
+                ),
+                op => handle_ok_result(
+                    quorum::update(&storage, &cluster, &name, op).await,
+                    |_| Ok(String::new()),
+                ),
+            }
+        }
+        #[cfg(feature = "cluster")]
+        Query::MemberLease { name, op } => handle_ok_result(
+            quorum::apply(&storage, &name, op).await,
             |_| Ok(String::new()),
         ),
+        Query::SemAcquire { name, max, ttl } => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64);
+            handle_ok_result(storage.acquire_permit(&name, max, ttl, now).await, |token| {
+                Ok(token.to_string())
+            })
+        }
+        Query::SemRelease { name, token } => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64);
+            handle_ok_result(storage.release_permit(&name, token, now).await, |_| Ok(String::new()))
+        }
+        Query::SetAt { key, data, at, tags } => {
+            let record = Record::new(data, None).with_tags(tags);
+            Ok(schedule::schedule(&storage, key, ScheduledWrite::Set(record), at).to_string())
+        }
+        Query::DelAt { key, at } => Ok(schedule::schedule(&storage, key, ScheduledWrite::Del, at).to_string()),
+        Query::Scheduled => Ok(storage.schedules.list()),
+        Query::CancelSchedule { id } => handle_ok_result(
+            storage.schedules.cancel(id),
+            |_| Ok(String::new()),
+        ),
+        Query::Keys { pattern, limit } => {
+            let limit = limit.map_or(storage.keys_limit, |limit| limit.min(storage.keys_limit));
+            let keys = storage.matching_keys(&pattern, limit).await;
+            Ok(keys.iter().map(|key| format!("{}\n", key)).collect())
+        }
+        Query::Publish { channel, message } => Ok(storage.pubsub.publish(&channel, &message).to_string()),
+        Query::PubSubChannels => Ok(storage.pubsub.channels()),
+        Query::Scan { cursor, count, pattern } => {
+            let count = count.min(storage.keys_limit);
+            let (cursor, keys) = storage.scan(cursor, count, pattern.as_ref()).await;
+            Ok(format!("{}\n{}", cursor, keys.iter().map(|key| format!("{}\n", key)).collect::<String>()))
+        }
+        Query::Expiring { within, limit } => Ok(expiring(&storage.expiring(within, limit).await)),
+        Query::GetByTag { tag } => Ok(storage
+            .tags
+            .keys(&tag)
+            .iter()
+            .map(|key| format!("{}\n", key))
+            .collect()),
+        Query::DelByTag { tag } => handle_ok_result(
+            storage.remove_tagged(&tag).await,
+            |removed| Ok(removed.to_string()),
+        ),
+        Query::Search { query, limit } => Ok(search::render(&storage.search.search(&query, limit))),
+        Query::CancelOperation { id } => handle_ok_result(
+            storage.operations.cancel(id),
+            |_| Ok(String::new()),
+        ),
+        Query::Get { .. }
+        | Query::Watch { .. }
+        | Query::Set { .. }
+        | Query::SetEx { .. }
+        | Query::Expire { .. }
+        | Query::Persist { .. }
+        | Query::Object { .. }
+        | Query::IncrByFloat { .. }
+        | Query::IncrBy { .. }
+        | Query::Append { .. }
+        | Query::GetSet { .. }
+        | Query::Bitfield { .. }
+        | Query::BloomReserve { .. }
+        | Query::BloomAdd { .. }
+        | Query::BloomExists { .. }
+        | Query::BloomInfo { .. }
+        | Query::CmsInit { .. }
+        | Query::CmsIncrBy { .. }
+        | Query::CmsCount { .. }
+        | Query::CmsMerge { .. }
+        | Query::CmsInfo { .. }
+        | Query::TopKReserve { .. }
+        | Query::TopKAdd { .. }
+        | Query::TopKList { .. }
+        | Query::TopKInfo { .. }
+        | Query::TsCreate { .. }
+        | Query::TsAdd { .. }
+        | Query::TsAddMany { .. }
+        | Query::TsRange { .. }
+        | Query::TsInfo { .. }
+        | Query::ZAdd { .. }
+        | Query::ZRange { .. }
+        | Query::ZRangeByScore { .. }
+        | Query::ZRem { .. }
+        | Query::ZIncrBy { .. }
+        | Query::ZRank { .. }
+        | Query::ZRemRangeByScore { .. }
+        | Query::SAdd { .. }
+        | Query::SRem { .. }
+        | Query::SMembers { .. }
+        | Query::SIsMember { .. }
+        | Query::SCard { .. }
+        | Query::SCombineStore { .. }
+        | Query::ListPush { .. }
+        | Query::ListPop { .. }
+        | Query::LRange { .. }
+        | Query::LLen { .. }
+        | Query::LMove { .. }
+        | Query::HSet { .. }
+        | Query::HGet { .. }
+        | Query::HDel { .. }
+        | Query::HGetAll { .. }
+        | Query::HIncrBy { .. }
+        | Query::HExpire { .. }
+        | Query::HTtl { .. }
+        | Query::VectorCreate { .. }
+        | Query::VectorAdd { .. }
+        | Query::VectorRemove { .. }
+        | Query::VectorSearch { .. }
+        | Query::VectorInfo { .. }
+        | Query::RateLimit { .. }
+        | Query::QueuePush { .. }
+        | Query::QueuePop { .. }
+        | Query::QueueAck { .. } => unreachable!("versioned queries are handled by handle_query"),
+        Query::Export { .. } | Query::Subscribe { .. } => {
+            unreachable!("streamed queries are handled by handle_query")
+        }
+        #[cfg(feature = "backup")]
+        Query::Backup { .. } => unreachable!("streamed queries are handled by handle_query"),
+        #[cfg(feature = "replication")]
+        Query::Replicate { .. } => unreachable!("streamed queries are handled by handle_query"),
+        #[cfg(feature = "metrics")]
+        Query::Stats => unreachable!("json queries are handled by handle_query"),
+        #[cfg(any(feature = "jemalloc", feature = "mimalloc"))]
+        Query::MemStats => unreachable!("json queries are handled by handle_query"),
+    }
+}
+
+/// Sums the weighted `sources` sketches into the `key` one, which has to exist with the
+/// same dimensions. Sources are copied first, one at a time: a source changing meanwhile
+/// is merged as it was when read.
+async fn merge_sketches(
+    storage: &Storage,
+    key: &str,
+    sources: Vec<(String, u64)>,
+) -> Result<Option<u64>, errors::TransactionError> {
+    let mut sketches = Vec::with_capacity(sources.len());
+    for (source, weight) in sources {
+        let (sketch, _) = storage
+            .read_value(&source, RecordKind::CountMin, |sketch| {
+                sketch
+                    .map(<[u8]>::to_vec)
+                    .ok_or(errors::TransactionError::RecordNotFound)
+            })
+            .await?;
+        sketches.push((sketch, weight));
+    }
+
+    storage
+        .update_value(key, RecordKind::CountMin, |value| {
+            let sketch = value.as_mut().ok_or(errors::TransactionError::RecordNotFound)?;
+            cms::merge(sketch, &sketches)?;
+            Ok(((), true))
+        })
+        .await
+        .map(|(_, version)| version)
+}
+
+// `None` once empty, the sorted set going away with its last member
+fn encode_sorted_set(set: &SortedSet) -> Option<Vec<u8>> {
+    (!set.is_empty()).then(|| set.encode())
+}
+
+// an empty set for a missing key
+fn decode_set(encoded: Option<&[u8]>) -> Result<Set, errors::TransactionError> {
+    match encoded {
+        Some(encoded) => Set::decode(encoded).ok_or(errors::TransactionError::WrongType),
+        None => Ok(Set::default()),
+    }
+}
+
+// `None` once empty, the set going away with its last member
+fn encode_set(set: &Set) -> Option<Vec<u8>> {
+    (!set.is_empty()).then(|| set.encode())
+}
+
+// `op` over the sets at `keys` stored at `destination`, replacing it, or removing it for an
+// empty result, with the shards of them all locked together; returns the member count of
+// the result
+async fn store_sets(
+    storage: &Storage,
+    op: SetOp,
+    destination: &str,
+    keys: &[String],
+) -> Result<(usize, Option<u64>), errors::TransactionError> {
+    let mut involved = keys.to_vec();
+    involved.push(destination.to_string());
+    let (len, versions) = storage
+        .update_values(&involved, RecordKind::Set, |values| {
+            let sets = keys
+                .iter()
+                .map(|key| decode_set(values[key].as_deref()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let combined = Set::combine(op, &sets);
+            values.insert(destination.to_string(), encode_set(&combined));
+            Ok(combined.len())
+        })
+        .await?;
+    Ok((len, versions.get(destination).copied()))
+}
+
+// an empty list for a missing key
+fn decode_list(encoded: Option<&[u8]>) -> Result<List, errors::TransactionError> {
+    match encoded {
+        Some(encoded) => List::decode(encoded).ok_or(errors::TransactionError::WrongType),
+        None => Ok(List::default()),
+    }
+}
+
+// an empty hash for a missing key, without the fields past their deadline, with whether
+// there were any
+fn decode_hash(encoded: Option<&[u8]>) -> Result<(Hash, bool), errors::TransactionError> {
+    match encoded {
+        Some(encoded) => Hash::decode(encoded, now_ms()).ok_or(errors::TransactionError::WrongType),
+        None => Ok((Hash::default(), false)),
+    }
+}
+
+// `None` once without fields, the hash going away with its last one
+fn encode_hash(hash: &Hash) -> Option<Vec<u8>> {
+    (!hash.is_empty()).then(|| hash.encode())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+// `None` once empty, the list going away with its last item
+fn encode_list(list: &List) -> Option<Vec<u8>> {
+    (!list.is_empty()).then(|| list.encode())
+}
+
+// up to `count` items from `end`, at least one
+fn pop(list: &mut List, end: End, count: usize) -> Result<Vec<String>, errors::TransactionError> {
+    let popped: Vec<String> = (0..count).map_while(|_| list.pop(end)).collect();
+    match popped.is_empty() {
+        true => Err(errors::TransactionError::RecordNotFound),
+        false => Ok(popped),
+    }
+}
+
+// pops the item at `from` of `source` and pushes it at `to` of `destination`, with the
+// shards of both locked together: the item is in one list or the other, never in none
+async fn move_item(
+    storage: &Storage,
+    source: &str,
+    destination: &str,
+    from: End,
+    to: End,
+) -> Result<(String, Option<u64>), errors::TransactionError> {
+    let keys = [source.to_string(), destination.to_string()];
+    let (item, versions) = storage
+        .update_values(&keys, RecordKind::List, |values| {
+            let mut source_list = decode_list(values[source].as_deref())?;
+            // a list moving an item to itself, rotating it
+            let mut destination_list = match source == destination {
+                true => None,
+                false => Some(decode_list(values[destination].as_deref())?),
+            };
+            let item = source_list.pop(from).ok_or(errors::TransactionError::RecordNotFound)?;
+            destination_list.as_mut().unwrap_or(&mut source_list).push(to, item.clone());
+
+            values.insert(source.to_string(), encode_list(&source_list));
+            if let Some(destination_list) = destination_list {
+                values.insert(destination.to_string(), encode_list(&destination_list));
+            }
+            Ok(item)
+        })
+        .await?;
+    Ok((item, versions.get(destination).copied()))
+}
+
+fn object(record: &Record, version: u64) -> String {
+    let mut object = format!("version:{}\nsize:{}\ntype:{}\n", version, record.data.len(), record.kind);
+    if let Some(ttl_policy) = &record.ttl_policy {
+        object.push_str(&format!("ttl:{}s\n", ttl_policy.expire_in().as_secs()));
+    }
+    if !record.tags.is_empty() {
+        object.push_str(&format!("tags:{}\n", record.tags.join(",")));
+    }
+    object
+}
+
+/// A `<key> <version> <length>` line followed by the value for every key, `<key> nil`
+/// for the missing ones.
+fn snapshot(keys: &[String], records: Vec<Option<(Record, u64)>>) -> Result<String, errors::Errors> {
+    let mut body = String::new();
+    for (key, record) in keys.iter().zip(records) {
+        match record {
+            Some((record, version)) => {
+                let value = record_to_string(record)?;
+                body.push_str(&format!("{} {} {}\n{}\n", key, version, value.len(), value));
+            }
+            None => body.push_str(&format!("{} nil\n", key)),
+        }
+    }
+    Ok(body)
+}
+
+// `<key> <deadline>` lines, deadlines in milliseconds since the unix epoch
+fn expiring(keys: &[(String, Duration)]) -> String {
+    let now = SystemTime::now();
+    keys.iter()
+        .map(|(key, left)| {
+            let deadline = (now + *left)
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |deadline| deadline.as_millis());
+            format!("{} {}\n", key, deadline)
+        })
+        .collect()
+}
+
+async fn info(storage: &Storage, section: Option<InfoSection>) -> String {
+    let shard_stats = storage.shard_stats().await;
+    let sections = match section {
+        Some(section) => vec![section],
+        None => InfoSection::ALL.to_vec(),
+    };
+
+    let mut info = String::new();
+    for section in sections {
+        if !info.is_empty() {
+            info.push('\n');
+        }
+        let _ = writeln!(info, "# {}", section);
+        match section {
+            InfoSection::Server => {
+                let _ = writeln!(info, "version:{}", env!("CARGO_PKG_VERSION"));
+                let _ = writeln!(info, "uptime_secs:{}", storage.stats.uptime().as_secs());
+                let _ = writeln!(info, "shards:{}", storage.shard_count());
+                let _ = writeln!(info, "seq:{}", storage.journal.last_seq());
+                if let Some(resharding) = storage.resharding.lock().unwrap().as_ref() {
+                    let _ = writeln!(info, "resharding:{}", resharding);
+                }
+            }
+            InfoSection::Clients => {
+                let (active, total) = storage.stats.connections();
+                let _ = writeln!(info, "connected_clients:{}", active);
+                let _ = writeln!(info, "total_connections:{}", total);
+            }
+            InfoSection::Memory => {
+                let dataset: usize = shard_stats.iter().map(|shard| shard.bytes).sum();
+                let _ = writeln!(info, "dataset_bytes:{}", dataset);
+                // measured once a second, only with a memory limit
+                if let Some(memory) = storage.stats.memory() {
+                    let _ = writeln!(info, "used_memory:{}", memory.usage);
+                    let _ = writeln!(info, "max_memory:{}", memory.limit);
+                    let _ = writeln!(info, "memory_policy:{}", memory.policy);
+                    let _ = writeln!(info, "memory_pressure:{}", memory.pressure as u8);
+                }
+            }
+            InfoSection::Persistence => {
+                let backups = storage.stats.backups();
+                let _ = writeln!(info, "backups_succeeded:{}", backups.succeeded);
+                let _ = writeln!(info, "backups_failed:{}", backups.failed);
+                let _ = writeln!(info, "backup_consecutive_failures:{}", backups.consecutive_failures);
+                if let Some(success) = backups.last_success {
+                    let _ = writeln!(info, "last_backup_at:{}", success.finished_at);
+                    let _ = writeln!(info, "last_backup_duration_ms:{}", success.duration_ms);
+                    let _ = writeln!(info, "last_backup_bytes:{}", success.bytes);
+                    let _ = writeln!(info, "last_backup_archive:{}", success.archive);
+                }
+                if let Some(failure) = backups.last_failure {
+                    let _ = writeln!(info, "last_backup_failure_at:{}", failure.failed_at);
+                    let _ = writeln!(info, "last_backup_error:{}", failure.error);
+                }
+                #[cfg(feature = "backup")]
+                match &storage.aof {
+                    Some(aof) => {
+                        let _ = writeln!(info, "aof_enabled:1");
+                        info.push_str(&aof.info());
+                    }
+                    None => {
+                        let _ = writeln!(info, "aof_enabled:0");
+                    }
+                }
+            }
+            InfoSection::Keyspace => {
+                let keys: usize = shard_stats.iter().map(|shard| shard.keys).sum();
+                let _ = writeln!(info, "keys:{}", keys);
+                if let Some(share) = stats::max_shard_share(&shard_stats) {
+                    let _ = writeln!(info, "max_shard_share:{:.4}", share);
+                }
+                let writes: Vec<u64> = shard_stats.iter().map(|shard| shard.writes).collect();
+                let rates = storage.stats.write_rates(&writes);
+                for (shard_index, (shard, rate)) in shard_stats.iter().zip(rates).enumerate() {
+                    let _ = writeln!(
+                        info,
+                        "shard_{}:keys={},bytes={},writes={},writes_per_sec={:.2},contended_locks={},lock_wait_us={},max_lock_wait_us={}",
+                        shard_index,
+                        shard.keys,
+                        shard.bytes,
+                        shard.writes,
+                        rate,
+                        shard.lock.contended_reads + shard.lock.contended_writes,
+                        shard.lock.read_wait_us + shard.lock.write_wait_us,
+                        shard.lock.max_wait_us
+                    );
+                }
+            }
+        }
     }
+    info
 }
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+// id of the next item (u64), little endian, then the items in push order as their id
+// (u64), when they can be delivered again (u64, milliseconds since the unix epoch),
+// deliveries so far (u32), payload length (u32) and payload
+const HEADER_LEN: usize = 8;
+const ITEM_HEADER_LEN: usize = 8 + 8 + 4 + 4;
+
+/// An item handed out by [`pop`].
+#[derive(Debug)]
+pub(crate) struct Delivery {
+    pub(crate) id: u64,
+    // including this one
+    pub(crate) deliveries: u32,
+    pub(crate) payload: Vec<u8>,
+}
+
+/// An empty queue.
+pub(crate) fn create() -> Vec<u8> {
+    1u64.to_le_bytes().to_vec()
+}
+
+/// Whether `queue` is one.
+pub(crate) fn is_valid(queue: &[u8]) -> bool {
+    if queue.len() < HEADER_LEN {
+        return false;
+    }
+    let mut at = HEADER_LEN;
+    while at < queue.len() {
+        if queue.len() - at < ITEM_HEADER_LEN {
+            return false;
+        }
+        at += ITEM_HEADER_LEN + read_u32(queue, at + 20) as usize;
+    }
+    at == queue.len()
+}
+
+/// Appends `payload`, returning its id.
+pub(crate) fn push(queue: &mut Vec<u8>, payload: &[u8]) -> u64 {
+    let id = read_u64(queue, 0);
+    queue[..8].copy_from_slice(&(id + 1).to_le_bytes());
+    queue.extend_from_slice(&id.to_le_bytes());
+    queue.extend_from_slice(&0u64.to_le_bytes());
+    queue.extend_from_slice(&0u32.to_le_bytes());
+    queue.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    queue.extend_from_slice(payload);
+    id
+}
+
+/// Hands out the oldest item deliverable at `now`, milliseconds since the unix epoch,
+/// hiding it for `timeout`: unless acknowledged by then, it is delivered again.
+pub(crate) fn pop(queue: &mut [u8], timeout: Duration, now: u64) -> Option<Delivery> {
+    let at = items(queue).find(|at| read_u64(queue, at + 8) <= now)?;
+    let deliveries = read_u32(queue, at + 16).saturating_add(1);
+    queue[at + 8..at + 16].copy_from_slice(&now.saturating_add(timeout.as_millis() as u64).to_le_bytes());
+    queue[at + 16..at + 20].copy_from_slice(&deliveries.to_le_bytes());
+
+    let len = read_u32(queue, at + 20) as usize;
+    Some(Delivery {
+        id: read_u64(queue, at),
+        deliveries,
+        payload: queue[at + ITEM_HEADER_LEN..at + ITEM_HEADER_LEN + len].to_vec(),
+    })
+}
+
+/// Removes the item `id`, returning whether it was queued.
+pub(crate) fn ack(queue: &mut Vec<u8>, id: u64) -> bool {
+    let Some(at) = items(queue).find(|at| read_u64(queue, *at) == id) else {
+        return false;
+    };
+    let len = read_u32(queue, at + 20) as usize;
+    queue.drain(at..at + ITEM_HEADER_LEN + len);
+    true
+}
+
+impl Delivery {
+    /// An `<id> <deliveries>` line followed by the payload.
+    pub(crate) fn render(self) -> Option<String> {
+        let payload = String::from_utf8(self.payload).ok()?;
+        Some(format!("{} {}\n{}", self.id, self.deliveries, payload))
+    }
+}
+
+// offsets of the items of a valid queue
+fn items(queue: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    let mut at = HEADER_LEN;
+    std::iter::from_fn(move || {
+        if at >= queue.len() {
+            return None;
+        }
+        let item = at;
+        at += ITEM_HEADER_LEN + read_u32(queue, at + 20) as usize;
+        Some(item)
+    })
+}
+
+fn read_u64(queue: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&queue[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn read_u32(queue: &[u8], at: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&queue[at..at + 4]);
+    u32::from_le_bytes(bytes)
+}
@@ -0,0 +1,208 @@
+//! Leases held by a majority of the `--cluster` nodes, Redlock style, so they outlive the
+//! loss of a node: `LOCK`, `RENEW` and `UNLOCK` with `quorum=true` are run by the node
+//! receiving them on every node, itself included, whatever the slot of the lease.
+//!
+//! A lease is taken on every node with the same token, chosen by the node gathering the
+//! quorum: its clock in microseconds times the number of nodes, plus its index. Tokens are
+//! unique to a node and grow with its clock. The lease holds once a majority of the nodes
+//! granted it within its ttl, for what is left of the ttl less an allowance for the clocks
+//! drifting apart; otherwise it is released again wherever it was taken.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use http_types::{Method, Request, StatusCode, Url};
+use log::warn;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use smol::{future::FutureExt, net::TcpStream, Timer};
+
+use crate::{
+    cluster::{Cluster, Layout},
+    errors::TransactionError,
+    storage::Storage,
+};
+
+// time a node has to answer, shortened for leases of a shorter ttl
+const NODE_TIMEOUT: Duration = Duration::from_millis(500);
+// drift allowed between the clocks of the nodes, on top of a hundredth of the ttl
+const MIN_DRIFT: Duration = Duration::from_millis(2);
+
+/// What a lease command does, on every node for `quorum=true` or on the node receiving it
+/// for `quorum=member`, the requests a node gathering a quorum sends.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum LeaseOp {
+    // the token is chosen by the node gathering the quorum, the requests it sends carry it
+    Lock { ttl: Duration, token: Option<u64> },
+    Renew { token: u64, ttl: Duration },
+    Unlock { token: u64 },
+}
+
+impl LeaseOp {
+    /// The command it belongs to.
+    pub(crate) fn command(&self) -> &'static str {
+        match self {
+            LeaseOp::Lock { .. } => "LOCK",
+            LeaseOp::Renew { .. } => "RENEW",
+            LeaseOp::Unlock { .. } => "UNLOCK",
+        }
+    }
+
+    // path of the request asking another node, `name` encoded
+    fn path(&self, name: &str) -> String {
+        let name = utf8_percent_encode(name, NON_ALPHANUMERIC);
+        match self {
+            LeaseOp::Lock { ttl, token } => format!(
+                "/LOCK/{}/{}ms?quorum=member&token={}",
+                name,
+                ttl.as_millis(),
+                token.unwrap_or_default()
+            ),
+            LeaseOp::Renew { token, ttl } => {
+                format!("/RENEW/{}/{}/{}ms?quorum=member", name, token, ttl.as_millis())
+            }
+            LeaseOp::Unlock { token } => format!("/UNLOCK/{}/{}?quorum=member", name, token),
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        match self {
+            LeaseOp::Lock { ttl, .. } | LeaseOp::Renew { ttl, .. } => NODE_TIMEOUT.min(*ttl / 2),
+            LeaseOp::Unlock { .. } => NODE_TIMEOUT,
+        }
+    }
+}
+
+/// Runs `op` on the lease `name` of this node only, for the node gathering a quorum.
+pub(crate) async fn apply(storage: &Storage, name: &str, op: LeaseOp) -> Result<(), TransactionError> {
+    match op {
+        LeaseOp::Lock { ttl, token: Some(token) } => storage.acquire_lock_as(name, ttl, token).await,
+        // the requests of the node gathering the quorum always carry one
+        LeaseOp::Lock { token: None, .. } => Err(TransactionError::LockNotHeld),
+        LeaseOp::Renew { token, ttl } => storage.renew_lock(name, token, ttl).await,
+        LeaseOp::Unlock { token } => storage.release_lock(name, token).await,
+    }
+}
+
+/// Takes the lease `name` for `ttl` on a majority of the nodes, returning its token.
+/// Fails with `LockHeld` when nodes refused it to another holder, `QuorumNotReached` when
+/// too few nodes answered.
+pub(crate) async fn lock(storage: &Storage, cluster: &Cluster, name: &str, ttl: Duration) -> Result<u64, TransactionError> {
+    let started = Instant::now();
+    // the nodes of now, while they change
+    let layout = cluster.layout();
+    let token = next_token(&layout);
+    let outcomes = on_every_node(storage, cluster, &layout, name, LeaseOp::Lock { ttl, token: Some(token) }).await;
+
+    // the nodes asked first let the lease go a little before the last ones
+    let drift = ttl / 100 + MIN_DRIFT;
+    let valid = started.elapsed() + drift < ttl;
+    if valid && granted(&outcomes) >= majority(&layout) {
+        return Ok(token);
+    }
+
+    // the token only releases the lease where this attempt took it
+    on_every_node(storage, cluster, &layout, name, LeaseOp::Unlock { token }).await;
+    match outcomes.iter().any(|outcome| matches!(outcome, Err(TransactionError::LockHeld))) {
+        true => Err(TransactionError::LockHeld),
+        false => Err(TransactionError::QuorumNotReached),
+    }
+}
+
+/// Renews or releases the lease `name` on every node, failing with `LockNotHeld` unless a
+/// majority of them did.
+pub(crate) async fn update(storage: &Storage, cluster: &Cluster, name: &str, op: LeaseOp) -> Result<(), TransactionError> {
+    let layout = cluster.layout();
+    let outcomes = on_every_node(storage, cluster, &layout, name, op).await;
+    match granted(&outcomes) >= majority(&layout) {
+        true => Ok(()),
+        false => Err(TransactionError::LockNotHeld),
+    }
+}
+
+fn majority(layout: &Layout) -> usize {
+    layout.nodes().len() / 2 + 1
+}
+
+fn granted(outcomes: &[Result<(), TransactionError>]) -> usize {
+    outcomes.iter().filter(|outcome| outcome.is_ok()).count()
+}
+
+// unique to this node and growing with its clock
+fn next_token(layout: &Layout) -> u64 {
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    let nodes = layout.nodes().len() as u64;
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros() as u64);
+    let token = micros.saturating_mul(nodes).saturating_add(layout.me() as u64);
+    // tokens taken in the same microsecond follow each other, keeping the index of the node
+    match LAST.fetch_max(token, Ordering::Relaxed) {
+        last if last >= token => LAST.fetch_add(nodes, Ordering::Relaxed) + nodes,
+        _ => token,
+    }
+}
+
+// `op` on this node and, at the same time, on the others
+async fn on_every_node(
+    storage: &Storage,
+    cluster: &Cluster,
+    layout: &Layout,
+    name: &str,
+    op: LeaseOp,
+) -> Vec<Result<(), TransactionError>> {
+    let asked: Vec<_> = layout
+        .nodes()
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| *index != layout.me())
+        .map(|(_, node)| smol::spawn(ask(node.clone(), op.path(name), cluster.api_key().map(str::to_owned), op.timeout())))
+        .collect();
+
+    let mut outcomes = vec![apply(storage, name, op).await];
+    for task in asked {
+        outcomes.push(task.await);
+    }
+    outcomes
+}
+
+// sends `path` to `node`, the errors it answers with mapped back
+async fn ask(node: String, path: String, api_key: Option<String>, timeout: Duration) -> Result<(), TransactionError> {
+    let answer = async {
+        let url = Url::parse(&format!("{}{}", node, path)).map_err(|e| e.to_string())?;
+        if url.scheme() != "http" {
+            return Err(format!("unsupported scheme {}", url.scheme()));
+        }
+        let host = url.host_str().unwrap_or_default().to_string();
+        let port = url.port_or_known_default().unwrap_or(80);
+        let stream = TcpStream::connect((host.as_str(), port)).await.map_err(|e| e.to_string())?;
+
+        let mut request = Request::new(Method::Get, url);
+        if let Some(api_key) = api_key {
+            request.insert_header("X-API-Key", api_key);
+        }
+        let mut response = async_h1::connect(stream, request).await.map_err(|e| e.to_string())?;
+        let body = response.body_string().await.map_err(|e| e.to_string())?;
+        Ok((response.status(), body))
+    }
+    .or(async {
+        Timer::after(timeout).await;
+        Err(format!("no answer within {:?}", timeout))
+    })
+    .await;
+
+    match answer {
+        Ok((status, _)) if status.is_success() => Ok(()),
+        Ok((StatusCode::Conflict, body)) if body.trim() == "lock_held" => Err(TransactionError::LockHeld),
+        Ok((StatusCode::Conflict, body)) if body.trim() == "lock_not_held" => Err(TransactionError::LockNotHeld),
+        Ok((status, body)) => {
+            warn!("quorum lease on {} refused: {} {}", node, status, body.trim());
+            Err(TransactionError::QuorumNotReached)
+        }
+        Err(e) => {
+            warn!("quorum lease on {} failed: {}", node, e);
+            Err(TransactionError::QuorumNotReached)
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+// window (u64, milliseconds), limit (u64), start of the current window (u64, milliseconds
+// since the unix epoch) and requests counted in the current and previous windows (u64),
+// little endian
+const LIMITER_LEN: usize = 5 * 8;
+
+/// Outcome of a request checked against a limiter.
+#[derive(Debug)]
+pub(crate) struct Verdict {
+    pub(crate) allowed: bool,
+    pub(crate) remaining: u64,
+    // until the current window ends
+    pub(crate) reset: Duration,
+}
+
+/// An empty limiter.
+pub(crate) fn create() -> Vec<u8> {
+    vec![0; LIMITER_LEN]
+}
+
+/// Counts a request made at `now`, milliseconds since the unix epoch, if fewer than `limit`
+/// were allowed over the last `window`. Requests of the previous window count in
+/// proportion of how much of it the last `window` still covers.
+///
+/// A limiter created with another window starts over. `None` if `limiter` is not one.
+pub(crate) fn check(limiter: &mut [u8], limit: u64, window: Duration, now: u64) -> Option<Verdict> {
+    if limiter.len() != LIMITER_LEN {
+        return None;
+    }
+    let window_ms = window.as_millis().max(1) as u64;
+    let [stored_window, _, mut start, mut current, mut previous] = read(limiter);
+
+    if stored_window != window_ms || now < start {
+        (start, current, previous) = (now, 0, 0);
+    }
+    let windows = (now - start) / window_ms;
+    if windows > 0 {
+        previous = if windows == 1 { current } else { 0 };
+        current = 0;
+        start += windows * window_ms;
+    }
+
+    let previous_share = 1.0 - (now - start) as f64 / window_ms as f64;
+    let estimate = (previous as f64 * previous_share).floor() as u64 + current;
+    let allowed = estimate < limit;
+    if allowed {
+        current += 1;
+    }
+
+    write(limiter, [window_ms, limit, start, current, previous]);
+    Some(Verdict {
+        allowed,
+        remaining: limit.saturating_sub(estimate + allowed as u64),
+        reset: Duration::from_millis(start + window_ms - now),
+    })
+}
+
+impl Verdict {
+    /// `allowed`, `remaining` and `reset` lines.
+    pub(crate) fn render(&self) -> String {
+        format!(
+            "allowed:{}\nremaining:{}\nreset:{}ms\n",
+            self.allowed,
+            self.remaining,
+            self.reset.as_millis()
+        )
+    }
+}
+
+fn read(limiter: &[u8]) -> [u64; 5] {
+    let mut fields = [0; 5];
+    for (field, bytes) in fields.iter_mut().zip(limiter.chunks_exact(8)) {
+        *field = u64::from_le_bytes(bytes.try_into().unwrap_or_default());
+    }
+    fields
+}
+
+fn write(limiter: &mut [u8], fields: [u64; 5]) {
+    for (bytes, field) in limiter.chunks_exact_mut(8).zip(fields) {
+        bytes.copy_from_slice(&field.to_le_bytes());
+    }
+}
@@ -1,30 +1,144 @@
-use std::time::{Duration, Instant};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use serde::{Deserialize, Serialize};
 
+use crate::errors::TransactionError;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Record {
     pub data: Vec<u8>,
     pub ttl_policy: Option<TTLPolicy>,
+    pub kind: RecordKind,
+    // labels set at write time, grouping records for GETBYTAG and DELBYTAG
+    pub tags: Vec<String>,
+}
+
+/// What the bytes of a record hold, commands of one kind refuse records of another.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordKind {
+    /// Plain value, as written by SET.
+    #[default]
+    Bytes,
+    /// Filter encoded by [`crate::bloom`].
+    Bloom,
+    /// Sketch encoded by [`crate::cms`].
+    CountMin,
+    /// Heavy hitters encoded by [`crate::topk`].
+    TopK,
+    /// Samples encoded by [`crate::timeseries`].
+    TimeSeries,
+    /// Vectors encoded by [`crate::vector`].
+    VectorIndex,
+    /// Lease taken by LOCK, holding its fencing token (u64, little endian).
+    Lock,
+    /// Request counts encoded by [`crate::ratelimit`].
+    RateLimit,
+    /// Permits taken by SEMACQUIRE, encoded by [`crate::semaphore`].
+    Semaphore,
+    /// Items pushed by QPUSH, encoded by [`crate::queue`].
+    Queue,
+    /// Members scored by ZADD, encoded by [`crate::sorted_set`].
+    SortedSet,
+    /// Members added by SADD, encoded by [`crate::set`].
+    Set,
+    /// Items pushed by LPUSH and RPUSH, encoded by [`crate::list`].
+    List,
+    /// Fields set by HSET, encoded by [`crate::hash`].
+    Hash,
+}
+
+impl RecordKind {
+    /// Every kind, in the order of their codes on the replication stream.
+    #[cfg(feature = "replication")]
+    pub(crate) const ALL: [RecordKind; 14] = [
+        RecordKind::Bytes,
+        RecordKind::Bloom,
+        RecordKind::CountMin,
+        RecordKind::TopK,
+        RecordKind::TimeSeries,
+        RecordKind::VectorIndex,
+        RecordKind::Lock,
+        RecordKind::RateLimit,
+        RecordKind::Semaphore,
+        RecordKind::Queue,
+        RecordKind::SortedSet,
+        RecordKind::Set,
+        RecordKind::List,
+        RecordKind::Hash,
+    ];
+}
+
+impl fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordKind::Bytes => write!(f, "bytes"),
+            RecordKind::Bloom => write!(f, "bloom"),
+            RecordKind::CountMin => write!(f, "cms"),
+            RecordKind::TopK => write!(f, "topk"),
+            RecordKind::TimeSeries => write!(f, "timeseries"),
+            RecordKind::VectorIndex => write!(f, "vectorindex"),
+            RecordKind::Lock => write!(f, "lock"),
+            RecordKind::RateLimit => write!(f, "ratelimit"),
+            RecordKind::Semaphore => write!(f, "semaphore"),
+            RecordKind::Queue => write!(f, "queue"),
+            RecordKind::SortedSet => write!(f, "zset"),
+            RecordKind::Set => write!(f, "set"),
+            RecordKind::List => write!(f, "list"),
+            RecordKind::Hash => write!(f, "hash"),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct TTLPolicy {
     pub ttl: Duration,
     pub last_policy_update: Instant,
+    // a sliding ttl starts over on every read
+    pub sliding: bool,
+    // milliseconds from the last update to the last read, shared by the copies of a record
+    read_at: Arc<AtomicU64>,
 }
 
 impl Record {
     pub fn new(data: Vec<u8>, ttl: Option<Duration>) -> Self {
+        Self::typed(RecordKind::Bytes, data, ttl)
+    }
+
+    pub fn typed(kind: RecordKind, data: Vec<u8>, ttl: Option<Duration>) -> Self {
         Self {
             data,
-            ttl_policy: {
-                match ttl {
-                    Some(ttl) => Some(TTLPolicy::new(ttl)),
-                    None => None,
-                }
-            },
+            ttl_policy: ttl.map(TTLPolicy::new),
+            kind,
+            tags: Vec::new(),
+        }
+    }
+
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Makes the ttl of the record, if any, start over on every read.
+    pub fn with_sliding_ttl(mut self) -> Self {
+        if let Some(ttl_policy) = &mut self.ttl_policy {
+            ttl_policy.sliding = true;
         }
+        self
+    }
+
+    /// The data of the record, if it is of the given kind.
+    pub(crate) fn value(&self, kind: RecordKind) -> Result<&[u8], TransactionError> {
+        if self.kind != kind {
+            return Err(TransactionError::WrongType);
+        }
+        Ok(&self.data)
     }
 
     /// Updates the Time-To-Live (TTL) policy of the current instance.
@@ -45,46 +159,63 @@ impl Record {
 
 impl TTLPolicy {
     pub fn new(ttl: Duration) -> Self {
+        Self::started(ttl, Instant::now(), false)
+    }
+
+    /// A policy whose ttl started running at `since`.
+    pub(crate) fn started(ttl: Duration, since: Instant, sliding: bool) -> Self {
         Self {
             ttl,
-            last_policy_update: Instant::now(),
+            last_policy_update: since,
+            sliding,
+            read_at: Arc::default(),
+        }
+    }
+
+    /// Restarts a sliding ttl that has not run out, other ones are left running.
+    pub(crate) fn touch(&self) {
+        if self.sliding && !self.expire_in().is_zero() {
+            let read_at = self.last_policy_update.elapsed().as_millis() as u64;
+            self.read_at.store(read_at, Ordering::Relaxed);
         }
     }
 
-    fn is_expired(&self) -> bool {
-        self.last_policy_update.elapsed() > self.ttl
+    /// Time since the ttl started running: since the last read for a sliding one.
+    pub(crate) fn idle(&self) -> Duration {
+        let read_at = Duration::from_millis(self.read_at.load(Ordering::Relaxed));
+        self.last_policy_update.elapsed().saturating_sub(read_at)
     }
 
     pub fn expire_in(&self) -> Duration {
-        if !self.is_expired() {
-            self.ttl - self.last_policy_update.elapsed()
-        } else {
-            Duration::ZERO
-        }
+        self.ttl.saturating_sub(self.idle())
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct SerializableTTLPolicy {
     ttl_secs: u64,
+    // seconds the ttl has been running
     last_policy_update: u64,
+    sliding: bool,
 }
 
 impl From<TTLPolicy> for SerializableTTLPolicy {
     fn from(policy: TTLPolicy) -> Self {
         SerializableTTLPolicy {
             ttl_secs: policy.ttl.as_secs(),
-            last_policy_update: policy.last_policy_update.elapsed().as_secs(),
+            last_policy_update: policy.idle().as_secs(),
+            sliding: policy.sliding,
         }
     }
 }
 
 impl From<SerializableTTLPolicy> for TTLPolicy {
     fn from(serializable: SerializableTTLPolicy) -> Self {
-        TTLPolicy {
-            ttl: Duration::from_secs(serializable.ttl_secs),
-            last_policy_update: Instant::now() - Duration::from_secs(serializable.last_policy_update),
-        }
+        TTLPolicy::started(
+            Duration::from_secs(serializable.ttl_secs),
+            Instant::now() - Duration::from_secs(serializable.last_policy_update),
+            serializable.sliding,
+        )
     }
 }
 
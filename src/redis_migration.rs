@@ -0,0 +1,196 @@
+use std::error;
+
+use http_types::Url;
+use log::{debug, info, warn};
+use smol::channel::{self, Receiver, Sender};
+
+use crate::{
+    import::encode_binary_entry,
+    remote::{MapperTarget, Redis, Reply},
+};
+
+// keys asked for by every SCAN call, and copied in one import
+const SCAN_COUNT: usize = 1000;
+// keyspace events waiting to be copied while the scan runs
+const FOLLOW_BACKLOG: usize = 100_000;
+
+/// Copies the string keys of a Redis instance matching `pattern`, with their values and
+/// TTLs, into the mapper instance at `to` through `/IMPORT`. Keys of other types and keys
+/// that are not valid UTF-8 are skipped and counted.
+///
+/// With `follow`, keyspace notifications are subscribed to before the scan and the keys
+/// they name are copied again, or deleted, until the process is stopped.
+pub(crate) fn migrate(
+    from: &Url,
+    pattern: Option<&str>,
+    follow: bool,
+    to: &str,
+    api_key: Option<&str>,
+) -> Result<(), Box<dyn error::Error>> {
+    smol::block_on(async {
+        let target = MapperTarget::new(to, api_key);
+        let mut redis = Redis::connect(from).await?;
+
+        // subscribed first, so changes made during the scan are not missed
+        let events = if follow {
+            Some(keyspace_events(&mut redis, from, pattern).await?)
+        } else {
+            None
+        };
+
+        let mut migration = Migration::default();
+        let mut cursor = "0".to_string();
+        loop {
+            let mut scan = vec!["SCAN".as_bytes(), cursor.as_bytes()];
+            if let Some(pattern) = pattern {
+                scan.extend(["MATCH".as_bytes(), pattern.as_bytes()]);
+            }
+            let count = SCAN_COUNT.to_string();
+            scan.extend(["COUNT".as_bytes(), count.as_bytes()]);
+
+            let (next, keys) = match redis.call(&[&scan]).await?.pop() {
+                Some(Reply::Array(Some(mut reply))) if reply.len() == 2 => {
+                    match (reply.remove(0), reply.remove(0)) {
+                        (Reply::Bulk(Some(next)), Reply::Array(Some(keys))) => (next, keys),
+                        _ => return Err("unexpected SCAN reply".into()),
+                    }
+                }
+                Some(Reply::Error(e)) => return Err(e.into()),
+                _ => return Err("unexpected SCAN reply".into()),
+            };
+
+            let keys: Vec<Vec<u8>> = keys
+                .into_iter()
+                .filter_map(|key| match key {
+                    Reply::Bulk(Some(key)) => Some(key),
+                    _ => None,
+                })
+                .collect();
+            migration.copy(&mut redis, &target, keys).await?;
+            info!("copied {} keys, {} skipped", migration.copied, migration.skipped);
+
+            cursor = String::from_utf8(next)?;
+            if cursor == "0" {
+                break;
+            }
+        }
+        info!("scan done: {} keys copied, {} skipped", migration.copied, migration.skipped);
+
+        let Some(events) = events else {
+            return Ok(());
+        };
+        info!("following changes until stopped");
+        while let Ok(key) = events.recv().await {
+            migration.copy(&mut redis, &target, vec![key]).await?;
+            if events.is_empty() {
+                info!("in sync: {} changes applied, {} deletes", migration.copied, migration.deleted);
+            }
+        }
+        Err("keyspace notifications connection closed".into())
+    })
+}
+
+#[derive(Default)]
+struct Migration {
+    copied: u64,
+    skipped: u64,
+    deleted: u64,
+}
+
+impl Migration {
+    /// Copies `keys` as they are now, keys gone from Redis are deleted from mapper.
+    async fn copy(&mut self, redis: &mut Redis, target: &MapperTarget, keys: Vec<Vec<u8>>) -> Result<(), Box<dyn error::Error>> {
+        let mut replies = {
+            let commands: Vec<[&[u8]; 2]> = keys
+                .iter()
+                .flat_map(|key| [[b"GET".as_slice(), key], [b"PTTL".as_slice(), key]])
+                .collect();
+            let commands: Vec<&[&[u8]]> = commands.iter().map(|command| command.as_slice()).collect();
+            redis.call(&commands).await?.into_iter()
+        };
+
+        let mut import = Vec::new();
+        let mut imported = 0;
+        for key in keys {
+            let (value, pttl) = (replies.next(), replies.next());
+            let Ok(key) = String::from_utf8(key) else {
+                self.skipped += 1;
+                continue;
+            };
+            match (value, pttl) {
+                (Some(Reply::Bulk(Some(value))), Some(Reply::Integer(pttl))) if pttl != -2 => {
+                    // -1 means no ttl, a key about to expire keeps at least a millisecond
+                    let ttl_ms = if pttl == -1 { 0 } else { pttl.max(1) as u64 };
+                    encode_binary_entry(&mut import, &key, &value, ttl_ms);
+                    imported += 1;
+                }
+                // expired or deleted since it was named
+                (Some(Reply::Bulk(None)), _) | (_, Some(Reply::Integer(-2))) => {
+                    target.delete(&key).await?;
+                    self.deleted += 1;
+                }
+                (Some(Reply::Error(e)), _) => {
+                    debug!("{}: skipped: {}", key, e);
+                    self.skipped += 1;
+                }
+                _ => return Err(format!("{}: unexpected GET or PTTL reply", key).into()),
+            }
+        }
+
+        if imported > 0 {
+            target.import(import).await?;
+            self.copied += imported;
+        }
+        Ok(())
+    }
+}
+
+/// A second connection receiving the keys changed in the database of `url`.
+async fn keyspace_events(redis: &mut Redis, url: &Url, pattern: Option<&str>) -> Result<Receiver<Vec<u8>>, Box<dyn error::Error>> {
+    // without the K flag and the generic, string and expired classes, changes go unseen
+    if let Some(Reply::Array(Some(config))) = redis
+        .call(&[&[b"CONFIG", b"GET", b"notify-keyspace-events"]])
+        .await?
+        .pop()
+    {
+        if let Some(Reply::Bulk(Some(flags))) = config.get(1) {
+            let flags = String::from_utf8_lossy(flags);
+            let classes = flags.contains('A') || ['g', '$', 'x'].iter().all(|class| flags.contains(*class));
+            if !flags.contains('K') || !classes {
+                return Err(format!("notify-keyspace-events is \"{}\", following needs at least \"Kg$x\"", flags).into());
+            }
+        }
+    }
+
+    let db = url.path().trim_start_matches('/');
+    let prefix = format!("__keyspace@{}__:", if db.is_empty() { "0" } else { db });
+    let channel = format!("{}{}", prefix, pattern.unwrap_or("*"));
+    let mut events = Redis::connect(url).await?;
+    events.send(&[&[b"PSUBSCRIBE", channel.as_bytes()]]).await?;
+    events.read().await?;
+
+    let (sender, receiver) = channel::bounded(FOLLOW_BACKLOG);
+    smol::spawn(forward_events(events, prefix, sender)).detach();
+    Ok(receiver)
+}
+
+async fn forward_events(mut events: Redis, prefix: String, sender: Sender<Vec<u8>>) {
+    loop {
+        let message = match events.read().await {
+            Ok(Reply::Array(Some(message))) => message,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("keyspace notifications: {}", e);
+                return;
+            }
+        };
+        // pmessage, pattern, channel, event
+        if let Some(Reply::Bulk(Some(channel))) = message.get(2) {
+            if let Some(key) = channel.strip_prefix(prefix.as_bytes()) {
+                if sender.send(key.to_vec()).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
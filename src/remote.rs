@@ -0,0 +1,224 @@
+// replication only follows a stream, copying keys and redis are left unused
+#![cfg_attr(not(any(feature = "migrate", feature = "mirror")), allow(dead_code, unused_imports))]
+
+use std::{error, future::Future, pin::Pin};
+
+use http_types::{Method, Request, Response, Url};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use smol::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::import::BulkFormat;
+
+/// Mapper instance keys are copied into, or writes followed from, over the HTTP API.
+#[derive(Debug, Clone)]
+pub(crate) struct MapperTarget {
+    address: String,
+    api_key: Option<String>,
+    // sent along the api key, to admin endpoints of instances with --admin-key
+    admin_key: Option<String>,
+}
+
+impl MapperTarget {
+    pub(crate) fn new(address: &str, api_key: Option<&str>) -> Self {
+        Self {
+            address: address.to_string(),
+            api_key: api_key.map(str::to_owned),
+            admin_key: None,
+        }
+    }
+
+    #[cfg(feature = "replication")]
+    pub(crate) fn with_admin_key(mut self, admin_key: Option<&str>) -> Self {
+        self.admin_key = admin_key.map(str::to_owned);
+        self
+    }
+
+    /// Imports entries in the [`BulkFormat::Binary`] encoding.
+    pub(crate) async fn import(&self, body: Vec<u8>) -> Result<(), String> {
+        let mut request = self.request(Method::Put, "/IMPORT")?;
+        request.set_body(body);
+        request.set_content_type(BulkFormat::Binary.content_type().into());
+        self.send(request).await
+    }
+
+    pub(crate) async fn delete(&self, key: &str) -> Result<(), String> {
+        let path = format!("/DEL/{}", utf8_percent_encode(key, NON_ALPHANUMERIC));
+        match self.send(self.request(Method::Get, &path)?).await {
+            // never copied, or already gone
+            Err(e) if e.starts_with("404") => Ok(()),
+            outcome => outcome,
+        }
+    }
+
+    #[cfg(feature = "mirror")]
+    pub(crate) async fn flush_all(&self) -> Result<(), String> {
+        self.send(self.request(Method::Get, "/FLUSHALL")?).await
+    }
+
+    #[cfg(any(feature = "mirror", feature = "replication"))]
+    pub(crate) fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn request(&self, method: Method, path: &str) -> Result<Request, String> {
+        let url = Url::parse(&format!("http://{}{}", self.address, path)).map_err(|e| e.to_string())?;
+        let mut request = Request::new(method, url);
+        if let Some(api_key) = &self.api_key {
+            request.insert_header("X-API-Key", api_key);
+        }
+        if let Some(admin_key) = &self.admin_key {
+            request.insert_header("X-Admin-Key", admin_key);
+        }
+        Ok(request)
+    }
+
+    /// Sends a GET of `path`, returning the response once its head is read, its body still
+    /// to be read from the connection.
+    #[cfg(feature = "replication")]
+    pub(crate) async fn open(&self, path: &str) -> Result<Response, String> {
+        let mut response = self.connect(self.request(Method::Get, path)?).await?;
+        if !response.status().is_success() {
+            let body = response.body_string().await.map_err(|e| e.to_string())?;
+            return Err(format!("{} {}", response.status(), body.trim()));
+        }
+        Ok(response)
+    }
+
+    async fn send(&self, request: Request) -> Result<(), String> {
+        let mut response = self.connect(request).await?;
+        let body = response.body_string().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("{} {}", response.status(), body.trim()));
+        }
+        Ok(())
+    }
+
+    async fn connect(&self, request: Request) -> Result<Response, String> {
+        let stream = TcpStream::connect(self.address.as_str())
+            .await
+            .map_err(|e| format!("{}: {}", self.address, e))?;
+        async_h1::connect(stream, request).await.map_err(|e| e.to_string())
+    }
+}
+
+/// Reply of the Redis serialization protocol (RESP2).
+// mirroring only looks for errors
+#[cfg_attr(not(feature = "migrate"), allow(dead_code))]
+#[derive(Debug)]
+pub(crate) enum Reply {
+    // status replies, like OK
+    Simple,
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+/// Connection to a Redis instance, replies are read in the order commands were sent.
+pub(crate) struct Redis {
+    stream: BufReader<TcpStream>,
+}
+
+impl Redis {
+    /// Connects to a `redis://[user:password@]host[:port][/db]` url.
+    pub(crate) async fn connect(url: &Url) -> Result<Self, Box<dyn error::Error>> {
+        if url.scheme() != "redis" {
+            return Err(format!("unsupported scheme {}, expected redis://", url.scheme()).into());
+        }
+        let host = url.host_str().ok_or("redis url without host")?;
+        let stream = TcpStream::connect((host, url.port().unwrap_or(6379))).await?;
+        let mut redis = Self {
+            stream: BufReader::new(stream),
+        };
+
+        if let Some(password) = url.password() {
+            let password = percent_decode_str(password).collect::<Vec<u8>>();
+            let username = percent_decode_str(url.username()).collect::<Vec<u8>>();
+            let auth: Vec<&[u8]> = if username.is_empty() {
+                vec![b"AUTH", &password]
+            } else {
+                vec![b"AUTH", &username, &password]
+            };
+            redis.expect_ok(&auth).await?;
+        }
+        let db = url.path().trim_start_matches('/');
+        if !db.is_empty() {
+            redis.expect_ok(&[b"SELECT", db.as_bytes()]).await?;
+        }
+        Ok(redis)
+    }
+
+    pub(crate) async fn expect_ok(&mut self, command: &[&[u8]]) -> Result<(), Box<dyn error::Error>> {
+        match self.call(&[command]).await?.pop() {
+            Some(Reply::Simple) => Ok(()),
+            Some(Reply::Error(e)) => Err(e.into()),
+            reply => Err(format!("unexpected reply: {:?}", reply).into()),
+        }
+    }
+
+    /// Sends the commands at once and reads their replies.
+    pub(crate) async fn call(&mut self, commands: &[&[&[u8]]]) -> Result<Vec<Reply>, String> {
+        self.send(commands).await?;
+        let mut replies = Vec::with_capacity(commands.len());
+        for _ in commands {
+            replies.push(self.read().await?);
+        }
+        Ok(replies)
+    }
+
+    pub(crate) async fn send(&mut self, commands: &[&[&[u8]]]) -> Result<(), String> {
+        let mut buff = Vec::new();
+        for command in commands {
+            buff.extend_from_slice(format!("*{}\r\n", command.len()).as_bytes());
+            for arg in command.iter() {
+                buff.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+                buff.extend_from_slice(arg);
+                buff.extend_from_slice(b"\r\n");
+            }
+        }
+        self.stream.get_mut().write_all(&buff).await.map_err(|e| e.to_string())
+    }
+
+    pub(crate) fn read(&mut self) -> Pin<Box<dyn Future<Output = Result<Reply, String>> + Send + '_>> {
+        Box::pin(async move {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await.map_err(|e| e.to_string())? == 0 {
+                return Err("connection closed".to_string());
+            }
+            let line = line.trim_end_matches("\r\n");
+            let (kind, rest) = line.split_at(line.len().min(1));
+            let len = || rest.parse::<i64>().map_err(|_| format!("malformed reply: {}", line));
+            let size = |len: i64| usize::try_from(len).map_err(|_| format!("malformed reply: {}", line));
+            match kind {
+                "+" => Ok(Reply::Simple),
+                "-" => Ok(Reply::Error(rest.to_string())),
+                ":" => Ok(Reply::Integer(len()?)),
+                "$" => match len()? {
+                    -1 => Ok(Reply::Bulk(None)),
+                    len => {
+                        let len = size(len)?;
+                        let mut bulk = vec![0; len + 2];
+                        self.stream.read_exact(&mut bulk).await.map_err(|e| e.to_string())?;
+                        bulk.truncate(len);
+                        Ok(Reply::Bulk(Some(bulk)))
+                    }
+                },
+                "*" => match len()? {
+                    -1 => Ok(Reply::Array(None)),
+                    len => {
+                        let len = size(len)?;
+                        let mut items = Vec::with_capacity(len);
+                        for _ in 0..len {
+                            items.push(self.read().await?);
+                        }
+                        Ok(Reply::Array(Some(items)))
+                    }
+                },
+                _ => Err(format!("malformed reply: {}", line)),
+            }
+        })
+    }
+}
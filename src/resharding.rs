@@ -0,0 +1,171 @@
+use std::{collections::BTreeMap, fmt};
+
+use log::{error, info};
+
+use crate::{errors::TransactionError, storage::Storage};
+
+// slots moved under a single pair of shard write locks
+const MIGRATION_BATCH_SLOTS: usize = 8;
+
+/// Progress of a background slot migration.
+#[derive(Debug, Clone)]
+pub(crate) struct Resharding {
+    pub(crate) operation: String,
+    pub(crate) moved_slots: usize,
+    pub(crate) total_slots: usize,
+}
+
+impl fmt::Display for Resharding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}/{} slots)", self.operation, self.moved_slots, self.total_slots)
+    }
+}
+
+/// A batch of slots handed over from one shard to another.
+struct SlotMove {
+    from: usize,
+    to: usize,
+    slots: Vec<usize>,
+}
+
+/// Splits a shard in two: half of its slots are moved into a newly activated shard
+/// while the storage keeps serving traffic. Returns the index of the new shard.
+pub(crate) fn split_shard(storage: &Storage, shard_index: usize) -> Result<usize, TransactionError> {
+    let mut resharding = storage.resharding.lock().unwrap();
+    if resharding.is_some() {
+        return Err(TransactionError::ReshardingInProgress);
+    }
+
+    if shard_index >= storage.shard_count() {
+        return Err(TransactionError::ShardNotFound);
+    }
+
+    let owned_slots = storage.owned_slots(shard_index);
+    if owned_slots.len() < 2 {
+        return Err(TransactionError::UnsplittableShard);
+    }
+
+    let new_shard = storage.add_shard()?;
+    let moving = owned_slots[owned_slots.len() / 2..].to_vec();
+
+    *resharding = Some(Resharding {
+        operation: format!("splitting shard {} into {}", shard_index, new_shard),
+        moved_slots: 0,
+        total_slots: moving.len(),
+    });
+
+    let moves = moving
+        .chunks(MIGRATION_BATCH_SLOTS)
+        .map(|slots| SlotMove {
+            from: shard_index,
+            to: new_shard,
+            slots: slots.to_vec(),
+        })
+        .collect();
+    smol::spawn(migrate(storage.clone(), moves, None)).detach();
+
+    Ok(new_shard)
+}
+
+/// Changes the total number of shards, every slot ends up owned by `slot % shard_count`.
+/// Keys are migrated in the background, when shrinking the exceeding shards are
+/// deactivated only once they have been drained. Returns the number of slots to move.
+pub(crate) fn resize(storage: &Storage, shard_count: usize) -> Result<usize, TransactionError> {
+    let mut resharding = storage.resharding.lock().unwrap();
+    if resharding.is_some() {
+        return Err(TransactionError::ReshardingInProgress);
+    }
+
+    if shard_count == 0 || shard_count > storage.max_shard_count() {
+        return Err(TransactionError::InvalidShardCount);
+    }
+
+    let mut moving: BTreeMap<(usize, usize), Vec<usize>> = BTreeMap::new();
+    for (slot, owner) in storage.layout().into_iter().enumerate() {
+        let target = slot % shard_count;
+        if owner != target {
+            moving.entry((owner, target)).or_default().push(slot);
+        }
+    }
+    let total_slots = moving.values().map(Vec::len).sum();
+
+    let previous_count = storage.shard_count();
+    if shard_count > previous_count {
+        storage.set_shard_count(shard_count);
+    }
+
+    *resharding = Some(Resharding {
+        operation: format!("resizing from {} to {} shards", previous_count, shard_count),
+        moved_slots: 0,
+        total_slots,
+    });
+
+    let moves = moving
+        .into_iter()
+        .flat_map(|((from, to), slots)| {
+            slots
+                .chunks(MIGRATION_BATCH_SLOTS)
+                .map(|slots| SlotMove {
+                    from,
+                    to,
+                    slots: slots.to_vec(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let final_count = (shard_count < previous_count).then_some(shard_count);
+    smol::spawn(migrate(storage.clone(), moves, final_count)).detach();
+
+    Ok(total_slots)
+}
+
+/// Moves the slots batch by batch. A cancelled migration stops between batches, moved
+/// slots stay with their new owner and drained shards are left active.
+async fn migrate(storage: Storage, moves: Vec<SlotMove>, final_shard_count: Option<usize>) {
+    let (description, total_slots) = match storage.resharding.lock().unwrap().as_ref() {
+        Some(resharding) => (resharding.operation.clone(), resharding.total_slots),
+        None => (String::new(), 0),
+    };
+    let operation = storage.operations.start("reshard", description);
+    operation.progress(0, total_slots as u64);
+
+    let mut moved_slots = 0;
+    for slot_move in moves {
+        if operation.is_cancelled() {
+            break;
+        }
+
+        // a shard failing to load stops the migration like a cancellation
+        if let Err(e) = storage.migrate_slots(slot_move.from, slot_move.to, &slot_move.slots).await {
+            if let Some(resharding) = storage.resharding.lock().unwrap().take() {
+                error!("{} stopped: {}", resharding, e);
+            }
+            return;
+        }
+
+        moved_slots += slot_move.slots.len();
+        if let Some(resharding) = storage.resharding.lock().unwrap().as_mut() {
+            resharding.moved_slots = moved_slots;
+        }
+        operation.progress(moved_slots as u64, total_slots as u64);
+
+        // let queued requests grab the shards between batches
+        smol::future::yield_now().await;
+    }
+
+    if operation.is_cancelled() {
+        if let Some(resharding) = storage.resharding.lock().unwrap().take() {
+            info!("{} cancelled", resharding);
+        }
+        return;
+    }
+
+    // drained shards own no slot anymore and can be deactivated
+    if let Some(shard_count) = final_shard_count {
+        storage.set_shard_count(shard_count);
+    }
+
+    if let Some(resharding) = storage.resharding.lock().unwrap().take() {
+        info!("{} completed", resharding);
+    }
+}
@@ -0,0 +1,254 @@
+//! Backups kept in an S3 compatible bucket with `--backup-s3-bucket`: the archive of every
+//! backup cycle is uploaded once written and verified, and downloaded at startup when there
+//! is no local backup to recover from, for instances whose disk does not outlive them.
+//!
+//! Requests are signed with AWS signature version 4 and name the bucket in the path,
+//! `<endpoint>/<bucket>/<key>`, which minio and the other S3 compatible stores accept too.
+
+use std::{io, path::Path, sync::Arc, time::{Duration, SystemTime}};
+
+use futures_rustls::{
+    rustls::{crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use hmac::{Hmac, Mac};
+use http_types::{Method, Request, Response, StatusCode, Url};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+use smol::{fs::File, future::FutureExt, io::BufReader, net::TcpStream, Timer};
+
+// characters left as they are in the path of a signed request
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+// sha256 of an empty payload, the one of downloads
+const EMPTY_PAYLOAD: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+// uploads stream the archive, it is not hashed beforehand
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+// object holding the archive, after --backup-s3-prefix
+const OBJECT_NAME: &str = "mapper-backup.zip";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) const DEFAULT_S3_REGION: &str = "us-east-1";
+
+/// Credentials requests are signed with.
+pub(crate) struct Credentials {
+    pub(crate) access_key: String,
+    pub(crate) secret_key: String,
+    // temporary credentials come with one
+    pub(crate) session_token: Option<String>,
+}
+
+pub(crate) struct Bucket {
+    // scheme, host and port of the store
+    endpoint: Url,
+    name: String,
+    region: String,
+    prefix: String,
+    credentials: Credentials,
+    // https endpoints only
+    tls: Option<TlsConnector>,
+}
+
+impl Bucket {
+    /// The bucket `name` of the store at `endpoint`, AWS in `region` without one, the archive
+    /// being stored under `prefix`.
+    pub(crate) fn new(
+        endpoint: Option<&str>,
+        name: &str,
+        region: &str,
+        prefix: &str,
+        credentials: Credentials,
+    ) -> Result<Self, String> {
+        let endpoint = endpoint.map_or_else(|| format!("https://s3.{}.amazonaws.com", region), str::to_owned);
+        let endpoint = Url::parse(&endpoint).map_err(|e| format!("invalid --backup-s3-endpoint {}: {}", endpoint, e))?;
+        if endpoint.host_str().is_none() || !matches!(endpoint.scheme(), "http" | "https") {
+            return Err(format!("invalid --backup-s3-endpoint {}, expected http(s)://host[:port]", endpoint));
+        }
+        if name.is_empty() || name.contains('/') {
+            return Err(format!("invalid --backup-s3-bucket {}", name));
+        }
+        if prefix.split('/').any(|segment| segment == "." || segment == "..") {
+            return Err(format!("invalid --backup-s3-prefix {}", prefix));
+        }
+
+        let tls = (endpoint.scheme() == "https")
+            .then(|| {
+                let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                    .with_safe_default_protocol_versions()
+                    .map(|builder| builder.with_root_certificates(roots).with_no_client_auth())
+                    .map(|config| TlsConnector::from(Arc::new(config)))
+                    .map_err(|e| e.to_string())
+            })
+            .transpose()?;
+        Ok(Self {
+            endpoint,
+            name: name.to_string(),
+            region: region.to_string(),
+            prefix: prefix.to_string(),
+            credentials,
+            tls,
+        })
+    }
+
+    /// Url of the archive, for logs.
+    pub(crate) fn object_url(&self) -> String {
+        format!("{}{}", self.endpoint.origin().ascii_serialization(), self.object_path())
+    }
+
+    /// Uploads the archive at `archive`, in place of the one of the bucket.
+    pub(crate) async fn upload(&self, archive: &Path) -> Result<(), String> {
+        let file = File::open(archive)
+            .await
+            .map_err(|e| format!("unable to read {}: {}", archive.display(), e))?;
+        let len = file.metadata().await.map_err(|e| e.to_string())?.len();
+
+        let mut request = self.request(Method::Put, UNSIGNED_PAYLOAD)?;
+        request.set_body(http_types::Body::from_reader(BufReader::new(file), Some(len as usize)));
+        request.set_content_type("application/zip".into());
+        let response = self.send(request).await?;
+        if !response.status().is_success() {
+            return Err(failure(response).await);
+        }
+        Ok(())
+    }
+
+    /// Downloads the archive of the bucket to `archive`, `false` when the bucket holds none.
+    pub(crate) async fn download(&self, archive: &Path) -> Result<bool, String> {
+        let mut response = self.send(self.request(Method::Get, EMPTY_PAYLOAD)?).await?;
+        match response.status() {
+            StatusCode::NotFound => {
+                let reason = failure(response).await;
+                // a missing bucket is a mistake, a missing archive a first start
+                return match reason.contains("NoSuchBucket") {
+                    true => Err(reason),
+                    false => Ok(false),
+                };
+            }
+            status if !status.is_success() => return Err(failure(response).await),
+            _ => {}
+        }
+
+        let mut file = File::create(archive)
+            .await
+            .map_err(|e| format!("unable to create {}: {}", archive.display(), e))?;
+        smol::io::copy(&mut response, &mut file)
+            .await
+            .map_err(|e| format!("unable to download to {}: {}", archive.display(), e))?;
+        file.sync_all().await.map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+
+    // `/<bucket>/<prefix><archive>`, every segment encoded
+    fn object_path(&self) -> String {
+        let key = format!("{}{}", self.prefix, OBJECT_NAME);
+        let segments: Vec<String> = [self.name.as_str()]
+            .into_iter()
+            .chain(key.split('/'))
+            .map(|segment| utf8_percent_encode(segment, UNRESERVED).to_string())
+            .collect();
+        format!("/{}", segments.join("/"))
+    }
+
+    // a request on the archive, signed with signature version 4
+    fn request(&self, method: Method, payload_hash: &str) -> Result<Request, String> {
+        let path = self.object_path();
+        let url = self.endpoint.join(&path).map_err(|e| e.to_string())?;
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{}", self.endpoint.host_str().unwrap_or_default(), port),
+            None => self.endpoint.host_str().unwrap_or_default().to_string(),
+        };
+        // 20240131T235959Z, the day being its first 8 characters
+        let amz_date: String = humantime::format_rfc3339_seconds(SystemTime::now())
+            .to_string()
+            .chars()
+            .filter(|c| *c != '-' && *c != ':')
+            .collect();
+        let scope = format!("{}/{}/s3/aws4_request", &amz_date[..8], self.region);
+
+        // sorted by name
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(session_token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", session_token.clone()));
+        }
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_request = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method, path, canonical_headers, signed_headers, payload_hash
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let secret = format!("AWS4{}", self.credentials.secret_key);
+        let signing_key = [&amz_date[..8], &self.region, "s3", "aws4_request"]
+            .iter()
+            .fold(secret.into_bytes(), |key, part| hmac(&key, part));
+        let signature = hex(&hmac(&signing_key, &string_to_sign));
+
+        let mut request = Request::new(method, url);
+        for (name, value) in headers {
+            request.insert_header(name, value);
+        }
+        request.insert_header(
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.credentials.access_key, scope, signed_headers, signature
+            ),
+        );
+        Ok(request)
+    }
+
+    async fn send(&self, request: Request) -> Result<Response, String> {
+        let host = self.endpoint.host_str().unwrap_or_default();
+        let port = self.endpoint.port_or_known_default().unwrap_or(443);
+        let stream = TcpStream::connect((host, port))
+            .or(async {
+                Timer::after(CONNECT_TIMEOUT).await;
+                Err(io::ErrorKind::TimedOut.into())
+            })
+            .await
+            .map_err(|e| format!("{}: {}", self.endpoint, e))?;
+
+        let response = match &self.tls {
+            Some(tls) => {
+                let server_name = ServerName::try_from(host.to_string()).map_err(|e| e.to_string())?;
+                let stream = tls
+                    .connect(server_name, stream)
+                    .await
+                    .map_err(|e| format!("{}: {}", self.endpoint, e))?;
+                async_h1::connect(stream, request).await
+            }
+            None => async_h1::connect(stream, request).await,
+        };
+        response.map_err(|e| e.to_string())
+    }
+}
+
+// S3 errors are xml documents, their <Code> says enough
+async fn failure(mut response: Response) -> String {
+    let body = response.body_string().await.unwrap_or_default();
+    let code = body
+        .split_once("<Code>")
+        .and_then(|(_, rest)| rest.split_once("</Code>"))
+        .map_or(body.trim(), |(code, _)| code);
+    format!("{} {}", response.status(), code)
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
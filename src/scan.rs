@@ -0,0 +1,57 @@
+//! Incremental iteration over the keyspace for `/SCAN`: every call returns a few keys and a
+//! cursor to resume from, so no shard stays read locked for the whole keyspace.
+//!
+//! Within a shard keys come in byte order, the cursor holding the last one returned, so keys
+//! added or removed meanwhile do not shift the others. Keys moved by a reshard during the
+//! iteration may be missed or returned twice.
+
+use std::{fmt, str::FromStr};
+
+// keys looked at by a /SCAN call without a count
+pub(crate) const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Position of an iteration: the shard it is in and the last key returned from it, the
+/// start of the shard without one. Written `0` at the start and once done, otherwise
+/// `<shard>.<last key in hex>`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Cursor {
+    pub(crate) shard: usize,
+    pub(crate) after: Option<String>,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.after {
+            Some(after) => {
+                let hex: String = after.bytes().map(|byte| format!("{:02x}", byte)).collect();
+                write!(f, "{}.{}", self.shard, hex)
+            }
+            None => write!(f, "{}", self.shard),
+        }
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (shard, after) = match s.split_once('.') {
+            Some((shard, hex)) => (shard, Some(hex)),
+            None => (s, None),
+        };
+        let shard = shard.parse().map_err(|_| ())?;
+        let after = match after {
+            Some(hex) if hex.len() % 2 == 0 && hex.is_ascii() => {
+                let bytes = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                    .collect::<Result<Vec<u8>, _>>()
+                    .map_err(|_| ())?;
+                Some(String::from_utf8(bytes).map_err(|_| ())?)
+            }
+            Some(_) => return Err(()),
+            None => None,
+        };
+        Ok(Cursor { shard, after })
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error};
+use smol::{
+    channel::{Receiver, Sender},
+    future::race,
+    Timer,
+};
+
+use crate::{
+    errors::TransactionError,
+    record::Record,
+    storage::{SetCondition, Storage},
+};
+
+/// Writes scheduled by SETAT and DELAT, listed and cancelled through `/SCHEDULED`.
+///
+/// Each one waits on its own timer like the ttl of a record does. Schedules live in
+/// memory only, a restart drops them.
+#[derive(Debug, Default)]
+pub(crate) struct Schedules {
+    next_id: AtomicU64,
+    pending: Mutex<BTreeMap<u64, Scheduled>>,
+}
+
+#[derive(Debug)]
+struct Scheduled {
+    key: String,
+    command: &'static str,
+    // milliseconds since the unix epoch
+    at: u64,
+    cancel: Sender<()>,
+}
+
+/// What a schedule does to its key once due.
+#[derive(Debug)]
+pub(crate) enum ScheduledWrite {
+    Set(Record),
+    Del,
+}
+
+impl ScheduledWrite {
+    fn command(&self) -> &'static str {
+        match self {
+            ScheduledWrite::Set(_) => "SET",
+            ScheduledWrite::Del => "DEL",
+        }
+    }
+}
+
+impl Schedules {
+    /// Forgets a pending schedule and wakes its timer.
+    pub(crate) fn cancel(&self, id: u64) -> Result<(), TransactionError> {
+        match self.pending.lock().unwrap().remove(&id) {
+            Some(scheduled) => {
+                let _ = scheduled.cancel.try_send(());
+                Ok(())
+            }
+            None => Err(TransactionError::ScheduleNotFound),
+        }
+    }
+
+    /// One `<id> <at> <command> <key>` line per pending schedule.
+    pub(crate) fn list(&self) -> String {
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, scheduled)| format!("{} {} {} {}\n", id, scheduled.at, scheduled.command, scheduled.key))
+            .collect()
+    }
+
+    // whoever takes a schedule out first, its timer or a cancel, decides its fate
+    fn take(&self, id: u64) -> bool {
+        self.pending.lock().unwrap().remove(&id).is_some()
+    }
+}
+
+/// Applies `write` to `key` at `at`, milliseconds since the unix epoch, right away if
+/// it has passed. Returns the id of the schedule.
+pub(crate) fn schedule(storage: &Storage, key: String, write: ScheduledWrite, at: u64) -> u64 {
+    let schedules = &storage.schedules;
+    let id = schedules.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+    let (cancel_s, cancel_r) = smol::channel::bounded(1);
+    schedules.pending.lock().unwrap().insert(
+        id,
+        Scheduled {
+            key: key.clone(),
+            command: write.command(),
+            at,
+            cancel: cancel_s,
+        },
+    );
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    let delay = Duration::from_millis(at.saturating_sub(now));
+    smol::spawn(run(storage.clone(), id, key, write, delay, cancel_r)).detach();
+    id
+}
+
+async fn run(storage: Storage, id: u64, key: String, write: ScheduledWrite, delay: Duration, cancel: Receiver<()>) {
+    let due = race(
+        async {
+            let _ = cancel.recv().await;
+            false
+        },
+        async {
+            Timer::after(delay).await;
+            true
+        },
+    )
+    .await;
+    if !due || !storage.schedules.take(id) {
+        debug!("schedule {} on key {} cancelled", id, key);
+        return;
+    }
+
+    let applied = match write {
+        ScheduledWrite::Set(record) => storage.set_record(&key, record, SetCondition::Always).await.map(|_| ()),
+        ScheduledWrite::Del => storage.remove_record(&key).await.map(|_| ()),
+    };
+    if let Err(e) = applied {
+        error!("scheduled write {} on key {} failed: {}", id, key, e);
+    }
+}
@@ -0,0 +1,138 @@
+//! Scripts of `/EVAL`, in Rhai, for read-modify-write logic run in one request: the shards
+//! of the keys a script declares stay write locked from its first read to its last write.
+//!
+//! A script works on a copy of the declared records, put in place once it returns, so a
+//! failing one writes nothing. It is stopped after a fixed number of operations, an endless
+//! loop would otherwise hold its shards forever.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use humantime::parse_duration;
+use log::debug;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Scope, AST};
+
+use crate::{
+    errors::TransactionError,
+    journal::ChangeKind,
+    record::{Record, RecordKind},
+};
+
+// operations of a script before it is stopped
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Final record of every key a script changed, `None` once removed, with the change to
+/// journal.
+pub(crate) type Changes = HashMap<String, (Option<Record>, ChangeKind)>;
+
+#[derive(Default)]
+struct State {
+    records: HashMap<String, Option<Record>>,
+    changes: HashMap<String, ChangeKind>,
+}
+
+impl State {
+    // the record of a declared key, an error for the others
+    fn record(&mut self, key: &str) -> Result<&mut Option<Record>, Box<EvalAltResult>> {
+        self.records
+            .get_mut(key)
+            .ok_or_else(|| format!("key {} is not declared in keys", key).into())
+    }
+}
+
+pub(crate) fn compile(script: &str) -> Result<AST, TransactionError> {
+    Engine::new()
+        .compile(script)
+        .map_err(|e| TransactionError::ScriptFailed(e.to_string()))
+}
+
+/// Runs `ast` with the `current` records of `keys`, returning what it evaluates to, empty
+/// for nothing, with the records it changed.
+pub(crate) fn run(
+    ast: &AST,
+    keys: &[String],
+    args: &[String],
+    current: HashMap<String, Option<Record>>,
+) -> Result<(String, Changes), TransactionError> {
+    let state = Arc::new(Mutex::new(State {
+        records: current,
+        ..State::default()
+    }));
+    let engine = engine(&state);
+
+    let strings = |strings: &[String]| strings.iter().cloned().map(Dynamic::from).collect::<Array>();
+    let mut scope = Scope::new();
+    scope.push_constant("KEYS", strings(keys));
+    scope.push_constant("ARGV", strings(args));
+
+    let result = engine
+        .eval_ast_with_scope::<Dynamic>(&mut scope, ast)
+        .map_err(|e| TransactionError::ScriptFailed(e.to_string()))?;
+    drop(engine);
+
+    let mut state = state.lock().unwrap();
+    let State { records, changes } = &mut *state;
+    let changes = changes
+        .drain()
+        .map(|(key, kind)| {
+            let record = records.remove(&key).flatten();
+            (key, (record, kind))
+        })
+        .collect();
+    let result = if result.is_unit() { String::new() } else { result.to_string() };
+    Ok((result, changes))
+}
+
+// `get`, `set`, `del` and `expire` over the declared records
+fn engine(state: &Arc<Mutex<State>>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.on_print(|text| debug!("script printed {}", text));
+    engine.on_debug(|text, _, _| debug!("script printed {}", text));
+
+    let get_state = state.clone();
+    engine.register_fn("get", move |key: &str| -> Result<Dynamic, Box<EvalAltResult>> {
+        let mut state = get_state.lock().unwrap();
+        let Some(record) = state.record(key)? else {
+            return Ok(Dynamic::UNIT);
+        };
+        let value = record.value(RecordKind::Bytes).map_err(|e| e.to_string())?;
+        let value = std::str::from_utf8(value).map_err(|_| format!("value of {} is not utf-8", key))?;
+        Ok(value.into())
+    });
+
+    // like SET, the ttl and tags of the record are dropped
+    let set_state = state.clone();
+    engine.register_fn("set", move |key: &str, value: Dynamic| -> Result<(), Box<EvalAltResult>> {
+        let mut state = set_state.lock().unwrap();
+        *state.record(key)? = Some(Record::new(value.to_string().into_bytes(), None));
+        state.changes.insert(key.to_string(), ChangeKind::Set);
+        Ok(())
+    });
+
+    let del_state = state.clone();
+    engine.register_fn("del", move |key: &str| -> Result<bool, Box<EvalAltResult>> {
+        let mut state = del_state.lock().unwrap();
+        if state.record(key)?.take().is_none() {
+            return Ok(false);
+        }
+        state.changes.insert(key.to_string(), ChangeKind::Del);
+        Ok(true)
+    });
+
+    let expire_state = state.clone();
+    engine.register_fn("expire", move |key: &str, ttl: &str| -> Result<bool, Box<EvalAltResult>> {
+        let ttl = parse_duration(ttl).map_err(|_| format!("unparsable ttl {}", ttl))?;
+        let mut state = expire_state.lock().unwrap();
+        match state.record(key)? {
+            Some(record) => record.update_ttl_policy(ttl),
+            None => return Ok(false),
+        }
+        // a record set by the script is journaled as set, with its ttl
+        state.changes.entry(key.to_string()).or_insert(ChangeKind::Expire);
+        Ok(true)
+    });
+    engine
+}
@@ -0,0 +1,139 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    sync::RwLock,
+};
+
+// BM25 term frequency saturation and length normalization
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+// longer runs of letters and digits are rarely words, they are not indexed
+const MAX_TOKEN_LEN: usize = 64;
+/// Results returned by a search without a limit.
+pub(crate) const DEFAULT_SEARCH_LIMIT: usize = 10;
+
+/// Inverted index of the plain values stored under a set of key prefixes, kept up to date
+/// by every write. Values are split in lowercase runs of letters and digits.
+#[derive(Debug, Default)]
+pub(crate) struct SearchIndex {
+    prefixes: Vec<String>,
+    postings: RwLock<Postings>,
+}
+
+#[derive(Debug, Default)]
+struct Postings {
+    // token -> key -> occurrences
+    keys: HashMap<String, HashMap<String, u32>>,
+    // key -> its distinct tokens and token count
+    documents: HashMap<String, (Vec<String>, u32)>,
+    total_tokens: u64,
+}
+
+impl SearchIndex {
+    /// Indexes the keys starting with one of `prefixes`, nothing without any.
+    pub(crate) fn new(prefixes: Vec<String>) -> Self {
+        Self {
+            prefixes,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.prefixes.is_empty()
+    }
+
+    /// Indexes `value` as the content of `key`, `None` dropping the key. Keys outside
+    /// the prefixes are ignored.
+    pub(crate) fn update(&self, key: &str, value: Option<&[u8]>) {
+        if !self.prefixes.iter().any(|prefix| key.starts_with(prefix.as_str())) {
+            return;
+        }
+        let mut postings = self.postings.write().unwrap();
+        postings.remove(key);
+        if let Some(value) = value {
+            postings.insert(key, &String::from_utf8_lossy(value));
+        }
+    }
+
+    /// The `limit` keys best matching any token of `query` with their score, best first.
+    /// Rare tokens weigh more, and so do the ones making up more of a value.
+    pub(crate) fn search(&self, query: &str, limit: usize) -> Vec<(String, f64)> {
+        let postings = self.postings.read().unwrap();
+        let documents = postings.documents.len() as f64;
+        let average_len = postings.total_tokens as f64 / documents.max(1.0);
+
+        let mut scores: HashMap<&str, f64> = HashMap::new();
+        for token in tokenize(query).collect::<HashSet<_>>() {
+            let Some(keys) = postings.keys.get(&token) else {
+                continue;
+            };
+            let matching = keys.len() as f64;
+            let idf = (1.0 + (documents - matching + 0.5) / (matching + 0.5)).ln();
+            for (key, occurrences) in keys {
+                let len = postings.documents.get(key).map_or(0, |(_, len)| *len) as f64;
+                let occurrences = *occurrences as f64;
+                let weight = occurrences * (K1 + 1.0)
+                    / (occurrences + K1 * (1.0 - B + B * len / average_len));
+                *scores.entry(key).or_default() += idf * weight;
+            }
+        }
+
+        let mut ranked: Vec<(String, f64)> = scores
+            .into_iter()
+            .map(|(key, score)| (key.to_string(), score))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        ranked.truncate(limit);
+        ranked
+    }
+}
+
+impl Postings {
+    fn insert(&mut self, key: &str, text: &str) {
+        let mut occurrences: HashMap<String, u32> = HashMap::new();
+        let mut len = 0;
+        for token in tokenize(text) {
+            *occurrences.entry(token).or_default() += 1;
+            len += 1;
+        }
+        if occurrences.is_empty() {
+            return;
+        }
+
+        let tokens = occurrences.keys().cloned().collect();
+        for (token, count) in occurrences {
+            self.keys.entry(token).or_default().insert(key.to_owned(), count);
+        }
+        self.documents.insert(key.to_owned(), (tokens, len));
+        self.total_tokens += len as u64;
+    }
+
+    fn remove(&mut self, key: &str) {
+        let Some((tokens, len)) = self.documents.remove(key) else {
+            return;
+        };
+        for token in tokens {
+            if let Some(keys) = self.keys.get_mut(&token) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&token);
+                }
+            }
+        }
+        self.total_tokens -= len as u64;
+    }
+}
+
+/// `<key> <score>` lines.
+pub(crate) fn render(results: &[(String, f64)]) -> String {
+    results.iter().fold(String::new(), |mut lines, (key, score)| {
+        let _ = writeln!(lines, "{} {:.4}", key, score);
+        lines
+    })
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty() && token.chars().count() <= MAX_TOKEN_LEN)
+        .map(str::to_lowercase)
+}
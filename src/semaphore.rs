@@ -0,0 +1,43 @@
+// every holder as its token (u64) and expiry (u64, milliseconds since the unix epoch),
+// little endian
+const HOLDER_LEN: usize = 8 + 8;
+
+/// Whether `semaphore` is one.
+pub(crate) fn is_valid(semaphore: &[u8]) -> bool {
+    semaphore.len().is_multiple_of(HOLDER_LEN)
+}
+
+/// Drops the holders expired at `now`, returning how many are left.
+pub(crate) fn purge(semaphore: &mut Vec<u8>, now: u64) -> usize {
+    let mut kept = 0;
+    for at in (0..semaphore.len()).step_by(HOLDER_LEN) {
+        if read_u64(semaphore, at + 8) > now {
+            semaphore.copy_within(at..at + HOLDER_LEN, kept * HOLDER_LEN);
+            kept += 1;
+        }
+    }
+    semaphore.truncate(kept * HOLDER_LEN);
+    kept
+}
+
+pub(crate) fn add(semaphore: &mut Vec<u8>, token: u64, expires_at: u64) {
+    semaphore.extend_from_slice(&token.to_le_bytes());
+    semaphore.extend_from_slice(&expires_at.to_le_bytes());
+}
+
+/// Removes the holder of `token`, returning whether there was one.
+pub(crate) fn remove(semaphore: &mut Vec<u8>, token: u64) -> bool {
+    let found = (0..semaphore.len())
+        .step_by(HOLDER_LEN)
+        .find(|at| read_u64(semaphore, *at) == token);
+    if let Some(at) = found {
+        semaphore.drain(at..at + HOLDER_LEN);
+    }
+    found.is_some()
+}
+
+fn read_u64(semaphore: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&semaphore[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
@@ -0,0 +1,111 @@
+use std::{collections::BTreeSet, fmt::Write};
+
+/// Distinct members, listed in name order.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Set {
+    members: BTreeSet<String>,
+}
+
+/// What SINTER, SUNION and SDIFF compute out of their sets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SetOp {
+    Inter,
+    Union,
+    // the members of the first set in none of the others
+    Diff,
+}
+
+impl Set {
+    /// Adds `member`, returning whether it is a new one.
+    pub(crate) fn add(&mut self, member: &str) -> bool {
+        self.members.insert(member.to_string())
+    }
+
+    /// Removes `member`, returning whether it was in.
+    pub(crate) fn remove(&mut self, member: &str) -> bool {
+        self.members.remove(member)
+    }
+
+    pub(crate) fn contains(&self, member: &str) -> bool {
+        self.members.contains(member)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Applies `op` to `sets` in order, missing sets being empty ones.
+    pub(crate) fn combine(op: SetOp, sets: &[Set]) -> Set {
+        let Some((first, others)) = sets.split_first() else {
+            return Set::default();
+        };
+        let members = match op {
+            SetOp::Inter => first
+                .members
+                .iter()
+                .filter(|member| others.iter().all(|set| set.contains(member)))
+                .cloned()
+                .collect(),
+            SetOp::Union => sets.iter().flat_map(|set| set.members.iter().cloned()).collect(),
+            SetOp::Diff => first
+                .members
+                .iter()
+                .filter(|member| !others.iter().any(|set| set.contains(member)))
+                .cloned()
+                .collect(),
+        };
+        Set { members }
+    }
+
+    /// One member per line.
+    pub(crate) fn render(&self) -> String {
+        self.members.iter().fold(String::new(), |mut list, member| {
+            let _ = writeln!(list, "{}", member);
+            list
+        })
+    }
+
+    /// Member count (u32), little endian, then every member in name order as its length
+    /// (u32) and name.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.members.len() as u32).to_le_bytes());
+        for member in &self.members {
+            bytes.extend_from_slice(&(member.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(member.as_bytes());
+        }
+        bytes
+    }
+
+    /// `None` if `bytes` do not hold one.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        let mut set = Set::default();
+        for _ in 0..reader.u32()? {
+            let len = reader.u32()? as usize;
+            let member = String::from_utf8(reader.take(len)?.to_vec()).ok()?;
+            if !set.add(&member) {
+                return None;
+            }
+        }
+        reader.0.is_empty().then_some(set)
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = self.0.split_at_checked(len)?;
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+}
@@ -0,0 +1,103 @@
+//! Hash functions spreading keys over the slots, picked with `--shard-hash`.
+//!
+//! Keys chosen by an untrusted party can be crafted to land in a single slot under an
+//! unkeyed hash, piling up in one shard: a keyed SipHash with a secret key prevents it.
+//! xxhash and fxhash are faster on long and short keys respectively.
+
+use std::{
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    str::FromStr,
+};
+
+use rustc_hash::FxHasher;
+use siphasher::sip::SipHasher13;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::storage::SLOT_COUNT;
+
+pub(crate) const DEFAULT_SHARD_HASH: &str = "siphash";
+
+/// Hash function of the slots, recorded in backups: a backup written with another one has
+/// its records redistributed on recovery.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ShardHash {
+    // the std hasher, unkeyed, as before the option existed
+    #[default]
+    Sip,
+    KeyedSip([u8; 16]),
+    XxHash,
+    FxHash,
+}
+
+impl ShardHash {
+    /// Hash slot of a key.
+    pub(crate) fn slot(&self, key: &str) -> usize {
+        let hash = match self {
+            ShardHash::Sip => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                hasher.finish()
+            }
+            ShardHash::KeyedSip(secret) => SipHasher13::new_with_key(secret).hash(key.as_bytes()),
+            ShardHash::XxHash => xxh3_64(key.as_bytes()),
+            ShardHash::FxHash => {
+                let mut hasher = FxHasher::default();
+                hasher.write(key.as_bytes());
+                hasher.finish()
+            }
+        };
+        hash as usize % SLOT_COUNT
+    }
+
+    /// The form it is parsed from, key included, as saved in backups.
+    #[cfg(feature = "backup")]
+    pub(crate) fn spec(&self) -> String {
+        match self {
+            ShardHash::KeyedSip(secret) => {
+                let hex: String = secret.iter().map(|byte| format!("{:02x}", byte)).collect();
+                format!("siphash:{}", hex)
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl FromStr for ShardHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "siphash" => Ok(ShardHash::Sip),
+            None if s == "xxhash" => Ok(ShardHash::XxHash),
+            None if s == "fxhash" => Ok(ShardHash::FxHash),
+            Some(("siphash", hex)) => {
+                let invalid = || "invalid siphash key, expected 32 hex digits".to_string();
+                if hex.len() != 32 || !hex.is_ascii() {
+                    return Err(invalid());
+                }
+                let mut secret = [0u8; 16];
+                for (i, byte) in secret.iter_mut().enumerate() {
+                    *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+                }
+                Ok(ShardHash::KeyedSip(secret))
+            }
+            _ => Err(format!(
+                "unknown shard hash {}, expected siphash, siphash:<32 hex digits key>, xxhash or fxhash",
+                s
+            )),
+        }
+    }
+}
+
+// the key is left out, it ends up in logs
+impl fmt::Display for ShardHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShardHash::Sip => write!(f, "siphash"),
+            ShardHash::KeyedSip(_) => write!(f, "keyed siphash"),
+            ShardHash::XxHash => write!(f, "xxhash"),
+            ShardHash::FxHash => write!(f, "fxhash"),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! The lock guarding a shard, picked with `--shard-lock`.
+//!
+//! A plain read-write lock keeps a single reader count per shard: every read of a hot
+//! shard updates the same cache line, from every core. A striped lock hands reads out
+//! several read locks in turn instead, writers take all of them, so concurrent reads no
+//! longer touch shared state and only writes get slower.
+
+use std::{
+    cell::{Cell, UnsafeCell},
+    fmt,
+    ops::{Deref, DerefMut},
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use crossbeam_utils::CachePadded;
+use smol::lock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::storage::Shard;
+
+pub(crate) const DEFAULT_SHARD_LOCK: &str = "rwlock";
+// more stripes than this only slow writers down
+const MAX_STRIPES: usize = 64;
+
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // every read takes the next stripe: the connections all run as tasks of the same
+    // executor thread, a stripe per thread would be one stripe for all of them. Threads
+    // start apart and count on their own, so picking one touches no shared state.
+    static NEXT_STRIPE: Cell<usize> = Cell::new(NEXT_THREAD.fetch_add(1, Ordering::Relaxed));
+}
+
+fn next_stripe() -> usize {
+    NEXT_STRIPE.with(|next| next.replace(next.get().wrapping_add(1)))
+}
+
+/// Kind of shard lock: `rwlock`, or `striped[:<stripes>]` with one stripe per core by
+/// default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ShardLockMode {
+    #[default]
+    RwLock,
+    Striped(usize),
+}
+
+impl ShardLockMode {
+    pub(crate) fn stripes(&self) -> usize {
+        match self {
+            ShardLockMode::RwLock => 1,
+            ShardLockMode::Striped(stripes) => *stripes,
+        }
+    }
+}
+
+impl FromStr for ShardLockMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "rwlock" => Ok(ShardLockMode::RwLock),
+            None if s == "striped" => Ok(ShardLockMode::Striped(
+                thread::available_parallelism().map_or(1, |cores| cores.get()).min(MAX_STRIPES),
+            )),
+            Some(("striped", stripes)) => match stripes.parse::<usize>() {
+                Ok(stripes) if (1..=MAX_STRIPES).contains(&stripes) => Ok(ShardLockMode::Striped(stripes)),
+                _ => Err(format!("invalid stripe count {}, expected 1 to {}", stripes, MAX_STRIPES)),
+            },
+            _ => Err(format!("unknown shard lock {}, expected rwlock or striped[:<stripes>]", s)),
+        }
+    }
+}
+
+impl fmt::Display for ShardLockMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShardLockMode::RwLock => write!(f, "rwlock"),
+            ShardLockMode::Striped(stripes) => write!(f, "striped:{}", stripes),
+        }
+    }
+}
+
+/// A shard behind one read-write lock per stripe: readers lock the next stripe, writers
+/// every stripe in order.
+pub(crate) struct ShardLock {
+    stripes: Box<[CachePadded<RwLock<()>>]>,
+    shard: UnsafeCell<Shard>,
+}
+
+// SAFETY: the shard is only reached through the guards, a shared reference while a stripe
+// is read locked, a mutable one while all of them are write locked
+unsafe impl Sync for ShardLock {}
+
+// the shard is left out, reading it needs a lock
+impl fmt::Debug for ShardLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardLock")
+            .field("stripes", &self.stripes.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ShardLock {
+    pub(crate) fn new(mode: ShardLockMode) -> Self {
+        Self {
+            stripes: (0..mode.stripes()).map(|_| CachePadded::new(RwLock::new(()))).collect(),
+            shard: UnsafeCell::new(Shard::default()),
+        }
+    }
+
+    fn stripe(&self) -> &RwLock<()> {
+        &self.stripes[next_stripe() % self.stripes.len()]
+    }
+
+    pub(crate) fn try_read(&self) -> Option<ShardReadGuard<'_>> {
+        let stripe = self.stripe().try_read()?;
+        Some(self.read_guard(stripe))
+    }
+
+    pub(crate) async fn read(&self) -> ShardReadGuard<'_> {
+        let stripe = self.stripe().read().await;
+        self.read_guard(stripe)
+    }
+
+    pub(crate) fn try_write(&self) -> Option<ShardWriteGuard<'_>> {
+        let mut stripes = Vec::with_capacity(self.stripes.len());
+        for stripe in self.stripes.iter() {
+            stripes.push(stripe.try_write()?);
+        }
+        Some(self.write_guard(stripes))
+    }
+
+    pub(crate) async fn write(&self) -> ShardWriteGuard<'_> {
+        let mut stripes = Vec::with_capacity(self.stripes.len());
+        for stripe in self.stripes.iter() {
+            stripes.push(stripe.write().await);
+        }
+        self.write_guard(stripes)
+    }
+
+    fn read_guard<'a>(&'a self, stripe: RwLockReadGuard<'a, ()>) -> ShardReadGuard<'a> {
+        ShardReadGuard {
+            _stripe: stripe,
+            // SAFETY: no writer while a stripe is read locked
+            shard: unsafe { &*self.shard.get() },
+        }
+    }
+
+    fn write_guard<'a>(&'a self, stripes: Vec<RwLockWriteGuard<'a, ()>>) -> ShardWriteGuard<'a> {
+        ShardWriteGuard {
+            stripes,
+            // SAFETY: no other reader or writer while every stripe is write locked
+            shard: unsafe { &mut *self.shard.get() },
+        }
+    }
+}
+
+pub(crate) struct ShardReadGuard<'a> {
+    _stripe: RwLockReadGuard<'a, ()>,
+    shard: &'a Shard,
+}
+
+impl Deref for ShardReadGuard<'_> {
+    type Target = Shard;
+
+    fn deref(&self) -> &Shard {
+        self.shard
+    }
+}
+
+pub(crate) struct ShardWriteGuard<'a> {
+    // released on drop
+    #[cfg_attr(not(feature = "backup"), allow(dead_code))]
+    stripes: Vec<RwLockWriteGuard<'a, ()>>,
+    shard: &'a mut Shard,
+}
+
+#[cfg(feature = "backup")]
+impl<'a> ShardWriteGuard<'a> {
+    /// Turns the write lock into a read lock, no writer getting in between.
+    pub(crate) fn downgrade(guard: Self) -> ShardReadGuard<'a> {
+        let ShardWriteGuard { mut stripes, shard } = guard;
+        let index = next_stripe() % stripes.len();
+        // the other stripes are released once the kept one is read locked
+        ShardReadGuard {
+            _stripe: RwLockWriteGuard::downgrade(stripes.swap_remove(index)),
+            shard,
+        }
+    }
+}
+
+impl Deref for ShardWriteGuard<'_> {
+    type Target = Shard;
+
+    fn deref(&self) -> &Shard {
+        self.shard
+    }
+}
+
+impl DerefMut for ShardWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Shard {
+        self.shard
+    }
+}
@@ -0,0 +1,125 @@
+//! Commands that took longer than `--slowlog-threshold` to handle, the last
+//! `--slowlog-size` of them, listed by `/SLOWLOG` to find what holds the shard locks.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub(crate) const DEFAULT_SLOWLOG_THRESHOLD_MS: u64 = 10;
+pub(crate) const DEFAULT_SLOWLOG_SIZE: usize = 128;
+
+// threshold while off, nothing takes that long
+const OFF: u64 = u64::MAX;
+
+#[derive(Debug)]
+pub(crate) struct SlowLog {
+    // checked by every command, before taking the lock
+    threshold_us: AtomicU64,
+    inner: Mutex<SlowLogInner>,
+}
+
+#[derive(Debug, Default)]
+struct SlowLogInner {
+    capacity: usize,
+    next_id: u64,
+    entries: VecDeque<SlowEntry>,
+}
+
+/// A command slower than the threshold, with the client that sent it.
+#[derive(Debug, Clone)]
+struct SlowEntry {
+    id: u64,
+    // seconds since the unix epoch
+    at: u64,
+    duration: Duration,
+    client: Option<String>,
+    command: &'static str,
+    key: Option<String>,
+}
+
+impl fmt::Display for SlowEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.id,
+            self.at,
+            self.duration.as_micros(),
+            self.client.as_deref().unwrap_or("-"),
+            self.command
+        )?;
+        match &self.key {
+            Some(key) => write!(f, " {}", key),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        Self {
+            threshold_us: AtomicU64::new(OFF),
+            inner: Mutex::new(SlowLogInner::default()),
+        }
+    }
+}
+
+impl SlowLog {
+    /// Records the commands taking `threshold` or longer, keeping the last `capacity`, above 0.
+    pub(crate) fn enable(&self, threshold: Duration, capacity: usize) {
+        self.inner.lock().unwrap().capacity = capacity;
+        self.threshold_us.store(threshold.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Records `command` if it took long enough, dropping the oldest entry when full.
+    pub(crate) fn command(&self, command: &'static str, key: Option<&str>, duration: Duration, client: Option<&str>) {
+        if (duration.as_micros() as u64) < self.threshold_us.load(Ordering::Relaxed) {
+            return;
+        }
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.len() == inner.capacity {
+            inner.entries.pop_front();
+        }
+        inner.next_id += 1;
+        let entry = SlowEntry {
+            id: inner.next_id,
+            at,
+            duration,
+            client: client.map(str::to_owned),
+            command,
+            key: key.map(str::to_owned),
+        };
+        inner.entries.push_back(entry);
+    }
+
+    /// The `count` most recent entries, all of them without one, newest first, one
+    /// `<id> <time> <duration in us> <client> <command> [<key>]` line each.
+    pub(crate) fn entries(&self, count: Option<usize>) -> String {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .entries
+            .iter()
+            .rev()
+            .take(count.unwrap_or(usize::MAX))
+            .map(|entry| format!("{}\n", entry))
+            .collect()
+    }
+
+    /// Empties the log, returning how many entries it held. Ids keep counting.
+    pub(crate) fn reset(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let cleared = inner.entries.len();
+        inner.entries.clear();
+        cleared
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+    ops::Bound,
+};
+
+/// Members ordered by score, members of equal score by name, for leaderboards: ranks and
+/// score ranges are read in order without sorting.
+#[derive(Debug, Default)]
+pub(crate) struct SortedSet {
+    scores: HashMap<String, f64>,
+    ordered: BTreeSet<(Score, String)>,
+}
+
+// total order over the scores, NaN is refused before getting here
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl SortedSet {
+    /// Sets the score of `member`, returning whether it is a new one.
+    pub(crate) fn add(&mut self, member: &str, score: f64) -> bool {
+        // -0 and 0 are the same score
+        let score = score + 0.0;
+        let previous = self.scores.insert(member.to_string(), score);
+        if let Some(previous) = previous {
+            self.ordered.remove(&(Score(previous), member.to_string()));
+        }
+        self.ordered.insert((Score(score), member.to_string()));
+        previous.is_none()
+    }
+
+    /// Adds `increment` to the score of `member`, 0 when it is not in, returning the new
+    /// score; `None` when it would not be a number, `+inf` plus `-inf`.
+    pub(crate) fn incr(&mut self, member: &str, increment: f64) -> Option<f64> {
+        let score = self.scores.get(member).copied().unwrap_or_default() + increment;
+        if score.is_nan() {
+            return None;
+        }
+        self.add(member, score);
+        Some(self.scores[member])
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Rank of `member`, 0 for the lowest score or the highest with `rev`, `None` when it is
+    /// not in. The members before it are counted, so it takes longer the further it is.
+    pub(crate) fn rank(&self, member: &str, rev: bool) -> Option<usize> {
+        let score = *self.scores.get(member)?;
+        let below = self.ordered.range(..(Score(score), member.to_string())).count();
+        Some(if rev { self.ordered.len() - 1 - below } else { below })
+    }
+
+    /// Removes `member`, returning whether it was in.
+    pub(crate) fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.ordered.remove(&(Score(score), member.to_string())),
+            None => false,
+        }
+    }
+
+    /// Members from rank `start` to rank `stop` included, lowest score first or highest
+    /// with `rev`. Negative ranks count from the end, -1 being the last member.
+    pub(crate) fn range(&self, start: i64, stop: i64, rev: bool) -> Vec<(&str, f64)> {
+        let len = self.ordered.len() as i64;
+        let rank = |rank: i64| if rank < 0 { len + rank } else { rank };
+        let (start, stop) = (rank(start).max(0), rank(stop).min(len - 1));
+        if start > stop {
+            return Vec::new();
+        }
+
+        let members = self.ordered.iter().map(|(score, member)| (member.as_str(), score.0));
+        let taken = (stop - start + 1) as usize;
+        match rev {
+            true => members.rev().skip(start as usize).take(taken).collect(),
+            false => members.skip(start as usize).take(taken).collect(),
+        }
+    }
+
+    /// Members scored between `min` and `max`, lowest first, from the `offset`th of them and
+    /// `limit` of them at most.
+    pub(crate) fn range_by_score(
+        &self,
+        min: Bound<f64>,
+        max: Bound<f64>,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Vec<(&str, f64)> {
+        let after_max = |score: f64| match max {
+            Bound::Included(max) => score > max,
+            Bound::Excluded(max) => score >= max,
+            Bound::Unbounded => false,
+        };
+        let from = match min {
+            Bound::Included(min) | Bound::Excluded(min) => Bound::Included((Score(min + 0.0), String::new())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        self.ordered
+            .range((from, Bound::Unbounded))
+            .skip_while(|(score, _)| matches!(min, Bound::Excluded(min) if score.0 <= min))
+            .take_while(|(score, _)| !after_max(score.0))
+            .skip(offset)
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(score, member)| (member.as_str(), score.0))
+            .collect()
+    }
+
+    /// Removes the members scored between `min` and `max`, returning how many.
+    pub(crate) fn remove_range_by_score(&mut self, min: Bound<f64>, max: Bound<f64>) -> usize {
+        let members: Vec<String> = self
+            .range_by_score(min, max, 0, None)
+            .into_iter()
+            .map(|(member, _)| member.to_string())
+            .collect();
+        members.iter().filter(|member| self.remove(member)).count()
+    }
+
+    /// Member count (u32), little endian, then every member by ascending score as its
+    /// score (f64), length (u32) and name.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.ordered.len() as u32).to_le_bytes());
+        for (score, member) in &self.ordered {
+            bytes.extend_from_slice(&score.0.to_le_bytes());
+            bytes.extend_from_slice(&(member.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(member.as_bytes());
+        }
+        bytes
+    }
+
+    /// `None` if `bytes` do not hold one.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        let mut set = SortedSet::default();
+        for _ in 0..reader.u32()? {
+            let score = f64::from_le_bytes(reader.take(8)?.try_into().ok()?);
+            let len = reader.u32()? as usize;
+            let member = String::from_utf8(reader.take(len)?.to_vec()).ok()?;
+            if score.is_nan() || !set.add(&member, score) {
+                return None;
+            }
+        }
+        reader.0.is_empty().then_some(set)
+    }
+}
+
+/// A score bound of ZRANGEBYSCORE and ZREMRANGEBYSCORE: a score, included, or `(` and a score, excluded.
+/// `-inf` and `+inf` are scores too.
+pub(crate) fn parse_bound(bound: &str) -> Option<Bound<f64>> {
+    let (bound, excluded) = match bound.strip_prefix('(') {
+        Some(bound) => (bound, true),
+        None => (bound, false),
+    };
+    let score = bound.parse::<f64>().ok().filter(|score| !score.is_nan())?;
+    Some(if excluded { Bound::Excluded(score) } else { Bound::Included(score) })
+}
+
+/// `<member> <score>` lines.
+pub(crate) fn render(members: &[(&str, f64)]) -> String {
+    members.iter().fold(String::new(), |mut list, (member, score)| {
+        let _ = writeln!(list, "{} {}", member, score);
+        list
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = self.0.split_at_checked(len)?;
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+}
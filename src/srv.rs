@@ -0,0 +1,82 @@
+//! Cluster nodes found in the DNS SRV records of `--discover srv:<name>`, like those of a
+//! Kubernetes headless service, in place of a `--cluster` file: every record is a node,
+//! its target and port, and the name is resolved again every `--discover-interval` so
+//! nodes joining or leaving get their share of the slots.
+
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use hickory_resolver::Resolver;
+use log::{info, warn};
+use smol::{Task, Timer};
+
+use crate::cluster::Cluster;
+
+/// Where the nodes are found, `srv:<name>`.
+#[derive(Debug, Clone)]
+pub(crate) struct Discover {
+    name: String,
+}
+
+impl FromStr for Discover {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("srv", name)) if !name.is_empty() => Ok(Self { name: name.to_string() }),
+            _ => Err(format!("expected srv:<name>, got {}", s)),
+        }
+    }
+}
+
+impl Discover {
+    /// `host:port` of every record, with the resolver of the system.
+    pub(crate) fn resolve(&self) -> Result<Vec<String>, String> {
+        let resolver = Resolver::from_system_conf().map_err(|e| format!("unable to resolve {}: {}", self.name, e))?;
+        self.resolve_with(&resolver)
+    }
+
+    fn resolve_with(&self, resolver: &Resolver) -> Result<Vec<String>, String> {
+        let records = resolver
+            .srv_lookup(self.name.as_str())
+            .map_err(|e| format!("unable to resolve {}: {}", self.name, e))?;
+        Ok(records
+            .iter()
+            .map(|record| format!("{}:{}", record.target().to_utf8().trim_end_matches('.'), record.port()))
+            .collect())
+    }
+}
+
+/// Resolves the nodes of the cluster again and again until [`Rediscovery::stop`].
+pub(crate) struct Rediscovery {
+    task: Task<()>,
+}
+
+impl Rediscovery {
+    /// Replaces the nodes of `cluster` with those of `discover` every `interval`, keeping
+    /// them when the name cannot be resolved.
+    pub(crate) fn start(discover: Discover, cluster: Arc<Cluster>, interval: Duration) -> Self {
+        let task = smol::spawn(async move {
+            let resolver = match Resolver::from_system_conf() {
+                Ok(resolver) => Arc::new(resolver),
+                Err(e) => return warn!("unable to resolve {} again: {}", discover.name, e),
+            };
+            loop {
+                Timer::after(interval).await;
+                // the resolver blocks
+                let (resolver, resolving) = (resolver.clone(), discover.clone());
+                match smol::unblock(move || resolving.resolve_with(&resolver)).await {
+                    Ok(nodes) if cluster.respread(&nodes) => {
+                        info!("cluster nodes now {}", cluster.layout().nodes().join(" "))
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("{}, keeping the cluster nodes", e),
+                }
+            }
+        });
+        Self { task }
+    }
+
+    pub(crate) async fn stop(self) {
+        self.task.cancel().await;
+    }
+}
@@ -0,0 +1,637 @@
+// without the metrics feature counters are still kept up to date, nothing reads them
+#![cfg_attr(not(feature = "metrics"), allow(dead_code))]
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+#[cfg(feature = "backup")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http_types::StatusCode;
+#[cfg(feature = "metrics")]
+use serde::Serialize;
+
+use crate::{
+    storage::ShardStats,
+    topk::{self, TopK},
+};
+
+/// Writes per second are averaged over at least this long.
+pub(crate) const WRITE_RATE_WINDOW: Duration = Duration::from_secs(10);
+// keys listed by `/ADMIN/HOTKEYS`, their counts halving every window
+const HOT_KEYS: u32 = 16;
+const HOT_KEYS_WINDOW: Duration = Duration::from_secs(60);
+
+/// Counters updated while serving requests, shared by every clone of the storage.
+#[derive(Debug)]
+pub(crate) struct Stats {
+    started_at: Instant,
+    connections_total: AtomicU64,
+    connections_active: AtomicU64,
+    commands: Mutex<BTreeMap<&'static str, u64>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired_keys: AtomicU64,
+    // removed by the evict memory policy
+    evicted_keys: AtomicU64,
+    // http responses by status class, 1xx to 5xx, and the time spent on them
+    http_responses: [AtomicU64; 5],
+    http_micros: AtomicU64,
+    last_backup: Mutex<Option<BackupReport>>,
+    backups: Mutex<BackupStatus>,
+    memory: Mutex<Option<MemoryReport>>,
+    #[cfg(feature = "mirror")]
+    mirror: Mutex<Option<MirrorReport>>,
+    #[cfg(feature = "replication")]
+    replication: Mutex<ReplicationReport>,
+    // shard write counts at the start of the current rate window
+    write_samples: Mutex<Option<(Instant, Vec<u64>)>>,
+    hot_keys: Mutex<TopK>,
+}
+
+/// A section of `/INFO`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InfoSection {
+    Server,
+    Clients,
+    Memory,
+    Persistence,
+    Keyspace,
+}
+
+impl InfoSection {
+    // in the order of a full /INFO
+    pub(crate) const ALL: [InfoSection; 5] = [
+        InfoSection::Server,
+        InfoSection::Clients,
+        InfoSection::Memory,
+        InfoSection::Persistence,
+        InfoSection::Keyspace,
+    ];
+}
+
+impl FromStr for InfoSection {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        InfoSection::ALL
+            .into_iter()
+            .find(|section| section.to_string().eq_ignore_ascii_case(s))
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for InfoSection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InfoSection::Server => write!(f, "server"),
+            InfoSection::Clients => write!(f, "clients"),
+            InfoSection::Memory => write!(f, "memory"),
+            InfoSection::Persistence => write!(f, "persistence"),
+            InfoSection::Keyspace => write!(f, "keyspace"),
+        }
+    }
+}
+
+/// Outcome of the last backup cycle that wrote an archive.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "metrics", derive(Serialize))]
+pub(crate) struct BackupReport {
+    // seconds since the unix epoch
+    pub(crate) finished_at: u64,
+    pub(crate) duration_ms: u64,
+    pub(crate) archive: String,
+    pub(crate) error: Option<String>,
+}
+
+/// Backup cycles since startup, with the last one that succeeded and the last failure.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "metrics", derive(Serialize))]
+pub(crate) struct BackupStatus {
+    pub(crate) succeeded: u64,
+    pub(crate) failed: u64,
+    // failures since the last success, above 0 while backups keep failing
+    pub(crate) consecutive_failures: u64,
+    pub(crate) last_success: Option<BackupSuccess>,
+    pub(crate) last_failure: Option<BackupFailure>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "metrics", derive(Serialize))]
+pub(crate) struct BackupSuccess {
+    // seconds since the unix epoch
+    pub(crate) finished_at: u64,
+    pub(crate) duration_ms: u64,
+    pub(crate) archive: String,
+    pub(crate) bytes: u64,
+    // shards serialized again, and copied from the previous archive unchanged
+    pub(crate) shards_written: usize,
+    pub(crate) shards_copied: usize,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "metrics", derive(Serialize))]
+pub(crate) struct BackupFailure {
+    // seconds since the unix epoch
+    pub(crate) failed_at: u64,
+    pub(crate) error: String,
+}
+
+/// What a successful backup cycle wrote.
+#[cfg(feature = "backup")]
+pub(crate) struct BackupWritten {
+    pub(crate) bytes: u64,
+    pub(crate) shards_written: usize,
+    pub(crate) shards_copied: usize,
+}
+
+/// Memory usage against `--max-memory`, measured every second.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "metrics", derive(Serialize))]
+pub(crate) struct MemoryReport {
+    pub(crate) limit: u64,
+    pub(crate) usage: u64,
+    pub(crate) pressure: bool,
+    pub(crate) policy: String,
+}
+
+/// Progress of the copy of writes to the `--mirror` secondary.
+#[cfg(feature = "mirror")]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "metrics", derive(Serialize))]
+pub(crate) struct MirrorReport {
+    pub(crate) target: String,
+    // changes copied, and dropped by the journal before they could be
+    pub(crate) mirrored: u64,
+    pub(crate) dropped: u64,
+    // typed records, which have no counterpart on the secondary
+    pub(crate) skipped: u64,
+    pub(crate) retries: u64,
+    // changes not copied yet, and how long the oldest of them has been waiting
+    pub(crate) lag_changes: u64,
+    pub(crate) lag_ms: u64,
+    pub(crate) last_error: Option<String>,
+}
+
+/// Replicas streaming from this instance, and on a replica its link to the primary.
+#[cfg(feature = "replication")]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "metrics", derive(Serialize))]
+pub(crate) struct ReplicationReport {
+    pub(crate) replicas: u64,
+    pub(crate) primary: Option<PrimaryLink>,
+}
+
+/// Link of a replica to its `--replica-of` primary.
+#[cfg(feature = "replication")]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "metrics", derive(Serialize))]
+pub(crate) struct PrimaryLink {
+    pub(crate) address: String,
+    pub(crate) connected: bool,
+    // journal sequence of the primary applied so far
+    pub(crate) position: u64,
+    // copies of every record, on first connection and when the primary cannot resume
+    pub(crate) full_syncs: u64,
+    pub(crate) last_error: Option<String>,
+}
+
+/// Lock acquisitions of a shard and the time spent waiting for them.
+///
+/// Uncontended acquisitions are not timed, only counted.
+#[derive(Debug, Default)]
+pub(crate) struct LockStats {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    contended_reads: AtomicU64,
+    contended_writes: AtomicU64,
+    read_wait_ns: AtomicU64,
+    write_wait_ns: AtomicU64,
+    max_wait_ns: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "metrics", derive(Serialize))]
+pub(crate) struct LockReport {
+    pub(crate) reads: u64,
+    pub(crate) writes: u64,
+    pub(crate) contended_reads: u64,
+    pub(crate) contended_writes: u64,
+    pub(crate) read_wait_us: u64,
+    pub(crate) write_wait_us: u64,
+    pub(crate) max_wait_us: u64,
+}
+
+impl LockStats {
+    pub(crate) fn read_acquired(&self, waited: Option<Duration>) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        if let Some(waited) = waited {
+            self.contended_reads.fetch_add(1, Ordering::Relaxed);
+            self.waited(&self.read_wait_ns, waited);
+        }
+    }
+
+    pub(crate) fn write_acquired(&self, waited: Option<Duration>) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        if let Some(waited) = waited {
+            self.contended_writes.fetch_add(1, Ordering::Relaxed);
+            self.waited(&self.write_wait_ns, waited);
+        }
+    }
+
+    fn waited(&self, total_ns: &AtomicU64, waited: Duration) {
+        let waited_ns = waited.as_nanos() as u64;
+        total_ns.fetch_add(waited_ns, Ordering::Relaxed);
+        self.max_wait_ns.fetch_max(waited_ns, Ordering::Relaxed);
+    }
+
+    pub(crate) fn report(&self) -> LockReport {
+        LockReport {
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            contended_reads: self.contended_reads.load(Ordering::Relaxed),
+            contended_writes: self.contended_writes.load(Ordering::Relaxed),
+            read_wait_us: self.read_wait_ns.load(Ordering::Relaxed) / 1000,
+            write_wait_us: self.write_wait_ns.load(Ordering::Relaxed) / 1000,
+            max_wait_us: self.max_wait_ns.load(Ordering::Relaxed) / 1000,
+        }
+    }
+}
+
+/// Counts a connection as active until dropped.
+pub(crate) struct ConnectionGuard(Arc<Stats>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a replica as streaming until dropped.
+#[cfg(feature = "replication")]
+pub(crate) struct ReplicaGuard(Arc<Stats>);
+
+#[cfg(feature = "replication")]
+impl Drop for ReplicaGuard {
+    fn drop(&mut self) {
+        self.0.replication.lock().unwrap().replicas -= 1;
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            connections_total: AtomicU64::new(0),
+            connections_active: AtomicU64::new(0),
+            commands: Mutex::new(BTreeMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            evicted_keys: AtomicU64::new(0),
+            http_responses: Default::default(),
+            http_micros: AtomicU64::new(0),
+            last_backup: Mutex::new(None),
+            backups: Mutex::new(BackupStatus::default()),
+            memory: Mutex::new(None),
+            #[cfg(feature = "mirror")]
+            mirror: Mutex::new(None),
+            #[cfg(feature = "replication")]
+            replication: Mutex::new(ReplicationReport::default()),
+            write_samples: Mutex::new(None),
+            hot_keys: Mutex::new(TopK::new(HOT_KEYS, Some(HOT_KEYS_WINDOW))),
+        }
+    }
+}
+
+impl Stats {
+    pub(crate) fn connection_opened(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    pub(crate) fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Connections open now, and opened since startup.
+    pub(crate) fn connections(&self) -> (u64, u64) {
+        (
+            self.connections_active.load(Ordering::Relaxed),
+            self.connections_total.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The last memory measurement, `None` without a memory limit.
+    pub(crate) fn memory(&self) -> Option<MemoryReport> {
+        self.memory.lock().unwrap().clone()
+    }
+
+    pub(crate) fn backups(&self) -> BackupStatus {
+        self.backups.lock().unwrap().clone()
+    }
+
+    pub(crate) fn command(&self, name: &'static str) {
+        *self.commands.lock().unwrap().entry(name).or_default() += 1;
+    }
+
+    /// Counts a request for `key` towards the hot keys.
+    pub(crate) fn key_requested(&self, key: &str) {
+        self.hot_keys.lock().unwrap().add(key, 1);
+    }
+
+    /// The most requested keys lately, one `<key> <count>` line each.
+    pub(crate) fn hot_keys(&self) -> String {
+        topk::render(self.hot_keys.lock().unwrap().list())
+    }
+
+    /// Counts a key lookup as a hit or a miss.
+    pub(crate) fn lookup(&self, found: bool) {
+        let counter = if found { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn expired(&self) {
+        self.expired_keys.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn http_response(&self, status: StatusCode, duration: Duration) {
+        let class = (status as usize / 100).clamp(1, 5) - 1;
+        self.http_responses[class].fetch_add(1, Ordering::Relaxed);
+        self.http_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn evicted(&self) {
+        self.evicted_keys.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn memory_usage(&self, report: MemoryReport) {
+        *self.memory.lock().unwrap() = Some(report);
+    }
+
+    /// Writes per second of every shard, averaged since the start of the rate window.
+    /// The window restarts once older than [`WRITE_RATE_WINDOW`], the first call
+    /// only opens it.
+    pub(crate) fn write_rates(&self, writes: &[u64]) -> Vec<f64> {
+        let mut samples = self.write_samples.lock().unwrap();
+        let now = Instant::now();
+
+        let rates = match samples.as_ref() {
+            Some((sampled_at, sampled)) => {
+                let elapsed = now.duration_since(*sampled_at).as_secs_f64().max(f64::EPSILON);
+                writes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, writes)| {
+                        // shards activated after the sample started from zero
+                        let sampled = sampled.get(i).copied().unwrap_or_default();
+                        writes.saturating_sub(sampled) as f64 / elapsed
+                    })
+                    .collect()
+            }
+            None => vec![0.0; writes.len()],
+        };
+
+        let expired = samples
+            .as_ref()
+            .is_none_or(|(sampled_at, _)| now.duration_since(*sampled_at) >= WRITE_RATE_WINDOW);
+        if expired {
+            *samples = Some((now, writes.to_vec()));
+        }
+        rates
+    }
+
+    #[cfg(feature = "discovery")]
+    pub(crate) fn last_backup(&self) -> Option<BackupReport> {
+        self.last_backup.lock().unwrap().clone()
+    }
+
+    #[cfg(feature = "backup")]
+    pub(crate) fn backup_finished(&self, archive: &str, duration: Duration, outcome: Result<BackupWritten, String>) {
+        let finished_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+
+        *self.last_backup.lock().unwrap() = Some(BackupReport {
+            finished_at,
+            duration_ms: duration.as_millis() as u64,
+            archive: archive.to_string(),
+            error: outcome.as_ref().err().cloned(),
+        });
+
+        let mut backups = self.backups.lock().unwrap();
+        match outcome {
+            Ok(written) => {
+                backups.succeeded += 1;
+                backups.consecutive_failures = 0;
+                backups.last_success = Some(BackupSuccess {
+                    finished_at,
+                    duration_ms: duration.as_millis() as u64,
+                    archive: archive.to_string(),
+                    bytes: written.bytes,
+                    shards_written: written.shards_written,
+                    shards_copied: written.shards_copied,
+                });
+            }
+            Err(error) => {
+                backups.failed += 1;
+                backups.consecutive_failures += 1;
+                backups.last_failure = Some(BackupFailure {
+                    failed_at: finished_at,
+                    error,
+                });
+            }
+        }
+    }
+
+    /// The backup counters, one `<name> <value>` line each, the last success and failure
+    /// left out until there is one.
+    #[cfg(feature = "backup")]
+    pub(crate) fn backup_status(&self) -> String {
+        let backups = self.backups.lock().unwrap().clone();
+        let mut lines = vec![
+            format!("succeeded {}", backups.succeeded),
+            format!("failed {}", backups.failed),
+            format!("consecutive_failures {}", backups.consecutive_failures),
+        ];
+        if let Some(success) = backups.last_success {
+            lines.extend([
+                format!("last_success_at {}", success.finished_at),
+                format!("last_success_duration_ms {}", success.duration_ms),
+                format!("last_success_archive {}", success.archive),
+                format!("last_success_bytes {}", success.bytes),
+                format!("last_success_shards_written {}", success.shards_written),
+                format!("last_success_shards_copied {}", success.shards_copied),
+            ]);
+        }
+        if let Some(failure) = backups.last_failure {
+            lines.extend([
+                format!("last_failure_at {}", failure.failed_at),
+                format!("last_failure_error {}", failure.error),
+            ]);
+        }
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    #[cfg(feature = "mirror")]
+    pub(crate) fn mirror_progress(&self, report: MirrorReport) {
+        *self.mirror.lock().unwrap() = Some(report);
+    }
+
+    #[cfg(feature = "replication")]
+    pub(crate) fn replica_attached(self: &Arc<Self>) -> ReplicaGuard {
+        self.replication.lock().unwrap().replicas += 1;
+        ReplicaGuard(self.clone())
+    }
+
+    #[cfg(feature = "replication")]
+    pub(crate) fn primary_progress(&self, link: PrimaryLink) {
+        self.replication.lock().unwrap().primary = Some(link);
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Serialize)]
+struct StatsReport {
+    uptime_secs: u64,
+    connections: ConnectionsReport,
+    commands: BTreeMap<&'static str, u64>,
+    keyspace: KeyspaceReport,
+    hits: u64,
+    misses: u64,
+    // hits over lookups, null before the first lookup
+    hit_ratio: Option<f64>,
+    expired_keys: u64,
+    evicted_keys: u64,
+    http: HttpReport,
+    last_backup: Option<BackupReport>,
+    backups: BackupStatus,
+    // null without a memory limit
+    memory: Option<MemoryReport>,
+    #[cfg(feature = "mirror")]
+    mirror: Option<MirrorReport>,
+    #[cfg(feature = "replication")]
+    replication: ReplicationReport,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Serialize)]
+struct ConnectionsReport {
+    active: u64,
+    total: u64,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Serialize)]
+struct HttpReport {
+    requests: u64,
+    // by status class, 2xx and so on
+    responses: BTreeMap<String, u64>,
+    // null before the first request
+    avg_latency_ms: Option<f64>,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Serialize)]
+struct KeyspaceReport {
+    keys: usize,
+    shards: usize,
+    // keys of the biggest shard over all keys, 1/shards when evenly spread
+    max_shard_share: Option<f64>,
+    distribution: Vec<ShardReport>,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Serialize)]
+struct ShardReport {
+    keys: usize,
+    bytes: usize,
+    writes: u64,
+    writes_per_sec: f64,
+    lock: LockReport,
+}
+
+#[cfg(feature = "metrics")]
+impl Stats {
+    /// Renders the counters as a json document.
+    pub(crate) fn to_json(&self, shard_stats: &[ShardStats]) -> String {
+        let keys: usize = shard_stats.iter().map(|shard| shard.keys).sum();
+        let writes: Vec<u64> = shard_stats.iter().map(|shard| shard.writes).collect();
+        let distribution = shard_stats
+            .iter()
+            .zip(self.write_rates(&writes))
+            .map(|(shard, writes_per_sec)| ShardReport {
+                keys: shard.keys,
+                bytes: shard.bytes,
+                writes: shard.writes,
+                writes_per_sec,
+                lock: shard.lock,
+            })
+            .collect();
+
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+
+        let report = StatsReport {
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            connections: ConnectionsReport {
+                active: self.connections_active.load(Ordering::Relaxed),
+                total: self.connections_total.load(Ordering::Relaxed),
+            },
+            commands: self.commands.lock().unwrap().clone(),
+            keyspace: KeyspaceReport {
+                keys,
+                shards: shard_stats.len(),
+                max_shard_share: max_shard_share(shard_stats),
+                distribution,
+            },
+            hits,
+            misses,
+            hit_ratio: (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64),
+            expired_keys: self.expired_keys.load(Ordering::Relaxed),
+            evicted_keys: self.evicted_keys.load(Ordering::Relaxed),
+            http: self.http_report(),
+            last_backup: self.last_backup.lock().unwrap().clone(),
+            backups: self.backups.lock().unwrap().clone(),
+            memory: self.memory.lock().unwrap().clone(),
+            #[cfg(feature = "mirror")]
+            mirror: self.mirror.lock().unwrap().clone(),
+            #[cfg(feature = "replication")]
+            replication: self.replication.lock().unwrap().clone(),
+        };
+
+        // plain structs of numbers and strings always serialize
+        serde_json::to_string(&report).unwrap_or_default()
+    }
+
+    fn http_report(&self) -> HttpReport {
+        let responses: BTreeMap<String, u64> = self
+            .http_responses
+            .iter()
+            .enumerate()
+            .map(|(class, count)| (format!("{}xx", class + 1), count.load(Ordering::Relaxed)))
+            .collect();
+        let requests = responses.values().sum();
+        let micros = self.http_micros.load(Ordering::Relaxed);
+        HttpReport {
+            requests,
+            responses,
+            avg_latency_ms: (requests > 0).then(|| micros as f64 / requests as f64 / 1000.0),
+        }
+    }
+}
+
+/// Share of all keys held by the biggest shard, `None` while empty.
+pub(crate) fn max_shard_share(shard_stats: &[ShardStats]) -> Option<f64> {
+    let keys: usize = shard_stats.iter().map(|shard| shard.keys).sum();
+    let max_keys = shard_stats.iter().map(|shard| shard.keys).max()?;
+    (keys > 0).then(|| max_keys as f64 / keys as f64)
+}
@@ -1,132 +1,1877 @@
 use std::{
-    collections::HashMap,
-    hash::{DefaultHasher, Hash, Hasher},
-    sync::Arc,
-    time::Duration,
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
+#[cfg(feature = "replication")]
+use std::sync::OnceLock;
 
+#[cfg(feature = "backup")]
+use crate::{aof::Aof, backup_handler::PendingShard};
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+#[cfg(feature = "cluster")]
+use crate::cluster::Cluster;
+#[cfg(feature = "scripting")]
+use crate::script;
 use crate::{
+    bitfield::{self, BitfieldOp},
     errors::TransactionError,
-    record::Record,
+    journal::{ChangeKind, Journal},
+    memory::Memory,
+    record::{Record, RecordKind},
+    operations::Operations,
+    pattern::Pattern,
+    pubsub::PubSub,
+    resharding::Resharding,
+    scan::Cursor,
+    schedule::Schedules,
+    search::SearchIndex,
+    semaphore,
+    shard_hash::ShardHash,
+    slowlog::SlowLog,
+    shard_lock::{ShardLock, ShardLockMode, ShardReadGuard, ShardWriteGuard},
+    stats::{LockReport, LockStats, Stats},
+    tags::TagIndex,
+    tombstones::Tombstones,
+    transaction::{self, TxCommand},
     wrapped_record::{TTLResult, WrappedRecord},
 };
 use crossbeam_utils::CachePadded;
-use serde::{Deserialize, Serialize};
-use smol::lock::RwLock;
+#[cfg(feature = "backup")]
+use log::error;
+use smol::{future::FutureExt, lock::RwLock, Timer};
+#[cfg(feature = "backup")]
+use smol::channel::{Receiver, Sender};
+
+/// Number of hash slots keys are distributed over, every slot is owned by exactly one shard.
+pub(crate) const SLOT_COUNT: usize = 16384;
+pub(crate) const DEFAULT_SHARD_COUNT: usize = 128;
+pub(crate) const MAX_SHARD_COUNT: usize = 1024;
+// keys removed under a single shard write lock by a pattern delete
+const DELETE_BATCH_KEYS: usize = 1024;
+// keys listed by /KEYS at most without --keys-limit
+pub(crate) const DEFAULT_KEYS_LIMIT: usize = 1000;
+// keys expiring first looked at by every eviction round, before the others
+const EVICTION_CANDIDATES: usize = 1024;
 
 #[derive(Debug, Clone)]
-pub struct Storage(pub(crate) Arc<[CachePadded<RwLock<Shard>>; 128]>);
+pub struct Storage {
+    // MAX_SHARD_COUNT of them whatever --shards is, the ones past shard_count unused: a
+    // split or a resize only hands slots over to more of them, as the read and write
+    // guards of in flight requests borrow their locks out of this slice, which could not
+    // be reallocated under them. An unused shard costs its lock and an empty map.
+    pub(crate) shards: Arc<[ShardLock]>,
+    // wait times of the shard locks, indexed like the shards
+    lock_stats: Arc<[CachePadded<LockStats>]>,
+
+    // owner shard of every hash slot
+    slots: Arc<[AtomicUsize]>,
+    pub(crate) shard_hash: ShardHash,
+    shard_count: Arc<AtomicUsize>,
+
+    // held exclusively while slots change owner, shared by whoever needs a stable layout
+    pub(crate) layout_lock: Arc<RwLock<()>>,
+    pub(crate) resharding: Arc<Mutex<Option<Resharding>>>,
+
+    pub(crate) journal: Arc<Journal>,
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) operations: Arc<Operations>,
+    pub(crate) search: Arc<SearchIndex>,
+    pub(crate) tags: Arc<TagIndex>,
+    pub(crate) schedules: Arc<Schedules>,
+    pub(crate) tombstones: Arc<Tombstones>,
+    pub(crate) pubsub: Arc<PubSub>,
+    pub(crate) memory: Arc<Memory>,
+    // keys listed by /KEYS at most
+    pub(crate) keys_limit: usize,
+    // backups asked for ahead of the next interval
+    #[cfg(feature = "backup")]
+    pub(crate) backup_requests: (Sender<()>, Receiver<()>),
+    // append-only file of --aof, whose writer some writes wait for
+    #[cfg(feature = "backup")]
+    pub(crate) aof: Option<Arc<Aof>>,
+    pub(crate) slowlog: Arc<SlowLog>,
+    // address of the primary on replicas, whose records only change by its writes
+    #[cfg(feature = "replication")]
+    pub(crate) primary: Arc<OnceLock<String>>,
+    // slots owned by every node of the cluster, none outside of cluster mode
+    #[cfg(feature = "cluster")]
+    pub(crate) cluster: Option<Arc<Cluster>>,
+    #[cfg(feature = "chaos")]
+    pub(crate) chaos: Arc<Chaos>,
+}
+
+#[derive(Debug, Default)]
+pub struct Shard {
+    // copy on write: a snapshot keeps the current map alive while writers get a fresh copy
+    pub(crate) records: Arc<HashMap<String, WrappedRecord>>,
+
+    // bumped on every mutation, tells backups which shards changed
+    pub(crate) version: u64,
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
-pub struct Shard(pub(crate) HashMap<String, WrappedRecord>);
+    // set while the shard content still lives in the backup archive,
+    // the first access loads it
+    #[cfg(feature = "backup")]
+    pub(crate) pending: Option<PendingShard>,
+}
+
+impl Shard {
+    pub(crate) fn records_mut(&mut self) -> &mut HashMap<String, WrappedRecord> {
+        self.version += 1;
+        Arc::make_mut(&mut self.records)
+    }
+
+    fn replace_records(&mut self, records: HashMap<String, WrappedRecord>) {
+        self.version += 1;
+        self.records = Arc::new(records);
+    }
+}
+
+/// When [`Storage::set_record`] writes, checked under the shard write lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum SetCondition {
+    #[default]
+    Always,
+    // NX, `key_exists` otherwise
+    IfAbsent,
+    // XX, `record_not_found` otherwise
+    IfExists,
+}
+
+/// Size and activity of a shard.
+pub(crate) struct ShardStats {
+    pub(crate) keys: usize,
+    // keys and values, without the map overhead
+    pub(crate) bytes: usize,
+    // mutations since startup
+    pub(crate) writes: u64,
+    pub(crate) lock: LockReport,
+}
+
+/// Point in time copy of every shard, consistent across shards.
+#[cfg(any(feature = "backup", feature = "replication"))]
+// replication only sends the records
+#[cfg_attr(not(feature = "backup"), allow(dead_code))]
+pub(crate) struct Snapshot {
+    pub(crate) shards: Vec<Arc<HashMap<String, WrappedRecord>>>,
+    pub(crate) versions: Vec<u64>,
+    pub(crate) layout: Vec<usize>,
+    pub(crate) shard_hash: ShardHash,
+    // journal sequence, not lower than the version of any record in the snapshot
+    pub(crate) seq: u64,
+}
 
 impl Default for Storage {
     fn default() -> Self {
-        Self(Arc::new(
-            std::array::from_fn(|_| CachePadded::new(RwLock::new(Shard::default())))
-        ))
+        Self {
+            shards: (0..MAX_SHARD_COUNT)
+                .map(|_| ShardLock::new(ShardLockMode::default()))
+                .collect(),
+            lock_stats: (0..MAX_SHARD_COUNT)
+                .map(|_| CachePadded::new(LockStats::default()))
+                .collect(),
+            slots: (0..SLOT_COUNT)
+                .map(|slot| AtomicUsize::new(slot % DEFAULT_SHARD_COUNT))
+                .collect(),
+            shard_count: Arc::new(AtomicUsize::new(DEFAULT_SHARD_COUNT)),
+            shard_hash: ShardHash::default(),
+            layout_lock: Arc::new(RwLock::new(())),
+            resharding: Arc::new(Mutex::new(None)),
+            journal: Arc::new(Journal::default()),
+            stats: Arc::new(Stats::default()),
+            operations: Arc::new(Operations::default()),
+            search: Arc::new(SearchIndex::default()),
+            tags: Arc::new(TagIndex::default()),
+            schedules: Arc::new(Schedules::default()),
+            tombstones: Arc::new(Tombstones::default()),
+            pubsub: Arc::new(PubSub::default()),
+            memory: Arc::new(Memory::default()),
+            keys_limit: DEFAULT_KEYS_LIMIT,
+            #[cfg(feature = "backup")]
+            backup_requests: smol::channel::bounded(1),
+            #[cfg(feature = "backup")]
+            aof: None,
+            slowlog: Arc::new(SlowLog::default()),
+            #[cfg(feature = "replication")]
+            primary: Arc::new(OnceLock::new()),
+            #[cfg(feature = "cluster")]
+            cluster: None,
+            #[cfg(feature = "chaos")]
+            chaos: Arc::new(Chaos::default()),
+        }
     }
 }
 
 impl Storage {
-    fn hash_key(&self, key: &str) -> usize {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hasher_finished = hasher.finish() as usize;
-        let len = self.0.len();
+    pub(crate) fn new(
+        journal_capacity: usize,
+        search_prefixes: Vec<String>,
+        shard_hash: ShardHash,
+        shard_lock: ShardLockMode,
+        shard_count: usize,
+        tombstone_ttl: Option<Duration>,
+        keys_limit: usize,
+    ) -> Self {
+        Self {
+            tombstones: Arc::new(Tombstones::new(tombstone_ttl)),
+            // every shard splits and resizes can reach, see `shards`
+            shards: (0..MAX_SHARD_COUNT).map(|_| ShardLock::new(shard_lock)).collect(),
+            slots: (0..SLOT_COUNT)
+                .map(|slot| AtomicUsize::new(slot % shard_count))
+                .collect(),
+            shard_count: Arc::new(AtomicUsize::new(shard_count)),
+            shard_hash,
+            journal: Arc::new(Journal::new(journal_capacity)),
+            search: Arc::new(SearchIndex::new(search_prefixes)),
+            keys_limit,
+            ..Default::default()
+        }
+    }
+
+    /// The storage of an instance appending its changes to `aof`.
+    #[cfg(feature = "backup")]
+    pub(crate) fn with_aof(self, aof: Option<Arc<Aof>>) -> Self {
+        Self { aof, ..self }
+    }
+
+    /// Whether this instance follows a primary, refusing writes of its own.
+    #[cfg(feature = "replication")]
+    pub(crate) fn is_replica(&self) -> bool {
+        self.primary.get().is_some()
+    }
+
+    /// The storage of a node owning the slots `cluster` gives it, of every slot without one.
+    #[cfg(feature = "cluster")]
+    pub(crate) fn with_cluster(self, cluster: Option<Arc<Cluster>>) -> Self {
+        Self { cluster, ..self }
+    }
+
+    /// Keeps the search and tag indexes in line with the record now at `key`, `None` once
+    /// removed. Only plain values are searchable, and a key written is no longer missing.
+    pub(crate) fn reindex(&self, key: &str, record: Option<&Record>) {
+        if record.is_some() {
+            self.tombstones.remove(key);
+        }
+        if self.search.is_enabled() {
+            let value = record.and_then(|record| record.value(RecordKind::Bytes).ok());
+            self.search.update(key, value);
+        }
+        self.tags.update(key, record.map_or(&[], |record| &record.tags));
+    }
+
+    fn unindex_shard(&self, shard: &Shard) {
+        shard.records.keys().for_each(|key| self.reindex(key, None));
+    }
+
+    pub(crate) fn key_slot(&self, key: &str) -> usize {
+        self.shard_hash.slot(key)
+    }
+
+    pub(crate) fn shard_count(&self) -> usize {
+        self.shard_count.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn max_shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Owner shard of every slot, indexed by slot.
+    pub(crate) fn layout(&self) -> Vec<usize> {
+        self.slots
+            .iter()
+            .map(|owner| owner.load(Ordering::Acquire))
+            .collect()
+    }
+
+    /// Restores a slot layout saved in a backup, the shard count follows the highest owner.
+    #[cfg(feature = "backup")]
+    pub(crate) fn restore_layout(&self, layout: &[usize]) -> bool {
+        if layout.len() != SLOT_COUNT || layout.iter().any(|owner| *owner >= self.max_shard_count()) {
+            return false;
+        }
+
+        for (slot, owner) in layout.iter().enumerate() {
+            self.slots[slot].store(*owner, Ordering::Release);
+        }
+        let shard_count = layout.iter().max().map_or(0, |owner| owner + 1);
+        self.shard_count.store(shard_count, Ordering::Release);
+        true
+    }
+
+    pub(crate) fn owned_slots(&self, shard_index: usize) -> Vec<usize> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, owner)| owner.load(Ordering::Acquire) == shard_index)
+            .map(|(slot, _)| slot)
+            .collect()
+    }
+
+    pub(crate) fn set_shard_count(&self, shard_count: usize) {
+        self.shard_count.store(shard_count, Ordering::Release);
+    }
+
+    /// Activates one more shard, returning its index.
+    pub(crate) fn add_shard(&self) -> Result<usize, TransactionError> {
+        self.shard_count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < self.max_shard_count()).then_some(count + 1)
+            })
+            .map_err(|_| TransactionError::ShardLimitReached)
+    }
+
+    /// Acquires the read lock of a shard, restoring it from the backup archive first
+    /// if it has not been loaded yet.
+    pub(crate) async fn read_shard(&self, shard_index: usize) -> Result<ShardReadGuard<'_>, TransactionError> {
+        let shard = self.shards.get(shard_index).ok_or(TransactionError::ShardNotFound)?;
+        let locked_shard = match shard.try_read() {
+            Some(locked_shard) => {
+                self.lock_stats[shard_index].read_acquired(None);
+                locked_shard
+            }
+            None => {
+                let start = Instant::now();
+                let locked_shard = shard.read().await;
+                self.lock_stats[shard_index].read_acquired(Some(start.elapsed()));
+                locked_shard
+            }
+        };
+        #[cfg(feature = "backup")]
+        if locked_shard.pending.is_some() {
+            drop(locked_shard);
+            return self
+                .write_shard(shard_index)
+                .await
+                .map(ShardWriteGuard::downgrade);
+        }
+
+        Ok(locked_shard)
+    }
+
+    /// Acquires the write lock of a shard, restoring it from the backup archive first
+    /// if it has not been loaded yet.
+    ///
+    /// A shard failing to load stays pending and its accesses fail, the next load is tried
+    /// by the next access.
+    pub(crate) async fn write_shard(&self, shard_index: usize) -> Result<ShardWriteGuard<'_>, TransactionError> {
+        let shard = self.shards.get(shard_index).ok_or(TransactionError::ShardNotFound)?;
+        #[allow(unused_mut)]
+        let mut locked_shard = match shard.try_write() {
+            Some(locked_shard) => {
+                self.lock_stats[shard_index].write_acquired(None);
+                locked_shard
+            }
+            None => {
+                let start = Instant::now();
+                let locked_shard = shard.write().await;
+                self.lock_stats[shard_index].write_acquired(Some(start.elapsed()));
+                locked_shard
+            }
+        };
+        #[cfg(feature = "backup")]
+        if let Some(pending) = &locked_shard.pending {
+            let records = pending.load().await.map_err(|e| {
+                error!("shard {} not restored: {}", shard_index, e);
+                TransactionError::ShardNotLoaded
+            })?;
+            let restored = self.restore_records(records);
+            locked_shard.replace_records(restored);
+            locked_shard.pending = None;
+        }
+
+        Ok(locked_shard)
+    }
+
+    /// Read locks the shard currently owning `key`.
+    ///
+    /// The owner is checked again once the lock is held: a slot only changes owner while
+    /// both shards are write locked, so a match means the key cannot move away anymore.
+    pub(crate) async fn read_key_shard(&self, key: &str) -> Result<(usize, ShardReadGuard<'_>), TransactionError> {
+        let slot = self.key_slot(key);
+        loop {
+            let shard_index = self.slots[slot].load(Ordering::Acquire);
+            let locked_shard = self.read_shard(shard_index).await?;
+            if self.slots[slot].load(Ordering::Acquire) == shard_index {
+                return Ok((shard_index, locked_shard));
+            }
+        }
+    }
+
+    /// Write locks the shard currently owning `key`, see [`Storage::read_key_shard`].
+    pub(crate) async fn write_key_shard(&self, key: &str) -> Result<(usize, ShardWriteGuard<'_>), TransactionError> {
+        let slot = self.key_slot(key);
+        loop {
+            let shard_index = self.slots[slot].load(Ordering::Acquire);
+            let locked_shard = self.write_shard(shard_index).await?;
+            if self.slots[slot].load(Ordering::Acquire) == shard_index {
+                return Ok((shard_index, locked_shard));
+            }
+        }
+    }
+
+    /// Moves the records of `slots` from shard `from` to shard `to` and hands over their ownership.
+    pub(crate) async fn migrate_slots(&self, from: usize, to: usize, slots: &[usize]) -> Result<(), TransactionError> {
+        let _layout = self.layout_lock.write().await;
+
+        // shards are always locked in index order
+        let (source, target) = if from < to {
+            let source = self.write_shard(from).await?;
+            (source, self.write_shard(to).await?)
+        } else {
+            let target = self.write_shard(to).await?;
+            (self.write_shard(from).await?, target)
+        };
+        let (mut source, mut target) = (source, target);
+
+        let moving: HashSet<usize> = slots.iter().copied().collect();
+        target.records_mut().extend(
+            source
+                .records_mut()
+                .extract_if(|key, _| moving.contains(&self.key_slot(key))),
+        );
+
+        for slot in slots {
+            self.slots[*slot].store(to, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Takes a consistent copy of the whole dataset.
+    ///
+    /// The read locks of all shards are held together, in index order, only for the time
+    /// needed to clone their maps: writers copy a map before changing it while it is shared.
+    #[cfg(any(feature = "backup", feature = "replication"))]
+    pub(crate) async fn snapshot(&self) -> Result<Snapshot, TransactionError> {
+        let _layout = self.layout_lock.read().await;
+
+        let shard_count = self.shard_count();
+        let mut locked_shards = Vec::with_capacity(shard_count);
+        for shard_index in 0..shard_count {
+            locked_shards.push(self.read_shard(shard_index).await?);
+        }
+
+        Ok(Snapshot {
+            shards: locked_shards
+                .iter()
+                .map(|locked_shard| locked_shard.records.clone())
+                .collect(),
+            versions: locked_shards
+                .iter()
+                .map(|locked_shard| locked_shard.version)
+                .collect(),
+            layout: self.layout(),
+            shard_hash: self.shard_hash,
+            seq: self.journal.last_seq(),
+        })
+    }
+
+    /// Replaces the content of a shard with records coming from a backup,
+    /// rescheduling the expiration of the ones having a ttl policy.
+    #[cfg(feature = "backup")]
+    pub(crate) async fn restore_shard(
+        &self,
+        shard_index: usize,
+        records: HashMap<String, WrappedRecord>,
+    ) {
+        if let Some(shard) = self.shards.get(shard_index) {
+            let mut locked_shard = shard.write().await;
+            self.unindex_shard(&locked_shard);
+            let restored = self.restore_records(records);
+            locked_shard.replace_records(restored);
+            locked_shard.pending = None;
+        }
+    }
+
+    /// Adds records coming from a backup written with another hash function to the shards
+    /// owning their keys now.
+    #[cfg(feature = "backup")]
+    pub(crate) async fn restore_rehashed(&self, records: HashMap<String, WrappedRecord>) {
+        let mut owned: BTreeMap<usize, HashMap<String, WrappedRecord>> = BTreeMap::new();
+        for (key, wrecord) in records {
+            let shard_index = self.slots[self.key_slot(&key)].load(Ordering::Acquire);
+            owned.entry(shard_index).or_default().insert(key, wrecord);
+        }
+        for (shard_index, records) in owned {
+            if let Ok(mut locked_shard) = self.write_shard(shard_index).await {
+                let restored = self.restore_records(records);
+                locked_shard.records_mut().extend(restored);
+            }
+        }
+    }
+
+    /// Applies the records of an uploaded backup, returning how many were restored. With
+    /// `replace` the current records are dropped first, journaled like a flush.
+    ///
+    /// Records go to the shards owning their keys, whatever the layout of the backup. The
+    /// write locks of all shards are held together, taken in index order, so no other
+    /// request sees a part of the restore.
+    #[cfg(feature = "backup")]
+    pub(crate) async fn restore_records_from(
+        &self,
+        records: Vec<(String, Record)>,
+        replace: bool,
+    ) -> Result<usize, TransactionError> {
+        let _layout = self.layout_lock.read().await;
+
+        let shard_count = self.shard_count();
+        let mut locked_shards = Vec::with_capacity(shard_count);
+        for shard_index in 0..shard_count {
+            locked_shards.push(self.write_shard(shard_index).await?);
+        }
+
+        if replace {
+            for locked_shard in locked_shards.iter_mut() {
+                self.unindex_shard(locked_shard);
+                for wrecord in locked_shard.records.values() {
+                    if let Some(timer) = &wrecord.detatched_task_ch {
+                        let _ = timer.try_send(TTLResult::Cancelled);
+                    }
+                }
+                locked_shard.replace_records(HashMap::new());
+                locked_shard.pending = None;
+            }
+            self.tombstones.clear();
+            self.journal.record(ChangeKind::FlushAll, None);
+        }
 
-        hasher_finished % len
+        let restored = records.len();
+        for (key, record) in records {
+            let shard_index = self.slots[self.key_slot(&key)].load(Ordering::Acquire);
+            let Some(locked_shard) = locked_shards.get_mut(shard_index) else {
+                continue;
+            };
+            let version = self.journal.record(ChangeKind::Set, Some(&key));
+            self.reindex(&key, Some(&record));
+            let wrecord = WrappedRecord::new(self.clone(), &key, record, version);
+            if let Some(timer) = locked_shard.records_mut().insert(key, wrecord).and_then(|prev| prev.detatched_task_ch) {
+                let _ = timer.try_send(TTLResult::Cancelled);
+            }
+        }
+        Ok(restored)
     }
 
-    pub async fn flush_all(&self) {
-        for rwlock in self.0.iter() {
-            rwlock.write().await.0.clear();
+    /// Marks a shard as lazily restorable, its content is loaded on first access.
+    #[cfg(feature = "backup")]
+    pub(crate) async fn defer_shard(&self, shard_index: usize, pending: PendingShard) {
+        if let Some(shard) = self.shards.get(shard_index) {
+            shard.write().await.pending = Some(pending);
         }
     }
 
+    #[cfg(feature = "backup")]
+    fn restore_records(
+        &self,
+        records: HashMap<String, WrappedRecord>,
+    ) -> HashMap<String, WrappedRecord> {
+        records
+            .into_iter()
+            .map(|(key, wrecord)| {
+                // records written before a restart must never look newer than later writes
+                self.journal.observe(wrecord.version);
+                self.reindex(&key, Some(&wrecord.record));
+                let restored = WrappedRecord::new(self.clone(), &key, wrecord.record, wrecord.version);
+                (key, restored)
+            })
+            .collect()
+    }
+
+    /// Empties every shard, stopping between shards if cancelled through the operations
+    /// registry. Even a cancelled flush is journaled once it emptied a shard.
+    pub async fn flush_all(&self) -> Result<(), TransactionError> {
+        let operation = self.operations.start("flushall", "flushing all shards".to_string());
+        let total = self.shards.len() as u64;
+        let mut flushed = 0;
+        for rwlock in self.shards.iter() {
+            if operation.is_cancelled() {
+                break;
+            }
+            let mut locked_shard = rwlock.write().await;
+            self.unindex_shard(&locked_shard);
+            locked_shard.replace_records(HashMap::new());
+            #[cfg(feature = "backup")]
+            {
+                locked_shard.pending = None;
+            }
+            flushed += 1;
+            operation.progress(flushed, total);
+        }
+
+        if flushed > 0 {
+            self.tombstones.clear();
+            self.journal.record(ChangeKind::FlushAll, None);
+        }
+        if flushed < total {
+            return Err(TransactionError::OperationCancelled);
+        }
+        Ok(())
+    }
+
     pub async fn db_size(&self) -> usize {
-        let mut tot_cap: usize = 0;
-        for rwlock in self.0.iter() {
-            tot_cap += rwlock.read().await.0.capacity();
+        let mut tot_len: usize = 0;
+        for shard_index in 0..self.shard_count() {
+            if let Ok(locked_shard) = self.read_shard(shard_index).await {
+                tot_len += locked_shard.records.len();
+            }
+        }
+        tot_len
+    }
+
+    /// Per shard key count, byte estimate and write count of the active shards.
+    pub(crate) async fn shard_stats(&self) -> Vec<ShardStats> {
+        let mut shard_stats = Vec::with_capacity(self.shard_count());
+        for shard_index in 0..self.shard_count() {
+            if let Ok(locked_shard) = self.read_shard(shard_index).await {
+                shard_stats.push(ShardStats {
+                    keys: locked_shard.records.len(),
+                    bytes: locked_shard
+                        .records
+                        .iter()
+                        .map(|(key, wrecord)| key.len() + wrecord.record.data.len())
+                        .sum(),
+                    writes: locked_shard.version,
+                    lock: self.lock_stats[shard_index].report(),
+                });
+            }
         }
-        return tot_cap;
+        shard_stats
+    }
+
+    /// The `limit` keys expiring first within `within`, with the time they have left,
+    /// soonest first. Every shard is scanned under its read lock.
+    pub(crate) async fn expiring(&self, within: Duration, limit: usize) -> Vec<(String, Duration)> {
+        // the soonest ones seen so far, the latest of them on top
+        let mut soonest: BinaryHeap<(Duration, String)> = BinaryHeap::with_capacity(limit + 1);
+        for shard_index in 0..self.shard_count() {
+            let Ok(locked_shard) = self.read_shard(shard_index).await else {
+                continue;
+            };
+            for (key, wrecord) in locked_shard.records.iter() {
+                let Some(ttl_policy) = &wrecord.record.ttl_policy else {
+                    continue;
+                };
+                let left = ttl_policy.expire_in();
+                let full = soonest.len() == limit;
+                if left > within || full && soonest.peek().is_some_and(|(latest, _)| left >= *latest) {
+                    continue;
+                }
+                soonest.push((left, key.clone()));
+                if soonest.len() > limit {
+                    soonest.pop();
+                }
+            }
+        }
+        soonest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(left, key)| (key, left))
+            .collect()
     }
 
     pub async fn get_record(&self, key: &str) -> Result<Record, TransactionError> {
-        match self.0.get(self.hash_key(key)) {
-            Some(shard) => match shard.read().await.0.get(key) {
-                Some(data) => Ok(data.record.clone()),
-                None => Err(TransactionError::RecordNotFound),
-            },
-            None => Err(TransactionError::ShardNotFound),
+        self.get_versioned_record(key)
+            .await
+            .map(|(record, _)| record)
+    }
+
+    /// Returns a record together with its logical clock.
+    /// Reading a record restarts its ttl if sliding.
+    pub async fn get_versioned_record(&self, key: &str) -> Result<(Record, u64), TransactionError> {
+        let (_, shard) = self.read_key_shard(key).await?;
+        let wrecord = shard.records.get(key);
+        self.stats.lookup(wrecord.is_some());
+        match wrecord {
+            Some(data) => {
+                if let Some(ttl_policy) = &data.record.ttl_policy {
+                    ttl_policy.touch();
+                }
+                Ok((data.record.clone(), data.version))
+            }
+            None if self.tombstones.is_buried(key) => Err(TransactionError::KnownMissing),
+            None => Err(TransactionError::RecordNotFound),
+        }
+    }
+
+    /// Waits up to `timeout` for the record at `key` to move away from `version`, 0 standing
+    /// for no record, then returns it like [`Storage::get_versioned_record`]. Without a
+    /// version it waits for the next change.
+    pub(crate) async fn watch_record(
+        &self,
+        key: &str,
+        version: Option<u64>,
+        timeout: Duration,
+    ) -> Result<(Record, u64), TransactionError> {
+        // watched before reading the version, a change in between is not missed
+        let changed = self.journal.watch(key);
+        let current = self
+            .read_key_shard(key)
+            .await?
+            .1
+            .records
+            .get(key)
+            .map_or(0, |wrecord| wrecord.version);
+        if version.is_none_or(|version| version == current) {
+            let _ = changed
+                .recv()
+                .or(async {
+                    Timer::after(timeout).await;
+                    Ok(())
+                })
+                .await;
+        }
+        drop(changed);
+        self.journal.unwatch(key);
+
+        self.get_versioned_record(key).await
+    }
+
+    /// Reads the records at `keys` with their version as of a single point in time, in the
+    /// order of `keys`, `None` for the missing ones.
+    ///
+    /// The read locks of the shards involved are held together, taken in index order, so
+    /// no write lands between the reads.
+    pub(crate) async fn get_snapshot(&self, keys: &[String]) -> Result<Vec<Option<(Record, u64)>>, TransactionError> {
+        let _layout = self.layout_lock.read().await;
+
+        let shard_of = |key: &str| self.slots[self.key_slot(key)].load(Ordering::Acquire);
+        let shard_indexes: BTreeSet<usize> = keys.iter().map(|key| shard_of(key)).collect();
+        let mut locked_shards = BTreeMap::new();
+        for shard_index in shard_indexes {
+            let locked_shard = self.read_shard(shard_index).await?;
+            locked_shards.insert(shard_index, locked_shard);
+        }
+
+        Ok(keys
+            .iter()
+            .map(|key| {
+                let wrecord = locked_shards
+                    .get(&shard_of(key))
+                    .and_then(|locked_shard| locked_shard.records.get(key));
+                self.stats.lookup(wrecord.is_some());
+                wrecord.map(|wrecord| {
+                    if let Some(ttl_policy) = &wrecord.record.ttl_policy {
+                        ttl_policy.touch();
+                    }
+                    (wrecord.record.clone(), wrecord.version)
+                })
+            })
+            .collect())
+    }
+
+    /// Reads the records at `keys` with their version, in the order of `keys`, `None` for
+    /// the missing ones. Every shard involved is read locked once, unlike
+    /// [`Storage::get_snapshot`] writes can land between two shards.
+    pub(crate) async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<(Record, u64)>>, TransactionError> {
+        let mut records = vec![None; keys.len()];
+        let mut moved = Vec::new();
+        for (shard_index, keys) in self.group_by_shard(keys.iter().enumerate().collect(), |(_, key)| key) {
+            let locked_db = self.read_shard(shard_index).await?;
+            for (position, key) in keys {
+                if !self.owns(shard_index, key) {
+                    moved.push((position, key));
+                    continue;
+                }
+
+                let wrecord = locked_db.records.get(key);
+                self.stats.lookup(wrecord.is_some());
+                records[position] = wrecord.map(|wrecord| {
+                    if let Some(ttl_policy) = &wrecord.record.ttl_policy {
+                        ttl_policy.touch();
+                    }
+                    (wrecord.record.clone(), wrecord.version)
+                });
+            }
+        }
+
+        for (position, key) in moved {
+            records[position] = match self.get_versioned_record(key).await {
+                Ok(record) => Some(record),
+                Err(TransactionError::RecordNotFound | TransactionError::KnownMissing) => None,
+                Err(e) => return Err(e),
+            };
         }
+        Ok(records)
     }
 
+    /// Applies the writes of a transaction all at once, returning the result of every
+    /// command: a failing command fails the transaction before anything is written.
+    ///
+    /// The write locks of the shards involved are held together, taken in index order
+    /// like [`Storage::get_snapshot`] does, so no other request sees a part of it.
+    pub(crate) async fn execute(&self, commands: &[TxCommand]) -> Result<Vec<String>, TransactionError> {
+        let _layout = self.layout_lock.read().await;
+
+        let shard_of = |key: &str| self.slots[self.key_slot(key)].load(Ordering::Acquire);
+        let mut locked_shards = self.write_shards(commands.iter().map(|command| shard_of(command.key()))).await?;
+
+        let staged = transaction::stage(commands, |key| {
+            locked_shards
+                .get(&shard_of(key))
+                .and_then(|locked_shard| locked_shard.records.get(key))
+                .map(|wrecord| &wrecord.record)
+        })?;
+
+        let mut versions = HashMap::new();
+        let results = commands
+            .iter()
+            .zip(staged.outcomes)
+            .map(|(command, (change, result))| {
+                let version = change.map(|kind| self.journal.record(kind, Some(command.key())));
+                if let Some(version) = version {
+                    versions.insert(command.key(), version);
+                }
+                result.or(version.map(|version| version.to_string())).unwrap_or_default()
+            })
+            .collect();
+
+        self.put_records(&mut locked_shards, staged.records, |key| versions.get(key).copied().unwrap_or_default());
+        Ok(results)
+    }
+
+    /// Runs an `/EVAL` script against `keys`, the only ones it can read or write, and
+    /// returns what it evaluates to. Its writes are applied once it returns, none if it
+    /// fails, with the shards of the keys write locked throughout as with
+    /// [`Storage::execute`].
+    #[cfg(feature = "scripting")]
+    pub(crate) async fn eval(&self, script: &str, keys: &[String], args: &[String]) -> Result<String, TransactionError> {
+        // before taking any lock, a script that does not compile locks nothing
+        let ast = script::compile(script)?;
+        let _layout = self.layout_lock.read().await;
+
+        let shard_of = |key: &str| self.slots[self.key_slot(key)].load(Ordering::Acquire);
+        let mut locked_shards = self.write_shards(keys.iter().map(|key| shard_of(key))).await?;
+
+        let current = keys
+            .iter()
+            .map(|key| {
+                let record = locked_shards
+                    .get(&shard_of(key))
+                    .and_then(|locked_shard| locked_shard.records.get(key))
+                    .map(|wrecord| wrecord.record.clone());
+                (key.clone(), record)
+            })
+            .collect();
+        let (result, changes) = script::run(&ast, keys, args, current)?;
+
+        let mut versions = HashMap::new();
+        let records = changes
+            .into_iter()
+            .map(|(key, (record, kind))| {
+                versions.insert(key.clone(), self.journal.record(kind, Some(&key)));
+                (key, record)
+            })
+            .collect();
+        self.put_records(&mut locked_shards, records, |key| versions.get(key).copied().unwrap_or_default());
+        Ok(result)
+    }
+
+    /// Changes the values of the `kind` records at `keys` all at once, `update` getting them
+    /// by key, `None` for the missing ones, and leaving `None` to remove a record. The
+    /// shards of the keys are write locked together as with [`Storage::execute`], so no
+    /// other request sees a part of the change. Changed records keep their ttl. Returns the
+    /// result of `update` with the version of every record left.
+    ///
+    /// Nothing is written when `update` fails.
+    pub(crate) async fn update_values<T>(
+        &self,
+        keys: &[String],
+        kind: RecordKind,
+        update: impl FnOnce(&mut HashMap<String, Option<Vec<u8>>>) -> Result<T, TransactionError>,
+    ) -> Result<(T, HashMap<String, u64>), TransactionError> {
+        let _layout = self.layout_lock.read().await;
+
+        let shard_of = |key: &str| self.slots[self.key_slot(key)].load(Ordering::Acquire);
+        let mut locked_shards = self.write_shards(keys.iter().map(|key| shard_of(key))).await?;
+
+        let mut current = HashMap::new();
+        for key in keys {
+            let wrecord = locked_shards
+                .get(&shard_of(key))
+                .and_then(|locked_shard| locked_shard.records.get(key));
+            if let Some(wrecord) = wrecord {
+                wrecord.record.value(kind)?;
+            }
+            current.insert(key.clone(), wrecord.map(|wrecord| (wrecord.record.clone(), wrecord.version)));
+        }
+        let mut values = current
+            .iter()
+            .map(|(key, record)| (key.clone(), record.as_ref().map(|(record, _)| record.data.clone())))
+            .collect();
+        let result = update(&mut values)?;
+
+        let mut versions = HashMap::new();
+        let mut records = HashMap::new();
+        for (key, value) in values {
+            let Some(previous) = current.remove(&key) else {
+                continue;
+            };
+            match (previous, value) {
+                (Some((record, version)), Some(data)) if record.data == data => {
+                    versions.insert(key, version);
+                }
+                (previous, Some(data)) => {
+                    let record = match previous {
+                        Some((mut record, _)) => {
+                            record.data = data;
+                            record
+                        }
+                        None => Record::typed(kind, data, None),
+                    };
+                    versions.insert(key.clone(), self.journal.record(ChangeKind::Set, Some(&key)));
+                    records.insert(key, Some(record));
+                }
+                (Some(_), None) => {
+                    self.journal.record(ChangeKind::Del, Some(&key));
+                    records.insert(key, None);
+                }
+                (None, None) => {}
+            }
+        }
+        self.put_records(&mut locked_shards, records, |key| versions.get(key).copied().unwrap_or_default());
+        Ok((result, versions))
+    }
+
+    // write locks the shards in index order, each one once
+    async fn write_shards(
+        &self,
+        shard_indexes: impl Iterator<Item = usize>,
+    ) -> Result<BTreeMap<usize, ShardWriteGuard<'_>>, TransactionError> {
+        let mut locked_shards = BTreeMap::new();
+        for shard_index in shard_indexes.collect::<BTreeSet<usize>>() {
+            let locked_shard = self.write_shard(shard_index).await?;
+            locked_shards.insert(shard_index, locked_shard);
+        }
+        Ok(locked_shards)
+    }
+
+    // puts the final records of a transaction in their locked shards, removing the `None` ones
+    fn put_records(
+        &self,
+        locked_shards: &mut BTreeMap<usize, ShardWriteGuard<'_>>,
+        records: HashMap<String, Option<Record>>,
+        version: impl Fn(&str) -> u64,
+    ) {
+        for (key, record) in records {
+            let shard_index = self.slots[self.key_slot(&key)].load(Ordering::Acquire);
+            let Some(locked_shard) = locked_shards.get_mut(&shard_index) else {
+                continue;
+            };
+            self.reindex(&key, record.as_ref());
+            let prev = match record {
+                Some(record) => {
+                    let wrecord = WrappedRecord::new(self.clone(), &key, record, version(&key));
+                    locked_shard.records_mut().insert(key, wrecord)
+                }
+                None => {
+                    self.tombstones.bury(&key, None);
+                    match locked_shard.records.contains_key(&key) {
+                        true => locked_shard.records_mut().remove(&key),
+                        false => None,
+                    }
+                }
+            };
+            if let Some(timer) = prev.and_then(|prev| prev.detatched_task_ch) {
+                let _ = timer.try_send(TTLResult::Cancelled);
+            }
+        }
+    }
+
+    /// Changes the ttl of a record, returning its new version.
     pub async fn update_ttl(
         &self,
         key: &str,
         new_ttl: Option<Duration>,
-    ) -> Result<(), TransactionError> {
-        let hash_index = self.hash_key(key);
-        match self.0.get(hash_index) {
-            Some(shard) => {
-                let mut record_lock = shard.write().await;
-                match record_lock.0.get_mut(key) {
-                    Some(wrecord) => {
-                        wrecord.update_ttl_policy(
-                            new_ttl,
-                            self.clone(),
-                            hash_index,
-                            key.to_owned(),
-                        );
-
-                        Ok(())
-                    }
+    ) -> Result<u64, TransactionError> {
+        match self.write_key_shard(key).await {
+            Ok((_, mut record_lock)) => {
+                match record_lock.records_mut().get_mut(key) {
+                    Some(wrecord) => Ok(self.apply_ttl(wrecord, key, new_ttl)),
                     None => Err(TransactionError::RecordNotFound),
                 }
             }
-            None => Err(TransactionError::ShardNotFound),
+            Err(e) => Err(e),
         }
     }
 
+    /// Sets or removes the ttl of many records, write locking every shard involved once.
+    /// Returns how many of the keys exist.
+    pub(crate) async fn update_ttls(
+        &self,
+        keys: Vec<String>,
+        new_ttl: Option<Duration>,
+    ) -> Result<usize, TransactionError> {
+        let mut updated = 0;
+        let mut moved = Vec::new();
+        for (shard_index, keys) in self.group_by_shard(keys, |key| key) {
+            let mut locked_db = self.write_shard(shard_index).await?;
+            for key in keys {
+                if !self.owns(shard_index, &key) {
+                    moved.push(key);
+                } else if locked_db.records.contains_key(&key) {
+                    if let Some(wrecord) = locked_db.records_mut().get_mut(&key) {
+                        self.apply_ttl(wrecord, &key, new_ttl);
+                        updated += 1;
+                    }
+                }
+            }
+        }
+
+        for key in moved {
+            match self.update_ttl(&key, new_ttl).await {
+                Ok(_) => updated += 1,
+                Err(TransactionError::RecordNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(updated)
+    }
+
+    /// Changes the ttl of a record under its shard write lock, returning its new version.
+    fn apply_ttl(&self, wrecord: &mut WrappedRecord, key: &str, new_ttl: Option<Duration>) -> u64 {
+        wrecord.update_ttl_policy(new_ttl, self.clone(), key.to_owned());
+        let kind = match new_ttl {
+            Some(_) => ChangeKind::Expire,
+            None => ChangeKind::Persist,
+        };
+        wrecord.version = self.journal.record(kind, Some(key));
+        wrecord.version
+    }
+
+    /// Groups items by the shard currently owning their key, in shard order.
+    fn group_by_shard<T>(&self, items: Vec<T>, key: impl Fn(&T) -> &str) -> BTreeMap<usize, Vec<T>> {
+        let mut by_shard: BTreeMap<usize, Vec<T>> = BTreeMap::new();
+        for item in items {
+            let shard_index = self.slots[self.key_slot(key(&item))].load(Ordering::Acquire);
+            by_shard.entry(shard_index).or_default().push(item);
+        }
+        by_shard
+    }
+
+    /// Whether `key` belongs to the shard, checked while holding its lock.
+    fn owns(&self, shard_index: usize, key: &str) -> bool {
+        self.slots[self.key_slot(key)].load(Ordering::Acquire) == shard_index
+    }
+
+    /// Adds `delta` to the integer stored at `key`, a missing key counting as 0, and returns
+    /// the new value with the record version. The ttl of the record is kept.
+    pub(crate) async fn incr_by(&self, key: &str, delta: i64) -> Result<(i64, u64), TransactionError> {
+        let mut value = 0;
+        let version = self
+            .write_record(key, |current| {
+                let (mut record, current) = match current {
+                    Some((record, _)) => {
+                        let current = std::str::from_utf8(record.value(RecordKind::Bytes)?)
+                            .ok()
+                            .and_then(|current| current.trim().parse::<i64>().ok())
+                            .ok_or(TransactionError::ValueNotAnInteger)?;
+                        (record.clone(), current)
+                    }
+                    None => (Record::new(Vec::new(), None), 0),
+                };
+                value = current.checked_add(delta).ok_or(TransactionError::IncrementOverflow)?;
+                record.data = value.to_string().into_bytes();
+                Ok(record)
+            })
+            .await?;
+        Ok((value, version))
+    }
+
+    /// Appends `data` to the value stored at `key`, a missing key counting as empty, and
+    /// returns the new length with the record version. The ttl of the record is kept.
+    pub(crate) async fn append(&self, key: &str, data: &[u8]) -> Result<(usize, u64), TransactionError> {
+        let mut len = 0;
+        let version = self
+            .write_record(key, |current| {
+                let record = match current {
+                    Some((record, _)) => {
+                        let mut appended = record.clone();
+                        appended.data = [record.value(RecordKind::Bytes)?, data].concat();
+                        appended
+                    }
+                    None => Record::new(data.to_vec(), None),
+                };
+                len = record.data.len();
+                Ok(record)
+            })
+            .await?;
+        Ok((len, version))
+    }
+
+    /// Adds `increment` to the float stored at `key`, a missing key counting as 0, and
+    /// returns the new value with the record version. The ttl of the record is kept.
+    pub(crate) async fn incr_by_float(&self, key: &str, increment: f64) -> Result<(f64, u64), TransactionError> {
+        let (_, mut locked_db) = self.write_key_shard(key).await?;
+
+        let current = match locked_db.records.get(key) {
+            Some(wrecord) => std::str::from_utf8(wrecord.record.value(RecordKind::Bytes)?)
+                .ok()
+                .and_then(|value| value.trim().parse::<f64>().ok())
+                .filter(|value| value.is_finite())
+                .ok_or(TransactionError::ValueNotAFloat)?,
+            None => 0.0,
+        };
+        let value = current + increment;
+        if !value.is_finite() {
+            return Err(TransactionError::IncrementOverflow);
+        }
+
+        let version = self.journal.record(ChangeKind::Set, Some(key));
+        let data = value.to_string().into_bytes();
+        match locked_db.records_mut().get_mut(key) {
+            Some(wrecord) => {
+                wrecord.record.data = data;
+                wrecord.version = version;
+                self.reindex(key, Some(&wrecord.record));
+            }
+            None => {
+                let record = Record::new(data, None);
+                self.reindex(key, Some(&record));
+                let wrecord = WrappedRecord::new(self.clone(), key, record, version);
+                locked_db.records_mut().insert(key.to_owned(), wrecord);
+            }
+        }
+        Ok((value, version))
+    }
+
+    /// Runs BITFIELD subcommands on the value at `key`, a missing key reading as zeroes.
+    /// Returns their results with the record version, a version only if the record exists.
+    ///
+    /// Read only subcommands never create the record, writes keep its ttl.
+    pub(crate) async fn bitfield(
+        &self,
+        key: &str,
+        ops: &[BitfieldOp],
+    ) -> Result<(Vec<Option<i64>>, Option<u64>), TransactionError> {
+        if !ops.iter().any(BitfieldOp::writes) {
+            let (_, locked_db) = self.read_key_shard(key).await?;
+            let wrecord = locked_db.records.get(key);
+            let mut data = match wrecord {
+                Some(wrecord) => wrecord.record.value(RecordKind::Bytes)?.to_vec(),
+                None => Vec::new(),
+            };
+            return Ok((bitfield::apply(&mut data, ops), wrecord.map(|wrecord| wrecord.version)));
+        }
+
+        let (_, mut locked_db) = self.write_key_shard(key).await?;
+        let current = locked_db.records.get(key);
+        let mut data = match current {
+            Some(wrecord) => wrecord.record.value(RecordKind::Bytes)?.to_vec(),
+            None => Vec::new(),
+        };
+        let results = bitfield::apply(&mut data, ops);
+
+        // nothing written if every write failed on overflow
+        let written = ops
+            .iter()
+            .zip(&results)
+            .any(|(op, result)| op.writes() && result.is_some());
+        if !written {
+            return Ok((results, current.map(|wrecord| wrecord.version)));
+        }
+
+        let version = self.journal.record(ChangeKind::Set, Some(key));
+        match locked_db.records_mut().get_mut(key) {
+            Some(wrecord) => {
+                wrecord.record.data = data;
+                wrecord.version = version;
+                self.reindex(key, Some(&wrecord.record));
+            }
+            None => {
+                let record = Record::new(data, None);
+                self.reindex(key, Some(&record));
+                let wrecord = WrappedRecord::new(self.clone(), key, record, version);
+                locked_db.records_mut().insert(key.to_owned(), wrecord);
+            }
+        }
+        Ok((results, Some(version)))
+    }
+
+    /// Takes the lease `name` for `ttl`, returning its fencing token: the version of the
+    /// lock record, greater than the token of any earlier lease.
+    pub(crate) async fn acquire_lock(&self, name: &str, ttl: Duration) -> Result<u64, TransactionError> {
+        self.take_lease(name, ttl, None).await
+    }
+
+    /// Takes the lease `name` for `ttl` with `token`, chosen by the node gathering a quorum
+    /// of nodes for it.
+    #[cfg(feature = "cluster")]
+    pub(crate) async fn acquire_lock_as(&self, name: &str, ttl: Duration, token: u64) -> Result<(), TransactionError> {
+        self.take_lease(name, ttl, Some(token)).await.map(|_| ())
+    }
+
+    async fn take_lease(&self, name: &str, ttl: Duration, token: Option<u64>) -> Result<u64, TransactionError> {
+        let (_, mut locked_db) = self.write_key_shard(name).await?;
+        if let Some(wrecord) = locked_db.records.get(name) {
+            wrecord.record.value(RecordKind::Lock)?;
+            // an expired lease may still wait for its timer
+            if !lease_expired(&wrecord.record) {
+                return Err(TransactionError::LockHeld);
+            }
+        }
+
+        let version = self.journal.record(ChangeKind::Set, Some(name));
+        let token = token.unwrap_or(version);
+        let record = Record::typed(RecordKind::Lock, token.to_le_bytes().to_vec(), Some(ttl));
+        self.reindex(name, Some(&record));
+        let wrecord = WrappedRecord::new(self.clone(), name, record, version);
+        if let Some(prev) = locked_db.records_mut().insert(name.to_owned(), wrecord) {
+            if let Some(timer) = prev.detatched_task_ch {
+                let _ = timer.try_send(TTLResult::Cancelled);
+            }
+        }
+        Ok(token)
+    }
+
+    /// Extends the lease `name` to `ttl` from now, if `token` still holds it.
+    pub(crate) async fn renew_lock(&self, name: &str, token: u64, ttl: Duration) -> Result<(), TransactionError> {
+        let (_, mut locked_db) = self.write_key_shard(name).await?;
+        check_lease(&locked_db, name, token)?;
+        if let Some(wrecord) = locked_db.records_mut().get_mut(name) {
+            self.apply_ttl(wrecord, name, Some(ttl));
+        }
+        Ok(())
+    }
+
+    /// Releases the lease `name`, if `token` still holds it.
+    pub(crate) async fn release_lock(&self, name: &str, token: u64) -> Result<(), TransactionError> {
+        let (_, mut locked_db) = self.write_key_shard(name).await?;
+        check_lease(&locked_db, name, token)?;
+        if let Some(prev) = locked_db.records_mut().remove(name) {
+            self.journal.record(ChangeKind::Del, Some(name));
+            self.reindex(name, None);
+            if let Some(timer) = prev.detatched_task_ch {
+                let _ = timer.try_send(TTLResult::Cancelled);
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes one of the `max` permits of the semaphore `name` for `ttl`, returning the token
+    /// of its holder. `now` is in milliseconds since the unix epoch, holders expired by then
+    /// give their permit back.
+    pub(crate) async fn acquire_permit(
+        &self,
+        name: &str,
+        max: u64,
+        ttl: Duration,
+        now: u64,
+    ) -> Result<u64, TransactionError> {
+        let (_, mut locked_db) = self.write_key_shard(name).await?;
+        let mut holders = match locked_db.records.get(name) {
+            Some(wrecord) => wrecord.record.value(RecordKind::Semaphore)?.to_vec(),
+            None => Vec::new(),
+        };
+        if !semaphore::is_valid(&holders) {
+            return Err(TransactionError::WrongType);
+        }
+        if semaphore::purge(&mut holders, now) as u64 >= max {
+            return Err(TransactionError::SemaphoreFull);
+        }
+
+        let token = self.journal.record(ChangeKind::Set, Some(name));
+        semaphore::add(&mut holders, token, now.saturating_add(ttl.as_millis() as u64));
+        match locked_db.records_mut().get_mut(name) {
+            Some(wrecord) => {
+                wrecord.record.data = holders;
+                wrecord.version = token;
+                self.reindex(name, Some(&wrecord.record));
+                // the record lasts as long as its last holder
+                let outlived = wrecord
+                    .record
+                    .ttl_policy
+                    .as_ref()
+                    .is_some_and(|ttl_policy| ttl_policy.expire_in() < ttl);
+                if outlived {
+                    self.apply_ttl(wrecord, name, Some(ttl));
+                }
+            }
+            None => {
+                let record = Record::typed(RecordKind::Semaphore, holders, Some(ttl));
+                self.reindex(name, Some(&record));
+                let wrecord = WrappedRecord::new(self.clone(), name, record, token);
+                locked_db.records_mut().insert(name.to_owned(), wrecord);
+            }
+        }
+        Ok(token)
+    }
+
+    /// Gives back the permit of the semaphore `name` held by `token`, the semaphore going
+    /// away with its last holder. `now` is in milliseconds since the unix epoch.
+    pub(crate) async fn release_permit(&self, name: &str, token: u64, now: u64) -> Result<(), TransactionError> {
+        let (_, mut locked_db) = self.write_key_shard(name).await?;
+        let Some(wrecord) = locked_db.records.get(name) else {
+            return Err(TransactionError::PermitNotHeld);
+        };
+        let mut holders = wrecord.record.value(RecordKind::Semaphore)?.to_vec();
+        if !semaphore::is_valid(&holders) {
+            return Err(TransactionError::WrongType);
+        }
+        semaphore::purge(&mut holders, now);
+        if !semaphore::remove(&mut holders, token) {
+            return Err(TransactionError::PermitNotHeld);
+        }
+
+        if holders.is_empty() {
+            if let Some(prev) = locked_db.records_mut().remove(name) {
+                self.journal.record(ChangeKind::Del, Some(name));
+                self.reindex(name, None);
+                if let Some(timer) = prev.detatched_task_ch {
+                    let _ = timer.try_send(TTLResult::Cancelled);
+                }
+            }
+        } else if let Some(wrecord) = locked_db.records_mut().get_mut(name) {
+            wrecord.record.data = holders;
+            wrecord.version = self.journal.record(ChangeKind::Set, Some(name));
+            self.reindex(name, Some(&wrecord.record));
+        }
+        Ok(())
+    }
+
+    /// Reads the value of the `kind` record at `key`, `read` getting `None` for a missing key.
+    /// Returns its result with the record version, a version only if the record exists.
+    pub(crate) async fn read_value<T>(
+        &self,
+        key: &str,
+        kind: RecordKind,
+        read: impl FnOnce(Option<&[u8]>) -> Result<T, TransactionError>,
+    ) -> Result<(T, Option<u64>), TransactionError> {
+        let (_, locked_db) = self.read_key_shard(key).await?;
+        match locked_db.records.get(key) {
+            Some(wrecord) => Ok((read(Some(wrecord.record.value(kind)?))?, Some(wrecord.version))),
+            None => Ok((read(None)?, None)),
+        }
+    }
+
+    /// Changes the value of the `kind` record at `key` in place, `update` getting `None` for
+    /// a missing key and telling whether it changed anything. Changes keep the ttl, a missing
+    /// key is created by a change leaving a value and removed by one leaving `None`. Returns
+    /// the result of `update` with the record version, a version only if the record exists.
+    ///
+    /// `update` must leave the value untouched when it fails.
+    pub(crate) async fn update_value<T>(
+        &self,
+        key: &str,
+        kind: RecordKind,
+        update: impl FnOnce(&mut Option<Vec<u8>>) -> Result<(T, bool), TransactionError>,
+    ) -> Result<(T, Option<u64>), TransactionError> {
+        self.update_value_with_ttl(key, kind, None, update).await
+    }
+
+    /// Like [`Storage::update_value`], a created record getting a sliding `idle_ttl` that
+    /// every update restarts: the record goes away once left alone that long.
+    pub(crate) async fn update_idle_value<T>(
+        &self,
+        key: &str,
+        kind: RecordKind,
+        idle_ttl: Duration,
+        update: impl FnOnce(&mut Option<Vec<u8>>) -> Result<(T, bool), TransactionError>,
+    ) -> Result<(T, Option<u64>), TransactionError> {
+        self.update_value_with_ttl(key, kind, Some(idle_ttl), update).await
+    }
+
+    async fn update_value_with_ttl<T>(
+        &self,
+        key: &str,
+        kind: RecordKind,
+        idle_ttl: Option<Duration>,
+        update: impl FnOnce(&mut Option<Vec<u8>>) -> Result<(T, bool), TransactionError>,
+    ) -> Result<(T, Option<u64>), TransactionError> {
+        let (_, mut locked_db) = self.write_key_shard(key).await?;
+
+        let Some(wrecord) = locked_db.records.get(key) else {
+            let mut value = None;
+            let (result, changed) = update(&mut value)?;
+            let Some(data) = value.filter(|_| changed) else {
+                return Ok((result, None));
+            };
+            let version = self.journal.record(ChangeKind::Set, Some(key));
+            let mut record = Record::typed(kind, data, idle_ttl);
+            if idle_ttl.is_some() {
+                record = record.with_sliding_ttl();
+            }
+            self.reindex(key, Some(&record));
+            let wrecord = WrappedRecord::new(self.clone(), key, record, version);
+            locked_db.records_mut().insert(key.to_owned(), wrecord);
+            return Ok((result, Some(version)));
+        };
+        wrecord.record.value(kind)?;
+
+        // taken out to be changed without a copy, records_mut counts it as a shard write
+        // even when nothing changes
+        let Some(wrecord) = locked_db.records_mut().get_mut(key) else {
+            return Err(TransactionError::RecordNotFound);
+        };
+        if let (Some(_), Some(ttl_policy)) = (idle_ttl, &wrecord.record.ttl_policy) {
+            ttl_policy.touch();
+        }
+        let mut value = Some(std::mem::take(&mut wrecord.record.data));
+        let outcome = update(&mut value);
+        let emptied = value.is_none();
+        wrecord.record.data = value.unwrap_or_default();
+
+        let (result, changed) = outcome?;
+        if !changed {
+            return Ok((result, Some(wrecord.version)));
+        }
+        if emptied {
+            // a list or set goes away with its last item
+            let prev = locked_db.records_mut().remove(key);
+            self.tombstones.bury(key, None);
+            self.journal.record(ChangeKind::Del, Some(key));
+            self.reindex(key, None);
+            if let Some(timer) = prev.and_then(|prev| prev.detatched_task_ch) {
+                let _ = timer.try_send(TTLResult::Cancelled);
+            }
+            return Ok((result, None));
+        }
+        wrecord.version = self.journal.record(ChangeKind::Set, Some(key));
+        self.reindex(key, Some(&wrecord.record));
+        Ok((result, Some(wrecord.version)))
+    }
+
+    /// Inserts or replaces a record as `condition` allows, returning its version.
     pub async fn set_record(
         &self,
         key: &str,
         client_record: Record,
-    ) -> Result<(), TransactionError> {
-        let shard_index = self.hash_key(key);
-        match self.0.get(shard_index) {
-            Some(shard) => {
-                let mut locked_db = shard.write().await;
-                let maybe_prev = locked_db.0.insert(
+        condition: SetCondition,
+    ) -> Result<u64, TransactionError> {
+        match self.write_key_shard(key).await {
+            Ok((_, mut locked_db)) => {
+                match (condition, locked_db.records.contains_key(key)) {
+                    (SetCondition::IfAbsent, true) => return Err(TransactionError::KeyExists),
+                    (SetCondition::IfExists, false) => return Err(TransactionError::RecordNotFound),
+                    _ => {}
+                }
+                let version = self.journal.record(ChangeKind::Set, Some(key));
+                self.reindex(key, Some(&client_record));
+                let maybe_prev = locked_db.records_mut().insert(
                     key.to_owned(),
-                    WrappedRecord::new(self.clone(), shard_index, key, client_record),
+                    WrappedRecord::new(self.clone(), key, client_record, version),
                 );
 
                 if let Some(prev) = maybe_prev {
                     if let Some(timer) = prev.detatched_task_ch {
-                        let _ = timer.send(TTLResult::Cancelled);
+                        let _ = timer.try_send(TTLResult::Cancelled);
                     }
                 }
-                return Ok(());
+                Ok(version)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replaces the record at `key` by the one `write` makes of the current record and its
+    /// version, `None` when missing, under the shard write lock. Returns the new version,
+    /// nothing is written when `write` fails.
+    pub(crate) async fn write_record(
+        &self,
+        key: &str,
+        write: impl FnOnce(Option<(&Record, u64)>) -> Result<Record, TransactionError>,
+    ) -> Result<u64, TransactionError> {
+        let (_, mut locked_db) = self.write_key_shard(key).await?;
+
+        let record = write(locked_db.records.get(key).map(|wrecord| (&wrecord.record, wrecord.version)))?;
+        let version = self.journal.record(ChangeKind::Set, Some(key));
+        self.reindex(key, Some(&record));
+        let maybe_prev = locked_db
+            .records_mut()
+            .insert(key.to_owned(), WrappedRecord::new(self.clone(), key, record, version));
+        if let Some(timer) = maybe_prev.and_then(|prev| prev.detatched_task_ch) {
+            let _ = timer.try_send(TTLResult::Cancelled);
+        }
+        Ok(version)
+    }
+
+    /// Inserts a batch of records, write locking every shard involved once, and returns
+    /// their versions in the order of `records`.
+    ///
+    /// Keys whose slot moves to another shard meanwhile are set one by one.
+    pub(crate) async fn set_many(&self, records: Vec<(String, Record)>) -> Result<Vec<u64>, TransactionError> {
+        let mut versions = vec![0; records.len()];
+        let mut moved = Vec::new();
+        for (shard_index, records) in self.group_by_shard(records.into_iter().enumerate().collect(), |(_, (key, _))| key) {
+            let mut locked_db = self.write_shard(shard_index).await?;
+            for (position, (key, record)) in records {
+                if !self.owns(shard_index, &key) {
+                    moved.push((position, (key, record)));
+                    continue;
+                }
+
+                let version = self.journal.record(ChangeKind::Set, Some(&key));
+                self.reindex(&key, Some(&record));
+                let wrecord = WrappedRecord::new(self.clone(), &key, record, version);
+                if let Some(prev) = locked_db.records_mut().insert(key, wrecord) {
+                    if let Some(timer) = prev.detatched_task_ch {
+                        let _ = timer.try_send(TTLResult::Cancelled);
+                    }
+                }
+                versions[position] = version;
+            }
+        }
+
+        for (position, (key, record)) in moved {
+            versions[position] = self.set_record(&key, record, SetCondition::Always).await?;
+        }
+        Ok(versions)
+    }
+
+    /// Lists the keys matching `pattern`, shard after shard, stopping at `limit` keys.
+    pub(crate) async fn matching_keys(&self, pattern: &Pattern, limit: usize) -> Vec<String> {
+        let _layout = self.layout_lock.read().await;
+
+        let mut keys = Vec::new();
+        for shard_index in 0..self.shard_count() {
+            if keys.len() >= limit {
+                break;
+            }
+            if let Ok(locked_shard) = self.read_shard(shard_index).await {
+                keys.extend(
+                    locked_shard
+                        .records
+                        .keys()
+                        .filter(|key| pattern.matches(key))
+                        .take(limit - keys.len())
+                        .cloned(),
+                );
             }
-            None => Err(TransactionError::ShardNotFound),
+            // let queued requests grab the shards between two scans
+            smol::future::yield_now().await;
         }
+        keys
     }
 
-    pub async fn remove_record(&self, key: &String) -> Result<(), TransactionError> {
-        match self.0.get(self.hash_key(key)) {
-            Some(shard) => {
-                let maybe_prev = shard.write().await.0.remove(key);
+    /// Looks at the next `count` keys from `cursor` on, moving to the following shards once
+    /// one is done, and returns the cursor to resume from with the ones matching `pattern`.
+    /// The returned cursor is back at the start once every shard has been looked at.
+    pub(crate) async fn scan(&self, mut cursor: Cursor, count: usize, pattern: Option<&Pattern>) -> (Cursor, Vec<String>) {
+        let mut keys = Vec::new();
+        let mut examined = 0;
+        while examined < count && cursor.shard < self.shard_count() {
+            let wanted = count - examined;
+            let next: Vec<String> = match self.read_shard(cursor.shard).await {
+                Ok(locked_shard) => {
+                    // the smallest keys past the cursor, without sorting the whole shard
+                    let mut smallest = BinaryHeap::with_capacity(wanted + 1);
+                    for key in locked_shard.records.keys() {
+                        if cursor.after.as_ref().is_none_or(|after| key > after) {
+                            smallest.push(key);
+                            if smallest.len() > wanted {
+                                smallest.pop();
+                            }
+                        }
+                    }
+                    smallest.into_sorted_vec().into_iter().cloned().collect()
+                }
+                Err(_) => Vec::new(),
+            };
+
+            examined += next.len();
+            cursor = match next.len() < wanted {
+                true => Cursor { shard: cursor.shard + 1, after: None },
+                false => Cursor { shard: cursor.shard, after: next.last().cloned() },
+            };
+            keys.extend(next.into_iter().filter(|key| pattern.is_none_or(|pattern| pattern.matches(key))));
+
+            // let queued requests grab the shards between two calls
+            smol::future::yield_now().await;
+        }
+
+        if cursor.shard >= self.shard_count() {
+            cursor = Cursor::default();
+        }
+        (cursor, keys)
+    }
+
+    /// Removes the keys matching `pattern`, returning how many were removed, or would be
+    /// with `dry_run`.
+    ///
+    /// Every shard is scanned under its read lock, then the matches are removed in batches
+    /// of short write locks; cancellation through the operations registry is checked
+    /// between batches. Slot migrations wait for the delete to finish.
+    pub(crate) async fn remove_matching(&self, pattern: &Pattern, dry_run: bool) -> Result<usize, TransactionError> {
+        let operation = self
+            .operations
+            .start("delete", format!("deleting keys matching {}", pattern));
+        let _layout = self.layout_lock.read().await;
+
+        let shard_count = self.shard_count();
+        let mut removed = 0;
+        for shard_index in 0..shard_count {
+            let matching: Vec<String> = self
+                .read_shard(shard_index)
+                .await?
+                .records
+                .keys()
+                .filter(|key| pattern.matches(key))
+                .cloned()
+                .collect();
+
+            if dry_run {
+                removed += matching.len();
+            } else {
+                for batch in matching.chunks(DELETE_BATCH_KEYS) {
+                    if operation.is_cancelled() {
+                        return Err(TransactionError::OperationCancelled);
+                    }
+                    let Ok(mut locked_shard) = self.write_shard(shard_index).await else {
+                        break;
+                    };
+                    for key in batch {
+                        // removed or expired since the scan
+                        if !locked_shard.records.contains_key(key) {
+                            continue;
+                        }
+                        if let Some(prev) = locked_shard.records_mut().remove(key) {
+                            self.journal.record(ChangeKind::Del, Some(key));
+                            self.reindex(key, None);
+                            self.tombstones.bury(key, None);
+                            if let Some(timer) = prev.detatched_task_ch {
+                                let _ = timer.try_send(TTLResult::Cancelled);
+                            }
+                            removed += 1;
+                        }
+                    }
+                    drop(locked_shard);
+
+                    // let queued requests grab the shard between batches
+                    smol::future::yield_now().await;
+                }
+            }
+            operation.progress(shard_index as u64 + 1, shard_count as u64);
+        }
+        Ok(removed)
+    }
+
+    /// Removes the records tagged with `tag`, returning how many were removed.
+    ///
+    /// Every shard holding some is write locked once, keys whose slot moves to another
+    /// shard meanwhile are removed one by one.
+    pub(crate) async fn remove_tagged(&self, tag: &str) -> Result<usize, TransactionError> {
+        let mut removed = 0;
+        let mut moved = Vec::new();
+        for (shard_index, keys) in self.group_by_shard(self.tags.keys(tag), |key| key) {
+            let mut locked_db = self.write_shard(shard_index).await?;
+            for key in keys {
+                if !self.owns(shard_index, &key) {
+                    moved.push(key);
+                } else if self.remove_if_tagged(&mut locked_db, &key, tag) {
+                    removed += 1;
+                }
+            }
+        }
+
+        for key in moved {
+            let (_, mut locked_db) = self.write_key_shard(&key).await?;
+            if self.remove_if_tagged(&mut locked_db, &key, tag) {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    // the record may have been replaced by an untagged one since the index was read
+    fn remove_if_tagged(&self, shard: &mut Shard, key: &str, tag: &str) -> bool {
+        let tagged = shard
+            .records
+            .get(key)
+            .is_some_and(|wrecord| wrecord.record.tags.iter().any(|record_tag| record_tag == tag));
+        if !tagged {
+            return false;
+        }
+        if let Some(prev) = shard.records_mut().remove(key) {
+            self.journal.record(ChangeKind::Del, Some(key));
+            self.reindex(key, None);
+            self.tombstones.bury(key, None);
+            if let Some(timer) = prev.detatched_task_ch {
+                let _ = timer.try_send(TTLResult::Cancelled);
+            }
+        }
+        true
+    }
+
+    /// Marks `key` as missing for `ttl`, the `--tombstone-ttl` by default, unless it exists.
+    pub(crate) async fn bury(&self, key: &str, ttl: Option<Duration>) -> Result<(), TransactionError> {
+        if !self.tombstones.is_enabled() {
+            return Err(TransactionError::TombstonesDisabled);
+        }
+        let (_, shard) = self.write_key_shard(key).await?;
+        if shard.records.contains_key(key) {
+            return Err(TransactionError::KeyExists);
+        }
+        self.tombstones.bury(key, ttl);
+        Ok(())
+    }
+
+    /// Forgets the tombstone of `key`, returning whether it had a live one.
+    pub(crate) fn unbury(&self, key: &str) -> Result<bool, TransactionError> {
+        match self.tombstones.is_enabled() {
+            true => Ok(self.tombstones.remove(key)),
+            false => Err(TransactionError::TombstonesDisabled),
+        }
+    }
+
+    /// Removes a record, returning whether it existed.
+    pub async fn remove_record(&self, key: &String) -> Result<bool, TransactionError> {
+        match self.write_key_shard(key).await {
+            Ok((_, mut shard)) => {
+                // a miss leaves the shard as it is, for backups and snapshots alike
+                let maybe_prev = match shard.records.contains_key(key.as_str()) {
+                    true => shard.records_mut().remove(key),
+                    false => None,
+                };
+                let existed = maybe_prev.is_some();
+                // deleting a missing key is telling it is missing too
+                self.tombstones.bury(key, None);
                 if let Some(prev) = maybe_prev {
+                    self.journal.record(ChangeKind::Del, Some(key));
+                    self.reindex(key, None);
                     if let Some(timer) = prev.detatched_task_ch {
-                        let _ = timer.send(TTLResult::Cancelled);
+                        let _ = timer.try_send(TTLResult::Cancelled);
                     }
                 }
 
-                Ok(())
+                Ok(existed)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes the record at `key` its primary expired, journaled and counted like an
+    /// expiration of this instance.
+    #[cfg(feature = "replication")]
+    pub(crate) async fn expire_record(&self, key: &str) -> Result<bool, TransactionError> {
+        let (_, mut shard) = self.write_key_shard(key).await?;
+        // a miss leaves the shard as it is, as in remove_record
+        if !shard.records.contains_key(key) {
+            return Ok(false);
+        }
+        shard.records_mut().remove(key);
+        self.journal.record(ChangeKind::Expired, Some(key));
+        self.reindex(key, None);
+        self.stats.expired();
+        Ok(true)
+    }
+
+    /// Removes the plain value at `key` and returns it, cancelling its TTL like
+    /// [`Storage::remove_record`]. Records of another type are left in place.
+    pub(crate) async fn take_record(&self, key: &str) -> Result<Record, TransactionError> {
+        let (_, mut shard) = self.write_key_shard(key).await?;
+
+        match shard.records.get(key) {
+            Some(wrecord) => wrecord.record.value(RecordKind::Bytes).map(|_| ())?,
+            None if self.tombstones.is_buried(key) => return Err(TransactionError::KnownMissing),
+            None => return Err(TransactionError::RecordNotFound),
+        }
+        let prev = shard.records_mut().remove(key).ok_or(TransactionError::RecordNotFound)?;
+        self.journal.record(ChangeKind::Del, Some(key));
+        self.reindex(key, None);
+        self.tombstones.bury(key, None);
+        if let Some(timer) = prev.detatched_task_ch {
+            let _ = timer.try_send(TTLResult::Cancelled);
+        }
+        Ok(prev.record)
+    }
+
+    /// Replaces the record at `key`, returning the plain value it held, `None` when
+    /// missing, with the new version. The TTL of the previous record is cancelled, a record
+    /// of another type is left in place.
+    pub(crate) async fn replace_record(&self, key: &str, record: Record) -> Result<(Option<Record>, u64), TransactionError> {
+        let mut previous = None;
+        let version = self
+            .write_record(key, |current| {
+                if let Some((current, _)) = current {
+                    current.value(RecordKind::Bytes)?;
+                    previous = Some(current.clone());
+                }
+                Ok(record)
+            })
+            .await?;
+        Ok((previous, version))
+    }
+
+    /// Has the backup loop run a cycle right away, once more at most when one is already
+    /// asked for.
+    #[cfg(feature = "backup")]
+    pub(crate) fn request_backup(&self) {
+        let _ = self.backup_requests.0.try_send(());
+    }
+
+    /// Removes records until their keys and values add up to `bytes`, the ones expiring
+    /// first before the others, returning how many were removed.
+    pub(crate) async fn evict(&self, bytes: u64) -> usize {
+        let mut freed = 0;
+        let mut evicted = 0;
+        for (key, _) in self.expiring(Duration::MAX, EVICTION_CANDIDATES).await {
+            if freed >= bytes {
+                return evicted;
+            }
+            let Ok((_, mut shard)) = self.write_key_shard(&key).await else {
+                continue;
+            };
+            // expired or removed since
+            if let Some(bytes) = self.evict_record(&mut shard, &key) {
+                freed += bytes;
+                evicted += 1;
+            }
+        }
+
+        for shard_index in 0..self.shard_count() {
+            if freed >= bytes {
+                break;
             }
-            None => Err(TransactionError::ShardNotFound),
+            let Ok(mut shard) = self.write_shard(shard_index).await else {
+                continue;
+            };
+            // in map order, as good as any without access times
+            let keys: Vec<String> = shard.records.keys().take(DELETE_BATCH_KEYS).cloned().collect();
+            for key in keys {
+                if freed >= bytes {
+                    break;
+                }
+                if let Some(bytes) = self.evict_record(&mut shard, &key) {
+                    freed += bytes;
+                    evicted += 1;
+                }
+            }
+        }
+        evicted
+    }
+
+    // bytes of key and value freed, `None` without a record
+    fn evict_record(&self, shard: &mut Shard, key: &str) -> Option<u64> {
+        if !shard.records.contains_key(key) {
+            return None;
         }
+        let prev = shard.records_mut().remove(key)?;
+        self.journal.record(ChangeKind::Del, Some(key));
+        self.reindex(key, None);
+        self.stats.evicted();
+        if let Some(timer) = prev.detatched_task_ch {
+            let _ = timer.try_send(TTLResult::Cancelled);
+        }
+        Some((key.len() + prev.record.data.len()) as u64)
+    }
+}
+
+fn lease_expired(record: &Record) -> bool {
+    record
+        .ttl_policy
+        .as_ref()
+        .is_some_and(|ttl_policy| ttl_policy.expire_in().is_zero())
+}
+
+// whether `token` holds the unexpired lease `name`
+fn check_lease(shard: &Shard, name: &str, token: u64) -> Result<(), TransactionError> {
+    let Some(wrecord) = shard.records.get(name) else {
+        return Err(TransactionError::LockNotHeld);
+    };
+    let held = wrecord.record.value(RecordKind::Lock)? == token.to_le_bytes();
+    if !held || lease_expired(&wrecord.record) {
+        return Err(TransactionError::LockNotHeld);
     }
+    Ok(())
 }
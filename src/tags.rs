@@ -0,0 +1,63 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+};
+
+/// Keys of every tag, kept up to date by every write of a record.
+#[derive(Debug, Default)]
+pub(crate) struct TagIndex {
+    // tagged keys, lets writes of untagged records skip the lock when none is
+    tagged: AtomicUsize,
+    index: RwLock<Tags>,
+}
+
+#[derive(Debug, Default)]
+struct Tags {
+    // tag -> keys
+    keys: HashMap<String, HashSet<String>>,
+    // key -> tags
+    tags: HashMap<String, Vec<String>>,
+}
+
+impl TagIndex {
+    /// Records `tags` as the tags of `key`, replacing the ones it had.
+    pub(crate) fn update(&self, key: &str, tags: &[String]) {
+        if tags.is_empty() && self.tagged.load(Ordering::Acquire) == 0 {
+            return;
+        }
+
+        let mut index = self.index.write().unwrap();
+        if let Some(previous) = index.tags.remove(key) {
+            for tag in previous {
+                if let Some(keys) = index.keys.get_mut(&tag) {
+                    keys.remove(key);
+                    if keys.is_empty() {
+                        index.keys.remove(&tag);
+                    }
+                }
+            }
+        }
+        if !tags.is_empty() {
+            for tag in tags {
+                index.keys.entry(tag.clone()).or_default().insert(key.to_owned());
+            }
+            index.tags.insert(key.to_owned(), tags.to_vec());
+        }
+        self.tagged.store(index.tags.len(), Ordering::Release);
+    }
+
+    /// Keys tagged with `tag`, sorted.
+    pub(crate) fn keys(&self, tag: &str) -> Vec<String> {
+        let index = self.index.read().unwrap();
+        let mut keys: Vec<String> = index
+            .keys
+            .get(tag)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default();
+        keys.sort_unstable();
+        keys
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+};
+
+use log::error;
+use smol::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    Async,
+};
+
+#[cfg(feature = "auth")]
+use crate::acl::{Acl, Grant};
+use crate::{http_query_parser::Query, middleware::is_admin_path, query_handler, storage::Storage};
+
+/// Settings shared by the connections of the text protocol listener.
+#[derive(Debug)]
+pub(crate) struct TextSettings {
+    // longest command line, its newline included
+    pub(crate) max_line: usize,
+    pub(crate) api_key: Option<String>,
+    pub(crate) admin_key: Option<String>,
+    #[cfg(feature = "auth")]
+    pub(crate) acl: Option<Arc<Acl>>,
+}
+
+// what a connection may run, keys not configured need no `AUTH`
+struct Session<'a> {
+    settings: &'a TextSettings,
+    api: bool,
+    admin: bool,
+    // with an acl, what the key sent by `AUTH` allows
+    #[cfg(feature = "auth")]
+    grant: Option<Arc<Grant>>,
+}
+
+impl<'a> Session<'a> {
+    fn new(settings: &'a TextSettings) -> Self {
+        #[cfg(feature = "auth")]
+        let open = settings.acl.is_none();
+        #[cfg(not(feature = "auth"))]
+        let open = true;
+        Self {
+            settings,
+            api: open && settings.api_key.is_none(),
+            admin: open && settings.admin_key.is_none(),
+            #[cfg(feature = "auth")]
+            grant: None,
+        }
+    }
+
+    fn auth(&mut self, key: &str) -> Vec<u8> {
+        // the grant then decides, command by command
+        #[cfg(feature = "auth")]
+        if let Some(acl) = &self.settings.acl {
+            self.grant = acl.grant(key);
+            self.api = self.grant.is_some();
+            self.admin = self.api;
+            return if self.api { ok(b"") } else { err("forbidden") };
+        }
+        let api = self.settings.api_key.as_deref() == Some(key);
+        let admin = self.settings.admin_key.as_deref() == Some(key);
+        self.api |= api;
+        self.admin |= admin;
+        if api || admin {
+            ok(b"")
+        } else {
+            err("forbidden")
+        }
+    }
+
+    async fn run(&self, line: &str, storage: &Storage) -> Vec<u8> {
+        if !self.api {
+            return err("forbidden");
+        }
+        let (url, query) = match Query::from_text(line) {
+            Ok(parsed) => parsed,
+            Err(e) => return err(e),
+        };
+        if !self.admin && is_admin_path(url.path()) {
+            return err("forbidden");
+        }
+        #[cfg(feature = "auth")]
+        if let Some(grant) = &self.grant {
+            if let Err(reason) = grant.check(is_admin_path(url.path()), query.is_write(), &query.keys()) {
+                return err(reason);
+            }
+        }
+
+        // never ends, the connection would be stuck on it
+        if matches!(query, Query::Subscribe { .. }) {
+            return err("subscribe_needs_http");
+        }
+        #[cfg(feature = "replication")]
+        if matches!(query, Query::Replicate { .. }) {
+            return err("replicate_needs_http");
+        }
+
+        storage.stats.command(query.name());
+        if let Some(key) = query.key() {
+            storage.stats.key_requested(key);
+        }
+        match query_handler::handle_query(query, storage.clone()).await {
+            Ok(output) => match output.body.into_bytes().await {
+                Ok(body) => ok(&body),
+                Err(e) => err(e),
+            },
+            Err(e) => err(e),
+        }
+    }
+}
+
+/// Serves a connection of the text protocol: one command per line, `GET foo` or `SET foo bar`,
+/// mirroring the HTTP routes. Replies are `OK <length>` followed by the response body on the
+/// next line, or `ERR <reason>`. `AUTH <key>` sends the api or admin key, `QUIT` closes.
+pub(crate) async fn handle_text_client(
+    stream: Async<TcpStream>,
+    address: SocketAddr,
+    storage: Storage,
+    settings: Arc<TextSettings>,
+) {
+    let _connection = storage.stats.connection_opened();
+    if let Err(e) = serve_commands(&stream, &storage, &settings).await {
+        error!("{} from {}", e, address);
+    }
+}
+
+async fn serve_commands(stream: &Async<TcpStream>, storage: &Storage, settings: &TextSettings) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut writer = stream;
+    let mut session = Session::new(settings);
+
+    let mut buff = Vec::new();
+    loop {
+        buff.clear();
+        let read = (&mut reader)
+            .take(settings.max_line as u64)
+            .read_until(b'\n', &mut buff)
+            .await?;
+        if read == 0 {
+            return Ok(());
+        }
+        // the rest of an overlong line would be taken for the next command
+        if read == settings.max_line && buff.last() != Some(&b'\n') {
+            writer.write_all(&err("body_too_large")).await?;
+            return Ok(());
+        }
+
+        let reply = match std::str::from_utf8(&buff).map(str::trim) {
+            Ok("") => continue,
+            Ok(line) => {
+                let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+                match command.to_ascii_uppercase().as_str() {
+                    "QUIT" => return Ok(()),
+                    "AUTH" => session.auth(argument.trim()),
+                    _ => session.run(line, storage).await,
+                }
+            }
+            Err(_) => err("unparsable_query"),
+        };
+        writer.write_all(&reply).await?;
+    }
+}
+
+fn ok(body: &[u8]) -> Vec<u8> {
+    let mut reply = format!("OK {}\n", body.len()).into_bytes();
+    reply.extend_from_slice(body);
+    reply.push(b'\n');
+    reply
+}
+
+fn err(reason: impl ToString) -> Vec<u8> {
+    format!("ERR {}\n", reason.to_string()).into_bytes()
+}
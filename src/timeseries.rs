@@ -0,0 +1,181 @@
+use std::{fmt::Write, time::Duration};
+
+// retention in milliseconds (u64, 0 to keep everything), little endian, then the samples
+// as a timestamp in milliseconds (u64) and a value (f64), by increasing timestamp
+const HEADER_LEN: usize = 8;
+const SAMPLE_LEN: usize = 8 + 8;
+
+/// How the samples of a bucket are folded into one.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Aggregation {
+    Avg,
+    Sum,
+    Min,
+    Max,
+    Count,
+    First,
+    Last,
+    // max - min
+    Range,
+}
+
+impl Aggregation {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "avg" => Some(Aggregation::Avg),
+            "sum" => Some(Aggregation::Sum),
+            "min" => Some(Aggregation::Min),
+            "max" => Some(Aggregation::Max),
+            "count" => Some(Aggregation::Count),
+            "first" => Some(Aggregation::First),
+            "last" => Some(Aggregation::Last),
+            "range" => Some(Aggregation::Range),
+            _ => None,
+        }
+    }
+
+    fn fold(&self, values: &[f64]) -> f64 {
+        let min = || values.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = || values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        match self {
+            Aggregation::Avg => values.iter().sum::<f64>() / values.len() as f64,
+            Aggregation::Sum => values.iter().sum(),
+            Aggregation::Min => min(),
+            Aggregation::Max => max(),
+            Aggregation::Count => values.len() as f64,
+            Aggregation::First => values[0],
+            Aggregation::Last => values[values.len() - 1],
+            Aggregation::Range => max() - min(),
+        }
+    }
+}
+
+/// An empty series, samples older than `retention` before the newest one being dropped.
+pub(crate) fn create(retention: Option<Duration>) -> Vec<u8> {
+    let retention_ms = retention.map_or(0, |retention| retention.as_millis().max(1) as u64);
+    retention_ms.to_le_bytes().to_vec()
+}
+
+/// Stores a sample, replacing the one with the same timestamp, and drops the samples
+/// that left the retention. Returns whether it was kept, `None` if `series` is not one.
+pub(crate) fn add(series: &mut Vec<u8>, timestamp: u64, value: f64) -> Option<bool> {
+    let samples = sample_count(series)?;
+    let retention = read_u64(series, 0);
+    let newest = (samples > 0).then(|| timestamp_at(series, samples - 1));
+    if retention > 0 && newest.is_some_and(|newest| timestamp < newest.saturating_sub(retention)) {
+        return Some(false);
+    }
+
+    let mut sample = [0; SAMPLE_LEN];
+    sample[..8].copy_from_slice(&timestamp.to_le_bytes());
+    sample[8..].copy_from_slice(&value.to_le_bytes());
+
+    // metrics mostly arrive in order, inserting at the end moves nothing
+    let index = partition(series, samples, |at| at < timestamp);
+    let at = HEADER_LEN + index * SAMPLE_LEN;
+    if index < samples && timestamp_at(series, index) == timestamp {
+        series[at..at + SAMPLE_LEN].copy_from_slice(&sample);
+    } else {
+        series.splice(at..at, sample);
+    }
+
+    if retention > 0 {
+        let samples = (series.len() - HEADER_LEN) / SAMPLE_LEN;
+        let cutoff = timestamp_at(series, samples - 1).saturating_sub(retention);
+        let expired = partition(series, samples, |at| at < cutoff);
+        series.drain(HEADER_LEN..HEADER_LEN + expired * SAMPLE_LEN);
+    }
+    Some(true)
+}
+
+/// The samples from `from` to `to` included, or with an aggregation one per `bucket`
+/// milliseconds holding samples, timestamped at the start of the bucket.
+/// `None` if `series` is not one.
+pub(crate) fn range(
+    series: &[u8],
+    from: u64,
+    to: u64,
+    aggregation: Option<(Aggregation, u64)>,
+) -> Option<Vec<(u64, f64)>> {
+    let samples = sample_count(series)?;
+    let start = partition(series, samples, |at| at < from);
+    let end = partition(series, samples, |at| at <= to);
+    let selected = (start..end.max(start)).map(|index| {
+        let at = HEADER_LEN + index * SAMPLE_LEN;
+        (read_u64(series, at), f64::from_bits(read_u64(series, at + 8)))
+    });
+
+    let Some((aggregation, bucket)) = aggregation else {
+        return Some(selected.collect());
+    };
+
+    let mut buckets = Vec::new();
+    let mut current: Option<(u64, Vec<f64>)> = None;
+    for (timestamp, value) in selected {
+        let bucket_start = timestamp - timestamp % bucket;
+        match &mut current {
+            Some((start, values)) if *start == bucket_start => values.push(value),
+            _ => {
+                if let Some((start, values)) = current.replace((bucket_start, vec![value])) {
+                    buckets.push((start, aggregation.fold(&values)));
+                }
+            }
+        }
+    }
+    if let Some((start, values)) = current {
+        buckets.push((start, aggregation.fold(&values)));
+    }
+    Some(buckets)
+}
+
+/// `retention`, `samples`, `first` and `last` lines. `None` if `series` is not one.
+pub(crate) fn info(series: &[u8]) -> Option<String> {
+    let samples = sample_count(series)?;
+    let mut info = format!("retention:{}ms\nsamples:{}\n", read_u64(series, 0), samples);
+    if samples > 0 {
+        let _ = write!(
+            info,
+            "first:{}\nlast:{}\n",
+            timestamp_at(series, 0),
+            timestamp_at(series, samples - 1)
+        );
+    }
+    Some(info)
+}
+
+/// `<timestamp> <value>` lines.
+pub(crate) fn render(samples: &[(u64, f64)]) -> String {
+    samples.iter().fold(String::new(), |mut lines, (timestamp, value)| {
+        let _ = writeln!(lines, "{} {}", timestamp, value);
+        lines
+    })
+}
+
+fn sample_count(series: &[u8]) -> Option<usize> {
+    let samples = series.len().checked_sub(HEADER_LEN)?;
+    (samples % SAMPLE_LEN == 0).then_some(samples / SAMPLE_LEN)
+}
+
+// index of the first sample whose timestamp fails `before`
+fn partition(series: &[u8], samples: usize, before: impl Fn(u64) -> bool) -> usize {
+    let (mut low, mut high) = (0, samples);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if before(timestamp_at(series, mid)) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+fn timestamp_at(series: &[u8], index: usize) -> u64 {
+    read_u64(series, HEADER_LEN + index * SAMPLE_LEN)
+}
+
+fn read_u64(series: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&series[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
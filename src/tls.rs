@@ -0,0 +1,39 @@
+//! HTTPS on the HTTP listener, turned on by `--tls-cert` and `--tls-key`, so API keys and
+//! values do not cross the network in clear. The text and memcached listeners stay plain.
+
+use std::{path::Path, sync::Arc};
+
+use futures_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+/// Acceptor presenting the PEM certificate chain at `cert`, leaf first, with the PEM
+/// private key at `key`.
+pub(crate) fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, String> {
+    let mut config = server_config(cert, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Configuration presenting the certificate chain at `cert` with the key at `key`, the
+/// protocols offered left to the listener.
+pub(crate) fn server_config(cert: &Path, key: &Path) -> Result<ServerConfig, String> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("unable to read --tls-cert {}: {}", cert.display(), e))?;
+    if chain.is_empty() {
+        return Err(format!("no certificate in --tls-cert {}", cert.display()));
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("unable to read --tls-key {}: {}", key.display(), e))?;
+
+    ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, private_key))
+        .map_err(|e| format!("invalid --tls-cert or --tls-key: {}", e))
+}
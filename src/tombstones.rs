@@ -0,0 +1,90 @@
+//! Negative caching: keys deleted, or marked as missing from the origin of a read-through
+//! cache, answer `known_missing` for a while instead of `record_not_found`, so a miss on
+//! them is not forwarded to the origin again by every client at once.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// tombstones kept before the expired ones are purged, grows with the live ones
+const MIN_PURGE_AT: usize = 1024;
+
+/// Keys known to be missing, each until its deadline. Off without a ttl.
+#[derive(Debug, Default)]
+pub(crate) struct Tombstones {
+    ttl: Option<Duration>,
+    inner: Mutex<TombstonesInner>,
+}
+
+#[derive(Debug, Default)]
+struct TombstonesInner {
+    deadlines: HashMap<String, Instant>,
+    purge_at: usize,
+}
+
+impl Tombstones {
+    pub(crate) fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            ttl,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.ttl.is_some()
+    }
+
+    /// Marks `key` as missing for `ttl`, the configured one by default.
+    pub(crate) fn bury(&self, key: &str, ttl: Option<Duration>) {
+        let Some(default_ttl) = self.ttl else {
+            return;
+        };
+        let ttl = ttl.unwrap_or(default_ttl);
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.deadlines.insert(key.to_string(), now + ttl);
+
+        // expired tombstones are only found again by a read of their key
+        if inner.deadlines.len() >= inner.purge_at.max(MIN_PURGE_AT) {
+            inner.deadlines.retain(|_, deadline| *deadline > now);
+            inner.purge_at = inner.deadlines.len() * 2;
+        }
+    }
+
+    /// Forgets the tombstone of `key`, written again or unmarked, returning whether it had
+    /// a live one.
+    pub(crate) fn remove(&self, key: &str) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        self.inner
+            .lock()
+            .unwrap()
+            .deadlines
+            .remove(key)
+            .is_some_and(|deadline| deadline > Instant::now())
+    }
+
+    pub(crate) fn is_buried(&self, key: &str) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        match inner.deadlines.get(key) {
+            Some(deadline) if *deadline > Instant::now() => true,
+            Some(_) => {
+                inner.deadlines.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        if self.is_enabled() {
+            self.inner.lock().unwrap().deadlines.clear();
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    fmt::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// tracking more items than listed keeps late risers from being evicted right away
+const TRACKED_PER_LISTED: usize = 2;
+pub(crate) const MAX_TOPK: u32 = 10_000;
+
+/// Heavy hitters of a stream, with the space saving algorithm: a bounded set of items is
+/// tracked, a new one replacing the least counted and inheriting its count. Counts may
+/// overestimate by what the replaced items had, items counted more than total / tracked
+/// times are always in.
+///
+/// With a window, counts are halved every time it elapses, so the list favours what was
+/// frequent lately.
+#[derive(Debug, Clone)]
+pub(crate) struct TopK {
+    k: u32,
+    window_ms: u64,
+    // milliseconds since the unix epoch of the last halving
+    decayed_at: u64,
+    // by descending count
+    entries: Vec<(String, u64)>,
+}
+
+impl TopK {
+    /// Lists `k` items, from 1 to [`MAX_TOPK`].
+    pub(crate) fn new(k: u32, window: Option<Duration>) -> Self {
+        Self {
+            k,
+            window_ms: window.map_or(0, |window| window.as_millis().max(1) as u64),
+            decayed_at: now_ms(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Counts `item` `increment` more times, returning the item it pushed out of the list.
+    pub(crate) fn add(&mut self, item: &str, increment: u64) -> Option<String> {
+        self.decay();
+        let k = self.k as usize;
+
+        let (index, was_listed) = match self.entries.iter().position(|(tracked, _)| tracked == item) {
+            Some(index) => {
+                self.entries[index].1 = self.entries[index].1.saturating_add(increment);
+                (index, index < k)
+            }
+            None if self.entries.len() < k * TRACKED_PER_LISTED => {
+                self.entries.push((item.to_string(), increment));
+                (self.entries.len() - 1, false)
+            }
+            None => {
+                let last = self.entries.len() - 1;
+                let inherited = self.entries[last].1;
+                self.entries[last] = (item.to_string(), inherited.saturating_add(increment));
+                (last, false)
+            }
+        };
+
+        // moves the item up, past the items it now outnumbers
+        let count = self.entries[index].1;
+        let position = self.entries[..index].partition_point(|(_, tracked)| *tracked >= count);
+        self.entries[position..=index].rotate_right(1);
+
+        (!was_listed && position < k)
+            .then(|| self.entries.get(k).map(|(expelled, _)| expelled.clone()))
+            .flatten()
+    }
+
+    /// The listed items with their count, most counted first.
+    pub(crate) fn list(&mut self) -> &[(String, u64)] {
+        self.decay();
+        &self.entries[..self.entries.len().min(self.k as usize)]
+    }
+
+    /// `k`, `window` and `tracked` lines.
+    pub(crate) fn info(&self) -> String {
+        format!(
+            "k:{}\nwindow:{}ms\ntracked:{}\n",
+            self.k,
+            self.window_ms,
+            self.entries.len()
+        )
+    }
+
+    fn decay(&mut self) {
+        if self.window_ms == 0 {
+            return;
+        }
+        let windows = now_ms().saturating_sub(self.decayed_at) / self.window_ms;
+        if windows == 0 {
+            return;
+        }
+
+        for (_, count) in &mut self.entries {
+            *count = count.checked_shr(windows.min(64) as u32).unwrap_or_default();
+        }
+        self.entries.retain(|(_, count)| *count > 0);
+        self.decayed_at += windows * self.window_ms;
+    }
+
+    /// k (u32), window (u64), last halving (u64) and entry count (u32), little endian,
+    /// then every entry as its count (u64), length (u32) and item.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.k.to_le_bytes());
+        bytes.extend_from_slice(&self.window_ms.to_le_bytes());
+        bytes.extend_from_slice(&self.decayed_at.to_le_bytes());
+        bytes.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for (item, count) in &self.entries {
+            bytes.extend_from_slice(&count.to_le_bytes());
+            bytes.extend_from_slice(&(item.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(item.as_bytes());
+        }
+        bytes
+    }
+
+    /// `None` if `bytes` do not hold one.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        let k = reader.u32()?;
+        let window_ms = reader.u64()?;
+        let decayed_at = reader.u64()?;
+        let entries = (0..reader.u32()?)
+            .map(|_| {
+                let count = reader.u64()?;
+                let len = reader.u32()? as usize;
+                let item = String::from_utf8(reader.take(len)?.to_vec()).ok()?;
+                Some((item, count))
+            })
+            .collect::<Option<_>>()?;
+
+        (k > 0 && reader.0.is_empty()).then_some(Self {
+            k,
+            window_ms,
+            decayed_at,
+            entries,
+        })
+    }
+}
+
+/// `<item> <count>` lines.
+pub(crate) fn render(entries: &[(String, u64)]) -> String {
+    entries.iter().fold(String::new(), |mut list, (item, count)| {
+        let _ = writeln!(list, "{} {}", item, count);
+        list
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = self.0.split_at_checked(len)?;
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
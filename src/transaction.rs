@@ -0,0 +1,195 @@
+use std::{collections::HashMap, time::Duration};
+
+use humantime::parse_duration;
+
+use crate::{
+    errors::{DeserializationError, TransactionError},
+    journal::ChangeKind,
+    record::{Record, RecordKind},
+};
+
+/// A write of an EXEC transaction.
+#[derive(Debug, Clone)]
+pub(crate) enum TxCommand {
+    Set { key: String, data: Vec<u8> },
+    Del { key: String },
+    IncrBy { key: String, delta: i64 },
+    IncrByFloat { key: String, increment: f64 },
+    Append { key: String, data: Vec<u8> },
+    Expire { key: String, ttl: Duration },
+    Persist { key: String },
+}
+
+impl TxCommand {
+    pub(crate) fn key(&self) -> &str {
+        match self {
+            TxCommand::Set { key, .. }
+            | TxCommand::Del { key }
+            | TxCommand::IncrBy { key, .. }
+            | TxCommand::IncrByFloat { key, .. }
+            | TxCommand::Append { key, .. }
+            | TxCommand::Expire { key, .. }
+            | TxCommand::Persist { key } => key,
+        }
+    }
+}
+
+/// The outcome of a transaction, computed before anything is written.
+#[derive(Debug, Default)]
+pub(crate) struct Staged {
+    // final record of every key changed, `None` once removed
+    pub(crate) records: HashMap<String, Option<Record>>,
+    // per command, the change to journal if any and its result, the version of the
+    // change when there is none
+    pub(crate) outcomes: Vec<(Option<ChangeKind>, Option<String>)>,
+}
+
+/// Parses one command per line: `SET <key> <value>`, the value running to the end of the
+/// line, `DEL <key>`, `INCR <key>`, `DECR <key>`, `INCRBY <key> <delta>`,
+/// `DECRBY <key> <delta>`, `INCRBYFLOAT <key> <increment>`, `APPEND <key> <value>`, the
+/// value running to the end of the line, `EXPIRE <key> <ttl>` and `PERSIST <key>`. Blank
+/// lines are skipped.
+pub(crate) fn parse(commands: &str) -> Result<Vec<TxCommand>, DeserializationError> {
+    let mut parsed = Vec::new();
+    for line in commands.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let mut tokens = line.splitn(3, ' ');
+        let command = tokens.next().unwrap_or_default().to_ascii_uppercase();
+        let key = tokens
+            .next()
+            .filter(|key| !key.is_empty())
+            .ok_or(DeserializationError::UnparsableQuery)?
+            .to_owned();
+        let argument = tokens.next();
+
+        parsed.push(match (command.as_str(), argument) {
+            ("SET", Some(value)) => TxCommand::Set {
+                key,
+                data: value.as_bytes().to_vec(),
+            },
+            ("DEL", None) => TxCommand::Del { key },
+            ("INCR", None) => TxCommand::IncrBy { key, delta: 1 },
+            ("DECR", None) => TxCommand::IncrBy { key, delta: -1 },
+            ("INCRBY", Some(delta)) => TxCommand::IncrBy {
+                key,
+                delta: delta.parse().map_err(|_| DeserializationError::UnparsableQuery)?,
+            },
+            ("DECRBY", Some(delta)) => TxCommand::IncrBy {
+                key,
+                delta: delta
+                    .parse::<i64>()
+                    .ok()
+                    .and_then(i64::checked_neg)
+                    .ok_or(DeserializationError::UnparsableQuery)?,
+            },
+            ("INCRBYFLOAT", Some(increment)) => TxCommand::IncrByFloat {
+                key,
+                increment: increment
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|increment| increment.is_finite())
+                    .ok_or(DeserializationError::UnparsableQuery)?,
+            },
+            ("APPEND", Some(value)) => TxCommand::Append {
+                key,
+                data: value.as_bytes().to_vec(),
+            },
+            ("EXPIRE", Some(ttl)) => TxCommand::Expire {
+                key,
+                ttl: parse_duration(ttl).map_err(|_| DeserializationError::UnparsableDuration)?,
+            },
+            ("PERSIST", None) => TxCommand::Persist { key },
+            _ => return Err(DeserializationError::UnparsableQuery),
+        });
+    }
+    if parsed.is_empty() {
+        return Err(DeserializationError::UnparsableQuery);
+    }
+    Ok(parsed)
+}
+
+/// Runs `commands` against the records `current` returns, each command seeing the
+/// changes of the previous ones. Fails on the first command failing, nothing is written.
+pub(crate) fn stage<'a>(
+    commands: &[TxCommand],
+    current: impl Fn(&str) -> Option<&'a Record>,
+) -> Result<Staged, TransactionError> {
+    let mut staged = Staged::default();
+    for command in commands {
+        let key = command.key();
+        let record = match staged.records.get(key) {
+            Some(record) => record.clone(),
+            None => current(key).cloned(),
+        };
+
+        let (change, result) = match command {
+            TxCommand::Set { data, .. } => (Some((Some(Record::new(data.clone(), None)), ChangeKind::Set)), None),
+            TxCommand::Del { .. } => match record {
+                Some(_) => (Some((None, ChangeKind::Del)), Some("1".to_string())),
+                None => (None, Some("0".to_string())),
+            },
+            TxCommand::IncrBy { delta, .. } => {
+                let current = match &record {
+                    Some(record) => std::str::from_utf8(record.value(RecordKind::Bytes)?)
+                        .ok()
+                        .and_then(|value| value.trim().parse::<i64>().ok())
+                        .ok_or(TransactionError::ValueNotAnInteger)?,
+                    None => 0,
+                };
+                let value = current.checked_add(*delta).ok_or(TransactionError::IncrementOverflow)?;
+                let mut record = record.unwrap_or_else(|| Record::new(Vec::new(), None));
+                record.data = value.to_string().into_bytes();
+                (Some((Some(record), ChangeKind::Set)), Some(value.to_string()))
+            }
+            TxCommand::IncrByFloat { increment, .. } => {
+                let current = match &record {
+                    Some(record) => std::str::from_utf8(record.value(RecordKind::Bytes)?)
+                        .ok()
+                        .and_then(|value| value.trim().parse::<f64>().ok())
+                        .filter(|value| value.is_finite())
+                        .ok_or(TransactionError::ValueNotAFloat)?,
+                    None => 0.0,
+                };
+                let value = current + increment;
+                if !value.is_finite() {
+                    return Err(TransactionError::IncrementOverflow);
+                }
+                // the ttl of the record is kept
+                let mut record = record.unwrap_or_else(|| Record::new(Vec::new(), None));
+                record.data = value.to_string().into_bytes();
+                (Some((Some(record), ChangeKind::Set)), Some(value.to_string()))
+            }
+            TxCommand::Append { data, .. } => {
+                let record = match record {
+                    Some(mut record) => {
+                        record.data = [record.value(RecordKind::Bytes)?, data].concat();
+                        record
+                    }
+                    None => Record::new(data.clone(), None),
+                };
+                let len = record.data.len();
+                (Some((Some(record), ChangeKind::Set)), Some(len.to_string()))
+            }
+            TxCommand::Expire { ttl, .. } => match record {
+                Some(mut record) => {
+                    record.update_ttl_policy(*ttl);
+                    (Some((Some(record), ChangeKind::Expire)), Some("1".to_string()))
+                }
+                None => (None, Some("0".to_string())),
+            },
+            TxCommand::Persist { .. } => match record {
+                Some(mut record) => {
+                    record.remove_ttl_policy();
+                    (Some((Some(record), ChangeKind::Persist)), Some("1".to_string()))
+                }
+                None => (None, Some("0".to_string())),
+            },
+        };
+
+        let change = change.map(|(record, kind)| {
+            staged.records.insert(key.to_owned(), record);
+            kind
+        });
+        staged.outcomes.push((change, result));
+    }
+    Ok(staged)
+}
@@ -0,0 +1,181 @@
+use std::fmt::Write;
+
+// dimension (u32) and metric (u8), little endian, then every entry as the length of its
+// id (u32), the id and `dimension` f32 components
+const HEADER_LEN: usize = 4 + 1;
+// like bloom filters, indexes stay within 512MB
+const MAX_INDEX_BYTES: usize = 512 * 1024 * 1024;
+pub(crate) const MAX_DIMENSION: u32 = 65_536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Metric {
+    /// Angle between vectors, stored normalized: `1 - cosine similarity`.
+    Cosine = 0,
+    /// Euclidean distance.
+    L2 = 1,
+}
+
+impl Metric {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "cosine" => Some(Metric::Cosine),
+            "l2" => Some(Metric::L2),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Metric::Cosine => "cosine",
+            Metric::L2 => "l2",
+        }
+    }
+
+    fn distance(&self, query: &[f32], stored: &[u8]) -> f32 {
+        let components = stored
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+        match self {
+            // both sides are unit vectors
+            Metric::Cosine => 1.0 - query.iter().zip(components).map(|(a, b)| a * b).sum::<f32>(),
+            Metric::L2 => query
+                .iter()
+                .zip(components)
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>()
+                .sqrt(),
+        }
+    }
+
+    /// The vector as stored and searched with: unit length for cosine. `None` for a zero
+    /// vector under cosine.
+    fn prepare(&self, mut vector: Vec<f32>) -> Option<Vec<f32>> {
+        if *self == Metric::Cosine {
+            let norm = vector.iter().map(|component| component * component).sum::<f32>().sqrt();
+            if norm == 0.0 || !norm.is_finite() {
+                return None;
+            }
+            vector.iter_mut().for_each(|component| *component /= norm);
+        }
+        Some(vector)
+    }
+}
+
+/// An empty index of `dimension` components vectors.
+pub(crate) fn create(dimension: u32, metric: Metric) -> Vec<u8> {
+    let mut index = dimension.to_le_bytes().to_vec();
+    index.push(metric as u8);
+    index
+}
+
+/// Stores `vector` under `id`, replacing the vector it had. Returns whether `id` is new,
+/// `None` if `vector` does not fit the index.
+pub(crate) fn add(index: &mut Vec<u8>, id: &str, vector: Vec<f32>) -> Option<bool> {
+    let (dimension, metric) = header(index)?;
+    if vector.len() != dimension as usize {
+        return None;
+    }
+    let vector = metric.prepare(vector)?;
+    let mut components = Vec::with_capacity(vector.len() * 4);
+    vector.iter().for_each(|component| components.extend_from_slice(&component.to_le_bytes()));
+
+    let found = entries(index)?.find(|(entry, _)| *entry == id).map(|(_, at)| at);
+    if let Some(at) = found {
+        index[at..at + components.len()].copy_from_slice(&components);
+        return Some(false);
+    }
+    if index.len() + 4 + id.len() + components.len() > MAX_INDEX_BYTES {
+        return None;
+    }
+    index.extend_from_slice(&(id.len() as u32).to_le_bytes());
+    index.extend_from_slice(id.as_bytes());
+    index.extend_from_slice(&components);
+    Some(true)
+}
+
+/// Removes the vector of `id`, returning whether there was one. `None` if `index` is not one.
+pub(crate) fn remove(index: &mut Vec<u8>, id: &str) -> Option<bool> {
+    let (dimension, _) = header(index)?;
+    let found = entries(index)?.find(|(entry, _)| *entry == id).map(|(_, at)| at);
+    let Some(at) = found else {
+        return Some(false);
+    };
+    index.drain(at - id.len() - 4..at + dimension as usize * 4);
+    Some(true)
+}
+
+/// The `k` stored vectors closest to `query` with their distance, closest first, by
+/// comparing it with all of them. `None` if `query` does not fit the index.
+pub(crate) fn search(index: &[u8], query: Vec<f32>, k: usize) -> Option<Vec<(String, f32)>> {
+    let (dimension, metric) = header(index)?;
+    if query.len() != dimension as usize {
+        return None;
+    }
+    let query = metric.prepare(query)?;
+
+    let vector_len = dimension as usize * 4;
+    let mut scored: Vec<(&str, f32)> = entries(index)?
+        .map(|(id, at)| (id, metric.distance(&query, &index[at..at + vector_len])))
+        .collect();
+    if k < scored.len() {
+        scored.select_nth_unstable_by(k, |a, b| a.1.total_cmp(&b.1));
+        scored.truncate(k);
+    }
+    scored.sort_by(|a, b| a.1.total_cmp(&b.1));
+    Some(scored.into_iter().map(|(id, distance)| (id.to_string(), distance)).collect())
+}
+
+/// `dimension`, `metric` and `vectors` lines. `None` if `index` is not one.
+pub(crate) fn info(index: &[u8]) -> Option<String> {
+    let (dimension, metric) = header(index)?;
+    Some(format!(
+        "dimension:{}\nmetric:{}\nvectors:{}\n",
+        dimension,
+        metric.name(),
+        entries(index)?.count()
+    ))
+}
+
+/// `<id> <distance>` lines.
+pub(crate) fn render(neighbours: &[(String, f32)]) -> String {
+    neighbours.iter().fold(String::new(), |mut lines, (id, distance)| {
+        let _ = writeln!(lines, "{} {}", id, distance);
+        lines
+    })
+}
+
+/// Components separated by commas or whitespace.
+pub(crate) fn parse(vector: &str) -> Option<Vec<f32>> {
+    vector
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|component| !component.is_empty())
+        .map(|component| component.parse::<f32>().ok().filter(|component| component.is_finite()))
+        .collect::<Option<Vec<_>>>()
+        .filter(|vector| !vector.is_empty())
+}
+
+fn header(index: &[u8]) -> Option<(u32, Metric)> {
+    let dimension = u32::from_le_bytes(index.get(..4)?.try_into().ok()?);
+    let metric = match index.get(4)? {
+        0 => Metric::Cosine,
+        1 => Metric::L2,
+        _ => return None,
+    };
+    Some((dimension, metric))
+}
+
+/// Every entry as its id and the offset of its vector. `None` if `index` is not one,
+/// entries past a truncated one are not listed.
+fn entries(index: &[u8]) -> Option<impl Iterator<Item = (&str, usize)>> {
+    let (dimension, _) = header(index)?;
+    let vector_len = dimension as usize * 4;
+    let mut at = HEADER_LEN;
+    Some(std::iter::from_fn(move || {
+        let id_len = u32::from_le_bytes(index.get(at..at + 4)?.try_into().ok()?) as usize;
+        let id = std::str::from_utf8(index.get(at + 4..at + 4 + id_len)?).ok()?;
+        let vector_at = at + 4 + id_len;
+        index.get(vector_at..vector_at + vector_len)?;
+        at = vector_at + vector_len;
+        Some((id, vector_at))
+    }))
+}
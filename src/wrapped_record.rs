@@ -8,12 +8,15 @@ use smol::{
     Timer,
 };
 
-use crate::{record::Record, storage::Storage};
+use crate::{journal::ChangeKind, record::Record, storage::Storage};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WrappedRecord {
     pub record: Record,
 
+    // logical clock: sequence number of the last change of the record
+    pub version: u64,
+
     #[serde(skip)]
     pub detatched_task_ch: Option<Sender<TTLResult>>,
 }
@@ -27,25 +30,32 @@ pub enum TTLResult {
 
 impl Display for TTLResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self)
+        match self {
+            TTLResult::Timout => write!(f, "timeout"),
+            TTLResult::Closed => write!(f, "closed"),
+            TTLResult::Cancelled => write!(f, "cancelled"),
+        }
     }
 }
 
 impl WrappedRecord {
-    pub fn new(db: Storage, shard_index: usize, key: &str, record: Record) -> WrappedRecord {
+    pub fn new(db: Storage, key: &str, record: Record, version: u64) -> WrappedRecord {
         match &record.ttl_policy {
             Some(ttl_policy) => {
                 let key: String = key.to_string();
-                let ttl = ttl_policy.ttl.clone();
-                let tc_s = create_ttl_check_channel(db, shard_index, key, ttl);
+                // restored records only have the remaining time left
+                let ttl = ttl_policy.expire_in();
+                let tc_s = create_ttl_check_channel(db, key, ttl);
 
                 WrappedRecord {
                     record,
-                    detatched_task_ch: Some(tc_s),
+                    version,
+                    detatched_task_ch: tc_s,
                 }
             }
             None => WrappedRecord {
                 record,
+                version,
                 detatched_task_ch: None,
             },
         }
@@ -55,25 +65,23 @@ impl WrappedRecord {
         &mut self,
         maybe_new_ttl: Option<Duration>,
         db: Storage,
-        shard_index: usize,
         key: String,
     ) {
         match maybe_new_ttl {
             Some(new_ttl) => {
                 if let Some(detatched_task_ch) = &self.detatched_task_ch {
-                    let _ = detatched_task_ch.send(TTLResult::Cancelled);
+                    let _ = detatched_task_ch.try_send(TTLResult::Cancelled);
                 }
                 //updating ttl
                 self.record.update_ttl_policy(new_ttl);
 
                 //creating new ttl channel
-                self.detatched_task_ch =
-                    Some(create_ttl_check_channel(db, shard_index, key, new_ttl));
+                self.detatched_task_ch = create_ttl_check_channel(db, key, new_ttl);
             }
             None => {
                 //cancelling previous ttl
                 if let Some(detatched_task_ch) = &self.detatched_task_ch {
-                    let _ = detatched_task_ch.send(TTLResult::Cancelled);
+                    let _ = detatched_task_ch.try_send(TTLResult::Cancelled);
                 }
 
                 self.record.remove_ttl_policy();
@@ -82,51 +90,69 @@ impl WrappedRecord {
     }
 }
 
+// no timer on a replica, the keys its primary expires come as deletions
 fn create_ttl_check_channel(
     db: Storage,
-    shard_index: usize,
     key: String,
     ttl: Duration,
-) -> Sender<TTLResult> {
+) -> Option<Sender<TTLResult>> {
+    #[cfg(feature = "replication")]
+    if db.is_replica() {
+        return None;
+    }
     let (tc_s, tc_r) = smol::channel::bounded::<TTLResult>(1);
 
-    smol::spawn(ttl_check(db, shard_index, key, tc_r, ttl)).detach();
-    tc_s
+    smol::spawn(ttl_check(db, key, tc_r, ttl)).detach();
+    Some(tc_s)
 }
 
 async fn ttl_check(
     storage: Storage,
-    shard_index: usize,
     key: String,
     detatched_task_ch: Receiver<TTLResult>,
-    ttl: Duration,
+    mut ttl: Duration,
 ) {
-    // waiting for 3 futures, the first that completes win:
-    // 1) if timer is cancelled or closed
-    // 2) if timer has timed out
-    let racing_result = race(
-        async {
-            match detatched_task_ch.recv().await {
-                Ok(cancelled) => cancelled,
-                Err(_) => TTLResult::Closed,
-            }
-        },
-        async {
-            Timer::after(ttl).await;
-            TTLResult::Timout
-        },
-    )
-    .await;
-
-    if let Some(shard) = storage.0.get(shard_index) {
+    loop {
+        // waiting for 3 futures, the first that completes win:
+        // 1) if timer is cancelled or closed
+        // 2) if timer has timed out
+        let racing_result = race(
+            async {
+                match detatched_task_ch.recv().await {
+                    Ok(cancelled) => cancelled,
+                    Err(_) => TTLResult::Closed,
+                }
+            },
+            async {
+                Timer::after(ttl).await;
+                TTLResult::Timout
+            },
+        )
+        .await;
+
         match racing_result {
-            // timer has timed out
+            // timer has timed out, the record may have moved to another shard in the meantime
             TTLResult::Timout => {
-                let mut locked_table = shard.write().await;
-                if let Some(wrecord) = locked_table.0.get(&key) {
-                    if let Some(_) = &wrecord.record.ttl_policy {
-                        debug!("timout occured, ttl is expired, removing key {}", key);
-                        let _prev = locked_table.0.remove(&key);
+                if let Ok((_, mut locked_table)) = storage.write_key_shard(&key).await {
+                    if let Some(wrecord) = locked_table.records.get(&key) {
+                        if let Some(ttl_policy) = &wrecord.record.ttl_policy {
+                            // read since the timer started, a sliding ttl runs out later
+                            let left = ttl_policy.expire_in();
+                            if ttl_policy.sliding && !left.is_zero() {
+                                ttl = left;
+                                continue;
+                            }
+                            #[cfg(feature = "chaos")]
+                            if storage.chaos.drop_expiration() {
+                                debug!("chaos: expiration of {} dropped", key);
+                                break;
+                            }
+                            debug!("timout occured, ttl is expired, removing key {}", key);
+                            let _prev = locked_table.records_mut().remove(&key);
+                            storage.journal.record(ChangeKind::Expired, Some(&key));
+                            storage.reindex(&key, None);
+                            storage.stats.expired();
+                        }
                     }
                 }
             }
@@ -135,5 +161,6 @@ async fn ttl_check(
             //channel is closed due to record drop
             TTLResult::Closed => debug!("channel closed for key {}", key),
         }
+        break;
     }
 }
@@ -0,0 +1,272 @@
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use clap::Parser;
+use mapper::{
+    client::MapperClient,
+    Mapper, MapperBuilder,
+};
+use smol::Timer;
+use tempfile::TempDir;
+
+/// Starts a server on a free port in a background thread, with `options` on top, waiting
+/// until it answers. Its backups go to the returned directory, removed once dropped at the
+/// end of the test.
+fn start_server(api_key: Option<&str>, options: &[&str]) -> (SocketAddr, TempDir) {
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let mut args = vec![
+        "mapper".to_string(),
+        "--address".to_string(),
+        address.to_string(),
+        "--logging-level".to_string(),
+        "error".to_string(),
+    ];
+    if let Some(api_key) = api_key {
+        args.extend(["--api-key".to_string(), api_key.to_string()]);
+    }
+    args.extend(options.iter().map(|option| option.to_string()));
+    let backup_path = TempDir::new().unwrap();
+    #[cfg(feature = "backup")]
+    args.extend(["--backup-path".to_string(), backup_path.path().display().to_string()]);
+
+    let mapper = Mapper::new(MapperBuilder::parse_from(args)).unwrap();
+    thread::spawn(move || smol::block_on(mapper.serve()));
+
+    smol::block_on(async {
+        let mut client = MapperClient::new(address);
+        if let Some(api_key) = api_key {
+            client = client.with_api_key(api_key);
+        }
+        for _ in 0..100 {
+            if client.ping().await.is_ok() {
+                return;
+            }
+            Timer::after(Duration::from_millis(20)).await;
+        }
+        panic!("server on {} did not start", address);
+    });
+    (address, backup_path)
+}
+
+/// Sends a request the client has no method for, returning the status line and body.
+fn request(address: SocketAddr, method: &str, path: &str, body: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        address,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+#[test]
+fn set_get_del() {
+    let (address, _backup_path) = start_server(None, &[]);
+    let client = MapperClient::new(address);
+    smol::block_on(async {
+        assert_eq!(client.get("missing").await.unwrap(), None);
+        assert!(!client.exists("missing").await.unwrap());
+
+        client.set("key", "value").await.unwrap();
+        assert_eq!(client.get("key").await.unwrap(), Some(b"value".to_vec()));
+        assert!(client.exists("key").await.unwrap());
+        assert_eq!(client.db_size().await.unwrap(), 1);
+
+        client.del("key").await.unwrap();
+        assert_eq!(client.get("key").await.unwrap(), None);
+        assert_eq!(client.db_size().await.unwrap(), 0);
+    });
+}
+
+#[test]
+fn versions_grow_with_every_change() {
+    let (address, _backup_path) = start_server(None, &[]);
+    let client = MapperClient::new(address);
+    smol::block_on(async {
+        let first = client.set("key", "a").await.unwrap();
+        let second = client.set("key", "b").await.unwrap();
+        let other = client.setex("other", "c", Duration::from_secs(60)).await.unwrap();
+        assert!(first < second);
+        assert!(second < other);
+    });
+}
+
+#[test]
+fn ttl_expire_and_persist() {
+    let (address, _backup_path) = start_server(None, &[]);
+    let client = MapperClient::new(address);
+    smol::block_on(async {
+        client.setex("key", "value", Duration::from_secs(60)).await.unwrap();
+        let ttl = client.ttl("key").await.unwrap().unwrap();
+        assert!(ttl <= Duration::from_secs(60) && ttl >= Duration::from_secs(58));
+
+        assert!(client.persist("key").await.unwrap());
+        assert_eq!(client.ttl("key").await.unwrap(), None);
+
+        assert!(client.expire("key", Duration::from_millis(100)).await.unwrap());
+        Timer::after(Duration::from_millis(300)).await;
+        assert_eq!(client.get("key").await.unwrap(), None);
+
+        assert!(!client.expire("missing", Duration::from_secs(1)).await.unwrap());
+        assert!(!client.persist("missing").await.unwrap());
+    });
+}
+
+#[test]
+fn keys_are_escaped() {
+    let (address, _backup_path) = start_server(None, &[]);
+    let client = MapperClient::new(address);
+    smol::block_on(async {
+        client.set("with space", "value").await.unwrap();
+        assert_eq!(client.get("with space").await.unwrap(), Some(b"value".to_vec()));
+    });
+}
+
+#[test]
+fn concurrent_requests_share_the_client() {
+    let (address, _backup_path) = start_server(None, &[]);
+    let client = Arc::new(MapperClient::new(address));
+    smol::block_on(async {
+        let writes: Vec<_> = (0..32)
+            .map(|i| {
+                let client = client.clone();
+                smol::spawn(async move { client.set(&format!("key{}", i), format!("value{}", i)).await })
+            })
+            .collect();
+        for write in writes {
+            write.await.unwrap();
+        }
+        assert_eq!(client.db_size().await.unwrap(), 32);
+
+        client.flush_all().await.unwrap();
+        assert_eq!(client.db_size().await.unwrap(), 0);
+    });
+}
+
+#[cfg(feature = "auth")]
+#[test]
+fn api_key_is_sent() {
+    use mapper::client::ClientError;
+
+    let (address, _backup_path) = start_server(Some("secret"), &[]);
+    smol::block_on(async {
+        let anonymous = MapperClient::new(address);
+        assert!(matches!(anonymous.ping().await, Err(ClientError::Forbidden)));
+
+        let wrong = MapperClient::new(address).with_api_key("wrong");
+        assert!(matches!(wrong.set("key", "value").await, Err(ClientError::Forbidden)));
+
+        let client = MapperClient::new(address).with_api_key("secret");
+        client.set("key", "value").await.unwrap();
+        assert_eq!(client.get("key").await.unwrap(), Some(b"value".to_vec()));
+    });
+}
+
+#[test]
+fn unchecked_api_keys_share_the_rate_limit_of_the_address() {
+    use mapper::client::ClientError;
+
+    let (address, _backup_path) = start_server(None, &["--rate-limit", "3/1m"]);
+    smol::block_on(async {
+        // a new key for every request, none of them checked without --api-key or --acl; the
+        // ping waiting for the server counts too
+        let mut answers = Vec::new();
+        for i in 0..3 {
+            answers.push(MapperClient::new(address).with_api_key(format!("key{}", i)).ping().await);
+        }
+        assert!(answers
+            .iter()
+            .any(|answer| matches!(answer, Err(ClientError::Rejected { status: 429, .. }))));
+    });
+}
+
+#[test]
+fn a_list_goes_away_with_its_last_item() {
+    let (address, _backup_path) = start_server(None, &[]);
+    let client = MapperClient::new(address);
+    smol::block_on(async {
+        request(address, "PUT", "/RPUSH/queue", "a\nb\n");
+        request(address, "GET", "/LMOVE/queue/inflight/LEFT/RIGHT", "");
+        assert!(client.exists("queue").await.unwrap());
+
+        let (status, body) = request(address, "GET", "/LPOP/queue", "");
+        assert!(status.contains("200"), "{}", status);
+        assert_eq!(body, "b\n");
+        assert!(!client.exists("queue").await.unwrap());
+
+        request(address, "GET", "/RPOPLPUSH/inflight/queue", "");
+        assert!(!client.exists("inflight").await.unwrap());
+        assert!(client.exists("queue").await.unwrap());
+    });
+}
+
+#[test]
+fn a_set_goes_away_with_its_last_member() {
+    let (address, _backup_path) = start_server(None, &[]);
+    let client = MapperClient::new(address);
+    smol::block_on(async {
+        request(address, "PUT", "/SADD/a", "x\n");
+        request(address, "PUT", "/SADD/b", "y\n");
+        request(address, "PUT", "/SUNIONSTORE/union", "a\nb\n");
+        assert!(client.exists("union").await.unwrap());
+
+        let (_, body) = request(address, "PUT", "/SREM/a", "x\n");
+        assert_eq!(body, "1");
+        assert!(!client.exists("a").await.unwrap());
+
+        request(address, "PUT", "/SINTERSTORE/union", "a\nb\n");
+        assert!(!client.exists("union").await.unwrap());
+    });
+}
+
+#[test]
+fn a_sorted_set_goes_away_with_its_last_member() {
+    let (address, _backup_path) = start_server(None, &[]);
+    let client = MapperClient::new(address);
+    smol::block_on(async {
+        request(address, "PUT", "/ZADD/board", "a 1\nb 2\n");
+        let (_, body) = request(address, "PUT", "/ZREM/board", "a\n");
+        assert_eq!(body, "1");
+        assert!(client.exists("board").await.unwrap());
+
+        let (_, body) = request(address, "GET", "/ZREMRANGEBYSCORE/board/-inf/+inf", "");
+        assert_eq!(body, "1");
+        assert!(!client.exists("board").await.unwrap());
+    });
+}
+
+#[test]
+fn a_hash_goes_away_with_its_last_field() {
+    let (address, _backup_path) = start_server(None, &[]);
+    let client = MapperClient::new(address);
+    smol::block_on(async {
+        request(address, "PUT", "/HSET/stats", "a 1\nb 2\nc 3\n");
+        let (_, body) = request(address, "PUT", "/HDEL/stats", "a\n");
+        assert_eq!(body, "1");
+        assert!(client.exists("stats").await.unwrap());
+
+        // a field past its deadline is dropped along with the one removed
+        request(address, "GET", "/HEXPIRE/stats/b/1ms", "");
+        Timer::after(Duration::from_millis(20)).await;
+        let (_, body) = request(address, "PUT", "/HDEL/stats", "c\n");
+        assert_eq!(body, "1");
+        assert!(!client.exists("stats").await.unwrap());
+    });
+}
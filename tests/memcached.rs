@@ -0,0 +1,73 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use clap::Parser;
+use mapper::{Mapper, MapperBuilder};
+use tempfile::TempDir;
+
+/// Starts a server with a memcached listener on a free port in a background thread, waiting
+/// until it accepts connections. Its backups go to the returned directory.
+fn start_server() -> (SocketAddr, TempDir) {
+    let free_address = || TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (address, memcached_address) = (free_address(), free_address());
+
+    let backup_path = TempDir::new().unwrap();
+    let mut args = vec![
+        "mapper".to_string(),
+        "--address".to_string(),
+        address.to_string(),
+        "--memcached-address".to_string(),
+        memcached_address.to_string(),
+        "--logging-level".to_string(),
+        "error".to_string(),
+    ];
+    if cfg!(feature = "backup") {
+        args.extend(["--backup-path".to_string(), backup_path.path().display().to_string()]);
+    }
+
+    let mapper = Mapper::new(MapperBuilder::parse_from(args)).unwrap();
+    thread::spawn(move || smol::block_on(mapper.serve()));
+
+    for _ in 0..100 {
+        if TcpStream::connect(memcached_address).is_ok() {
+            return (memcached_address, backup_path);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("memcached listener on {} did not start", memcached_address);
+}
+
+fn reply_line(reader: &mut impl BufRead) -> String {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    line
+}
+
+#[test]
+fn oversized_value_closes_the_connection() {
+    let (address, _backup_path) = start_server();
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    // a length overflowing once the trailing \r\n is counted
+    stream.write_all(b"set key 0 0 18446744073709551615\r\nvalue\r\n").unwrap();
+    let mut reader = BufReader::new(stream);
+    assert_eq!(reply_line(&mut reader), "SERVER_ERROR object too large for cache\r\n");
+    // the data block is not read as commands
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"set key 0 0 5\r\nvalue\r\nget key\r\n").unwrap();
+    let mut reader = BufReader::new(stream);
+    assert_eq!(reply_line(&mut reader), "STORED\r\n");
+    assert_eq!(reply_line(&mut reader), "VALUE key 0 5\r\n");
+    assert_eq!(reply_line(&mut reader), "value\r\n");
+    assert_eq!(reply_line(&mut reader), "END\r\n");
+}